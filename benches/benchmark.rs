@@ -2,23 +2,157 @@
 // performance regressions. To run them, use `cargo bench`.
 
 use rustic::trace::*;
+use rustic::asset::{SceneFile, World, dynamic_image_to_cpu_buffer, fallback_cpu_buffer};
+use rustic::denoise::{GpuATrous, atrous_denoise};
+use rustic::encode::encode_png;
+use rustic::kernel::KERNEL;
+use rustic::scenes::{SceneBuilder, diffuse};
 
 use criterion::{criterion_group, criterion_main, Criterion};
+use glam::{UVec3, Vec3, Vec4};
+use image::ImageEncoder;
+use shared_structs::CpuImage;
+
+// Terrain-like ground made of long diagonal strips, whose bounding boxes overlap almost everywhere, with a light above
+fn build_sliver_ground() -> World {
+    let mut builder = SceneBuilder::default();
+    let ground = builder.push_material(diffuse(Vec3::splat(0.8)));
+    for i in 0..500 {
+        let z = i as f32 * 0.1 - 25.0;
+        let corners = [Vec3::new(-25.0, 0.0, z), Vec3::new(25.0, 0.0, z + 25.0), Vec3::new(25.0, 0.0, z + 25.1), Vec3::new(-25.0, 0.0, z + 0.1)];
+        builder.push_quad(corners, Vec3::Y, ground);
+    }
+    let mut light = diffuse(Vec3::ONE);
+    light.emissive = Vec4::new(1.0, 1.0, 1.0, 10.0);
+    let light = builder.push_material(light);
+    builder.push_sphere(Vec3::new(0.0, 5.0, 0.0), 1.0, light);
+    builder.build()
+}
+
+// One sample per pixel of a world on the CPU, looking down at the ground at an angle
+fn render_world(world: &World, size: u32) -> f32 {
+    let config = TracingConfig {
+        width: size,
+        height: size,
+        cam_position: Vec4::new(0.0, 3.0, -10.0, 0.0),
+        cam_rotation: Vec4::new(0.4, 0.0, 0.0, 0.0),
+        ..Default::default()
+    };
+    let atlas_buffer = dynamic_image_to_cpu_buffer(world.atlas.clone());
+    let atlas = CpuImage::new(&atlas_buffer, world.atlas.width(), world.atlas.height());
+    let skybox_buffer = fallback_cpu_buffer();
+    let skybox = CpuImage::new(&skybox_buffer, 2, 2);
+    let mut total = 0.0;
+    for y in 0..size {
+        for x in 0..size {
+            let (radiance, ..) = kernels::trace_pixel(
+                UVec3::new(x, y, 1),
+                &config,
+                glam::UVec2::new(x, y),
+                &world.per_vertex_buffer,
+                &world.index_buffer,
+                &world.bvh.nodes,
+                &world.material_data_buffer,
+                &world.light_pick_buffer,
+                &world.light_tree_buffer,
+                &shared_structs::Sampler,
+                &atlas,
+                &skybox,
+                &mut kernels::stats::NoBounceStats,
+            );
+            total += radiance.x;
+        }
+    }
+    total
+}
 
 pub fn criterion_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("Performance regression tests");
     group.sample_size(10);
     group.bench_function("Startup time (GPU)", |b| { // 3.021s
-        b.iter(|| trace_gpu("scenes/BreakTime.glb", None, setup_trace(1280, 720, 0)))
+        b.iter(|| trace_gpu(&[SceneFile::new("scenes/BreakTime.glb")], None, Default::default(), setup_trace(1280, 720, 0)))
     });
     group.bench_function("Startup time (CPU)", |b| { // 2.855s
-        b.iter(|| trace_cpu("scenes/BreakTime.glb", None, setup_trace(1280, 720, 0)))
+        b.iter(|| trace_cpu(&[SceneFile::new("scenes/BreakTime.glb")], None, Default::default(), setup_trace(1280, 720, 0)))
     });
     group.bench_function("160 samples (GPU)", |b| { // 2.408s
-        b.iter(|| trace_gpu("scenes/DarkCornell.glb", None, setup_trace(1280, 720, 160)))
+        b.iter(|| trace_gpu(&[SceneFile::new("scenes/DarkCornell.glb")], None, Default::default(), setup_trace(1280, 720, 160)))
     });
     group.bench_function("32 samples (CPU)", |b| { // 12.891s
-        b.iter(|| trace_cpu("scenes/DarkCornell.glb", None, setup_trace(1280, 720, 32)))
+        b.iter(|| trace_cpu(&[SceneFile::new("scenes/DarkCornell.glb")], None, Default::default(), setup_trace(1280, 720, 32)))
+    });
+    group.finish();
+
+    // Dispatch overhead, on a render small enough that it dominates
+    let batched = |samples_per_dispatch| {
+        let state = setup_trace(128, 128, 1024);
+        state.config.write().samples_per_dispatch = samples_per_dispatch;
+        trace_gpu(&[SceneFile::new("scenes/DarkCornell.glb")], None, Default::default(), state)
+    };
+    let mut group = c.benchmark_group("Batching");
+    group.sample_size(10);
+    group.bench_function("128x128 1024 samples, 1 per dispatch (GPU)", |b| {
+        b.iter(|| batched(1))
+    });
+    group.bench_function("128x128 1024 samples, 8 per dispatch (GPU)", |b| {
+        b.iter(|| batched(8))
+    });
+    group.finish();
+
+    // Spatial splits against plain SAH, on a scene where regular bounding boxes overlap badly
+    let plain = build_sliver_ground();
+    let mut split = build_sliver_ground();
+    split.rebuild_with_spatial_splits();
+    let mut group = c.benchmark_group("BVH");
+    group.sample_size(10);
+    group.bench_function("Sliver ground build (SAH)", |b| {
+        b.iter(build_sliver_ground)
+    });
+    group.bench_function("Sliver ground build (SAH, then spatial splits)", |b| {
+        b.iter(|| build_sliver_ground().rebuild_with_spatial_splits())
+    });
+    group.bench_function("Sliver ground 128x128 sample (SAH)", |b| {
+        b.iter(|| render_world(&plain, 128))
+    });
+    group.bench_function("Sliver ground 128x128 sample (spatial splits)", |b| {
+        b.iter(|| render_world(&split, 128))
+    });
+    group.finish();
+
+    // À-Trous on the CPU and as GPU compute passes, including the upload and readback, against OIDN
+    let (width, height) = (1280, 720);
+    let noisy = (0..width * height * 3).map(|i| 0.5 + ((i * 7919) % 101) as f32 / 200.0).collect::<Vec<_>>();
+    let albedo = vec![0.8; width * height * 3];
+    let normal = (0..width * height * 3).map(|i| if i % 3 == 2 { 1.0 } else { 0.0 }).collect::<Vec<_>>();
+    let mut gpu_atrous = GpuATrous::new(KERNEL, width as u32, height as u32);
+    let mut group = c.benchmark_group("Denoising");
+    group.sample_size(10);
+    group.bench_function("720p À-Trous (CPU)", |b| {
+        b.iter(|| atrous_denoise(width, height, &mut noisy.clone(), &albedo, &normal))
+    });
+    group.bench_function("720p À-Trous (GPU)", |b| {
+        b.iter(|| gpu_atrous.denoise(&mut noisy.clone(), &albedo, &normal))
+    });
+    #[cfg(feature = "oidn")]
+    group.bench_function("720p OIDN", |b| {
+        b.iter(|| rustic::denoise::oidn_denoise(width, height, &mut noisy.clone()))
+    });
+    group.finish();
+
+    // Rendered images are mostly smooth, so a gradient is a more representative input than noise
+    let (width, height) = (3840, 2160);
+    let rgba = (0..width * height * 4).map(|i| ((i / 4) % width * 255 / width) as u8).collect::<Vec<_>>();
+    let mut group = c.benchmark_group("Image encoding");
+    group.sample_size(10);
+    group.bench_function("4K PNG (image crate)", |b| {
+        b.iter(|| {
+            let mut png = Vec::new();
+            image::codecs::png::PngEncoder::new(&mut png).write_image(&rgba, width, height, image::ColorType::Rgba8).unwrap();
+            png
+        })
+    });
+    group.bench_function("4K PNG (parallel strips)", |b| {
+        b.iter(|| encode_png(width, height, &rgba))
     });
     group.finish();
 }
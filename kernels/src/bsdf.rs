@@ -1,9 +1,9 @@
-use shared_structs::{MaterialData, TracingConfig};
+use shared_structs::{MaterialData, TracingConfig, WorkingSpace};
 use spirv_std::{glam::{Vec3, Vec2, Vec4Swizzles}};
 #[allow(unused_imports)]
 use spirv_std::num_traits::Float;
 
-use crate::{rng, util::{self}};
+use crate::{rng, util::{self}, texture};
 use shared_structs::{Image, Sampler};
 
 type Spectrum = Vec3;
@@ -13,7 +13,7 @@ type Spectrum = Vec3;
 pub enum LobeType {
     #[default] DiffuseReflection,
     SpecularReflection,
-    #[allow(dead_code)] DiffuseTransmission,
+    DiffuseTransmission,
     SpecularTransmission,
 }
 
@@ -182,23 +182,99 @@ const DIELECTRIC_IOR: f32 = 1.5;
 const DIELECTRIC_F0_SQRT: f32 = (DIELECTRIC_IOR - 1.0) / (DIELECTRIC_IOR + 1.0);
 const DIELECTRIC_F0: f32 = DIELECTRIC_F0_SQRT * DIELECTRIC_F0_SQRT;
 
+// The clearcoat is a colorless layer with an IOR of 1.5, whose strength is scaled down as in the Disney BRDF
+const CLEARCOAT_F0: f32 = 0.04;
+const CLEARCOAT_WEIGHT: f32 = 0.25;
+
+// Metallic-roughness BSDF, with the rest of the Disney principled parameters on top. At their defaults, it is a
+// Lambertian diffuse lobe under a GGX specular lobe. The diffuse lobe stays Lambertian rather than using Burley's
+// retro-reflective diffuse, which isn't energy conserving, so a white material still disappears in the furnace.
+// Sheen and subsurface are part of the diffuse lobe, and the clearcoat is part of the specular lobe. Anisotropy
+// stretches the GGX distribution along the tangent, for brushed metal, and needs a tangent to be present.
+// https://media.disneyanimation.com/uploads/production/publication_asset/48/asset/s2012_pbs_disney_brdf_notes_v3.pdf
 pub struct PBR {
     pub albedo: Spectrum,
     pub roughness: f32,
     pub metallic: f32,
     pub specular_weight_clamp: Vec2,
+    pub specular_color: Spectrum, // Reflectance of the dielectric base at normal incidence
+    pub sheen: Spectrum,
+    pub subsurface: f32,
+    pub clearcoat: f32,
+    pub clearcoat_alpha: f32,
+    pub anisotropy: f32,
+    pub tangent: Vec3, // Direction highlights are stretched in, not necessarily perpendicular to the normal
 }
 
 impl PBR {
+    fn is_anisotropic(&self) -> bool {
+        self.anisotropy > 0.0 && self.tangent != Vec3::ZERO
+    }
+
+    // Roughness along the tangent and bitangent, as in the Disney BRDF
+    fn anisotropic_alphas(&self) -> (f32, f32) {
+        let aspect = (1.0 - 0.9 * self.anisotropy).sqrt();
+        ((self.roughness / aspect).max(util::EPS), (self.roughness * aspect).max(util::EPS))
+    }
+
+    fn alphas(&self) -> (f32, f32) {
+        if self.is_anisotropic() {
+            self.anisotropic_alphas()
+        } else {
+            (self.roughness, self.roughness)
+        }
+    }
+
+    // Tangent and bitangent perpendicular to the normal, which may be bent by a normal map
+    fn tangent_frame(&self, normal: Vec3) -> (Vec3, Vec3) {
+        let tangent = (self.tangent - normal * normal.dot(self.tangent)).normalize_or_zero();
+        let tangent = if tangent == Vec3::ZERO { util::create_cartesian(normal).1 } else { tangent };
+        (tangent, normal.cross(tangent))
+    }
+
+    fn distribution(&self, normal: Vec3, halfway: Vec3) -> f32 {
+        if self.is_anisotropic() {
+            let (tangent, bitangent) = self.tangent_frame(normal);
+            let (alpha_x, alpha_y) = self.anisotropic_alphas();
+            util::anisotropic_ggx_distribution(normal, tangent, bitangent, halfway, alpha_x, alpha_y)
+        } else {
+            util::ggx_distribution(normal, halfway, self.roughness)
+        }
+    }
+
+    fn geometry(&self, normal: Vec3, view_direction: Vec3, sample_direction: Vec3) -> f32 {
+        if self.is_anisotropic() {
+            let (tangent, bitangent) = self.tangent_frame(normal);
+            let (alpha_x, alpha_y) = self.anisotropic_alphas();
+            util::geometry_anisotropic_ggx(normal, tangent, bitangent, view_direction, alpha_x, alpha_y)
+                * util::geometry_anisotropic_ggx(normal, tangent, bitangent, sample_direction, alpha_x, alpha_y)
+        } else {
+            util::geometry_smith_schlick_ggx(normal, view_direction, sample_direction, self.roughness)
+        }
+    }
+
     fn evaluate_diffuse_fast(
         &self,
         cos_theta: f32,
+        n_dot_v: f32,
+        l_dot_h: f32,
         specular_weight: f32,
         ks: Vec3,
     ) -> Spectrum {
         let kd = (Vec3::splat(1.0) - ks) * (1.0 - self.metallic);
-        let diffuse = kd * self.albedo / core::f32::consts::PI;
-        diffuse * cos_theta / (1.0 - specular_weight)
+        let mut diffuse = kd * self.albedo / core::f32::consts::PI;
+        if self.subsurface > 0.0 {
+            diffuse *= util::lerp(1.0, self.subsurface_factor(cos_theta, n_dot_v, l_dot_h), self.subsurface);
+        }
+        let sheen = self.sheen * (1.0 - l_dot_h).powi(5) * (1.0 - self.metallic);
+        (diffuse + sheen) * cos_theta / (1.0 - specular_weight)
+    }
+
+    // The Hanrahan-Krueger inspired flattening of the diffuse lobe from the Disney BRDF, relative to Lambertian
+    fn subsurface_factor(&self, n_dot_l: f32, n_dot_v: f32, l_dot_h: f32) -> f32 {
+        let fss90 = l_dot_h * l_dot_h * self.roughness;
+        let fss = util::lerp(1.0, fss90, (1.0 - n_dot_l).powi(5)) * util::lerp(1.0, fss90, (1.0 - n_dot_v).powi(5));
+        1.25 * (fss * (1.0 / (n_dot_l + n_dot_v).max(util::EPS) - 0.5) + 0.5)
     }
 
     fn evaluate_specular_fast(
@@ -211,13 +287,29 @@ impl PBR {
         specular_weight: f32,
         ks: Vec3,
     ) -> Spectrum {
-        let g_term = util::geometry_smith_schlick_ggx(normal, view_direction, sample_direction, self.roughness);
+        let g_term = self.geometry(normal, view_direction, sample_direction);
         let specular_numerator = d_term * g_term * ks;
         let specular_denominator = 4.0 * normal.dot(view_direction).max(0.0) * cos_theta;
-        let specular = specular_numerator / specular_denominator.max(util::EPS);
+        let mut specular = specular_numerator / specular_denominator.max(util::EPS);
+        if self.clearcoat > 0.0 {
+            specular += Vec3::splat(self.evaluate_clearcoat(view_direction, normal, sample_direction));
+        }
         specular * cos_theta / specular_weight
     }
 
+    fn evaluate_clearcoat(&self, view_direction: Vec3, normal: Vec3, sample_direction: Vec3) -> f32 {
+        let halfway = (view_direction + sample_direction).normalize();
+        let d_term = util::gtr1_distribution(normal.dot(halfway).max(0.0), self.clearcoat_alpha);
+        let fresnel = util::lerp(CLEARCOAT_F0, 1.0, (1.0 - halfway.dot(sample_direction).max(0.0)).powi(5));
+        let g_term = util::smith_g_ggx(normal.dot(sample_direction).max(0.0), 0.25) * util::smith_g_ggx(normal.dot(view_direction).max(0.0), 0.25);
+        CLEARCOAT_WEIGHT * self.clearcoat * d_term * fresnel * g_term
+    }
+
+    // Chance of sampling the clearcoat rather than the base, once the specular lobe has been picked
+    fn clearcoat_probability(&self) -> f32 {
+        0.5 * self.clearcoat
+    }
+
     fn pdf_diffuse_fast(&self, cos_theta: f32) -> f32 {
         cos_theta / core::f32::consts::PI
     }
@@ -229,7 +321,17 @@ impl PBR {
         halfway: Vec3,
         d_term: f32,
     ) -> f32 {
-        (d_term * normal.dot(halfway)) / (4.0 * view_direction.dot(halfway))
+        let (tangent, bitangent) = self.tangent_frame(normal);
+        let (alpha_x, alpha_y) = self.alphas();
+        let masking = util::geometry_anisotropic_ggx(normal, tangent, bitangent, view_direction, alpha_x, alpha_y);
+        let base = util::ggx_vndf_pdf_reflection(normal, view_direction, d_term, masking);
+        if self.clearcoat > 0.0 {
+            let n_dot_h = normal.dot(halfway).max(0.0);
+            let clearcoat = util::gtr1_distribution(n_dot_h, self.clearcoat_alpha) * n_dot_h / (4.0 * view_direction.dot(halfway)).max(util::EPS);
+            util::lerp(base, clearcoat, self.clearcoat_probability())
+        } else {
+            base
+        }
     }
 }
 
@@ -250,13 +352,15 @@ impl BSDF for PBR {
         let cos_theta = normal.dot(sample_direction).max(0.0);
         let halfway = (view_direction + sample_direction).normalize();
 
-        let f0 = Vec3::splat(DIELECTRIC_F0).lerp(self.albedo, self.metallic);
+        let f0 = self.specular_color.lerp(self.albedo, self.metallic);
         let ks = util::fresnel_schlick(halfway.dot(view_direction).max(0.0), f0);
 
         if lobe_type == LobeType::DiffuseReflection {
-            self.evaluate_diffuse_fast(cos_theta, specular_weight, ks)
+            let n_dot_v = normal.dot(view_direction).max(0.0);
+            let l_dot_h = halfway.dot(sample_direction).max(0.0);
+            self.evaluate_diffuse_fast(cos_theta, n_dot_v, l_dot_h, specular_weight, ks)
         } else {
-            let d_term = util::ggx_distribution(normal, halfway, self.roughness);
+            let d_term = self.distribution(normal, halfway);
             self.evaluate_specular_fast(
                 view_direction,
                 normal,
@@ -290,28 +394,33 @@ impl BSDF for PBR {
             .normalize();
             (sampled_direction, LobeType::DiffuseReflection)
         } else {
-            let reflection_direction = util::reflect(-view_direction, normal);
-            let sampled_direction = util::sample_ggx(
-                rng_sample.x,
-                rng_sample.y,
-                reflection_direction,
-                self.roughness,
-            );
+            // The specular lobe was picked with rng_sample.z below the specular weight, so scaled, it picks the layer
+            let sampled_direction = if self.clearcoat > 0.0 && rng_sample.z < specular_weight * self.clearcoat_probability() {
+                let halfway = util::sample_gtr1(rng_sample.x, rng_sample.y, normal, self.clearcoat_alpha);
+                util::reflect(-view_direction, halfway)
+            } else {
+                let (tangent, bitangent) = self.tangent_frame(normal);
+                let (alpha_x, alpha_y) = self.alphas();
+                let halfway = util::sample_ggx_vndf(rng_sample.x, rng_sample.y, view_direction, normal, tangent, bitangent, alpha_x, alpha_y);
+                util::reflect(-view_direction, halfway)
+            };
             (sampled_direction, LobeType::SpecularReflection)
         };
 
         let cos_theta = normal.dot(sampled_direction).max(util::EPS);
         let halfway = (view_direction + sampled_direction).normalize();
 
-        let f0 = Vec3::splat(DIELECTRIC_F0).lerp(self.albedo, self.metallic);
+        let f0 = self.specular_color.lerp(self.albedo, self.metallic);
         let ks = util::fresnel_schlick(halfway.dot(view_direction).max(0.0), f0);
 
         let (sampled_direction, sampled_lobe, pdf, spectrum) = if sampled_lobe == LobeType::DiffuseReflection {
             let pdf = self.pdf_diffuse_fast(cos_theta);
-            let spectrum = self.evaluate_diffuse_fast(cos_theta, specular_weight, ks);
+            let n_dot_v = normal.dot(view_direction).max(0.0);
+            let l_dot_h = halfway.dot(sampled_direction).max(0.0);
+            let spectrum = self.evaluate_diffuse_fast(cos_theta, n_dot_v, l_dot_h, specular_weight, ks);
             (sampled_direction, LobeType::DiffuseReflection, pdf, spectrum)
         } else {
-            let d_term = util::ggx_distribution(normal, halfway, self.roughness);
+            let d_term = self.distribution(normal, halfway);
             let pdf = self.pdf_specular_fast(view_direction, normal, halfway, d_term);
             let spectrum = self.evaluate_specular_fast(
                 view_direction,
@@ -345,30 +454,38 @@ impl BSDF for PBR {
             self.pdf_diffuse_fast(cos_theta)
         } else {
             let halfway = (view_direction + sample_direction).normalize();
-            let d_term = util::ggx_distribution(normal, halfway, self.roughness);
+            let d_term = self.distribution(normal, halfway);
             self.pdf_specular_fast(view_direction, normal, halfway, d_term)
         }
     }
 }
 
-pub fn get_pbr_bsdf(config: &TracingConfig, material: &MaterialData, uv: Vec2, atlas: &Image!(2D, type=f32, sampled), sampler: &Sampler) -> PBR {
-    let albedo = if material.has_albedo_texture() {
-        let scaled_uv = material.albedo.xy() + uv * material.albedo.zw();
-        let albedo = atlas.sample_by_lod(*sampler, scaled_uv, 0.0);
+// Dielectric glass with the same tint and roughness as the PBR BSDF of the material
+pub fn get_glass_bsdf(pbr: &PBR) -> Glass {
+    Glass {
+        albedo: pbr.albedo,
+        ior: DIELECTRIC_IOR,
+        roughness: pbr.roughness,
+    }
+}
+
+// The vertex color multiplies the albedo, so it tints textured and untextured materials alike
+pub fn get_pbr_bsdf(config: &TracingConfig, material: &MaterialData, uv: Vec2, tangent: Vec3, vertex_color: Vec3, lod: f32, atlas: &Image!(2D, type=f32, sampled), sampler: &Sampler) -> PBR {
+    let albedo_srgb = if material.has_albedo_texture() {
+        let albedo = texture::sample_atlas(atlas, sampler, material.albedo, uv, lod);
         albedo.xyz()
     } else {
         material.albedo.xyz()
-    };
+    } * vertex_color;
+    let albedo = WorkingSpace::from_u32(config.working_space).from_linear_srgb(albedo_srgb);
     let roughness = if material.has_roughness_texture() {
-        let scaled_uv = material.roughness.xy() + uv * material.roughness.zw();
-        let roughness = atlas.sample_by_lod(*sampler, scaled_uv, 0.0);
+        let roughness = texture::sample_atlas(atlas, sampler, material.roughness, uv, lod);
         roughness.x
     } else {
         material.roughness.x
     };
     let metallic = if material.has_metallic_texture() {
-        let scaled_uv = material.metallic.xy() + uv * material.metallic.zw();
-        let metallic = atlas.sample_by_lod(*sampler, scaled_uv, 0.0);
+        let metallic = texture::sample_atlas(atlas, sampler, material.metallic, uv, lod);
         metallic.x
     } else {
         material.metallic.x
@@ -378,10 +495,22 @@ pub fn get_pbr_bsdf(config: &TracingConfig, material: &MaterialData, uv: Vec2, a
     let roughness = roughness.max(util::EPS);
     let metallic = metallic.min(1.0 - util::EPS);
 
+    // Tinting uses the hue of the albedo, with its luminance divided out
+    let luminance = albedo_srgb.dot(Vec3::new(0.2126, 0.7152, 0.0722));
+    let tint = if luminance > 0.0 { albedo / luminance } else { Vec3::ONE };
+
     PBR {
         albedo,
         roughness,
         metallic,
         specular_weight_clamp: config.specular_weight_clamp,
+        specular_color: Vec3::splat(DIELECTRIC_F0 * 2.0 * material.specular) * Vec3::ONE.lerp(tint, material.specular_tint),
+        sheen: material.sheen * Vec3::ONE.lerp(tint, material.sheen_tint),
+        // With a radius, subsurface is the chance of a random walk instead, and the surface itself stays Lambertian
+        subsurface: if material.has_subsurface_scattering() { 0.0 } else { material.subsurface },
+        clearcoat: material.clearcoat,
+        clearcoat_alpha: util::lerp(0.1, 0.001, material.clearcoat_gloss),
+        anisotropy: material.anisotropy,
+        tangent,
     }
-}
\ No newline at end of file
+}
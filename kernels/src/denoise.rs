@@ -0,0 +1,59 @@
+use shared_structs::ATrousConfig;
+use spirv_std::glam::{Vec3, Vec4, Vec4Swizzles};
+#[allow(unused_imports)]
+use spirv_std::num_traits::Float;
+
+pub const ATROUS_ITERATIONS: u32 = 5;
+const ATROUS_KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+const ATROUS_COLOR_PHI: f32 = 1.0;
+const ATROUS_NORMAL_PHI: f32 = 0.1;
+const ATROUS_ALBEDO_PHI: f32 = 0.1;
+
+// Config of the given iteration of the filter, counting from 0. The step size doubles each iteration, and the color
+// weight tightens as the image gets smoother.
+pub fn atrous_config(width: u32, height: u32, iteration: u32) -> ATrousConfig {
+    let step = 1 << iteration;
+    ATrousConfig {
+        width,
+        height,
+        step,
+        color_phi: ATROUS_COLOR_PHI / step as f32,
+    }
+}
+
+fn edge_weight(p: Vec4, q: Vec4, phi: f32) -> f32 {
+    let difference = p.xyz() - q.xyz();
+    (-difference.dot(difference) / phi).exp()
+}
+
+// One iteration of the edge-avoiding À-Trous wavelet filter (Dammertz et al. 2010) at a pixel. It applies a 5x5 B3
+// spline kernel with holes, so the footprint grows exponentially at constant cost over the iterations. Taps are
+// weighted down by their difference in color, normal and albedo to preserve edges. Shared by the GPU passes and the
+// CPU fallback, so both filter the same way.
+pub fn atrous_pixel(x: u32, y: u32, config: &ATrousConfig, src: &[Vec4], albedo: &[Vec4], normal: &[Vec4]) -> Vec4 {
+    let p = (y * config.width + x) as usize;
+    let mut sum = Vec3::ZERO;
+    let mut total_weight = 0.0;
+    for j in 0..5 {
+        let sy = y as i32 + (j as i32 - 2) * config.step as i32;
+        if sy < 0 || sy >= config.height as i32 {
+            continue;
+        }
+        for i in 0..5 {
+            let sx = x as i32 + (i as i32 - 2) * config.step as i32;
+            if sx < 0 || sx >= config.width as i32 {
+                continue;
+            }
+            let q = (sy as u32 * config.width + sx as u32) as usize;
+            let weight = ATROUS_KERNEL[i]
+                * ATROUS_KERNEL[j]
+                * edge_weight(src[p], src[q], config.color_phi)
+                * edge_weight(normal[p], normal[q], ATROUS_NORMAL_PHI)
+                * edge_weight(albedo[p], albedo[q], ATROUS_ALBEDO_PHI);
+            sum += src[q].xyz() * weight;
+            total_weight += weight;
+        }
+    }
+    // The center tap always has weight, so this never divides by zero
+    (sum / total_weight).extend(src[p].w)
+}
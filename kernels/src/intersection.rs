@@ -1,64 +1,160 @@
-use shared_structs::{BVHNode, PerVertexData};
+use shared_structs::{BVHNode, PerVertexData, PrimitiveType, PackedPrimitive};
 #[allow(unused_imports)]
 use spirv_std::num_traits::Float;
-use spirv_std::{glam::{UVec4, Vec4, Vec3, Vec4Swizzles}, num_traits::Signed};
+use spirv_std::{glam::{UVec4, Vec3}, num_traits::Signed};
 
 use crate::vec::FixedVec;
 
-// Adapted from raytri.c
-fn muller_trumbore(ro: Vec3, rd: Vec3, a: Vec3, b: Vec3, c: Vec3, out_t: &mut f32, out_backface: &mut bool) -> bool
+fn axis(v: Vec3, index: usize) -> f32 {
+    match index {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+// Watertight ray-triangle intersection, from "Watertight Ray/Triangle Intersection" (Woop et al. 2013).
+// Vertices are transformed into a space where the ray starts at the origin and points down +z, so the edge tests
+// become 2D and are evaluated identically for both triangles sharing an edge. Hence rays can't slip through seams.
+// The paper falls back to double precision when an edge function is exactly 0, but we can't rely on f64 support on
+// the GPU. Treating 0 as inside instead means seam hits may be reported twice, which is harmless.
+fn intersect_triangle(ro: Vec3, rd: Vec3, a: Vec3, b: Vec3, c: Vec3, out_t: &mut f32, out_backface: &mut bool) -> bool
 {
     *out_t = 0.0;
 
-    let edge1 = b - a;
-    let edge2 = c - a;
- 
-    // begin calculating determinant - also used to calculate U parameter
-    let pv = rd.cross(edge2);
- 
-    // if determinant is near zero, ray lies in plane of triangle
-    let det = edge1.dot(pv);
-    *out_backface = det.is_negative();
+    // Use the dimension where the ray direction is largest as z
+    let abs_dir = rd.abs();
+    let kz = if abs_dir.x > abs_dir.y && abs_dir.x > abs_dir.z {
+        0
+    } else if abs_dir.y > abs_dir.z {
+        1
+    } else {
+        2
+    };
+    let mut kx = if kz == 2 { 0 } else { kz + 1 };
+    let mut ky = if kx == 2 { 0 } else { kx + 1 };
+
+    // Swap x and y to preserve the winding direction of triangles
+    if axis(rd, kz) < 0.0 {
+        core::mem::swap(&mut kx, &mut ky);
+    }
+
+    // Shear constants
+    let sz = 1.0 / axis(rd, kz);
+    let sx = axis(rd, kx) * sz;
+    let sy = axis(rd, ky) * sz;
+
+    // Vertices relative to the ray origin, sheared and scaled
+    let a = a - ro;
+    let b = b - ro;
+    let c = c - ro;
+    let ax = axis(a, kx) - sx * axis(a, kz);
+    let ay = axis(a, ky) - sy * axis(a, kz);
+    let bx = axis(b, kx) - sx * axis(b, kz);
+    let by = axis(b, ky) - sy * axis(b, kz);
+    let cx = axis(c, kx) - sx * axis(c, kz);
+    let cy = axis(c, ky) - sy * axis(c, kz);
 
-    if det.abs() < 1e-6 {
+    // Scaled barycentric coordinates
+    let u = cx * by - cy * bx;
+    let v = ax * cy - ay * cx;
+    let w = bx * ay - by * ax;
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
         return false;
     }
- 
-    let inv_det = 1.0 / det;
- 
-    // calculate distance from vert0 to ray origin
-    let tv = ro - a;
- 
-    // calculate U parameter and test bounds
-    let u = tv.dot(pv) * inv_det;
-    if u < 0.0 || u > 1.0 {
+
+    // if determinant is zero, ray lies in plane of triangle
+    let det = u + v + w;
+    if det == 0.0 {
         return false;
     }
- 
-    // prepare to test V parameter
-    let qv = tv.cross(edge1);
- 
-    // calculate V parameter and test bounds
-    let v = rd.dot(qv) * inv_det;
-    if v < 0.0 || u + v > 1.0 {
+    *out_backface = det.is_negative();
+
+    // Scaled hit distance
+    let az = sz * axis(a, kz);
+    let bz = sz * axis(b, kz);
+    let cz = sz * axis(c, kz);
+    let t = (u * az + v * bz + w * cz) / det;
+    if t < 0.0 {
         return false;
     }
- 
-    let t = edge2.dot(qv) * inv_det;
+    *out_t = t;
+
+    return true;
+}
+
+// Parallelogram spanned by the edges b - a and c - a
+fn intersect_quad(ro: Vec3, rd: Vec3, a: Vec3, b: Vec3, c: Vec3, out_t: &mut f32, out_backface: &mut bool) -> bool {
+    *out_t = 0.0;
+
+    let edge_u = b - a;
+    let edge_v = c - a;
+    let normal = edge_u.cross(edge_v);
+    let denom = normal.dot(rd);
+    if denom == 0.0 {
+        return false;
+    }
+    let t = normal.dot(a - ro) / denom;
     if t < 0.0 {
         return false;
     }
+
+    // Coordinates of the hit point in the basis of the edges
+    let p = ro + rd * t - a;
+    let w = normal / normal.dot(normal);
+    let u = w.dot(p.cross(edge_v));
+    let v = w.dot(edge_u.cross(p));
+    if u < 0.0 || u > 1.0 || v < 0.0 || v > 1.0 {
+        return false;
+    }
+    *out_backface = denom > 0.0;
     *out_t = t;
- 
+
     return true;
 }
 
+// Returns the nearest hit past `t_min`, so rays starting on the surface can exit the sphere. The discriminant is
+// computed as in "Precision Improvements for Ray/Sphere Intersection" from RT gems 1, which is stable for large spheres.
+fn intersect_sphere(ro: Vec3, rd: Vec3, center: Vec3, radius: f32, t_min: f32, out_t: &mut f32, out_backface: &mut bool) -> bool {
+    *out_t = 0.0;
+
+    let oc = ro - center;
+    let a = rd.dot(rd);
+    let b = oc.dot(rd);
+    let l = oc - rd * (b / a);
+    let discriminant = a * (radius * radius - l.dot(l));
+    if discriminant < 0.0 {
+        return false;
+    }
+    let root = discriminant.sqrt();
+    let t_near = (-b - root) / a;
+    let t_far = (-b + root) / a;
+    let t = if t_near > t_min { t_near } else { t_far };
+    if t <= t_min {
+        return false;
+    }
+    *out_backface = rd.dot(oc + rd * t) > 0.0; // Hit from the inside
+    *out_t = t;
+
+    return true;
+}
+
+// Intersect a primitive given the positions of its vertices, see `PrimitiveType` for the layout. Only spheres use the radius.
+fn intersect_primitive(primitive_type: PrimitiveType, ro: Vec3, rd: Vec3, a: Vec3, b: Vec3, c: Vec3, radius: f32, t_min: f32, out_t: &mut f32, out_backface: &mut bool) -> bool {
+    match primitive_type {
+        PrimitiveType::Triangle => intersect_triangle(ro, rd, a, b, c, out_t, out_backface),
+        PrimitiveType::Quad => intersect_quad(ro, rd, a, b, c, out_t, out_backface),
+        PrimitiveType::Sphere => intersect_sphere(ro, rd, a, radius, t_min, out_t, out_backface),
+    }
+}
+
 pub struct TraceResult {
     pub triangle: UVec4,
     pub triangle_index: u32,
     pub t: f32,
     pub hit: bool,
     pub backface: bool,
+    pub primitive_tests: u32, // Primitives the ray was tested against, for profiling
 }
 
 impl Default for TraceResult {
@@ -69,27 +165,35 @@ impl Default for TraceResult {
             t: 1000000.0,
             hit: false,
             backface: false,
+            primitive_tests: 0,
         }
     }
 }
 
 #[allow(dead_code)]
-fn intersect_slow_as_shit(
-    vertex_buffer: &[Vec4],
-    index_buffer: &[UVec4],
+fn intersect_slow_as_shit<P: PackedPrimitive>(
+    per_vertex_buffer: &[PerVertexData],
+    index_buffer: &[P],
     ro: Vec3,
-    rd: Vec3
+    rd: Vec3,
+    t_min: f32,
+    t_max: f32,
 ) -> TraceResult {
-    let mut result = TraceResult::default();
+    let mut result = TraceResult {
+        t: t_max,
+        ..Default::default()
+    };
     for i in 0..index_buffer.len() {
-        let triangle = index_buffer[i];
-        let a = vertex_buffer[triangle.x as usize].xyz();
-        let b = vertex_buffer[triangle.y as usize].xyz();
-        let c = vertex_buffer[triangle.z as usize].xyz();
+        let triangle = index_buffer[i].unpack();
+        let a = per_vertex_buffer[triangle.x as usize].vertex;
+        let b = per_vertex_buffer[triangle.y as usize].vertex;
+        let c = per_vertex_buffer[triangle.z as usize].vertex;
+        let radius = per_vertex_buffer[triangle.x as usize].radius;
 
         let mut t = 0.0;
         let mut backface = false;
-        if muller_trumbore(ro, rd, a, b, c, &mut t, &mut backface) && t > 0.001 && t < result.t {
+        result.primitive_tests += 1;
+        if intersect_primitive(PrimitiveType::of(triangle), ro, rd, a, b, c, radius, t_min, &mut t, &mut backface) && t > t_min && t < result.t {
             result.triangle = triangle;
             result.triangle_index = i as u32;
             result.t = result.t.min(t);
@@ -101,7 +205,7 @@ fn intersect_slow_as_shit(
 }
 
 // TODO: Optimize this
-fn intersect_aabb(aabb_min: Vec3, aabb_max: Vec3, ro: Vec3, rd: Vec3, prev_min_t: f32) -> f32 {
+fn intersect_aabb(aabb_min: Vec3, aabb_max: Vec3, ro: Vec3, rd: Vec3, min_t: f32, prev_min_t: f32) -> f32 {
     let tx1 = (aabb_min.x - ro.x) / rd.x; 
     let tx2 = (aabb_max.x - ro.x) / rd.x;
     let mut tmin = tx1.min(tx2);
@@ -114,7 +218,7 @@ fn intersect_aabb(aabb_min: Vec3, aabb_max: Vec3, ro: Vec3, rd: Vec3, prev_min_t
     let tz2 = (aabb_max.z - ro.z) / rd.z;
     tmin = tmin.max(tz1.min(tz2));
     tmax = tmax.min(tz1.max(tz2));
-    if tmax >= tmin && tmax > 0.0 && tmin < prev_min_t {
+    if tmax >= tmin && tmax > min_t && tmin < prev_min_t {
         tmin
     } else { 
         f32::INFINITY
@@ -123,33 +227,40 @@ fn intersect_aabb(aabb_min: Vec3, aabb_max: Vec3, ro: Vec3, rd: Vec3, prev_min_t
 
 pub struct BVHReference<'a> {
     pub nodes: &'a [BVHNode],
+    pub t_min: f32,
+    pub t_max: f32,
 }
 
 impl<'a> BVHReference<'a> {
     #[allow(dead_code)]
-    pub fn intersect_fixed_order(&self, vertex_buffer: &[Vec4], index_buffer: &[UVec4], ro: Vec3, rd: Vec3) -> TraceResult {
+    pub fn intersect_fixed_order<P: PackedPrimitive>(&self, per_vertex_buffer: &[PerVertexData], index_buffer: &[P], ro: Vec3, rd: Vec3) -> TraceResult {
         let mut stack = FixedVec::<usize, 32>::new();
         stack.push(0);
 
-        let mut result = TraceResult::default();
+        let mut result = TraceResult {
+            t: self.t_max,
+            ..Default::default()
+        };
         while !stack.is_empty() {
             let node_index = stack.pop().unwrap();
             let node = &self.nodes[node_index];
-            if intersect_aabb(node.aabb_min(), node.aabb_max(), ro, rd, result.t).is_infinite() {
+            if intersect_aabb(node.aabb_min(), node.aabb_max(), ro, rd, self.t_min, result.t).is_infinite() {
                 continue;
             }
 
             if node.is_leaf() {
                 for i in 0..node.triangle_count() {
                     let triangle_index = node.first_triangle_index() + i;
-                    let triangle = index_buffer[triangle_index as usize];
-                    let a = vertex_buffer[triangle.x as usize].xyz();
-                    let b = vertex_buffer[triangle.y as usize].xyz();
-                    let c = vertex_buffer[triangle.z as usize].xyz();
+                    let triangle = index_buffer[triangle_index as usize].unpack();
+                    let a = per_vertex_buffer[triangle.x as usize].vertex;
+                    let b = per_vertex_buffer[triangle.y as usize].vertex;
+                    let c = per_vertex_buffer[triangle.z as usize].vertex;
+                    let radius = per_vertex_buffer[triangle.x as usize].radius;
     
                     let mut t = 0.0;
                     let mut backface = false;
-                    if muller_trumbore(ro, rd, a, b, c, &mut t, &mut backface) && t > 0.001 && t < result.t {
+                    result.primitive_tests += 1;
+                    if intersect_primitive(PrimitiveType::of(triangle), ro, rd, a, b, c, radius, self.t_min, &mut t, &mut backface) && t > self.t_min && t < result.t {
                         result.triangle = triangle;
                         result.triangle_index = triangle_index;
                         result.t = result.t.min(t);
@@ -166,33 +277,38 @@ impl<'a> BVHReference<'a> {
         result
     }
 
-    pub fn intersect_nearest(&self, per_vertex_buffer: &[PerVertexData], index_buffer: &[UVec4], ro: Vec3, rd: Vec3) -> TraceResult {
-        self.intersect_front_to_back::<true>(per_vertex_buffer, index_buffer, ro, rd, 0.0)
+    pub fn intersect_nearest<P: PackedPrimitive>(&self, per_vertex_buffer: &[PerVertexData], index_buffer: &[P], ro: Vec3, rd: Vec3) -> TraceResult {
+        self.intersect_front_to_back::<true, P>(per_vertex_buffer, index_buffer, ro, rd, 0.0)
     }
 
-    pub fn intersect_any(&self, per_vertex_buffer: &[PerVertexData], index_buffer: &[UVec4], ro: Vec3, rd: Vec3, max_t: f32) -> TraceResult {
-        self.intersect_front_to_back::<false>(per_vertex_buffer, index_buffer, ro, rd, max_t)
+    pub fn intersect_any<P: PackedPrimitive>(&self, per_vertex_buffer: &[PerVertexData], index_buffer: &[P], ro: Vec3, rd: Vec3, max_t: f32) -> TraceResult {
+        self.intersect_front_to_back::<false, P>(per_vertex_buffer, index_buffer, ro, rd, max_t)
     }
 
-    fn intersect_front_to_back<const NEAREST_HIT: bool>(&self, per_vertex_buffer: &[PerVertexData], index_buffer: &[UVec4], ro: Vec3, rd: Vec3, max_t: f32) -> TraceResult {
+    fn intersect_front_to_back<const NEAREST_HIT: bool, P: PackedPrimitive>(&self, per_vertex_buffer: &[PerVertexData], index_buffer: &[P], ro: Vec3, rd: Vec3, max_t: f32) -> TraceResult {
         let mut stack = FixedVec::<usize, 32>::new();
         stack.push(0);
 
-        let mut result = TraceResult::default();
+        let mut result = TraceResult {
+            t: self.t_max,
+            ..Default::default()
+        };
         while !stack.is_empty() {
             let node_index = stack.pop().unwrap();
             let node = &self.nodes[node_index];
             if node.is_leaf() {
                 for i in 0..node.triangle_count() {
                     let triangle_index = node.first_triangle_index() + i;
-                    let triangle = index_buffer[triangle_index as usize];
-                    let a = per_vertex_buffer[triangle.x as usize].vertex.xyz();
-                    let b = per_vertex_buffer[triangle.y as usize].vertex.xyz();
-                    let c = per_vertex_buffer[triangle.z as usize].vertex.xyz();
+                    let triangle = index_buffer[triangle_index as usize].unpack();
+                    let a = per_vertex_buffer[triangle.x as usize].vertex;
+                    let b = per_vertex_buffer[triangle.y as usize].vertex;
+                    let c = per_vertex_buffer[triangle.z as usize].vertex;
+                    let radius = per_vertex_buffer[triangle.x as usize].radius;
     
                     let mut t = 0.0;
                     let mut backface = false;
-                    if muller_trumbore(ro, rd, a, b, c, &mut t, &mut backface) && t > 0.001 && t < result.t && (NEAREST_HIT || t <= max_t) {
+                    result.primitive_tests += 1;
+                    if intersect_primitive(PrimitiveType::of(triangle), ro, rd, a, b, c, radius, self.t_min, &mut t, &mut backface) && t > self.t_min && t < result.t && (NEAREST_HIT || t <= max_t) {
                         result.triangle = triangle;
                         result.triangle_index = triangle_index;
                         result.t = result.t.min(t);
@@ -209,8 +325,8 @@ impl<'a> BVHReference<'a> {
                 let mut max_index = node.right_node_index() as usize;
                 let mut min_child = &self.nodes[min_index];
                 let mut max_child = &self.nodes[max_index];
-                let mut min_dist = intersect_aabb(min_child.aabb_min(), min_child.aabb_max(), ro, rd, result.t);
-                let mut max_dist = intersect_aabb(max_child.aabb_min(), max_child.aabb_max(), ro, rd, result.t);
+                let mut min_dist = intersect_aabb(min_child.aabb_min(), min_child.aabb_max(), ro, rd, self.t_min, result.t);
+                let mut max_dist = intersect_aabb(max_child.aabb_min(), max_child.aabb_max(), ro, rd, self.t_min, result.t);
                 if min_dist > max_dist {
                     core::mem::swap(&mut min_index, &mut max_index);
                     core::mem::swap(&mut min_dist, &mut max_dist);
@@ -3,38 +3,147 @@
 use bsdf::BSDF;
 use glam::*;
 use intersection::BVHReference;
+use stats::{BounceStats, RayCounts, WorkgroupBounceStats};
 use shared_structs::{Image, Sampler};
-use shared_structs::{TracingConfig, BVHNode, MaterialData, PerVertexData, LightPickEntry, NextEventEstimation};
+use shared_structs::{BOUNCE_STATS_LEN, TracingConfig, ATrousConfig, BVHNode, MaterialData, PerVertexData, LightPickEntry, LightTreeNode, NextEventEstimation, LightSampling, MisHeuristic, RussianRoulette, ClampMode, WorkingSpace, PrimitiveType, PackedPrimitive, CompactPrimitive, material_index, sample_aperture, welford_update, equirect_bilinear, blend_bilinear};
 #[allow(unused_imports)]
 use spirv_std::num_traits::Float;
 use spirv_std::{glam, spirv};
 
 mod bsdf;
-mod rng;
+pub mod rng;
 mod util;
-mod intersection;
+pub mod intersection;
 mod vec;
 mod skybox;
-mod light_pick;
+pub mod light_pick;
+mod texture;
+mod subsurface;
+pub mod stats;
+pub mod denoise;
+
+// Surfaces hidden from the camera that a primary ray steps through before it gives up and sees the next one anyway
+const MAX_HIDDEN_LAYERS: u32 = 4;
+
+// Light arriving from the environment along a ray that escaped the scene
+fn background_radiance(
+    config: &TracingConfig,
+    working_space: WorkingSpace,
+    skybox: &Image!(2D, type=f32, sampled),
+    ray_origin: Vec3,
+    ray_direction: Vec3,
+) -> Vec3 {
+    if config.has_skybox == 0 && config.background_color.w != 0.0 {
+        // Flat background color
+        working_space.from_linear_srgb(config.background_color.xyz())
+    } else if config.has_skybox == 0 {
+        // Fallback to procedural skybox
+        working_space.from_linear_srgb(skybox::scatter(config.sun_direction, ray_origin, ray_direction))
+    } else {
+        // Read skybox from image
+        let rotation = config.sun_direction.z.atan2(config.sun_direction.x) + config.skybox_rotation;
+        let rotated = Mat3::from_rotation_y(rotation) * ray_direction;
+        let u = 0.5 + rotated.z.atan2(rotated.x) / (2.0 * core::f32::consts::PI);
+        let v = 1.0 - (0.5 + rotated.y.clamp(-1.0, 1.0).asin() / core::f32::consts::PI);
+        let intensity = config.sun_direction.w * (1.0 / 15.0);
+        let (texels, weights) = equirect_bilinear(Vec2::new(u, v), config.skybox_width, config.skybox_height);
+        let sky = blend_bilinear(texels, weights, |texel| skybox.fetch(texel)).xyz() * intensity;
+        working_space.from_linear_srgb(sky)
+    }
+}
+
+// Limit light that reached the camera after scattering off `scatterings` surfaces, if it is indirect, see
+// `TracingConfig::indirect_clamp` and `ClampMode`
+fn clamp_indirect(config: &TracingConfig, scatterings: u32, contribution: Vec3) -> Vec3 {
+    if scatterings < 2 || config.indirect_clamp <= 0.0 {
+        contribution
+    } else {
+        ClampMode::from_u32(config.clamp_mode).apply(contribution, config.indirect_clamp)
+    }
+}
 
 #[cfg_attr(target_arch = "spirv", inline(always))]
-pub fn trace_pixel(
+pub fn trace_pixel<P: PackedPrimitive, S: BounceStats>(
     id: UVec3,
     config: &TracingConfig,
     rng: UVec2,
     per_vertex_buffer: &[PerVertexData],
-    index_buffer: &[UVec4],
+    index_buffer: &[P],
     nodes_buffer: &[BVHNode],
     material_data_buffer: &[MaterialData],
     light_pick_buffer: &[LightPickEntry],
+    light_tree_buffer: &[LightTreeNode],
     sampler: &Sampler,
     atlas: &Image!(2D, type=f32, sampled),
     skybox: &Image!(2D, type=f32, sampled),
-) -> (Vec4, UVec2) {
-    let nee_mode = NextEventEstimation::from_u32(config.nee);
-    let nee = nee_mode.uses_nee();
+    stats: &mut S,
+) -> (Vec4, Vec4, Vec4, Vec4, UVec2) {
     let mut rng_state = rng::RngState::new(rng);
+    let (ray_origin, ray_direction, clipped) = camera_ray(id, config, &mut rng_state);
+    let (radiance, specular, albedo, mut normal, next_rng) = trace_path(
+        config,
+        rng_state,
+        ray_origin,
+        ray_direction,
+        per_vertex_buffer,
+        index_buffer,
+        nodes_buffer,
+        material_data_buffer,
+        light_pick_buffer,
+        light_tree_buffer,
+        sampler,
+        atlas,
+        skybox,
+        stats,
+    );
+    // The depth guide is measured from the camera rather than the near plane
+    if normal.w > 0.0 {
+        normal.w += clipped;
+    }
+    (radiance, specular, albedo, normal, next_rng)
+}
+
+// Same as `trace_pixel`, but starting from a given ray rather than one through a pixel, for paths that don't start at
+// the camera, such as when baking lightmaps
+#[cfg_attr(target_arch = "spirv", inline(always))]
+pub fn trace_ray<P: PackedPrimitive, S: BounceStats>(
+    config: &TracingConfig,
+    rng: UVec2,
+    ray_origin: Vec3,
+    ray_direction: Vec3,
+    per_vertex_buffer: &[PerVertexData],
+    index_buffer: &[P],
+    nodes_buffer: &[BVHNode],
+    material_data_buffer: &[MaterialData],
+    light_pick_buffer: &[LightPickEntry],
+    light_tree_buffer: &[LightTreeNode],
+    sampler: &Sampler,
+    atlas: &Image!(2D, type=f32, sampled),
+    skybox: &Image!(2D, type=f32, sampled),
+    stats: &mut S,
+) -> (Vec4, Vec4, Vec4, Vec4, UVec2) {
+    trace_path(
+        config,
+        rng::RngState::new(rng),
+        ray_origin,
+        ray_direction,
+        per_vertex_buffer,
+        index_buffer,
+        nodes_buffer,
+        material_data_buffer,
+        light_pick_buffer,
+        light_tree_buffer,
+        sampler,
+        atlas,
+        skybox,
+        stats,
+    )
+}
 
+// Primary ray through the pixel `id`, jittered for anti-aliasing, and through the aperture for depth of field. It starts
+// at the near plane, which is returned as the distance along the ray from the lens.
+#[cfg_attr(target_arch = "spirv", inline(always))]
+fn camera_ray(id: UVec3, config: &TracingConfig, rng_state: &mut rng::RngState) -> (Vec3, Vec3, f32) {
     // Get anti-aliased pixel coordinates.
     let suv = id.xy().as_vec2() + rng_state.gen_r2();
     let mut uv = Vec2::new(
@@ -42,52 +151,170 @@ pub fn trace_pixel(
         1.0 - suv.y as f32 / config.height as f32,
     ) * 2.0
         - 1.0;
-    uv.y *= config.height as f32 / config.width as f32;
+    // The horizontal field of view spans the width of the image, which is `pixel_aspect` times wider than it would be
+    // with square pixels, so the image plane is that much shorter relative to it
+    uv.y *= config.height as f32 / (config.width as f32 * config.pixel_aspect);
 
     // Setup camera.
+    let euler_mat = Mat3::from_rotation_y(config.cam_rotation.y) * Mat3::from_rotation_x(config.cam_rotation.x);
+    if config.ortho_height > 0.0 {
+        // Orthographic, parallel rays from points across a rectangle `ortho_height` tall on either side of the center
+        let offset = uv * (config.ortho_height * config.width as f32 * config.pixel_aspect / config.height as f32);
+        let forward = euler_mat * Vec3::Z;
+        let ray_origin = config.cam_position.xyz() + euler_mat * offset.extend(0.0);
+        return (ray_origin + forward * config.near_clip, forward, config.near_clip);
+    }
+    uv *= (config.fov * 0.5).tan();
     let mut ray_origin = config.cam_position.xyz();
     let mut ray_direction = Vec3::new(uv.x, uv.y, 1.0).normalize();
-    let euler_mat = Mat3::from_rotation_y(config.cam_rotation.y) * Mat3::from_rotation_x(config.cam_rotation.x);
-    ray_direction = euler_mat * ray_direction;
+    if config.aperture_radius > 0.0 {
+        // Thin lens, every ray through the aperture converges on the same point of the focal plane
+        let focus_point = ray_direction * (config.focus_distance / ray_direction.z);
+        let lens_point = sample_aperture(config.aperture_blades, config.aperture_rotation, rng_state.gen_r2()) * config.aperture_radius;
+        ray_origin += euler_mat * lens_point.extend(0.0);
+        ray_direction = (focus_point - lens_point.extend(0.0)).normalize();
+    }
+    // The near plane is flat, so rays towards the edges of the image travel further to reach it
+    let clipped = config.near_clip / ray_direction.z;
+    (ray_origin + euler_mat * ray_direction * clipped, euler_mat * ray_direction, clipped)
+}
+
+// The bounce loop shared by every kind of primary ray
+#[cfg_attr(target_arch = "spirv", inline(always))]
+fn trace_path<P: PackedPrimitive, S: BounceStats>(
+    config: &TracingConfig,
+    mut rng_state: rng::RngState,
+    mut ray_origin: Vec3,
+    mut ray_direction: Vec3,
+    per_vertex_buffer: &[PerVertexData],
+    index_buffer: &[P],
+    nodes_buffer: &[BVHNode],
+    material_data_buffer: &[MaterialData],
+    light_pick_buffer: &[LightPickEntry],
+    light_tree_buffer: &[LightTreeNode],
+    sampler: &Sampler,
+    atlas: &Image!(2D, type=f32, sampled),
+    skybox: &Image!(2D, type=f32, sampled),
+    stats: &mut S,
+) -> (Vec4, Vec4, Vec4, Vec4, UVec2) {
+    let nee_mode = NextEventEstimation::from_u32(config.nee);
+    let nee = nee_mode.uses_nee();
+    let mis_heuristic = MisHeuristic::from_u32(config.mis_heuristic);
+    let light_sampling = LightSampling::from_u32(config.light_sampling);
+    let working_space = WorkingSpace::from_u32(config.working_space);
 
     let bvh = BVHReference {
         nodes: nodes_buffer,
+        t_min: config.ray_t_min,
+        t_max: config.ray_t_max,
     };
 
+    // Ray cone for texture filtering, starting with the angle subtended by a single pixel.
+    let pixel_spread_angle = (2.0 / config.width as f32).atan();
+    let mut cone_width = 0.0;
+
     let mut throughput = Vec3::ONE;
     let mut radiance = Vec3::ZERO;
     let mut last_bsdf_sample = bsdf::BSDFSample::default();
     let mut last_light_sample = light_pick::DirectLightSample::default(); 
 
+    // Albedo, normal and depth at the first hit, used as guides for denoising and reprojection.
+    let mut first_albedo = Vec3::ZERO;
+    let mut first_normal = Vec3::ZERO;
+    let mut first_depth = 0.0;
+
+    // Whether the primary ray hit anything, accumulated into the alpha channel of the output
+    let mut coverage = 0.0;
+
+    // Whether the first bounce picked a specular lobe, in which case all the light of the path goes to the specular pass
+    let mut specular_path = false;
+
+    let mut ray_counts = RayCounts::default();
+
     for bounce in 0..config.max_bounces {
-        let trace_result = bvh.intersect_nearest(per_vertex_buffer, index_buffer, ray_origin, ray_direction);
+        stats.record(bounce, throughput);
+        let mut trace_result = bvh.intersect_nearest(per_vertex_buffer, index_buffer, ray_origin, ray_direction);
+        ray_counts.add(&trace_result);
+        if bounce == 0 {
+            // Step through surfaces hidden from the camera, up to a few layers deep. The distance is kept from the
+            // camera, so the depth guide and ray cone see past them too.
+            let mut skipped = 0.0;
+            for _ in 0..MAX_HIDDEN_LAYERS {
+                if !trace_result.hit || !material_data_buffer[material_index(trace_result.triangle) as usize].is_hidden_from_camera() {
+                    break;
+                }
+                skipped += trace_result.t;
+                trace_result = bvh.intersect_nearest(per_vertex_buffer, index_buffer, ray_origin + ray_direction * skipped, ray_direction);
+                ray_counts.add(&trace_result);
+            }
+            trace_result.t += skipped;
+        }
         let hit = ray_origin + ray_direction * trace_result.t;
 
         if !trace_result.hit {
-            if config.has_skybox == 0 {
-                // Fallback to procedural skybox
-                radiance += throughput * skybox::scatter(config.sun_direction, ray_origin, ray_direction);
+            if bounce == 0 && config.transparent_background != 0 {
+                // Leave the background empty, so the radiance is premultiplied by coverage
             } else {
-                // Read skybox from image
-                let rotation = config.sun_direction.z.atan2(config.sun_direction.x);
-                let rotated = Mat3::from_rotation_y(rotation) * ray_direction;
-                let u = 0.5 + rotated.z.atan2(rotated.x) / (2.0 * core::f32::consts::PI);
-                let v = 1.0 - (0.5 + rotated.y.asin() / core::f32::consts::PI);
-                let intensity = config.sun_direction.w * (1.0 / 15.0);
-                radiance += throughput * skybox.sample_by_lod(*sampler, Vec2::new(u, v), 0.0).xyz() * intensity;
+                radiance += clamp_indirect(config, bounce, throughput * background_radiance(config, working_space, skybox, ray_origin, ray_direction));
             }
             break;
         } else {
+            if bounce == 0 {
+                coverage = 1.0;
+            }
+
             // Get material
-            let material_index = trace_result.triangle.w;
-            let material = material_data_buffer[material_index as usize];
+            let material = material_data_buffer[material_index(trace_result.triangle) as usize];
+
+            // Interpolate vertex data
+            let vertex_data_a = per_vertex_buffer[trace_result.triangle.x as usize];
+            let vertex_data_b = per_vertex_buffer[trace_result.triangle.y as usize];
+            let vertex_data_c = per_vertex_buffer[trace_result.triangle.z as usize];
+            let vert_a = vertex_data_a.vertex;
+            let vert_b = vertex_data_b.vertex;
+            let vert_c = vertex_data_c.vertex;
+            let norm_a = vertex_data_a.normal.xyz();
+            let norm_b = vertex_data_b.normal.xyz();
+            let norm_c = vertex_data_c.normal.xyz();
+            let uv_a = vertex_data_a.uv0;
+            let uv_b = vertex_data_b.uv0;
+            let uv_c = vertex_data_c.uv0;
+
+            // Widen the ray cone by the distance travelled. We ignore surface curvature, so the spread
+            // angle stays the same across bounces.
+            cone_width += pixel_spread_angle * trace_result.t;
+
+            let (mut normal, mut uv, tangent, texture_lod, vertex_color) = if PrimitiveType::of(trace_result.triangle) == PrimitiveType::Sphere {
+                // Spheres have no per-vertex data, so derive it from the hit position. UVs are equirectangular.
+                let radius = vertex_data_a.radius;
+                let normal = (hit - vert_a) / radius;
+                let uv = texture::sphere_uv(normal);
+                let tangent = Vec3::new(-normal.z, 0.0, normal.x).normalize_or_zero();
+                let uv_density = 1.0 / (4.0 * core::f32::consts::PI * radius * radius);
+                (normal, uv, tangent, texture::ray_cone_lod_from_density(cone_width, ray_direction, normal, uv_density), vertex_data_a.color.xyz())
+            } else {
+                // Barycentrics extrapolate linearly, so this works for the far half of quads too
+                let bary = util::barycentric(hit, vert_a, vert_b, vert_c);
+                let normal = bary.x * norm_a + bary.y * norm_b + bary.z * norm_c;
+                let uv = bary.x * uv_a + bary.y * uv_b + bary.z * uv_c;
+                let tangent_a = vertex_data_a.tangent.xyz();
+                let tangent_b = vertex_data_b.tangent.xyz();
+                let tangent_c = vertex_data_c.tangent.xyz();
+                let tangent = bary.x * tangent_a + bary.y * tangent_b + bary.z * tangent_c;
+                let vertex_color = bary.x * vertex_data_a.color.xyz() + bary.y * vertex_data_b.color.xyz() + bary.z * vertex_data_c.color.xyz();
+                (normal, uv, tangent, texture::ray_cone_lod(cone_width, ray_direction, normal, vert_a, vert_b, vert_c, uv_a, uv_b, uv_c), vertex_color)
+            };
+            if uv.clamp(Vec2::ZERO, Vec2::ONE) != uv {
+                uv = uv.fract(); // wrap UVs
+            }
 
-            // Add emission
-            if material.emissive.xyz() != Vec3::ZERO {
+            // Add emission, which needs the UV for emissive textures
+            if material.emission() != Vec3::ZERO {
                 // Emissive triangles are single-sided
                 if trace_result.backface {
                     break; // Break since emissives don't bounce light
                 }
+                let emission = working_space.from_linear_srgb(texture::material_emission(&material, atlas, sampler, uv, texture_lod));
 
                 // We want to add emissive contribution if:
                 // - We are not doing NEE at all.
@@ -95,73 +322,204 @@ pub fn trace_pixel(
                 // - This is a non-diffuse bounce (so we don't double count emissive light).
                 // AND we aren't hitting a backface (to match direct light sampling behavior).
                 if !nee || bounce == 0 || last_bsdf_sample.sampled_lobe != bsdf::LobeType::DiffuseReflection {
-                    radiance += util::mask_nan(throughput * material.emissive.xyz());
+                    radiance += clamp_indirect(config, bounce, util::mask_nan(throughput * emission));
                     break;
                 }
 
                 // If we have hit a light source, and we are using NEE with MIS, we use last bounces data
                 // to add the BSDF contribution, weighted by MIS.
                 if nee_mode.uses_mis() && last_bsdf_sample.sampled_lobe == bsdf::LobeType::DiffuseReflection {
-                    let direct_contribution = light_pick::calculate_bsdf_mis_contribution(&trace_result, &last_bsdf_sample, &last_light_sample);
-                    radiance += util::mask_nan(direct_contribution);
+                    let direct_contribution = light_pick::calculate_bsdf_mis_contribution(&trace_result, emission, &last_bsdf_sample, &last_light_sample, mis_heuristic);
+                    radiance += clamp_indirect(config, bounce, util::mask_nan(direct_contribution));
                     break;
                 }
             }
 
-            // Interpolate vertex data
-            let vertex_data_a = per_vertex_buffer[trace_result.triangle.x as usize];
-            let vertex_data_b = per_vertex_buffer[trace_result.triangle.y as usize];
-            let vertex_data_c = per_vertex_buffer[trace_result.triangle.z as usize];
-            let vert_a = vertex_data_a.vertex.xyz();
-            let vert_b = vertex_data_b.vertex.xyz();
-            let vert_c = vertex_data_c.vertex.xyz();
-            let norm_a = vertex_data_a.normal.xyz();
-            let norm_b = vertex_data_b.normal.xyz();
-            let norm_c = vertex_data_c.normal.xyz();
-            let uv_a = vertex_data_a.uv0;
-            let uv_b = vertex_data_b.uv0;
-            let uv_c = vertex_data_c.uv0;
-            let bary = util::barycentric(hit, vert_a, vert_b, vert_c);
-            let mut normal = bary.x * norm_a + bary.y * norm_b + bary.z * norm_c;
-            let mut uv = bary.x * uv_a + bary.y * uv_b + bary.z * uv_c;
-            if uv.clamp(Vec2::ZERO, Vec2::ONE) != uv {
-                uv = uv.fract(); // wrap UVs
-            }
-
             // Apply normal map
             if material.has_normal_texture() {
-                let scaled_uv = material.normals.xy() + uv * material.normals.zw();
-                let normal_map = atlas.sample_by_lod(*sampler, scaled_uv, 0.0) * 2.0 - 1.0;
-                let tangent_a = vertex_data_a.tangent.xyz();
-                let tangent_b = vertex_data_b.tangent.xyz();
-                let tangent_c = vertex_data_c.tangent.xyz();
-                let tangent = bary.x * tangent_a + bary.y * tangent_b + bary.z * tangent_c;
+                let normal_map = material.decode_normal_map(texture::sample_atlas(atlas, sampler, material.normals, uv, texture_lod).xyz());
                 let tbn = Mat3::from_cols(tangent, tangent.cross(normal), normal);
-                normal = (tbn * normal_map.xyz()).normalize();
+                normal = (tbn * normal_map).normalize();
             }
             
+            // Shadow catchers are invisible to the camera, showing the background darkened by the fraction of the light
+            // reaching them that the rest of the scene blocks. That is estimated with a light sample, taken with and
+            // without its shadow ray, and a cosine weighted ray towards the sky. Emitters are lights rather than
+            // occluders, so a sky ray hitting one still counts as seeing the sky.
+            // Seen through other surfaces, they are regular diffuse surfaces.
+            if bounce == 0 && material.is_shadow_catcher() {
+                let facing_normal = if normal.dot(ray_direction) > 0.0 { -normal } else { normal };
+                let shadow_origin = hit + facing_normal * config.ray_t_min;
+
+                // Light sample, as irradiance over pi like the sky below, so both can be added up
+                let white = bsdf::Lambertian { albedo: Vec3::ONE };
+                let sample_light = |cast_shadows: bool, light_rng_state: &mut rng::RngState, ray_counts: &mut RayCounts| {
+                    light_pick::sample_direct_lighting(
+                        NextEventEstimation::DirectLightSampling,
+                        mis_heuristic,
+                        light_sampling,
+                        working_space,
+                        cast_shadows,
+                        index_buffer,
+                        per_vertex_buffer,
+                        material_data_buffer,
+                        light_pick_buffer,
+                        light_tree_buffer,
+                        &bvh,
+                        sampler,
+                        atlas,
+                        Vec3::ONE,
+                        &white,
+                        shadow_origin,
+                        facing_normal,
+                        ray_direction,
+                        light_rng_state,
+                        ray_counts,
+                    ).direct_light_contribution
+                };
+                // Both pick the same point on the same light
+                let mut unshadowed_rng_state = rng_state;
+                let mut lit = sample_light(config.shadows != 0, &mut rng_state, &mut ray_counts);
+                let mut unshadowed = sample_light(false, &mut unshadowed_rng_state, &mut ray_counts);
+
+                // Sky sample, with a pdf of cos / pi, so the radiance it sees is its estimate of irradiance over pi
+                let (up, nt, nb) = util::create_cartesian(facing_normal);
+                let rng_sample = rng_state.gen_r2();
+                let sample = util::cosine_sample_hemisphere(rng_sample.x, rng_sample.y);
+                let sky_direction = sample.x * nb + sample.y * up + sample.z * nt;
+                let sky = background_radiance(config, working_space, skybox, shadow_origin, sky_direction);
+                let sky_trace = bvh.intersect_nearest(per_vertex_buffer, index_buffer, shadow_origin, sky_direction);
+                ray_counts.add(&sky_trace);
+                let sky_visible = !sky_trace.hit
+                    || material_data_buffer[material_index(sky_trace.triangle) as usize].emission() != Vec3::ZERO;
+                if sky_visible {
+                    lit += sky;
+                }
+                unshadowed += sky;
+
+                // Without any light to block, there is no shadow
+                let luminance = Vec3::new(0.2126, 0.7152, 0.0722);
+                let unshadowed_luminance = unshadowed.dot(luminance);
+                let visibility = if unshadowed_luminance > 0.0 {
+                    (lit.dot(luminance) / unshadowed_luminance).clamp(0.0, 1.0)
+                } else {
+                    1.0
+                };
+                if config.transparent_background != 0 {
+                    // Only the shadow is left, as black with the occluded fraction as alpha
+                    coverage = 1.0 - visibility;
+                } else {
+                    radiance += visibility * background_radiance(config, working_space, skybox, ray_origin, ray_direction);
+                }
+                break;
+            }
+
             // Sample BSDF
-            let bsdf = bsdf::get_pbr_bsdf(config, &material, uv, atlas, sampler);
-            let bsdf_sample = bsdf.sample(-ray_direction, normal, &mut rng_state);
-            last_bsdf_sample = bsdf_sample;
+            let bsdf = bsdf::get_pbr_bsdf(config, &material, uv, tangent, vertex_color, texture_lod, atlas, sampler);
+            if bounce == 0 {
+                first_albedo = bsdf.albedo;
+                first_normal = normal;
+                first_depth = trace_result.t;
+            }
 
-            // Sample lights directly
-            if nee && bsdf_sample.sampled_lobe == bsdf::LobeType::DiffuseReflection {
-                last_light_sample = light_pick::sample_direct_lighting(
-                    nee_mode,
-                    index_buffer,
-                    per_vertex_buffer,
-                    material_data_buffer,
-                    light_pick_buffer,
+            // Preview fill light, reflected diffusely as if it came equally from every direction
+            if config.ambient.xyz() != Vec3::ZERO {
+                let ambient = bsdf.albedo * (1.0 - bsdf.metallic) * working_space.from_linear_srgb(config.ambient.xyz());
+                radiance += clamp_indirect(config, bounce + 1, util::mask_nan(throughput * ambient));
+            }
+
+            // Subsurface scattering stochastically replaces the regular BSDF with a random walk through the inside of the
+            // mesh, which leaves it somewhere else. The light it picks up there is found by the next bounce, so like
+            // glass it skips NEE.
+            if material.has_subsurface_scattering() && rng_state.gen_r1() < material.subsurface {
+                let inward_normal = if normal.dot(ray_direction) > 0.0 { normal } else { -normal };
+                let walk = subsurface::random_walk(
                     &bvh,
-                    throughput,
-                    &bsdf,
+                    per_vertex_buffer,
+                    index_buffer,
                     hit,
-                    normal,
-                    ray_direction,
-                    &mut rng_state
+                    inward_normal,
+                    bsdf.albedo,
+                    material.subsurface_radius.xyz(),
+                    &mut rng_state,
+                    &mut ray_counts,
                 );
-                radiance += util::mask_nan(last_light_sample.direct_light_contribution);
+                let Some((exit, exit_direction, walk_throughput)) = walk else {
+                    break;
+                };
+                last_bsdf_sample = bsdf::BSDFSample {
+                    sampled_lobe: bsdf::LobeType::DiffuseTransmission,
+                    ..Default::default()
+                };
+                throughput *= walk_throughput;
+                ray_direction = exit_direction;
+                ray_origin = exit + ray_direction * config.ray_t_min;
+                continue;
+            }
+            // Transmissive materials stochastically pick glass, whose lobes are all delta, so they skip NEE below
+            let bsdf_sample = if material.transmission.x > 0.0 && rng_state.gen_r1() < material.transmission.x {
+                bsdf::get_glass_bsdf(&bsdf).sample(-ray_direction, normal, &mut rng_state)
+            } else {
+                bsdf.sample(-ray_direction, normal, &mut rng_state)
+            };
+            last_bsdf_sample = bsdf_sample;
+            if bounce == 0 {
+                specular_path = bsdf_sample.sampled_lobe != bsdf::LobeType::DiffuseReflection;
+            }
+
+            // Sample lights directly
+            if nee && bsdf_sample.sampled_lobe == bsdf::LobeType::DiffuseReflection {
+                // Several light samples are averaged. The first draws from the path's sequence, the rest from forks of it,
+                // as one sequence doesn't have the dimensions for many. The last sample is kept for the BSDF side of MIS,
+                // the weights still sum to 1 with it, so the estimate stays unbiased.
+                let light_samples = config.light_samples.max(1);
+                let fork_seed = if light_samples > 1 { (rng_state.gen_r1() * 4294967295.0) as u32 } else { 0 };
+                let mut direct_light = Vec3::ZERO;
+                for light_sample_index in 0..light_samples {
+                    let mut light_rng_state = if light_sample_index == 0 {
+                        rng_state
+                    } else {
+                        rng_state.fork(fork_seed.wrapping_add(light_sample_index))
+                    };
+                    last_light_sample = light_pick::sample_direct_lighting(
+                        nee_mode,
+                        mis_heuristic,
+                        light_sampling,
+                        working_space,
+                        config.shadows != 0,
+                        index_buffer,
+                        per_vertex_buffer,
+                        material_data_buffer,
+                        light_pick_buffer,
+                        light_tree_buffer,
+                        &bvh,
+                        sampler,
+                        atlas,
+                        throughput,
+                        &bsdf,
+                        hit,
+                        normal,
+                        ray_direction,
+                        &mut light_rng_state,
+                        &mut ray_counts,
+                    );
+                    if light_sample_index == 0 {
+                        rng_state = light_rng_state;
+                    }
+                    direct_light += last_light_sample.direct_light_contribution;
+                }
+                // The light sample scatters off this surface on its way, on top of the previous bounces
+                radiance += clamp_indirect(config, bounce + 1, util::mask_nan(direct_light / light_samples as f32));
+            }
+
+            // The material's bounce budget ends the path here, after its direct light has been gathered
+            if material.max_bounces != 0 && bounce + 1 >= material.max_bounces {
+                break;
+            }
+
+            // Per the glTF spec, ambient occlusion only darkens indirect diffuse light, so direct light from NEE is left alone
+            if material.has_occlusion_texture() && bsdf_sample.sampled_lobe == bsdf::LobeType::DiffuseReflection {
+                throughput *= texture::sample_atlas(atlas, sampler, material.occlusion, uv, texture_lod).x;
             }
 
             // Attenuate by BSDF
@@ -169,11 +527,16 @@ pub fn trace_pixel(
 
             // Update ray
             ray_direction = bsdf_sample.sampled_direction;
-            ray_origin = hit + ray_direction * util::EPS;
+            ray_origin = hit + ray_direction * config.ray_t_min;
 
             // Russian roulette
             if bounce > config.min_bounces {
-                let prob = throughput.max_element();
+                let mut prob = throughput.max_element();
+                // Paths which just scattered off a dark surface carry little light onward, so they are cut sooner
+                if RussianRoulette::from_u32(config.russian_roulette) == RussianRoulette::Albedo {
+                    // Black surfaces would give a chance of 0, which a random number of exactly 0 divides by
+                    prob = (prob * bsdf.albedo.max_element()).clamp(1e-4, 1.0);
+                }
                 if rng_state.gen_r1() > prob {
                     break;
                 }
@@ -182,13 +545,189 @@ pub fn trace_pixel(
         }
     }
 
-    (radiance.extend(1.0), rng_state.next_state())
+    // Light reaching the camera directly, such as from the background, ends the path before the first bounce, so it
+    // always counts as diffuse
+    let specular = if specular_path { radiance } else { Vec3::ZERO };
+    stats.record_rays(ray_counts);
+
+    (radiance.extend(coverage), specular.extend(0.0), first_albedo.extend(1.0), first_normal.extend(first_depth), rng_state.next_state())
+}
+
+// Trace `samples` paths through a pixel and average them. The accumulation still gets a single sample, so pixels can
+// be refined faster than the rest of the image without changing how the image is averaged. At least 1 sample.
+#[cfg_attr(target_arch = "spirv", inline(always))]
+pub fn trace_pixel_averaged<P: PackedPrimitive, S: BounceStats>(
+    samples: u32,
+    id: UVec3,
+    config: &TracingConfig,
+    rng: UVec2,
+    per_vertex_buffer: &[PerVertexData],
+    index_buffer: &[P],
+    nodes_buffer: &[BVHNode],
+    material_data_buffer: &[MaterialData],
+    light_pick_buffer: &[LightPickEntry],
+    light_tree_buffer: &[LightTreeNode],
+    sampler: &Sampler,
+    atlas: &Image!(2D, type=f32, sampled),
+    skybox: &Image!(2D, type=f32, sampled),
+    stats: &mut S,
+) -> (Vec4, Vec4, Vec4, Vec4, UVec2) {
+    let mut radiance = Vec4::ZERO;
+    let mut specular = Vec4::ZERO;
+    let mut albedo = Vec4::ZERO;
+    let mut normal = Vec4::ZERO;
+    let mut rng_state = rng;
+    for _ in 0..samples {
+        let sample = trace_pixel(
+            id,
+            config,
+            rng_state,
+            per_vertex_buffer,
+            index_buffer,
+            nodes_buffer,
+            material_data_buffer,
+            light_pick_buffer,
+            light_tree_buffer,
+            sampler,
+            atlas,
+            skybox,
+            stats,
+        );
+        radiance += sample.0;
+        specular += sample.1;
+        albedo += sample.2;
+        normal += sample.3;
+        rng_state = sample.4;
+    }
+    let scale = 1.0 / samples as f32;
+    (radiance * scale, specular * scale, albedo * scale, normal * scale, rng_state)
 }
 
+// Samples per dispatch of a pixel. The mask only applies at the resolution it was painted at, so previews at reduced
+// resolution ignore it.
+pub fn pixel_samples(config: &TracingConfig, sample_mask: &[u32], index: usize) -> u32 {
+    if sample_mask.len() == (config.width * config.height) as usize {
+        sample_mask[index].max(1)
+    } else {
+        1
+    }
+}
+
+// Trace a pixel and accumulate the results into the output buffers. Shared by the entry points, which only differ
+// in the width of the index buffer, see `CompactPrimitive`.
+#[cfg_attr(target_arch = "spirv", inline(always))]
+fn trace_and_accumulate<P: PackedPrimitive>(
+    id: UVec3,
+    local_index: u32,
+    config: &TracingConfig,
+    rng: &mut [UVec2],
+    output: &mut [Vec4],
+    per_vertex_buffer: &[PerVertexData],
+    index_buffer: &[P],
+    nodes_buffer: &[BVHNode],
+    material_data_buffer: &[MaterialData],
+    light_pick_buffer: &[LightPickEntry],
+    sampler: &Sampler,
+    atlas: &Image!(2D, type=f32, sampled),
+    skybox: &Image!(2D, type=f32, sampled),
+    albedo_output: &mut [Vec4],
+    normal_output: &mut [Vec4],
+    light_tree_buffer: &[LightTreeNode],
+    variance_output: &mut [Vec4],
+    specular_output: &mut [Vec4],
+    stats: &mut [u32],
+    workgroup_stats: &mut [u32; BOUNCE_STATS_LEN],
+    sample_mask: &[u32],
+) {
+    // Every invocation has to reach the barriers of the statistics, including those outside the image
+    let mut workgroup_stats = WorkgroupBounceStats::new(workgroup_stats, stats, local_index);
+    // Handle non-divisible workgroup sizes.
+    if id.x < config.width && id.y < config.height {
+        trace_and_accumulate_pixel(
+            id,
+            config,
+            rng,
+            output,
+            per_vertex_buffer,
+            index_buffer,
+            nodes_buffer,
+            material_data_buffer,
+            light_pick_buffer,
+            sampler,
+            atlas,
+            skybox,
+            albedo_output,
+            normal_output,
+            light_tree_buffer,
+            variance_output,
+            specular_output,
+            &mut workgroup_stats,
+            sample_mask,
+        );
+    }
+    workgroup_stats.flush(stats, local_index);
+}
+
+#[cfg_attr(target_arch = "spirv", inline(always))]
+fn trace_and_accumulate_pixel<P: PackedPrimitive>(
+    id: UVec3,
+    config: &TracingConfig,
+    rng: &mut [UVec2],
+    output: &mut [Vec4],
+    per_vertex_buffer: &[PerVertexData],
+    index_buffer: &[P],
+    nodes_buffer: &[BVHNode],
+    material_data_buffer: &[MaterialData],
+    light_pick_buffer: &[LightPickEntry],
+    sampler: &Sampler,
+    atlas: &Image!(2D, type=f32, sampled),
+    skybox: &Image!(2D, type=f32, sampled),
+    albedo_output: &mut [Vec4],
+    normal_output: &mut [Vec4],
+    light_tree_buffer: &[LightTreeNode],
+    variance_output: &mut [Vec4],
+    specular_output: &mut [Vec4],
+    stats: &mut WorkgroupBounceStats,
+    sample_mask: &[u32],
+) {
+    let index = (id.y * config.width + id.x) as usize;
+    let samples = pixel_samples(config, sample_mask, index);
+
+    // Each sample continues the sequence where the previous one left off, and is accumulated on its own, so a batch
+    // of samples gives the same result as dispatching them one at a time.
+    let mut rng_state = rng[index];
+    for _ in 0..config.samples_per_dispatch.max(1) {
+        let (radiance, specular, albedo, normal, next_rng_state) = trace_pixel_averaged(
+            samples,
+            id,
+            config,
+            rng_state,
+            per_vertex_buffer,
+            index_buffer,
+            nodes_buffer,
+            material_data_buffer,
+            light_pick_buffer,
+            light_tree_buffer,
+            sampler,
+            atlas,
+            skybox,
+            stats,
+        );
+
+        output[index] += radiance;
+        specular_output[index] += specular;
+        albedo_output[index] += albedo;
+        normal_output[index] += normal;
+        variance_output[index] = welford_update(variance_output[index], radiance.xyz());
+        rng_state = next_rng_state;
+    }
+    rng[index] = rng_state;
+}
 
 #[spirv(compute(threads(8, 8, 1)))]
 pub fn trace_kernel(
     #[spirv(global_invocation_id)] id: UVec3,
+    #[spirv(local_invocation_index)] local_index: u32,
     #[spirv(uniform, descriptor_set = 0, binding = 0)] config: &TracingConfig,
     #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] rng: &mut [UVec2],
     #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] output: &mut [Vec4],
@@ -200,18 +739,71 @@ pub fn trace_kernel(
     #[spirv(descriptor_set = 0, binding = 8)] sampler: &Sampler,
     #[spirv(descriptor_set = 0, binding = 9)] atlas: &Image!(2D, type=f32, sampled),
     #[spirv(descriptor_set = 0, binding = 10)] skybox: &Image!(2D, type=f32, sampled),
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 11)] albedo_output: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 12)] normal_output: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 13)] light_tree_buffer: &[LightTreeNode],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 14)] variance_output: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 15)] specular_output: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 16)] stats: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 17)] sample_mask: &[u32],
+    #[spirv(workgroup)] workgroup_stats: &mut [u32; BOUNCE_STATS_LEN],
 ) {
-    // Handle non-divisible workgroup sizes.
-    if id.x > config.width || id.y > config.height {
-        return;
-    }
-    
-    let index = (id.y * config.width + id.x) as usize;
+    trace_and_accumulate(
+        id,
+        local_index,
+        config,
+        rng,
+        output,
+        per_vertex_buffer,
+        index_buffer,
+        nodes_buffer,
+        material_data_buffer,
+        light_pick_buffer,
+        sampler,
+        atlas,
+        skybox,
+        albedo_output,
+        normal_output,
+        light_tree_buffer,
+        variance_output,
+        specular_output,
+        stats,
+        workgroup_stats,
+        sample_mask,
+    );
+}
 
-    let (radiance, rng_state) = trace_pixel(
+// Same as `trace_kernel`, but reading a compact index buffer with 16 bit indices
+#[spirv(compute(threads(8, 8, 1)))]
+pub fn trace_kernel_compact(
+    #[spirv(global_invocation_id)] id: UVec3,
+    #[spirv(local_invocation_index)] local_index: u32,
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] config: &TracingConfig,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] rng: &mut [UVec2],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] output: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] per_vertex_buffer: &[PerVertexData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] index_buffer: &[CompactPrimitive],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] nodes_buffer: &[BVHNode],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] material_data_buffer: &[MaterialData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 7)] light_pick_buffer: &[LightPickEntry],
+    #[spirv(descriptor_set = 0, binding = 8)] sampler: &Sampler,
+    #[spirv(descriptor_set = 0, binding = 9)] atlas: &Image!(2D, type=f32, sampled),
+    #[spirv(descriptor_set = 0, binding = 10)] skybox: &Image!(2D, type=f32, sampled),
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 11)] albedo_output: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 12)] normal_output: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 13)] light_tree_buffer: &[LightTreeNode],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 14)] variance_output: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 15)] specular_output: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 16)] stats: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 17)] sample_mask: &[u32],
+    #[spirv(workgroup)] workgroup_stats: &mut [u32; BOUNCE_STATS_LEN],
+) {
+    trace_and_accumulate(
         id,
+        local_index,
         config,
-        rng[index],
+        rng,
+        output,
         per_vertex_buffer,
         index_buffer,
         nodes_buffer,
@@ -220,8 +812,30 @@ pub fn trace_kernel(
         sampler,
         atlas,
         skybox,
+        albedo_output,
+        normal_output,
+        light_tree_buffer,
+        variance_output,
+        specular_output,
+        stats,
+        workgroup_stats,
+        sample_mask,
     );
-    
-    output[index] += radiance;
-    rng[index] = rng_state;
+}
+
+// One iteration of the À-Trous denoiser, see `denoise::atrous_pixel`. It has its own bindings, the image is denoised
+// after it has been read back and resolved, so none of the tracing resources are needed.
+#[spirv(compute(threads(8, 8, 1)))]
+pub fn atrous_kernel(
+    #[spirv(global_invocation_id)] id: UVec3,
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] config: &ATrousConfig,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] input: &[Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] output: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] albedo: &[Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] normal: &[Vec4],
+) {
+    if id.x >= config.width || id.y >= config.height {
+        return;
+    }
+    output[(id.y * config.width + id.x) as usize] = denoise::atrous_pixel(id.x, id.y, config, input, albedo, normal);
 }
@@ -1,9 +1,9 @@
-use shared_structs::{LightPickEntry, PerVertexData, MaterialData, NextEventEstimation};
-use spirv_std::glam::{Vec3, UVec4, Vec4Swizzles};
+use shared_structs::{Image, Sampler, LightPickEntry, LightTreeNode, PerVertexData, MaterialData, NextEventEstimation, MisHeuristic, LightSampling, WorkingSpace, PrimitiveType, PackedPrimitive, material_index};
+use spirv_std::glam::{Vec2, Vec3};
 #[allow(unused_imports)]
 use spirv_std::num_traits::Float;
 
-use crate::{rng::RngState, util, bsdf::{self, BSDF}, intersection::{BVHReference, self}};
+use crate::{rng::RngState, util, texture, bsdf::{self, BSDF}, intersection::{BVHReference, self}, stats::RayCounts};
 
 pub fn pick_light(table: &[LightPickEntry], rng_state: &mut RngState) -> (u32, f32, f32) {
     let rng = rng_state.gen_r2();
@@ -15,13 +15,77 @@ pub fn pick_light(table: &[LightPickEntry], rng_state: &mut RngState) -> (u32, f
     }
 }
 
-// https://www.cs.princeton.edu/~funk/tog02.pdf equation 1
+// Stochastic traversal of the light tree, at each node picking a child proportional to its importance.
+// The pdf of picking the triangle is the product of the choices made on the way down.
+pub fn pick_light_from_tree(tree: &[LightTreeNode], point: Vec3, normal: Vec3, rng_state: &mut RngState) -> (u32, f32, f32) {
+    // Use as many dimensions as the light pick table, and reuse the same random number at every level by rescaling it
+    let mut rng = rng_state.gen_r2().x;
+    let mut node = tree[0];
+    let mut pdf = 1.0;
+    while !node.is_leaf() {
+        let left = tree[node.left_node_index as usize];
+        let right = tree[node.left_node_index as usize + 1];
+        let left_importance = left.importance(point, normal);
+        let right_importance = right.importance(point, normal);
+        let total_importance = left_importance + right_importance;
+        let left_probability = if total_importance > 0.0 { left_importance / total_importance } else { 0.5 };
+        if rng < left_probability {
+            rng /= left_probability;
+            node = left;
+            pdf *= left_probability;
+        } else {
+            rng = (rng - left_probability) / (1.0 - left_probability);
+            node = right;
+            pdf *= 1.0 - left_probability;
+        }
+        rng = rng.min(0.99999994); // Keep the rescaled number below 1
+    }
+    (node.triangle_index(), node.triangle_area(), pdf)
+}
+
+// https://www.cs.princeton.edu/~funk/tog02.pdf equation 1. Uniform over the area, so the pdf w.r.t area is
+// 1 / area, which `calculate_light_pdf` converts to solid angle.
 pub fn pick_triangle_point(a: Vec3, b: Vec3, c: Vec3, rng_state: &mut RngState) -> Vec3 {
     let rng = rng_state.gen_r2();
     let r1_sqrt = rng.x.sqrt();
     (1.0 - r1_sqrt) * a + (r1_sqrt * (1.0 - rng.y)) * b + (r1_sqrt * rng.y) * c
 }
 
+pub fn pick_quad_point(a: Vec3, b: Vec3, c: Vec3, rng_state: &mut RngState) -> Vec3 {
+    let rng = rng_state.gen_r2();
+    a + (b - a) * rng.x + (c - a) * rng.y
+}
+
+// Uniformly samples the cone of directions from `point` towards the sphere, which is much less noisy than sampling its
+// area since every sample lands on the visible side. See "Monte Carlo Techniques for Direct Lighting Calculations" by
+// Shirley et al. Returns the sampled point on the sphere and the pdf w.r.t solid angle, which is 0 inside the sphere.
+pub fn pick_sphere_point(center: Vec3, radius: f32, point: Vec3, rng_state: &mut RngState) -> (Vec3, f32) {
+    let rng = rng_state.gen_r2();
+    let to_center = center - point;
+    let distance_squared = to_center.length_squared();
+    if distance_squared <= radius * radius {
+        return (center, 0.0);
+    }
+
+    // 1 - cos_theta_max, written to avoid cancellation for small and distant spheres
+    let sin_theta_max_squared = radius * radius / distance_squared;
+    let cos_theta_max = (1.0 - sin_theta_max_squared).max(0.0).sqrt();
+    let one_minus_cos_theta_max = sin_theta_max_squared / (1.0 + cos_theta_max);
+
+    let cos_theta = 1.0 - rng.x * one_minus_cos_theta_max;
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * core::f32::consts::PI * rng.y;
+    let distance = distance_squared.sqrt();
+    let (up, right, forward) = util::create_cartesian(to_center / distance);
+    let direction = up * cos_theta + right * (sin_theta * phi.cos()) + forward * (sin_theta * phi.sin());
+
+    // Nearest intersection with the sphere along the sampled direction
+    let projected = direction.dot(to_center);
+    let offset = (radius * radius - (distance_squared - projected * projected)).max(0.0).sqrt();
+    let light_point = point + direction * (projected - offset);
+    (light_point, 1.0 / (2.0 * core::f32::consts::PI * one_minus_cos_theta_max))
+}
+
 // PDF of picking a point on a light source w.r.t area
 // - light_area is the area of the light source
 // - light_distance is the distance from the chosen point to the point being shaded
@@ -78,10 +142,10 @@ pub fn calculate_light_pdf(light_area: f32, light_distance: f32, light_normal: V
     light_distance.powi(2) / (light_area * cos_theta)
 }
 
-pub fn get_weight(nee_mode: NextEventEstimation, p1: f32, p2: f32) -> f32 {
+pub fn get_weight(nee_mode: NextEventEstimation, heuristic: MisHeuristic, p1: f32, p2: f32) -> f32 {
     match nee_mode {
         NextEventEstimation::None => 1.0,
-        NextEventEstimation::MultipleImportanceSampling => util::power_heuristic(p1, p2),
+        NextEventEstimation::MultipleImportanceSampling => heuristic.weight(p1, p2),
         NextEventEstimation::DirectLightSampling => 1.0,
     }
 }
@@ -90,26 +154,34 @@ pub fn get_weight(nee_mode: NextEventEstimation, p1: f32, p2: f32) -> f32 {
 pub struct DirectLightSample {
     pub light_area: f32,
     pub light_normal: Vec3,
+    pub light_solid_angle_pdf: f32, // Only set for spheres, which are sampled by solid angle rather than area
     pub light_pick_pdf: f32,
-    pub light_emission: Vec3,
     pub light_triangle_index: u32,
     pub throughput: Vec3,
     pub direct_light_contribution: Vec3,
 }
 
-pub fn sample_direct_lighting(
+pub fn sample_direct_lighting<P: PackedPrimitive>(
     nee_mode: NextEventEstimation,
-    index_buffer: &[UVec4],
+    mis_heuristic: MisHeuristic,
+    light_sampling: LightSampling,
+    working_space: WorkingSpace,
+    cast_shadows: bool,
+    index_buffer: &[P],
     per_vertex_buffer: &[PerVertexData],
     material_data_buffer: &[MaterialData],
     light_pick_buffer: &[LightPickEntry],
+    light_tree_buffer: &[LightTreeNode],
     bvh: &BVHReference,
+    sampler: &Sampler,
+    atlas: &Image!(2D, type=f32, sampled),
     throughput: Vec3,
     surface_bsdf: &impl BSDF,
     surface_point: Vec3,
     surface_normal: Vec3,
     ray_direction: Vec3,
     rng_state: &mut RngState,
+    ray_counts: &mut RayCounts,
 ) -> DirectLightSample {
     // If the first entry is a sentinel, there are no lights
     let mut info = DirectLightSample::default();
@@ -118,36 +190,75 @@ pub fn sample_direct_lighting(
     }
 
     // Pick a light, get its surface properties
-    let (light_index, light_area, light_pick_pdf) = pick_light(&light_pick_buffer, rng_state);
-    let light_triangle = index_buffer[light_index as usize];
-    let light_vert_a = per_vertex_buffer[light_triangle.x as usize].vertex.xyz();
-    let light_vert_b = per_vertex_buffer[light_triangle.y as usize].vertex.xyz();
-    let light_vert_c = per_vertex_buffer[light_triangle.z as usize].vertex.xyz();
-    let light_norm_a = per_vertex_buffer[light_triangle.x as usize].normal.xyz();
-    let light_norm_b = per_vertex_buffer[light_triangle.y as usize].normal.xyz();
-    let light_norm_c = per_vertex_buffer[light_triangle.z as usize].normal.xyz();
-    let light_normal = (light_norm_a + light_norm_b + light_norm_c) / 3.0; // lights can use flat shading, no need to pay for interpolation
-    let light_material = material_data_buffer[light_triangle.w as usize];
-    let light_emission = light_material.emissive.xyz();
+    let (light_index, light_area, light_pick_pdf) = match light_sampling {
+        LightSampling::Power => pick_light(&light_pick_buffer, rng_state),
+        LightSampling::LightTree => pick_light_from_tree(light_tree_buffer, surface_point, surface_normal, rng_state),
+    };
+    let light_triangle = index_buffer[light_index as usize].unpack();
+    let light_vert_a = per_vertex_buffer[light_triangle.x as usize].vertex;
+    let light_vert_b = per_vertex_buffer[light_triangle.y as usize].vertex;
+    let light_vert_c = per_vertex_buffer[light_triangle.z as usize].vertex;
+    // The pdf conversion needs the geometric normal, which also points out of the front face like the backface check
+    // on BSDF hits expects. Averaged vertex normals are shorter than 1 and tilted on smooth shaded lights, which biases them.
+    let mut light_normal = (light_vert_b - light_vert_a).cross(light_vert_c - light_vert_a).normalize_or_zero();
+    let light_material = material_data_buffer[material_index(light_triangle) as usize];
 
     // Pick a point on the light
-    let light_point = pick_triangle_point(light_vert_a, light_vert_b, light_vert_c, rng_state);
+    let mut light_solid_angle_pdf = 0.0;
+    let light_point = match PrimitiveType::of(light_triangle) {
+        PrimitiveType::Triangle => pick_triangle_point(light_vert_a, light_vert_b, light_vert_c, rng_state),
+        PrimitiveType::Quad => pick_quad_point(light_vert_a, light_vert_b, light_vert_c, rng_state),
+        PrimitiveType::Sphere => {
+            let radius = per_vertex_buffer[light_triangle.x as usize].radius;
+            let (light_point, pdf) = pick_sphere_point(light_vert_a, radius, surface_point, rng_state);
+            light_normal = (light_point - light_vert_a) / radius;
+            light_solid_angle_pdf = pdf;
+            light_point
+        }
+    };
+
+    // Emissive textures are sampled at the picked point. Light samples have no ray cone, so they use the finest level.
+    let light_emission = if light_material.has_emissive_texture() {
+        let uv = if PrimitiveType::of(light_triangle) == PrimitiveType::Sphere {
+            texture::sphere_uv(light_normal)
+        } else {
+            let bary = util::barycentric(light_point, light_vert_a, light_vert_b, light_vert_c);
+            bary.x * per_vertex_buffer[light_triangle.x as usize].uv0
+                + bary.y * per_vertex_buffer[light_triangle.y as usize].uv0
+                + bary.z * per_vertex_buffer[light_triangle.z as usize].uv0
+        };
+        let uv = if uv.clamp(Vec2::ZERO, Vec2::ONE) != uv { uv.fract() } else { uv };
+        texture::material_emission(&light_material, atlas, sampler, uv, f32::NEG_INFINITY)
+    } else {
+        light_material.emission()
+    };
+    let light_emission = working_space.from_linear_srgb(light_emission);
+
     let light_direction_unorm = light_point - surface_point;
     let light_distance = light_direction_unorm.length();
     let light_direction = light_direction_unorm / light_distance;
 
-    // Sample the light directly using MIS
+    // Sample the light directly using MIS. Without shadows, every light counts as visible.
     let mut direct = Vec3::ZERO;
-    let light_trace = bvh.intersect_any(
-        per_vertex_buffer,
-        index_buffer,
-        surface_point + light_direction * util::EPS,
-        light_direction,
-        light_distance - util::EPS * 2.0,
-    );
-    if !light_trace.hit {
-        // Calculate light pdf for this sample
-        let light_pdf = calculate_light_pdf(light_area, light_distance, light_normal, light_direction);
+    let mut occluded = false;
+    if cast_shadows {
+        let light_trace = bvh.intersect_any(
+            per_vertex_buffer,
+            index_buffer,
+            surface_point + light_direction * bvh.t_min,
+            light_direction,
+            light_distance - bvh.t_min * 2.0,
+        );
+        ray_counts.add(&light_trace);
+        occluded = light_trace.hit;
+    }
+    if !occluded {
+        // Calculate light pdf for this sample. A sphere containing the surface point has a pdf of 0, it isn't visible.
+        let light_pdf = if PrimitiveType::of(light_triangle) == PrimitiveType::Sphere {
+            light_solid_angle_pdf
+        } else {
+            calculate_light_pdf(light_area, light_distance, light_normal, light_direction)
+        };
         if light_pdf > 0.0 {
             // Calculate BSDF attenuation for this sample
             let bsdf_attenuation = surface_bsdf.evaluate(-ray_direction, surface_normal, light_direction, bsdf::LobeType::DiffuseReflection);
@@ -155,7 +266,7 @@ pub fn sample_direct_lighting(
             let bsdf_pdf = surface_bsdf.pdf(-ray_direction, surface_normal, light_direction, bsdf::LobeType::DiffuseReflection);
             if bsdf_pdf > 0.0 {
                 // MIS - add the weighted sample
-                let weight = get_weight(nee_mode, light_pdf, bsdf_pdf);
+                let weight = get_weight(nee_mode, mis_heuristic, light_pdf, bsdf_pdf);
                 direct = (bsdf_attenuation * light_emission * weight / light_pdf) / light_pick_pdf;
             }
         }
@@ -164,8 +275,8 @@ pub fn sample_direct_lighting(
     // Write out data for the next bounce to use
     info.light_area = light_area;
     info.light_normal = light_normal;
+    info.light_solid_angle_pdf = light_solid_angle_pdf;
     info.light_pick_pdf = light_pick_pdf;
-    info.light_emission = light_emission;
     info.light_triangle_index = light_index;
     info.throughput = throughput;
     info.direct_light_contribution = throughput * direct;
@@ -176,22 +287,29 @@ pub fn sample_direct_lighting(
 // - We are using NEE with MIS
 // - We have hit a light source
 // - That last bounce was diffuse, so we did direct light sampling
+// `emission` is that of the light at the hit point, which differs from the sampled point on textured lights.
 pub fn calculate_bsdf_mis_contribution(
     trace_result: &intersection::TraceResult,
+    emission: Vec3,
     last_bsdf_sample: &bsdf::BSDFSample,
-    last_light_sample: &DirectLightSample
+    last_light_sample: &DirectLightSample,
+    mis_heuristic: MisHeuristic,
 ) -> Vec3 {
     // If we haven't hit the same light as we sampled directly, no contribution
     if trace_result.triangle_index != last_light_sample.light_triangle_index {
         return Vec3::ZERO;
     }
 
-    // Calculate the light pdf for this sample
-    let light_pdf = calculate_light_pdf(last_light_sample.light_area, trace_result.t, last_light_sample.light_normal, last_bsdf_sample.sampled_direction);
+    // Calculate the light pdf for this sample. Solid angle sampling of spheres is uniform over the visible cone.
+    let light_pdf = if PrimitiveType::of(trace_result.triangle) == PrimitiveType::Sphere {
+        last_light_sample.light_solid_angle_pdf
+    } else {
+        calculate_light_pdf(last_light_sample.light_area, trace_result.t, last_light_sample.light_normal, last_bsdf_sample.sampled_direction)
+    };
     if light_pdf > 0.0 {
         // MIS - add the weighted sample
-        let weight = get_weight(NextEventEstimation::MultipleImportanceSampling, last_bsdf_sample.pdf, light_pdf);
-        let direct = (last_bsdf_sample.spectrum * last_light_sample.light_emission * weight / last_bsdf_sample.pdf) / last_light_sample.light_pick_pdf;
+        let weight = get_weight(NextEventEstimation::MultipleImportanceSampling, mis_heuristic, last_bsdf_sample.pdf, light_pdf);
+        let direct = (last_bsdf_sample.spectrum * emission * weight / last_bsdf_sample.pdf) / last_light_sample.light_pick_pdf;
         last_light_sample.throughput * direct
     } else {
         Vec3::ZERO
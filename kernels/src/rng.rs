@@ -1,6 +1,5 @@
 use spirv_std::glam::{UVec2, Vec2, Vec3};
 
-#[allow(dead_code)]
 #[cfg(target_arch = "spirv")]
 pub fn pcg_hash(input: u32) -> u32 {
     let state = input * 747796405u32 + 2891336453u32;
@@ -8,7 +7,6 @@ pub fn pcg_hash(input: u32) -> u32 {
     (word >> 22u32) ^ word
 }
 
-#[allow(dead_code)]
 #[cfg(not(target_arch = "spirv"))]
 pub fn pcg_hash(input: u32) -> u32 {
     let state = input.overflowing_mul(747796405u32).0.overflowing_add(2891336453u32).0;
@@ -31,6 +29,7 @@ pub fn lds(n: u32, dimension: usize, offset: u32) -> f32 {
     (LDS_PRIMES[dimension].wrapping_mul(n.wrapping_add(offset))) as f32 * INV_U32_MAX_FLOAT 
 }
 
+#[derive(Copy, Clone)]
 pub struct RngState {
     state: UVec2,
     dimension: usize,
@@ -44,6 +43,11 @@ impl RngState {
         }
     }
 
+    // Another sequence for the same sample, offset by a hash of the seed, for loops that would run out of dimensions
+    pub fn fork(&self, seed: u32) -> Self {
+        Self::new(UVec2::new(self.state.x, pcg_hash(seed)))
+    }
+
     pub fn next_state(&self) -> UVec2 {
         UVec2::new(self.state.x + 1, self.state.y)
     }
@@ -60,4 +64,25 @@ impl RngState {
     pub fn gen_r3(&mut self) -> Vec3 {
         Vec3::new(self.gen_r1(), self.gen_r1(), self.gen_r1())
     }
-}
\ No newline at end of file
+}
+// Hashes its way through random numbers, for loops that can run for longer than the low discrepancy sequence has
+// dimensions, like random walks. Each instance should be seeded from a dimension of `RngState`.
+pub struct HashRngState {
+    state: u32,
+}
+
+impl HashRngState {
+    pub fn new(seed: u32) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn gen_r1(&mut self) -> f32 {
+        self.state = pcg_hash(self.state);
+        // 24 bits, so the result is always below 1
+        (self.state >> 8) as f32 * (1.0 / 16777216.0)
+    }
+
+    pub fn gen_r2(&mut self) -> Vec2 {
+        Vec2::new(self.gen_r1(), self.gen_r1())
+    }
+}
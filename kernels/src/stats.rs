@@ -0,0 +1,151 @@
+use shared_structs::{bounce_stats_entry, bounce_stats_fixed_point, BOUNCE_STATS_LEN, BOUNCE_STATS_STRIDE, RAY_STATS_ENTRY};
+use spirv_std::glam::Vec3;
+
+use crate::intersection::TraceResult;
+use spirv_std::memory::Scope;
+#[cfg(target_arch = "spirv")]
+use spirv_std::memory::Semantics;
+
+// Receives the throughput of every path at the start of every bounce, see `BOUNCE_STATS_BOUNCES`, and the work done
+// tracing each sample
+pub trait BounceStats {
+    fn record(&mut self, bounce: u32, throughput: Vec3);
+    fn record_rays(&mut self, counts: RayCounts);
+}
+
+pub struct NoBounceStats;
+
+impl BounceStats for NoBounceStats {
+    fn record(&mut self, _bounce: u32, _throughput: Vec3) {}
+    fn record_rays(&mut self, _counts: RayCounts) {}
+}
+
+// Rays cast by a sample, of any kind, and the primitives they were tested against. These are added up locally and
+// recorded once at the end of the sample, so the workgroup counters only see a couple of atomic adds per sample.
+#[derive(Copy, Clone, Default)]
+pub struct RayCounts {
+    pub rays: u32,
+    pub primitive_tests: u32,
+}
+
+impl RayCounts {
+    pub fn add(&mut self, trace_result: &TraceResult) {
+        self.rays += 1;
+        self.primitive_tests += trace_result.primitive_tests;
+    }
+}
+
+// Invocations in a workgroup of the trace kernels, which share the counters of a `WorkgroupBounceStats`
+pub const WORKGROUP_INVOCATIONS: u32 = 64;
+
+// Statistics added up in workgroup shared memory, then added to a buffer shared by every invocation once the workgroup
+// is done, so the buffer sees one atomic add per counter per workgroup, rather than per invocation. wgpu doesn't allow
+// empty buffers, so a buffer shorter than `BOUNCE_STATS_LEN` turns them off. Every invocation of the workgroup has to
+// call `new` and `flush`, since they wait for each other.
+pub struct WorkgroupBounceStats<'a> {
+    counters: &'a mut [u32; BOUNCE_STATS_LEN],
+    enabled: bool,
+}
+
+impl<'a> WorkgroupBounceStats<'a> {
+    // Clear the counters of the workgroup, each invocation taking its share
+    pub fn new(counters: &'a mut [u32; BOUNCE_STATS_LEN], stats: &[u32], local_index: u32) -> Self {
+        let enabled = stats.len() >= BOUNCE_STATS_LEN;
+        if enabled {
+            let mut index = local_index as usize;
+            while index < BOUNCE_STATS_LEN {
+                counters[index] = 0;
+                index += WORKGROUP_INVOCATIONS as usize;
+            }
+        }
+        workgroup_barrier();
+        Self { counters, enabled }
+    }
+
+    // Add the counters of the workgroup to the buffer, once every invocation has recorded its own
+    pub fn flush(&self, stats: &mut [u32], local_index: u32) {
+        workgroup_barrier();
+        if !self.enabled {
+            return;
+        }
+        let mut index = local_index as usize;
+        while index < BOUNCE_STATS_LEN {
+            // Low words of 64 bit sums carry into the word after them, which is added on its own
+            let value = self.counters[index];
+            if is_low_word(index) {
+                add_wide::<DEVICE>(stats, index, value);
+            } else if value != 0 {
+                atomic_add::<DEVICE>(&mut stats[index], value);
+            }
+            index += WORKGROUP_INVOCATIONS as usize;
+        }
+    }
+}
+
+impl BounceStats for WorkgroupBounceStats<'_> {
+    fn record(&mut self, bounce: u32, throughput: Vec3) {
+        if !self.enabled {
+            return;
+        }
+        let entry = bounce_stats_entry(bounce);
+        add_wide::<WORKGROUP>(self.counters, entry, bounce_stats_fixed_point(throughput));
+        atomic_add::<WORKGROUP>(&mut self.counters[entry + 2], 1);
+    }
+
+    fn record_rays(&mut self, counts: RayCounts) {
+        if !self.enabled {
+            return;
+        }
+        add_wide::<WORKGROUP>(self.counters, RAY_STATS_ENTRY, counts.rays);
+        add_wide::<WORKGROUP>(self.counters, RAY_STATS_ENTRY + 2, counts.primitive_tests);
+    }
+}
+
+// Whether a word of the statistics is the low word of a 64 bit sum, see `BOUNCE_STATS_BOUNCES`
+fn is_low_word(index: usize) -> bool {
+    let word = index % BOUNCE_STATS_STRIDE;
+    word == 0 || (index >= RAY_STATS_ENTRY && word == 2)
+}
+
+// Scopes of the atomic adds, for counters of the workgroup and of the whole dispatch
+const WORKGROUP: u32 = Scope::Workgroup as u32;
+const DEVICE: u32 = Scope::Device as u32;
+
+// Add to a 64 bit sum stored as a low and a high word
+fn add_wide<const SCOPE: u32>(buffer: &mut [u32], index: usize, value: u32) {
+    if value == 0 {
+        return;
+    }
+    let low = atomic_add::<SCOPE>(&mut buffer[index], value);
+    if low > u32::MAX - value {
+        atomic_add::<SCOPE>(&mut buffer[index + 1], 1); // Carry
+    }
+}
+
+// Returns the previous value
+#[cfg(target_arch = "spirv")]
+fn atomic_add<const SCOPE: u32>(target: &mut u32, value: u32) -> u32 {
+    unsafe { spirv_std::arch::atomic_i_add::<u32, SCOPE, { Semantics::NONE.bits() }>(target, value) }
+}
+
+#[cfg(not(target_arch = "spirv"))]
+fn atomic_add<const SCOPE: u32>(target: &mut u32, value: u32) -> u32 {
+    let previous = *target;
+    *target = previous.wrapping_add(value);
+    previous
+}
+
+// Wait for every invocation of the workgroup, and make their writes to shared memory visible
+#[cfg(target_arch = "spirv")]
+fn workgroup_barrier() {
+    unsafe {
+        spirv_std::arch::control_barrier::<
+            WORKGROUP,
+            WORKGROUP,
+            { Semantics::WORKGROUP_MEMORY.bits() | Semantics::ACQUIRE_RELEASE.bits() },
+        >()
+    }
+}
+
+#[cfg(not(target_arch = "spirv"))]
+fn workgroup_barrier() {}
@@ -0,0 +1,95 @@
+use shared_structs::{PerVertexData, PackedPrimitive};
+use spirv_std::glam::Vec3;
+#[allow(unused_imports)]
+use spirv_std::num_traits::Float;
+
+use crate::{rng::{RngState, HashRngState}, util, intersection::BVHReference, stats::RayCounts};
+
+// Paths that are still inside the medium after this many scattering events are dropped
+const SUBSURFACE_MAX_STEPS: u32 = 64;
+
+// Single scattering albedo which gives roughly `albedo` as the overall reflectance of a thick slab, after any number
+// of scattering events. From "Practical and Controllable Subsurface Scattering for Production Path Tracing", Chiang et al.
+fn invert_albedo(albedo: f32) -> f32 {
+    let s = 4.09712 + 4.20863 * albedo - (9.59217 + 41.6808 * albedo + 17.7126 * albedo * albedo).sqrt();
+    1.0 - s * s
+}
+
+fn single_scattering_albedo(albedo: Vec3) -> Vec3 {
+    Vec3::new(invert_albedo(albedo.x), invert_albedo(albedo.y), invert_albedo(albedo.z))
+}
+
+fn transmittance(extinction: Vec3, distance: f32) -> Vec3 {
+    Vec3::new((-extinction.x * distance).exp(), (-extinction.y * distance).exp(), (-extinction.z * distance).exp())
+}
+
+fn average(value: Vec3) -> f32 {
+    (value.x + value.y + value.z) * (1.0 / 3.0)
+}
+
+// Random walk through the inside of a mesh, starting at `entry` on its surface. Distances are sampled for one color
+// channel at a time, picked uniformly, and weighted by the average pdf of all channels, so media that are much more
+// transparent to one color than the others stay unbiased. Scattering is isotropic, and the boundary is index matched,
+// so the path leaves the mesh in the direction it was going. Returns where it left, in which direction, and its
+// throughput, or None if it didn't make it out.
+pub fn random_walk<P: PackedPrimitive>(
+    bvh: &BVHReference,
+    per_vertex_buffer: &[PerVertexData],
+    index_buffer: &[P],
+    entry: Vec3,
+    inward_normal: Vec3,
+    albedo: Vec3,
+    mean_free_path: Vec3,
+    rng_state: &mut RngState,
+    ray_counts: &mut RayCounts,
+) -> Option<(Vec3, Vec3, Vec3)> {
+    let extinction = 1.0 / mean_free_path.max(Vec3::splat(util::EPS));
+    let scattering = extinction * single_scattering_albedo(albedo);
+
+    // Refract diffusely into the surface
+    let (up, nt, nb) = util::create_cartesian(inward_normal);
+    let rng_sample = rng_state.gen_r2();
+    let sample = util::cosine_sample_hemisphere(rng_sample.x, rng_sample.y);
+    let mut direction = sample.x * nb + sample.y * up + sample.z * nt;
+
+    // The walk can take more random numbers than the low discrepancy sequence has dimensions
+    let mut walk_rng = HashRngState::new((rng_state.gen_r1() * 4294967295.0) as u32);
+    let mut position = entry;
+    let mut throughput = Vec3::ONE;
+    for _ in 0..SUBSURFACE_MAX_STEPS {
+        let rng_sample = walk_rng.gen_r2();
+        let channel_extinction = if rng_sample.x < 1.0 / 3.0 {
+            extinction.x
+        } else if rng_sample.x < 2.0 / 3.0 {
+            extinction.y
+        } else {
+            extinction.z
+        };
+        let distance = -(1.0 - rng_sample.y).ln() / channel_extinction;
+
+        let trace_result = bvh.intersect_nearest(per_vertex_buffer, index_buffer, position, direction);
+        ray_counts.add(&trace_result);
+        if trace_result.hit && trace_result.t < distance {
+            // Made it to the surface, with the chance of getting this far being the average transmittance
+            let surface_transmittance = transmittance(extinction, trace_result.t);
+            let pdf = average(surface_transmittance);
+            if pdf <= 0.0 {
+                return None;
+            }
+            throughput *= surface_transmittance / pdf;
+            return Some((position + direction * trace_result.t, direction, throughput));
+        }
+
+        // Scatter inside the medium
+        let scatter_transmittance = transmittance(extinction, distance);
+        let pdf = average(extinction * scatter_transmittance);
+        if pdf <= 0.0 {
+            return None;
+        }
+        throughput *= scattering * scatter_transmittance / pdf;
+        position += direction * distance;
+        let rng_sample = walk_rng.gen_r2();
+        direction = util::uniform_sample_sphere(rng_sample.x, rng_sample.y);
+    }
+    None
+}
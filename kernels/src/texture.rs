@@ -0,0 +1,82 @@
+use shared_structs::{Image, Sampler, MaterialData, ATLAS_SIZE, ATLAS_MIP_LEVELS};
+use spirv_std::glam::{Vec2, Vec3, Vec4, Vec4Swizzles};
+#[allow(unused_imports)]
+use spirv_std::num_traits::Float;
+
+// Map a UV in the base level of the atlas to the corresponding UV in the given mip level.
+// See `build_mip_chain` on the host side for the layout.
+fn atlas_level_uv(uv: Vec2, level: u32) -> Vec2 {
+    if level == 0 {
+        Vec2::new(uv.x / 1.5, uv.y)
+    } else {
+        let scale = 1.0 / (1u32 << level) as f32;
+        Vec2::new((1.0 + uv.x * scale) / 1.5, 1.0 - 2.0 * scale + uv.y * scale)
+    }
+}
+
+// Texture LOD in UV space from ray cones, see "Texture Level of Detail Strategies for Real-Time Ray Tracing"
+// by Akenine-Möller et al. in RT gems 1 chapter 20. This doesn't know the size of the texture being sampled yet,
+// that is accounted for in `sample_atlas`.
+pub fn ray_cone_lod(
+    cone_width: f32,
+    ray_direction: Vec3,
+    normal: Vec3,
+    vert_a: Vec3,
+    vert_b: Vec3,
+    vert_c: Vec3,
+    uv_a: Vec2,
+    uv_b: Vec2,
+    uv_c: Vec2,
+) -> f32 {
+    let uv_area = (uv_b - uv_a).perp_dot(uv_c - uv_a).abs();
+    let world_area = (vert_b - vert_a).cross(vert_c - vert_a).length();
+    ray_cone_lod_from_density(cone_width, ray_direction, normal, uv_area / world_area)
+}
+
+// Same as `ray_cone_lod`, for surfaces where the ratio of UV area to world space area is known directly
+pub fn ray_cone_lod_from_density(cone_width: f32, ray_direction: Vec3, normal: Vec3, uv_density: f32) -> f32 {
+    let lod = 0.5 * uv_density.log2() + cone_width.log2() - ray_direction.dot(normal).abs().log2();
+    if lod.is_finite() {
+        lod
+    } else {
+        f32::NEG_INFINITY
+    }
+}
+
+// Trilinearly sample a texture with the given atlas location (`st`) from the atlas.
+pub fn sample_atlas(atlas: &Image!(2D, type=f32, sampled), sampler: &Sampler, st: Vec4, uv: Vec2, lod: f32) -> Vec4 {
+    let scaled_uv = st.xy() + uv * st.zw();
+
+    // Account for the size of the texture, and don't go past the 1x1 level of it, to avoid bleeding
+    let texture_size = (st.z * st.w).sqrt() * ATLAS_SIZE as f32;
+    let max_level = texture_size.log2().min((ATLAS_MIP_LEVELS - 1) as f32).max(0.0);
+    let lod = (lod + texture_size.log2()).max(0.0).min(max_level);
+
+    let level = lod.floor();
+    let t = lod - level;
+    let level = level as u32;
+    let color = atlas.sample_by_lod(*sampler, atlas_level_uv(scaled_uv, level), 0.0);
+    if t > 0.0 && level + 1 < ATLAS_MIP_LEVELS {
+        let next_color = atlas.sample_by_lod(*sampler, atlas_level_uv(scaled_uv, level + 1), 0.0);
+        color.lerp(next_color, t)
+    } else {
+        color
+    }
+}
+
+// Equirectangular UVs of analytic spheres, from the normal at the point on the sphere
+pub fn sphere_uv(normal: Vec3) -> Vec2 {
+    Vec2::new(
+        0.5 + normal.z.atan2(normal.x) / (2.0 * core::f32::consts::PI),
+        normal.y.clamp(-1.0, 1.0).acos() / core::f32::consts::PI,
+    )
+}
+
+// Linear sRGB radiance emitted by the material at the given UV, which is the emissive color scaled by the texture
+pub fn material_emission(material: &MaterialData, atlas: &Image!(2D, type=f32, sampled), sampler: &Sampler, uv: Vec2, lod: f32) -> Vec3 {
+    if material.has_emissive_texture() {
+        material.emission() * sample_atlas(atlas, sampler, material.emissive_texture, uv, lod).xyz()
+    } else {
+        material.emission()
+    }
+}
@@ -4,12 +4,11 @@ use spirv_std::num_traits::Float;
 
 pub const EPS: f32 = 0.001;
 
-#[allow(dead_code)]
 pub fn uniform_sample_sphere(r1: f32, r2: f32) -> Vec3 {
     let cos_phi = 2.0 * r1 - 1.0;
-    let sin_phi = (1.0 - cos_phi * cos_phi).sqrt();
+    let sin_phi = (1.0 - cos_phi * cos_phi).max(0.0).sqrt();
     let theta = 2.0 * core::f32::consts::PI * r2;
-    Vec3::new(sin_phi * theta.cos(), cos_phi, sin_phi * theta.cos())
+    Vec3::new(sin_phi * theta.cos(), cos_phi, sin_phi * theta.sin())
 }
 
 #[allow(dead_code)]
@@ -63,27 +62,6 @@ pub fn ggx_distribution(normal: Vec3, halfway: Vec3, roughness: f32) -> f32 {
     numerator / denominator
 }
 
-// https://blog.selfshadow.com/publications/s2013-shading-course/karis/s2013_pbs_epic_notes_v2.pdf
-pub fn sample_ggx(r1: f32, r2: f32, reflection_direction: Vec3, roughness: f32) -> Vec3 {
-    let a = roughness * roughness;
-
-    let phi = 2.0 * core::f32::consts::PI * r1;
-    let cos_theta = ((1.0 - r2) / (r2 * (a * a - 1.0) + 1.0)).sqrt();
-    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
-
-    let halfway = Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta);
-
-    let up = if reflection_direction.z.abs() < 0.999 {
-        Vec3::new(0.0, 0.0, 1.0)
-    } else {
-        Vec3::new(1.0, 0.0, 0.0)
-    };
-    let tangent = up.cross(reflection_direction).normalize();
-    let bitangent = reflection_direction.cross(tangent);
-
-    (tangent * halfway.x + bitangent * halfway.y + reflection_direction * halfway.z).normalize()
-}
-
 // GGX distribution with respect to microsurface normal (NOT halfway vector)
 // https://www.cs.cornell.edu/~srm/publications/EGSR07-btdf.pdf equation 33
 #[allow(dead_code)]
@@ -226,6 +204,82 @@ pub fn geometry_smith_schlick_ggx(
         * geometry_schlick_ggx(normal, light_direction, roughness)
 }
 
+// Anisotropic GGX distribution, with roughness `alpha_x` along the tangent and `alpha_y` along the bitangent.
+// Reduces to `ggx_distribution` when both equal the roughness.
+pub fn anisotropic_ggx_distribution(normal: Vec3, tangent: Vec3, bitangent: Vec3, halfway: Vec3, alpha_x: f32, alpha_y: f32) -> f32 {
+    let x = halfway.dot(tangent) / alpha_x;
+    let y = halfway.dot(bitangent) / alpha_y;
+    let z = normal.dot(halfway).max(0.0);
+    let denominator = x * x + y * y + z * z;
+    1.0 / (core::f32::consts::PI * alpha_x * alpha_y * denominator * denominator).max(EPS)
+}
+
+// Sample a halfway vector from the GGX distribution of normals visible from `view_direction`, with roughness `alpha_x`
+// along the tangent and `alpha_y` along the bitangent. Unlike sampling the distribution times (n . h), no samples are
+// wasted on microfacets facing away from the viewer, which matters most for rough surfaces seen at grazing angles.
+// "Sampling the GGX Distribution of Visible Normals", Heitz. https://jcgt.org/published/0007/04/01/
+pub fn sample_ggx_vndf(r1: f32, r2: f32, view_direction: Vec3, normal: Vec3, tangent: Vec3, bitangent: Vec3, alpha_x: f32, alpha_y: f32) -> Vec3 {
+    // Stretch the view direction, so the distribution becomes a hemisphere
+    let view = Vec3::new(view_direction.dot(tangent) * alpha_x, view_direction.dot(bitangent) * alpha_y, view_direction.dot(normal).max(EPS)).normalize();
+
+    // Sample the projection of the hemisphere onto the plane perpendicular to the view direction
+    let length_squared = view.x * view.x + view.y * view.y;
+    let t1 = if length_squared > 0.0 { Vec3::new(-view.y, view.x, 0.0) / length_squared.sqrt() } else { Vec3::X };
+    let t2 = view.cross(t1);
+    let radius = r1.sqrt();
+    let phi = 2.0 * core::f32::consts::PI * r2;
+    let p1 = radius * phi.cos();
+    let s = 0.5 * (1.0 + view.z);
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * radius * phi.sin();
+    let hemisphere_normal = t1 * p1 + t2 * p2 + view * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+
+    // Unstretch back to the ellipsoid
+    let local = Vec3::new(hemisphere_normal.x * alpha_x, hemisphere_normal.y * alpha_y, hemisphere_normal.z.max(0.0)).normalize();
+    (tangent * local.x + bitangent * local.y + normal * local.z).normalize()
+}
+
+// Pdf of reflecting `view_direction` about a halfway vector sampled by `sample_ggx_vndf`, with respect to the
+// reflected direction. `d_term` is the distribution at that halfway vector, and `masking` is the Smith masking of the
+// view direction, as given by `geometry_anisotropic_ggx`.
+pub fn ggx_vndf_pdf_reflection(normal: Vec3, view_direction: Vec3, d_term: f32, masking: f32) -> f32 {
+    masking * d_term / (4.0 * normal.dot(view_direction).max(EPS))
+}
+
+// Smith masking for anisotropic GGX, from the projected roughness in the direction of `direction`. With equal alphas,
+// it is the exact masking of isotropic GGX.
+pub fn geometry_anisotropic_ggx(normal: Vec3, tangent: Vec3, bitangent: Vec3, direction: Vec3, alpha_x: f32, alpha_y: f32) -> f32 {
+    let cos_theta = normal.dot(direction).max(EPS);
+    let x = direction.dot(tangent) * alpha_x;
+    let y = direction.dot(bitangent) * alpha_y;
+    let lambda = ((1.0 + (x * x + y * y) / (cos_theta * cos_theta)).sqrt() - 1.0) * 0.5;
+    1.0 / (1.0 + lambda)
+}
+
+// Generalized Trowbridge-Reitz with gamma = 1, the long tailed distribution of the Disney clearcoat. `alpha` is the
+// already squared roughness. https://media.disneyanimation.com/uploads/production/publication_asset/48/asset/s2012_pbs_disney_brdf_notes_v3.pdf
+pub fn gtr1_distribution(n_dot_h: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let t = 1.0 + (a2 - 1.0) * n_dot_h * n_dot_h;
+    (a2 - 1.0) / (core::f32::consts::PI * a2.ln() * t)
+}
+
+// Sample a halfway vector proportionally to GTR1(h) * (n . h)
+pub fn sample_gtr1(r1: f32, r2: f32, normal: Vec3, alpha: f32) -> Vec3 {
+    let a2 = alpha * alpha;
+    let cos_theta = ((1.0 - a2.powf(1.0 - r1)) / (1.0 - a2)).max(0.0).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * core::f32::consts::PI * r2;
+    let (up, nt, nb) = create_cartesian(normal);
+    (nb * (sin_theta * phi.cos()) + up * cos_theta + nt * (sin_theta * phi.sin())).normalize()
+}
+
+// Separable Smith GGX masking for one direction, with the 1 / (2 n . v) of the microfacet BRDF folded in
+pub fn smith_g_ggx(n_dot_v: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let b = n_dot_v * n_dot_v;
+    1.0 / (n_dot_v + (a2 + b - a2 * b).sqrt())
+}
+
 pub fn fresnel_schlick(cos_theta: f32, f0: Vec3) -> Vec3 {
     f0 + (Vec3::ONE - f0) * (1.0 - cos_theta).powi(5)
 }
@@ -250,16 +304,6 @@ pub fn barycentric(p: Vec3, a: Vec3, b: Vec3, c: Vec3) -> Vec3 {
     Vec3::new(1.0 - v - w, v, w)
 }
 
-pub fn power_heuristic(p1: f32, p2: f32) -> f32 {
-    let p1_2 = p1 * p1;
-    p1_2 / (p1_2 + p2 * p2)
-}
-
-#[allow(dead_code)]
-pub fn balance_heuristic(p1: f32, p2: f32) -> f32 {
-    p1 / (p1 + p2)
-}
-
 pub fn positive_characteristic(x: f32) -> f32 {
     if x > 0.0 {
         1.0
@@ -7,7 +7,7 @@ pub mod polyfill {
 
 #[cfg(not(target_arch = "spirv"))]
 pub mod polyfill {
-    use glam::{Vec4, Vec2, IVec2};
+    use glam::{Vec4, Vec2, IVec2, UVec2};
 
     #[derive(Clone, Copy)]
     pub struct Sampler;
@@ -35,6 +35,11 @@ pub mod polyfill {
             self.buffer[y * self.width as usize + x]
         }
 
+        // A single texel, without filtering
+        pub fn fetch(&self, coord: UVec2) -> Vec4 {
+            self.buffer[(coord.y * self.width + coord.x) as usize]
+        }
+
         pub fn sample_by_lod(&self, _sampler: Sampler, coord: Vec2, _lod: f32) -> Vec4 {
             let scaled_uv = coord * Vec2::new(self.width as f32, self.height as f32);
             let frac_uv = scaled_uv.fract();
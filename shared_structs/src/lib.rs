@@ -1,14 +1,35 @@
 #![no_std]
 
 use bytemuck::{Pod, Zeroable};
-use glam::{Vec3, Vec4, Vec4Swizzles, Vec2};
+use glam::{Mat3, UVec2, UVec4, Vec3, Vec4, Vec4Swizzles, Vec2};
+use static_assertions::const_assert_eq;
+#[allow(unused_imports)]
+use spirv_std::num_traits::Float;
 
 mod image_polyfill;
 pub use image_polyfill::polyfill::{Image, Sampler};
 #[cfg(not(target_arch = "spirv"))]
 pub use image_polyfill::polyfill::CpuImage;
 
+// Size of the base level of the texture atlas. The mip chain is stored in a strip to the
+// right of the base level, making the atlas image 1.5 times as wide as it is tall.
+pub const ATLAS_SIZE: u32 = 4096;
+pub const ATLAS_MIP_LEVELS: u32 = 8;
 
+// Structs in this file are shared with the kernels, which read them with the std430 layout (std140 for the config
+// uniform), so Vec4s are 16 byte aligned, Vec2s 8 byte aligned, and everything else 4 byte aligned. The layouts are
+// written out above each struct as byte offsets. The sizes are checked at compile time so they can't drift, and the
+// offsets of every field by tests/layout_tests.rs, since offsets can't be computed in constants on our toolchain.
+// Fields are ordered so no implicit padding is needed, and structs containing a Vec4 are padded to a multiple of 16
+// bytes explicitly, since that is the array stride the GPU uses.
+
+// 0 cam_position, 16 cam_rotation, 32 width, 36 height, 40 min_bounces, 44 max_bounces, 48 sun_direction, 64 nee,
+// 68 has_skybox, 72 specular_weight_clamp, 80 background_color, 96 ray_t_min, 100 ray_t_max, 104 light_sampling,
+// 108 transparent_background, 112 aperture_radius, 116 focus_distance, 120 aperture_blades, 124 aperture_rotation,
+// 128 working_space, 132 skybox_rotation, 136 fov, 140 indirect_clamp, 144 pixel_aspect,
+// 148 samples_per_dispatch, 152 shadows, 156 light_samples, 160 ambient, 176 russian_roulette, 180 near_clip,
+// 184 skybox_width, 188 skybox_height, 192 mis_heuristic, 196 ortho_height, 200 clamp_mode, 204 _padding.
+// 208 bytes in total.
 #[repr(C)]
 #[derive(Copy, Clone, Pod, Zeroable)]
 pub struct TracingConfig {
@@ -22,7 +43,54 @@ pub struct TracingConfig {
     pub nee: u32,
     pub has_skybox: u32,
     pub specular_weight_clamp: Vec2,
+    pub background_color: Vec4, // w = 1 replaces the procedural sky with a flat color when there is no skybox
+    // Hits closer than `ray_t_min` are ignored, and new rays are offset by it, to avoid self-intersection.
+    // Hits further than `ray_t_max` count as misses. These should scale with the scene, the defaults suit
+    // scenes measured in meters. Too small a minimum causes shadow acne, too large leaks light through corners.
+    pub ray_t_min: f32,
+    pub ray_t_max: f32,
+    pub light_sampling: u32,
+    pub transparent_background: u32, // Primary rays which escape contribute no radiance, for compositing with alpha
+    // Thin lens depth of field, an aperture radius of 0 is a pinhole camera. With 3 or more blades, the aperture is a
+    // regular polygon with that many sides, rotated by `aperture_rotation` radians, otherwise it is a disk.
+    pub aperture_radius: f32,
+    pub focus_distance: f32,
+    pub aperture_blades: u32,
+    pub aperture_rotation: f32,
+    pub working_space: u32,
+    pub skybox_rotation: f32, // Radians around the up axis, on top of the rotation from the sun direction
+    pub fov: f32, // Horizontal field of view in radians, the vertical one follows from the aspect ratio
+    // Light which scattered off more than one surface is scaled down to at most this bright, per sample, which
+    // removes fireflies from indirect light at the cost of some energy. Direct light is left alone. 0 = off.
+    pub indirect_clamp: f32,
+    // Width of a pixel divided by its height, for anamorphic formats. Above 1, each pixel covers more of the scene
+    // horizontally, so the image looks squashed horizontally when shown with square pixels.
+    pub pixel_aspect: f32,
+    // Samples each pixel takes per dispatch, one after the other. More samples per dispatch means fewer dispatches
+    // and host round trips for the same sample count, at the cost of longer dispatches.
+    pub samples_per_dispatch: u32,
+    // Whether light samples check that nothing is in the way. Turning them off makes direct light unoccluded, which is
+    // wrong, but saves a ray per bounce for fast previews. 1 = on.
+    pub shadows: u32,
+    pub light_samples: u32,
+    // Constant fill light added to the diffuse response of every surface hit, so scenes without lights aren't black.
+    // Not physically based, it is only meant for previewing geometry and materials. xyz is linear sRGB, 0 = off.
+    pub ambient: Vec4,
+    pub russian_roulette: u32, // See `RussianRoulette`
+    // Distance in front of the camera where primary rays start, so anything closer is cut away, for cameras placed
+    // inside or right up against geometry. 0 = off.
+    pub near_clip: f32,
+    // Size of the skybox image, for filtering it by hand, see `equirect_bilinear`. Filled in when tracing.
+    pub skybox_width: u32,
+    pub skybox_height: u32,
+    pub mis_heuristic: u32, // See `MisHeuristic`
+    // Half the height of the view of an orthographic camera, in scene units, the width follows from the aspect ratio.
+    // 0 = perspective, with `fov`.
+    pub ortho_height: f32,
+    pub clamp_mode: u32, // How `indirect_clamp` limits a color, see `ClampMode`
+    pub _padding: u32,
 }
+const_assert_eq!(core::mem::size_of::<TracingConfig>(), 208);
 
 impl Default for TracingConfig {
     fn default() -> Self {
@@ -37,29 +105,247 @@ impl Default for TracingConfig {
             nee: 0,
             has_skybox: 0,
             specular_weight_clamp: Vec2::new(0.1, 0.9),
+            background_color: Vec4::ZERO,
+            ray_t_min: 0.001,
+            ray_t_max: 1000000.0,
+            light_sampling: 0,
+            transparent_background: 0,
+            aperture_radius: 0.0,
+            focus_distance: 5.0,
+            aperture_blades: 0,
+            aperture_rotation: 0.0,
+            working_space: 0,
+            skybox_rotation: 0.0,
+            fov: core::f32::consts::FRAC_PI_2,
+            indirect_clamp: 0.0,
+            pixel_aspect: 1.0,
+            samples_per_dispatch: 1,
+            shadows: 1,
+            light_samples: 1,
+            ambient: Vec4::ZERO,
+            russian_roulette: 0,
+            near_clip: 0.0,
+            skybox_width: 0,
+            skybox_height: 0,
+            mis_heuristic: 0,
+            ortho_height: 0.0,
+            clamp_mode: 0,
+            _padding: 0,
         }
     }
 }
 
+// Uniform of one iteration of the À-Trous denoiser, see `atrous_config` in the kernels.
+// 0 width, 4 height, 8 step, 12 color_phi. 16 bytes in total.
 #[repr(C)]
 #[derive(Copy, Clone, Pod, Zeroable, Default)]
+pub struct ATrousConfig {
+    pub width: u32,
+    pub height: u32,
+    pub step: u32, // Distance in pixels between the taps of the kernel
+    pub color_phi: f32, // How quickly taps are weighted down by their difference in color
+}
+const_assert_eq!(core::mem::size_of::<ATrousConfig>(), 16);
+
+// Bilinear lookup of an equirectangular image at uv, as the texels to blend, x0, x1, y0 and y1, and the weights of x1
+// and y1. Texel centers are at half integers, like on the GPU. u is longitude, so it wraps around and the texels on
+// either side of the seam blend into each other, while v is latitude, which is clamped, so the poles don't blend with
+// each other. The hardware sampler can't do both at once.
+pub fn equirect_bilinear(uv: Vec2, width: u32, height: u32) -> (UVec4, Vec2) {
+    let (width, height) = (width.max(1), height.max(1));
+    let x = uv.x * width as f32 - 0.5;
+    let y = (uv.y * height as f32 - 0.5).clamp(0.0, (height - 1) as f32);
+    let (x_floor, y_floor) = (x.floor(), y.floor());
+    let x0 = (x_floor as i32).rem_euclid(width as i32) as u32;
+    let y0 = y_floor as u32;
+    let texels = UVec4::new(x0, (x0 + 1) % width, y0, (y0 + 1).min(height - 1));
+    (texels, Vec2::new(x - x_floor, y - y_floor))
+}
+
+// Blend the texels picked by `equirect_bilinear`, read with `fetch`
+pub fn blend_bilinear(texels: UVec4, weights: Vec2, fetch: impl Fn(UVec2) -> Vec4) -> Vec4 {
+    let top = fetch(texels.xz()).lerp(fetch(texels.yz()), weights.x);
+    let bottom = fetch(texels.xw()).lerp(fetch(texels.yw()), weights.x);
+    top.lerp(bottom, weights.y)
+}
+
+// Map a uniform sample in the unit square to a uniform point on the aperture, which has a radius of 1. Polygons
+// are split into one triangle per blade, each with the same area, so picking one uniformly keeps the density uniform.
+pub fn sample_aperture(blades: u32, rotation: f32, u: Vec2) -> Vec2 {
+    if blades < 3 {
+        // Concentric disk mapping, which has less distortion than the polar mapping
+        let offset = u * 2.0 - Vec2::ONE;
+        if offset == Vec2::ZERO {
+            return Vec2::ZERO;
+        }
+        let (radius, theta) = if offset.x.abs() > offset.y.abs() {
+            (offset.x, core::f32::consts::FRAC_PI_4 * (offset.y / offset.x))
+        } else {
+            (offset.y, core::f32::consts::FRAC_PI_2 - core::f32::consts::FRAC_PI_4 * (offset.x / offset.y))
+        };
+        return Vec2::new(theta.cos(), theta.sin()) * radius;
+    }
+
+    let blade = ((u.x * blades as f32) as u32).min(blades - 1);
+    let remapped = u.x * blades as f32 - blade as f32;
+    let corner = |index: u32| {
+        let angle = rotation + index as f32 * (2.0 * core::f32::consts::PI / blades as f32);
+        Vec2::new(angle.cos(), angle.sin())
+    };
+
+    // Uniform point in the triangle spanned by the center and 2 adjacent corners
+    let distance = remapped.sqrt();
+    (corner(blade) * (1.0 - u.y) + corner(blade + 1) * u.y) * distance
+}
+
+// Running statistics of the luminance of the samples in a pixel, using Welford's online algorithm, which doesn't
+// lose precision like summing squares does. Stored as (sample count, mean, sum of squared differences from the mean, 0).
+pub fn welford_update(state: Vec4, radiance: Vec3) -> Vec4 {
+    let value = radiance.dot(Vec3::new(0.2126, 0.7152, 0.0722));
+    let count = state.x + 1.0;
+    let delta = value - state.y;
+    let mean = state.y + delta / count;
+    Vec4::new(count, mean, state.z + delta * (value - mean), 0.0)
+}
+
+// Per bounce throughput statistics, for finding where energy is lost in dark renders. At the start of each bounce, the
+// kernels add up the throughput of the paths still alive, as the mean of its color channels in fixed point, and count
+// them. Sums are 64 bits split over 2 words, since 32 bits can overflow within a frame. Each bounce is stored as
+// (sum low word, sum high word, path count, unused), and bounces past the last entry are added to it.
+pub const BOUNCE_STATS_BOUNCES: usize = 16;
+pub const BOUNCE_STATS_STRIDE: usize = 4;
+// After the bounces, the rays cast and the primitives they were tested against, as (rays low word, rays high word,
+// primitive tests low word, primitive tests high word)
+pub const RAY_STATS_ENTRY: usize = BOUNCE_STATS_BOUNCES * BOUNCE_STATS_STRIDE;
+pub const BOUNCE_STATS_LEN: usize = RAY_STATS_ENTRY + BOUNCE_STATS_STRIDE;
+pub const BOUNCE_STATS_SCALE: f32 = 1024.0;
+const BOUNCE_STATS_MAX_THROUGHPUT: f32 = 16383.0; // Keeps values within 24 bits
+
+pub fn bounce_stats_entry(bounce: u32) -> usize {
+    (bounce as usize).min(BOUNCE_STATS_BOUNCES - 1) * BOUNCE_STATS_STRIDE
+}
+
+pub fn bounce_stats_fixed_point(throughput: Vec3) -> u32 {
+    let mean = (throughput.x + throughput.y + throughput.z) / 3.0;
+    if mean.is_nan() || mean <= 0.0 {
+        return 0;
+    }
+    (mean.min(BOUNCE_STATS_MAX_THROUGHPUT) * BOUNCE_STATS_SCALE + 0.5) as u32
+}
+
+// Variance of the averaged pixel, which is the sample variance divided by the sample count. This is the noise left
+// in the image, and shrinks as samples are added. Unknown until there are 2 samples, in which case it is 0.
+pub fn welford_variance_of_mean(state: Vec4) -> f32 {
+    if state.x < 2.0 {
+        return 0.0;
+    }
+    state.z / (state.x - 1.0) / state.x
+}
+
+// 0 emissive, 16 albedo, 32 roughness, 48 metallic, 64 normals, 80 transmission, 96 occlusion, 112 has_albedo_texture,
+// 116 has_metallic_texture, 120 has_roughness_texture, 124 has_normal_texture, 128 normal_scale,
+// 132 flip_normal_green, 136 has_occlusion_texture, 140 shadow_catcher, 144 max_bounces, 148 hidden_from_camera,
+// 152 has_emissive_texture, 156 _padding, 160 specular,
+// 164 specular_tint, 168 sheen, 172 sheen_tint, 176 clearcoat, 180 clearcoat_gloss, 184 subsurface, 188 anisotropy,
+// 192 subsurface_radius, 208 emissive_texture. 224 bytes in total.
+#[repr(C)]
+#[derive(Copy, Clone, Pod, Zeroable)]
 pub struct MaterialData { // each Vec4 is either a color or an atlas location
-    pub emissive: Vec4,
+    pub emissive: Vec4, // xyz is the color, w is the strength it is multiplied by
     pub albedo: Vec4,
     pub roughness: Vec4,
     pub metallic: Vec4,
     pub normals: Vec4,
+    pub transmission: Vec4, // x is the chance of refracting rather than using the regular BSDF, never textured
+    pub occlusion: Vec4, // Atlas location of the ambient occlusion map, which is only read from a texture
     has_albedo_texture: u32,
     has_metallic_texture: u32,
     has_roughness_texture: u32,
     has_normal_texture: u32,
+    pub normal_scale: f32, // Scales the tangent space x and y of the normal map, 0 is flat
+    flip_normal_green: u32, // Set for DirectX style normal maps, where green points down the texture
+    has_occlusion_texture: u32,
+    shadow_catcher: u32, // Invisible to the camera except for the shadows it receives, for compositing
+    // Paths end after scattering off this material once they have bounced this many times, even if the global
+    // maximum would let them continue, or 0 to only use the global maximum. Lets mostly diffuse scenes use few
+    // bounces, while mirrors and glass still get long enough chains. It is a hard cutoff, applied before Russian
+    // roulette and regardless of the minimum bounce count, so like the global maximum it loses the remaining light.
+    pub max_bounces: u32,
+    // Primary rays pass through the material as if it wasn't there, while every other ray still sees it, so it lights
+    // the scene and casts shadows without being seen. For lights hidden from the camera, such as fill panels.
+    hidden_from_camera: u32,
+    has_emissive_texture: u32,
+    pub _padding: u32,
+    // The rest of the Disney principled parameters, on top of albedo (base color), metallic and roughness. They are
+    // all in [0, 1], and are never textured.
+    pub specular: f32, // Reflectance of the dielectric base, 0.5 is an IOR of 1.5
+    pub specular_tint: f32, // Tints the dielectric reflection towards the albedo
+    pub sheen: f32, // Extra grazing reflection, for cloth
+    pub sheen_tint: f32, // Tints the sheen towards the albedo
+    pub clearcoat: f32, // Strength of a second, colorless specular layer on top
+    pub clearcoat_gloss: f32, // 1 is a sharp clearcoat, 0 a blurry one
+    pub subsurface: f32, // Flattens the diffuse lobe, approximating light scattering under the surface
+    pub anisotropy: f32, // Stretches highlights along the tangent of the surface, for brushed metal
+    // xyz is the mean free path of light under the surface for each channel, in scene units, w is unused. If any of
+    // them is above 0, `subsurface` is the chance of a random walk through the inside of the mesh rather than
+    // flattening the diffuse lobe, so light bleeds through thin parts, as in wax, marble and skin.
+    pub subsurface_radius: Vec4,
+    // Atlas location of the emissive texture, which multiplies `emissive` rather than replacing it, so the strength
+    // still applies. Kept apart from `emissive`, since lights are picked by the untextured emission.
+    pub emissive_texture: Vec4,
+}
+const_assert_eq!(core::mem::size_of::<MaterialData>(), 224);
+
+impl Default for MaterialData {
+    fn default() -> Self {
+        Self {
+            emissive: Vec4::ZERO,
+            albedo: Vec4::ZERO,
+            roughness: Vec4::ZERO,
+            metallic: Vec4::ZERO,
+            normals: Vec4::ZERO,
+            transmission: Vec4::ZERO,
+            occlusion: Vec4::ZERO,
+            has_albedo_texture: 0,
+            has_metallic_texture: 0,
+            has_roughness_texture: 0,
+            has_normal_texture: 0,
+            normal_scale: 1.0,
+            flip_normal_green: 0,
+            has_occlusion_texture: 0,
+            shadow_catcher: 0,
+            max_bounces: 0,
+            hidden_from_camera: 0,
+            has_emissive_texture: 0,
+            _padding: 0,
+            specular: 0.5,
+            specular_tint: 0.0,
+            sheen: 0.0,
+            sheen_tint: 0.5,
+            clearcoat: 0.0,
+            clearcoat_gloss: 1.0,
+            subsurface: 0.0,
+            anisotropy: 0.0,
+            subsurface_radius: Vec4::ZERO,
+            emissive_texture: Vec4::ZERO,
+        }
+    }
 }
 
 impl MaterialData {
+    // Emitted radiance, which can be brighter than the color alone
+    pub fn emission(&self) -> Vec3 {
+        self.emissive.xyz() * self.emissive.w
+    }
+
     pub fn has_albedo_texture(&self) -> bool {
         self.has_albedo_texture != 0
     }
 
+    pub fn has_subsurface_scattering(&self) -> bool {
+        self.subsurface_radius.xyz().max_element() > 0.0
+    }
+
     pub fn set_has_albedo_texture(&mut self, has_albedo_texture: bool) {
         self.has_albedo_texture = if has_albedo_texture { 1 } else { 0 };
     }
@@ -87,18 +373,243 @@ impl MaterialData {
     pub fn set_has_normal_texture(&mut self, has_normal_texture: bool) {
         self.has_normal_texture = if has_normal_texture { 1 } else { 0 };
     }
+
+    pub fn has_occlusion_texture(&self) -> bool {
+        self.has_occlusion_texture != 0
+    }
+
+    pub fn set_has_occlusion_texture(&mut self, has_occlusion_texture: bool) {
+        self.has_occlusion_texture = if has_occlusion_texture { 1 } else { 0 };
+    }
+
+    pub fn has_emissive_texture(&self) -> bool {
+        self.has_emissive_texture != 0
+    }
+
+    pub fn set_has_emissive_texture(&mut self, has_emissive_texture: bool) {
+        self.has_emissive_texture = if has_emissive_texture { 1 } else { 0 };
+    }
+
+    pub fn flip_normal_green(&self) -> bool {
+        self.flip_normal_green != 0
+    }
+
+    pub fn set_flip_normal_green(&mut self, flip_normal_green: bool) {
+        self.flip_normal_green = if flip_normal_green { 1 } else { 0 };
+    }
+
+    pub fn is_shadow_catcher(&self) -> bool {
+        self.shadow_catcher != 0
+    }
+
+    pub fn set_shadow_catcher(&mut self, shadow_catcher: bool) {
+        self.shadow_catcher = if shadow_catcher { 1 } else { 0 };
+    }
+
+    pub fn is_hidden_from_camera(&self) -> bool {
+        self.hidden_from_camera != 0
+    }
+
+    pub fn set_hidden_from_camera(&mut self, hidden_from_camera: bool) {
+        self.hidden_from_camera = if hidden_from_camera { 1 } else { 0 };
+    }
+
+    // Turn a normal map texel into an unnormalized tangent space normal, applying the strength and green convention
+    pub fn decode_normal_map(&self, texel: Vec3) -> Vec3 {
+        let normal = texel * 2.0 - Vec3::ONE;
+        let green = if self.flip_normal_green() { -normal.y } else { normal.y };
+        Vec3::new(normal.x * self.normal_scale, green * self.normal_scale, normal.z)
+    }
+
+    // Clamp values to the range the BSDFs expect, and replace NaNs and infinities, which would otherwise spread
+    // through the image as black pixels or fireflies. Textured values hold atlas locations, so they are left alone.
+    // Returns whether anything had to be changed.
+    pub fn sanitize(&mut self) -> bool {
+        let mut changed = false;
+        self.emissive = sanitize_vec4(self.emissive, 0.0, 0.0, f32::MAX, &mut changed);
+        if !self.has_albedo_texture() {
+            self.albedo = sanitize_vec4(self.albedo, 0.0, 0.0, 1.0, &mut changed);
+        }
+        if !self.has_roughness_texture() {
+            self.roughness = sanitize_vec4(self.roughness, 1.0, 0.0, 1.0, &mut changed);
+        }
+        if !self.has_metallic_texture() {
+            self.metallic = sanitize_vec4(self.metallic, 0.0, 0.0, 1.0, &mut changed);
+        }
+        self.transmission = sanitize_vec4(self.transmission, 0.0, 0.0, 1.0, &mut changed);
+        self.normal_scale = sanitize_value(self.normal_scale, 1.0, 0.0, f32::MAX, &mut changed);
+        self.specular = sanitize_value(self.specular, 0.5, 0.0, 1.0, &mut changed);
+        self.specular_tint = sanitize_value(self.specular_tint, 0.0, 0.0, 1.0, &mut changed);
+        self.sheen = sanitize_value(self.sheen, 0.0, 0.0, 1.0, &mut changed);
+        self.sheen_tint = sanitize_value(self.sheen_tint, 0.5, 0.0, 1.0, &mut changed);
+        self.clearcoat = sanitize_value(self.clearcoat, 0.0, 0.0, 1.0, &mut changed);
+        self.clearcoat_gloss = sanitize_value(self.clearcoat_gloss, 1.0, 0.0, 1.0, &mut changed);
+        self.subsurface = sanitize_value(self.subsurface, 0.0, 0.0, 1.0, &mut changed);
+        self.anisotropy = sanitize_value(self.anisotropy, 0.0, 0.0, 1.0, &mut changed);
+        self.subsurface_radius = sanitize_vec4(self.subsurface_radius, 0.0, 0.0, f32::MAX, &mut changed);
+        changed
+    }
+}
+
+// Non-finite values are replaced by `fallback`, others are clamped. Sets `changed` if the value had to be changed.
+fn sanitize_value(value: f32, fallback: f32, min: f32, max: f32, changed: &mut bool) -> f32 {
+    let sanitized = if value.is_finite() { value.clamp(min, max) } else { fallback };
+    *changed |= sanitized.to_bits() != value.to_bits();
+    sanitized
+}
+
+fn sanitize_vec4(value: Vec4, fallback: f32, min: f32, max: f32, changed: &mut bool) -> Vec4 {
+    Vec4::new(
+        sanitize_value(value.x, fallback, min, max, changed),
+        sanitize_value(value.y, fallback, min, max, changed),
+        sanitize_value(value.z, fallback, min, max, changed),
+        sanitize_value(value.w, fallback, min, max, changed),
+    )
 }
 
+// 0 vertex, 12 radius, 16 normal, 32 tangent, 48 uv0, 56 uv1, 64 color. 80 bytes in total.
 #[repr(C)]
-#[derive(Copy, Clone, Pod, Zeroable, Default)]
+#[derive(Copy, Clone, Pod, Zeroable)]
 pub struct PerVertexData {
-    pub vertex: Vec4,
+    pub vertex: Vec3,
+    pub radius: f32, // Only used by spheres, see `PrimitiveType`. Shares the 16 bytes of the position, so it's read with it
     pub normal: Vec4,
     pub tangent: Vec4,
     pub uv0: Vec2,
     pub uv1: Vec2,
+    pub color: Vec4, // Linear vertex color, xyz multiplies the albedo. White for meshes without vertex colors
+}
+const_assert_eq!(core::mem::size_of::<PerVertexData>(), 80);
+
+impl Default for PerVertexData {
+    fn default() -> Self {
+        Self {
+            vertex: Vec3::ZERO,
+            radius: 0.0,
+            normal: Vec4::ZERO,
+            tangent: Vec4::ZERO,
+            uv0: Vec2::ZERO,
+            uv1: Vec2::ZERO,
+            color: Vec4::ONE,
+        }
+    }
+}
+
+// Primitives are stored in the index buffer as 3 vertex indices, with the material index in w. The top bits of w
+// hold the primitive type, which is 0 for triangles, so plain meshes don't need to care about it.
+// - Quads are the parallelogram spanned by the vertices, a + u * (b - a) + v * (c - a) for u, v in [0, 1].
+//   The front face is the one that (b - a) x (c - a) points out of.
+// - Spheres only use the first vertex. Its position is the center, and the radius is stored next to it.
+#[repr(u32)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PrimitiveType {
+    Triangle,
+    Quad,
+    Sphere,
+}
+
+impl core::fmt::Debug for PrimitiveType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PrimitiveType::Triangle => write!(f, "Triangle"),
+            PrimitiveType::Quad => write!(f, "Quad"),
+            PrimitiveType::Sphere => write!(f, "Sphere"),
+        }
+    }
+}
+
+const PRIMITIVE_TYPE_SHIFT: u32 = 30;
+const MATERIAL_INDEX_MASK: u32 = (1 << PRIMITIVE_TYPE_SHIFT) - 1;
+
+impl PrimitiveType {
+    pub fn to_u32(self) -> u32 {
+        match self {
+            PrimitiveType::Triangle => 0,
+            PrimitiveType::Quad => 1,
+            PrimitiveType::Sphere => 2,
+        }
+    }
+
+    pub fn from_u32(value: u32) -> Self {
+        match value {
+            0 => PrimitiveType::Triangle,
+            1 => PrimitiveType::Quad,
+            2 => PrimitiveType::Sphere,
+            _ => PrimitiveType::Triangle,
+        }
+    }
+
+    pub fn of(primitive: UVec4) -> Self {
+        Self::from_u32(primitive.w >> PRIMITIVE_TYPE_SHIFT)
+    }
 }
 
+pub fn make_primitive(a: u32, b: u32, c: u32, material_index: u32, primitive_type: PrimitiveType) -> UVec4 {
+    UVec4::new(a, b, c, (material_index & MATERIAL_INDEX_MASK) | (primitive_type.to_u32() << PRIMITIVE_TYPE_SHIFT))
+}
+
+pub fn material_index(primitive: UVec4) -> u32 {
+    primitive.w & MATERIAL_INDEX_MASK
+}
+
+// Half size index buffer entry, for scenes where every vertex index fits in 16 bits, and the material index and
+// primitive type fit in another 16. That is at most 65536 vertices and 16384 materials, which covers most small
+// scenes, and halves the memory taken by the index buffer. Storage buffers are read 32 bits at a time, so the 16 bit
+// values are packed in pairs: a and b in the first word, c and the material index with its type in the second.
+// 0 ab, 4 c_material. 8 bytes in total.
+#[repr(C)]
+#[derive(Copy, Clone, Pod, Zeroable, Default)]
+pub struct CompactPrimitive {
+    ab: u32,
+    c_material: u32,
+}
+const_assert_eq!(core::mem::size_of::<CompactPrimitive>(), 8);
+
+pub const COMPACT_PRIMITIVE_MAX_VERTICES: usize = 1 << 16;
+pub const COMPACT_PRIMITIVE_MAX_MATERIALS: usize = 1 << COMPACT_PRIMITIVE_TYPE_SHIFT;
+const COMPACT_PRIMITIVE_TYPE_SHIFT: u32 = 14;
+
+impl CompactPrimitive {
+    // Only valid if the indices are within the limits above, see `fits`
+    pub fn pack(primitive: UVec4) -> Self {
+        let material = material_index(primitive) | (PrimitiveType::of(primitive).to_u32() << COMPACT_PRIMITIVE_TYPE_SHIFT);
+        Self {
+            ab: primitive.x | (primitive.y << 16),
+            c_material: primitive.z | (material << 16),
+        }
+    }
+
+    pub fn fits(vertex_count: usize, material_count: usize) -> bool {
+        vertex_count <= COMPACT_PRIMITIVE_MAX_VERTICES && material_count <= COMPACT_PRIMITIVE_MAX_MATERIALS
+    }
+}
+
+// Index buffer entries the kernels can read primitives from, either full width `UVec4`s or `CompactPrimitive`s
+pub trait PackedPrimitive: Copy {
+    fn unpack(self) -> UVec4;
+}
+
+impl PackedPrimitive for UVec4 {
+    fn unpack(self) -> UVec4 {
+        self
+    }
+}
+
+impl PackedPrimitive for CompactPrimitive {
+    fn unpack(self) -> UVec4 {
+        let material = self.c_material >> 16;
+        make_primitive(
+            self.ab & 0xFFFF,
+            self.ab >> 16,
+            self.c_material & 0xFFFF,
+            material & (COMPACT_PRIMITIVE_MAX_MATERIALS as u32 - 1),
+            PrimitiveType::from_u32(material >> COMPACT_PRIMITIVE_TYPE_SHIFT),
+        )
+    }
+}
+
+// 0 triangle_index_a, 4 triangle_area_a, 8 triangle_pick_pdf_a, 12 triangle_index_b, 16 triangle_area_b,
+// 20 triangle_pick_pdf_b, 24 ratio. 28 bytes in total, there are no vectors so it only needs 4 byte alignment.
 #[repr(C)]
 #[derive(Copy, Clone, Pod, Zeroable, Default)]
 pub struct LightPickEntry {
@@ -110,6 +621,7 @@ pub struct LightPickEntry {
     pub triangle_pick_pdf_b: f32,
     pub ratio: f32,
 }
+const_assert_eq!(core::mem::size_of::<LightPickEntry>(), 28);
 
 // wgpu doesn't allow 0-sized buffers, so we use negative ratios to indicate sentinel values
 impl LightPickEntry {
@@ -118,12 +630,82 @@ impl LightPickEntry {
     }
 }
 
+// Node of a BVH over emissive triangles, used to pick lights by their estimated contribution to a shading point.
+// Children are stored next to each other, so only the index of the left child is needed. Each leaf holds 1 triangle.
+// 0 aabb_min, 16 aabb_max, 32 cone_axis, 48 left_node_index, 52 leaf, 56 _padding. 64 bytes in total.
+#[repr(C)]
+#[derive(Copy, Clone, Pod, Zeroable, Default)]
+pub struct LightTreeNode {
+    pub aabb_min: Vec4, // w = emitted power of all triangles below this node
+    pub aabb_max: Vec4, // w = half-angle of the cone bounding the triangle normals
+    pub cone_axis: Vec4, // w = triangle area for leaves
+    pub left_node_index: u32, // triangle index for leaves
+    pub leaf: u32,
+    pub _padding: [u32; 2],
+}
+const_assert_eq!(core::mem::size_of::<LightTreeNode>(), 64);
+
+impl LightTreeNode {
+    pub fn is_leaf(&self) -> bool {
+        self.leaf != 0
+    }
+
+    pub fn triangle_index(&self) -> u32 {
+        self.left_node_index
+    }
+
+    pub fn triangle_area(&self) -> f32 {
+        self.cone_axis.w
+    }
+
+    pub fn power(&self) -> f32 {
+        self.aabb_min.w
+    }
+
+    // Conservative estimate of how much light below this node reaches a shading point, based on
+    // "Importance Sampling of Many Lights with Adaptive Tree Splitting" by Conty Estevez and Kulla.
+    // Emissive triangles are single-sided, and only diffuse reflection is light sampled, so both the
+    // emission and the reception bound are limited to a hemisphere.
+    pub fn importance(&self, point: Vec3, normal: Vec3) -> f32 {
+        let center = (self.aabb_min.xyz() + self.aabb_max.xyz()) * 0.5;
+        let radius = (self.aabb_max.xyz() - center).length();
+        let to_center = center - point;
+        let distance_squared = to_center.length_squared().max(radius * radius);
+        let distance = to_center.length();
+        if distance <= radius {
+            // Inside the bounds, any orientation is possible
+            return self.power() / distance_squared;
+        }
+        let direction = to_center / distance;
+        let theta_u = (radius / distance).asin(); // Half-angle subtended by the bounding sphere
+
+        // Smallest possible angle between an emitter normal and the direction to the point
+        let theta = self.cone_axis.xyz().dot(-direction).clamp(-1.0, 1.0).acos();
+        let theta_o = self.aabb_max.w;
+        let theta_emission = (theta - theta_o - theta_u).max(0.0);
+        if theta_emission >= core::f32::consts::FRAC_PI_2 {
+            return 0.0;
+        }
+
+        // Smallest possible angle between the shading normal and the direction to an emitter
+        let theta_i = normal.dot(direction).clamp(-1.0, 1.0).acos();
+        let theta_reception = (theta_i - theta_u).max(0.0);
+        if theta_reception >= core::f32::consts::FRAC_PI_2 {
+            return 0.0;
+        }
+
+        self.power() * theta_emission.cos() * theta_reception.cos() / distance_squared
+    }
+}
+
+// 0 aabb_min, 16 aabb_max. 32 bytes in total. The w components hold u32s, reinterpreted as f32s.
 #[repr(C)]
 #[derive(Clone, Copy, Pod, Zeroable)]
 pub struct BVHNode {
     aabb_min: Vec4, // w = triangle count
     aabb_max: Vec4, // w = left_node if triangle_count is 0, first_triangle_index if triangle_count is 1
 }
+const_assert_eq!(core::mem::size_of::<BVHNode>(), 32);
 
 impl Default for BVHNode {
     fn default() -> Self {
@@ -233,4 +815,289 @@ impl NextEventEstimation {
     pub fn uses_nee(&self) -> bool {
         self != &NextEventEstimation::None
     }
-}
\ No newline at end of file
+}
+
+// Bradford adapted from D65 to the D60 white point of ACES, so white stays white
+pub const LINEAR_SRGB_TO_ACESCG: Mat3 = Mat3::from_cols(
+    Vec3::new(0.6130974, 0.0701937, 0.0206156),
+    Vec3::new(0.3395231, 0.9163539, 0.1095698),
+    Vec3::new(0.0473795, 0.0134524, 0.8698146),
+);
+pub const ACESCG_TO_LINEAR_SRGB: Mat3 = Mat3::from_cols(
+    Vec3::new(1.7050510, -0.1302564, -0.0240034),
+    Vec3::new(-0.6217921, 1.1408047, -0.1289690),
+    Vec3::new(-0.0832589, -0.0105483, 1.1529723),
+);
+
+// Color space light transport is computed in. Scene colors, textures and skyboxes are all linear sRGB, and are
+// converted where they enter the path tracer. The image is converted back to linear sRGB before post processing.
+// Wider spaces like ACEScg give more plausible colors where saturated colors bounce off each other.
+#[repr(u32)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub enum WorkingSpace {
+    LinearSrgb,
+    AcesCg,
+}
+
+impl core::fmt::Debug for WorkingSpace {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            WorkingSpace::LinearSrgb => write!(f, "Linear sRGB"),
+            WorkingSpace::AcesCg => write!(f, "ACEScg"),
+        }
+    }
+}
+
+impl WorkingSpace {
+    pub fn to_u32(self) -> u32 {
+        match self {
+            WorkingSpace::LinearSrgb => 0,
+            WorkingSpace::AcesCg => 1,
+        }
+    }
+
+    pub fn from_u32(value: u32) -> Self {
+        match value {
+            0 => WorkingSpace::LinearSrgb,
+            1 => WorkingSpace::AcesCg,
+            _ => WorkingSpace::LinearSrgb,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "linear-srgb" => Some(WorkingSpace::LinearSrgb),
+            "acescg" => Some(WorkingSpace::AcesCg),
+            _ => None,
+        }
+    }
+
+    pub fn from_linear_srgb(self, color: Vec3) -> Vec3 {
+        match self {
+            WorkingSpace::LinearSrgb => color,
+            WorkingSpace::AcesCg => LINEAR_SRGB_TO_ACESCG * color,
+        }
+    }
+
+    pub fn to_linear_srgb(self, color: Vec3) -> Vec3 {
+        match self {
+            WorkingSpace::LinearSrgb => color,
+            WorkingSpace::AcesCg => ACESCG_TO_LINEAR_SRGB * color,
+        }
+    }
+}
+
+// What the chance of a path surviving Russian roulette is based on
+#[repr(u32)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub enum RussianRoulette {
+    Throughput, // The largest component of the path throughput
+    Albedo, // The throughput scaled by the largest component of the albedo, so paths end sooner after dark surfaces
+}
+
+impl core::fmt::Debug for RussianRoulette {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RussianRoulette::Throughput => write!(f, "Throughput"),
+            RussianRoulette::Albedo => write!(f, "Albedo"),
+        }
+    }
+}
+
+impl RussianRoulette {
+    pub fn to_u32(self) -> u32 {
+        match self {
+            RussianRoulette::Throughput => 0,
+            RussianRoulette::Albedo => 1,
+        }
+    }
+
+    pub fn from_u32(value: u32) -> Self {
+        match value {
+            0 => RussianRoulette::Throughput,
+            1 => RussianRoulette::Albedo,
+            _ => RussianRoulette::Throughput,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "throughput" => Some(RussianRoulette::Throughput),
+            "albedo" => Some(RussianRoulette::Albedo),
+            _ => None,
+        }
+    }
+}
+
+// How `TracingConfig::indirect_clamp` limits the brightness of a sample
+#[repr(u32)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ClampMode {
+    Luminance, // Scale the whole color down until its luminance is at the limit, which dims it but keeps its hue
+    Channel, // Clamp each channel to the limit on its own, which turns saturated fireflies whiter
+}
+
+impl core::fmt::Debug for ClampMode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ClampMode::Luminance => write!(f, "Luminance"),
+            ClampMode::Channel => write!(f, "Per channel"),
+        }
+    }
+}
+
+impl ClampMode {
+    pub fn to_u32(self) -> u32 {
+        match self {
+            ClampMode::Luminance => 0,
+            ClampMode::Channel => 1,
+        }
+    }
+
+    pub fn from_u32(value: u32) -> Self {
+        match value {
+            0 => ClampMode::Luminance,
+            1 => ClampMode::Channel,
+            _ => ClampMode::Luminance,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "luminance" => Some(ClampMode::Luminance),
+            "channel" => Some(ClampMode::Channel),
+            _ => None,
+        }
+    }
+
+    // Limit `color` to `max`, which has to be positive
+    pub fn apply(self, color: Vec3, max: f32) -> Vec3 {
+        match self {
+            ClampMode::Luminance => {
+                let luminance = color.dot(Vec3::new(0.2126, 0.7152, 0.0722));
+                if luminance <= max { color } else { color * (max / luminance) }
+            }
+            ClampMode::Channel => color.min(Vec3::splat(max)),
+        }
+    }
+}
+
+// How the light sample and the BSDF sample of the same light are weighted against each other with multiple importance
+// sampling. `weight` gives the weight of the strategy with pdf p1, when the other one has pdf p2. The weights of both
+// strategies always add up to 1, so every heuristic is unbiased, they only differ in variance.
+#[repr(u32)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MisHeuristic {
+    Power, // p1^2 / (p1^2 + p2^2), the power heuristic with beta = 2
+    Balance, // p1 / (p1 + p2)
+    Cutoff, // Like balance, but strategies with a pdf below MIS_CUTOFF times the largest one get no weight
+    Maximum, // 1 for the strategy with the largest pdf, 0 for the other, split evenly on a tie
+}
+
+// Fraction of the largest pdf below which the cutoff heuristic drops a strategy
+pub const MIS_CUTOFF: f32 = 0.1;
+
+impl core::fmt::Debug for MisHeuristic {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MisHeuristic::Power => write!(f, "Power"),
+            MisHeuristic::Balance => write!(f, "Balance"),
+            MisHeuristic::Cutoff => write!(f, "Cutoff"),
+            MisHeuristic::Maximum => write!(f, "Maximum"),
+        }
+    }
+}
+
+impl MisHeuristic {
+    pub fn to_u32(self) -> u32 {
+        match self {
+            MisHeuristic::Power => 0,
+            MisHeuristic::Balance => 1,
+            MisHeuristic::Cutoff => 2,
+            MisHeuristic::Maximum => 3,
+        }
+    }
+
+    pub fn from_u32(value: u32) -> Self {
+        match value {
+            0 => MisHeuristic::Power,
+            1 => MisHeuristic::Balance,
+            2 => MisHeuristic::Cutoff,
+            3 => MisHeuristic::Maximum,
+            _ => MisHeuristic::Power,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "power" => Some(MisHeuristic::Power),
+            "balance" => Some(MisHeuristic::Balance),
+            "cutoff" => Some(MisHeuristic::Cutoff),
+            "maximum" => Some(MisHeuristic::Maximum),
+            _ => None,
+        }
+    }
+
+    pub fn weight(self, p1: f32, p2: f32) -> f32 {
+        match self {
+            MisHeuristic::Power => {
+                let p1_2 = p1 * p1;
+                p1_2 / (p1_2 + p2 * p2)
+            }
+            MisHeuristic::Balance => p1 / (p1 + p2),
+            MisHeuristic::Cutoff => {
+                let cutoff = MIS_CUTOFF * p1.max(p2);
+                if p1 < cutoff {
+                    0.0
+                } else if p2 < cutoff {
+                    1.0
+                } else {
+                    p1 / (p1 + p2)
+                }
+            }
+            MisHeuristic::Maximum => {
+                if p1 > p2 {
+                    1.0
+                } else if p1 < p2 {
+                    0.0
+                } else {
+                    0.5
+                }
+            }
+        }
+    }
+}
+
+// How a light is picked for next event estimation
+#[repr(u32)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub enum LightSampling {
+    Power, // Proportional to emitted power, regardless of the shading point
+    LightTree, // Proportional to the estimated contribution to the shading point
+}
+
+impl core::fmt::Debug for LightSampling {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            LightSampling::Power => write!(f, "Power"),
+            LightSampling::LightTree => write!(f, "Light tree"),
+        }
+    }
+}
+
+impl LightSampling {
+    pub fn to_u32(self) -> u32 {
+        match self {
+            LightSampling::Power => 0,
+            LightSampling::LightTree => 1,
+        }
+    }
+
+    pub fn from_u32(value: u32) -> Self {
+        match value {
+            0 => LightSampling::Power,
+            1 => LightSampling::LightTree,
+            _ => LightSampling::Power,
+        }
+    }
+}
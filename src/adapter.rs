@@ -0,0 +1,122 @@
+// The GPUs wgpu can trace on. Tracing uses its own framework, separate from the device the window is drawn with, so
+// these are enumerated with the same backends the framework is created with.
+
+use parking_lot::Mutex;
+
+lazy_static::lazy_static! {
+    // Index into `enumerate_adapters` of the adapter picked with `select_adapter`, if any
+    static ref SELECTED_ADAPTER: Mutex<Option<usize>> = Mutex::new(None);
+    // Limits of the adapter the framework was created with, see `tracing_limits`
+    static ref TRACING_LIMITS: Mutex<Option<wgpu::Limits>> = Mutex::new(None);
+    // Features of the same adapter, see `tracing_supports_timestamps`
+    static ref TRACING_FEATURES: Mutex<Option<wgpu::Features>> = Mutex::new(None);
+}
+
+// Pixels covered by each workgroup of the tracing kernel along x and y, matching its `threads(8, 8, 1)`
+pub const WORKGROUP_SIZE: u32 = 8;
+
+// Backends to look for adapters on. Can be overridden with the WGPU_BACKEND environment variable.
+pub fn framework_backends() -> wgpu::Backends {
+    wgpu::util::backend_bits_from_env().unwrap_or(wgpu::Backends::PRIMARY)
+}
+
+// Every adapter on the framework backends, in a stable order, so they can be referred to by their index
+pub fn enumerate_adapters() -> Vec<wgpu::Adapter> {
+    let instance = wgpu::Instance::new(framework_backends());
+    instance.enumerate_adapters(framework_backends()).collect()
+}
+
+// One line naming the adapter, and one with the limits that matter for tracing
+pub fn describe_adapter(index: usize, info: &wgpu::AdapterInfo, limits: &wgpu::Limits) -> String {
+    format!(
+        "{}: {} ({:?}, {:?})\n   max storage buffer binding size {}, max storage buffers per shader stage {}, max compute invocations per workgroup {}, max workgroups per dimension {} (max resolution {}), max 2D texture size {}",
+        index,
+        info.name,
+        info.backend,
+        info.device_type,
+        limits.max_storage_buffer_binding_size,
+        limits.max_storage_buffers_per_shader_stage,
+        limits.max_compute_invocations_per_workgroup,
+        limits.max_compute_workgroups_per_dimension,
+        max_resolution(limits),
+        limits.max_texture_dimension_2d,
+    )
+}
+
+// Largest width or height the GPU can trace. Each frame is traced in a single dispatch, so each side can have at most
+// as many workgroups as a dispatch allows along one dimension. Every device allows at least 65535, which is 524280
+// pixels.
+pub fn max_resolution(limits: &wgpu::Limits) -> u32 {
+    limits.max_compute_workgroups_per_dimension.saturating_mul(WORKGROUP_SIZE)
+}
+
+// Workgroups to dispatch along x and y to trace an image of the given size, or an error if it is larger than the
+// device can dispatch, which would otherwise fail without tracing anything
+pub fn dispatch_size(width: u32, height: u32, limits: &wgpu::Limits) -> Result<(u32, u32), String> {
+    let max_resolution = max_resolution(limits);
+    if width > max_resolution || height > max_resolution {
+        return Err(format!(
+            "{}x{} is larger than the {} pixels per side this device can trace",
+            width, height, max_resolution
+        ));
+    }
+    Ok((width.div_ceil(WORKGROUP_SIZE), height.div_ceil(WORKGROUP_SIZE)))
+}
+
+// Remember the limits and features of the adapter the framework is created with
+pub(crate) fn set_tracing_limits(limits: wgpu::Limits, features: wgpu::Features) {
+    *TRACING_LIMITS.lock() = Some(limits);
+    *TRACING_FEATURES.lock() = Some(features);
+}
+
+// Limits of the adapter tracing happens on, creating the framework if it doesn't exist yet
+pub fn tracing_limits() -> wgpu::Limits {
+    lazy_static::initialize(&crate::trace::FW);
+    TRACING_LIMITS.lock().clone().unwrap_or_default()
+}
+
+// Whether the adapter tracing happens on can time work on the GPU itself with timestamp queries, which some backends
+// lack. Creates the framework if it doesn't exist yet.
+pub fn tracing_supports_timestamps() -> bool {
+    lazy_static::initialize(&crate::trace::FW);
+    TRACING_FEATURES.lock().map_or(false, |features| features.contains(wgpu::Features::TIMESTAMP_QUERY))
+}
+
+pub fn print_adapters() {
+    let adapters = enumerate_adapters();
+    if adapters.is_empty() {
+        println!("No adapters found");
+    }
+    for (index, adapter) in adapters.iter().enumerate() {
+        println!("{}", describe_adapter(index, &adapter.get_info(), &adapter.limits()));
+    }
+}
+
+// Index of the adapter a selector refers to. Numbers are indices into the list, anything else picks the first adapter
+// whose name contains it, ignoring case, so "nvidia" or "radeon" is enough to pick a discrete GPU.
+pub fn find_adapter(names: &[String], selector: &str) -> Option<usize> {
+    if let Ok(index) = selector.trim().parse::<usize>() {
+        return (index < names.len()).then_some(index);
+    }
+    let selector = selector.trim().to_lowercase();
+    names.iter().position(|name| name.to_lowercase().contains(&selector))
+}
+
+// Trace on the adapter the selector refers to, see `find_adapter`, rather than the one wgpu prefers. Has to be called
+// before the framework is first used, since it is only created once.
+pub fn select_adapter(selector: &str) -> Result<(), String> {
+    let names = enumerate_adapters().iter().map(|adapter| adapter.get_info().name).collect::<Vec<_>>();
+    match find_adapter(&names, selector) {
+        Some(index) => {
+            *SELECTED_ADAPTER.lock() = Some(index);
+            Ok(())
+        }
+        None => Err(format!("No adapter matches \"{}\", run with --list-adapters to see which there are", selector)),
+    }
+}
+
+// The adapter picked with `select_adapter`, or None to let wgpu pick
+pub fn selected_adapter() -> Option<wgpu::Adapter> {
+    let index = (*SELECTED_ADAPTER.lock())?;
+    enumerate_adapters().into_iter().nth(index)
+}
@@ -11,13 +11,17 @@ use wgpu::util::DeviceExt;
 use winit::dpi::PhysicalSize;
 
 use glam::{Mat3, Vec3};
-use shared_structs::NextEventEstimation;
+use shared_structs::{ClampMode, NextEventEstimation, LightSampling, MisHeuristic, RussianRoulette, WorkingSpace};
 
-use crate::trace::{trace_cpu, trace_gpu, TracingState};
+use crate::asset::{CoordinateSystem, SceneFile};
+use crate::denoise::Denoiser;
+use crate::encode::{save_png, save_exr, save_hdr, apply_coverage_alpha, dither_tile, AlphaMode};
+use crate::post::apply_exposure;
+use crate::trace::{trace_cpu, trace_gpu, Aov, TracingState};
 
 #[repr(u32)]
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
-enum Tonemapping {
+pub enum Tonemapping {
     None,
     Reinhard,
     ACESNarkowicz,
@@ -25,6 +29,7 @@ enum Tonemapping {
     ACESHill,
     Neutral,
     Uncharted,
+    AgX,
 }
 
 impl Debug for Tonemapping {
@@ -37,10 +42,45 @@ impl Debug for Tonemapping {
             Tonemapping::ACESHill => write!(f, "ACES (H)"),
             Tonemapping::Neutral => write!(f, "Neutral"),
             Tonemapping::Uncharted => write!(f, "Uncharted"),
+            Tonemapping::AgX => write!(f, "AgX"),
         }
     }
 }
 
+impl Tonemapping {
+    // Names used on the command line, see `--tonemap`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Tonemapping::None),
+            "reinhard" => Some(Tonemapping::Reinhard),
+            "aces" => Some(Tonemapping::ACESNarkowicz),
+            "aces-overexposed" => Some(Tonemapping::ACESNarkowiczOverexposed),
+            "aces-hill" => Some(Tonemapping::ACESHill),
+            "neutral" => Some(Tonemapping::Neutral),
+            "uncharted" => Some(Tonemapping::Uncharted),
+            "agx" => Some(Tonemapping::AgX),
+            _ => None,
+        }
+    }
+}
+
+// Must match the layout of `Uniforms` in render.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct RenderUniforms {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) tonemapping: u32,
+    pub(crate) exposure: f32,
+    pub(crate) dither: u32,
+    pub(crate) dither_seed: u32,
+    pub(crate) srgb_target: u32,
+    pub(crate) _padding: u32,
+}
+
+// The window title doubles as a reminder of the key bindings, see `handle_render_keys`
+pub const WINDOW_TITLE: &str = "rust-path-tracer - Space: pause/resume, Period: render one sample, S: save frame";
+
 fn is_image(img: &str) -> bool {
     img.ends_with(".png")
     || img.ends_with(".jpg")
@@ -57,11 +97,21 @@ pub struct App {
 
     use_cpu: bool,
     tonemapping: Tonemapping,
-    selected_scene: String,
+    exposure: f32,
+    dither: bool, // Offsets 8-bit output by blue noise, see `quantize_dithered`
+    dither_seed: u32,
+    alpha_mode: AlphaMode,
+    aovs: Vec<Aov>, // Saved as .exr files next to each saved image
+    output_path: Option<String>, // Where renders which finish on their own are saved, the format follows the extension
+    coordinate_system: CoordinateSystem,
+    selected_scene: Vec<SceneFile>,
     selected_skybox: Option<String>,
     show_environment_window: bool,
+    show_post_process_window: bool,
     last_input: Instant,
     mouse_delta: (f32, f32),
+    priority_samples: u32, // Samples per dispatch of pixels painted with the priority brush
+    priority_brush_radius: f32, // Pixels
 
     device: wgpu::Device,
     queue: wgpu::Queue,
@@ -111,6 +161,8 @@ impl App {
             tracing_state,
             last_input: Instant::now(),
             mouse_delta: (0.0, 0.0),
+            priority_samples: 4,
+            priority_brush_radius: 24.0,
             device,
             queue,
             window,
@@ -119,11 +171,19 @@ impl App {
             surface_format,
             egui_renderer,
             compute_join_handle: None,
-            selected_scene: "scene.glb".to_string(),
+            selected_scene: vec![SceneFile::new("scene.glb")],
             selected_skybox: None,
             tonemapping: Tonemapping::None,
+            alpha_mode: AlphaMode::default(),
+            aovs: Vec::new(),
+            output_path: None,
+            coordinate_system: CoordinateSystem::default(),
+            exposure: 0.0,
+            dither: false,
+            dither_seed: 0,
             use_cpu: false,
             show_environment_window: false,
+            show_post_process_window: false,
         }
     }
 
@@ -143,7 +203,7 @@ impl App {
             let (config, framebuffer) = TracingState::make_view_dependent_state(size.width, size.height, Some(*self.tracing_state.config.read()));
             *self.tracing_state.config.write() = config;
             *self.tracing_state.framebuffer.write() = framebuffer;
-            self.tracing_state.samples.store(0, Ordering::Relaxed);
+            self.tracing_state.reset(); // The previous render was stopped above, so this takes effect right away
 
             let render_resources = PaintCallbackResources::new(&self.device, self.surface_format, size.width, size.height);
             self.egui_renderer.paint_callback_resources.insert(render_resources);
@@ -152,14 +212,15 @@ impl App {
         let tracing_state = self.tracing_state.clone();
 
         let use_cpu = self.use_cpu;
-        let path = self.selected_scene.clone();
+        let scene = self.selected_scene.clone();
         let skybox_path = self.selected_skybox.clone();
+        let coordinate_system = self.coordinate_system;
         self.compute_join_handle = Some(std::thread::spawn(move || {
             let skybox_path_ref = skybox_path.as_ref().map(|s| s.as_str());
             if use_cpu {
-                trace_cpu(&path, skybox_path_ref, tracing_state);
+                trace_cpu(&scene, skybox_path_ref, coordinate_system, tracing_state);
             } else {
-                trace_gpu(&path, skybox_path_ref, tracing_state);
+                trace_gpu(&scene, skybox_path_ref, coordinate_system, tracing_state);
             }
         }));
     }
@@ -180,7 +241,7 @@ impl App {
         }
     }
 
-    fn set_skybox(&mut self, skybox: &str) {
+    pub fn set_skybox(&mut self, skybox: &str) {
         self.selected_skybox = Some(skybox.to_string());
         self.tracing_state.config.write().has_skybox = 1;
         self.restart_current_render(false);
@@ -192,14 +253,276 @@ impl App {
         self.restart_current_render(false);
     }
 
-    fn set_scene(&mut self, scene: &str) {
-        self.selected_scene = scene.to_string();
+    // With a transparent background, saved images get the primary ray coverage as alpha
+    pub fn set_transparent_background(&mut self, transparent: bool) {
+        self.tracing_state.config.write().transparent_background = transparent as u32;
+        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_shadows(&mut self, shadows: bool) {
+        self.tracing_state.config.write().shadows = shadows as u32;
+        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_light_samples(&mut self, light_samples: u32) {
+        self.tracing_state.config.write().light_samples = light_samples.max(1);
+        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_near_clip(&mut self, near_clip: f32) {
+        self.tracing_state.config.write().near_clip = near_clip.max(0.0);
+        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_animated_noise(&mut self, animated_noise: bool) {
+        self.tracing_state.animated_noise.store(animated_noise, Ordering::Relaxed);
+    }
+
+    pub fn set_ray_range(&mut self, t_min: f32, t_max: f32) {
+        let mut config = self.tracing_state.config.write();
+        config.ray_t_min = t_min;
+        config.ray_t_max = t_max;
+        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_russian_roulette(&mut self, russian_roulette: RussianRoulette) {
+        self.tracing_state.config.write().russian_roulette = russian_roulette.to_u32();
+        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_mis_heuristic(&mut self, heuristic: MisHeuristic) {
+        self.tracing_state.config.write().mis_heuristic = heuristic.to_u32();
+        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_working_space(&mut self, working_space: WorkingSpace) {
+        self.tracing_state.config.write().working_space = working_space.to_u32();
+        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_skybox_rotation(&mut self, degrees: f32) {
+        self.tracing_state.config.write().skybox_rotation = degrees.to_radians();
+        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_samples_per_dispatch(&mut self, samples_per_dispatch: u32) {
+        self.tracing_state.config.write().samples_per_dispatch = samples_per_dispatch.max(1);
+        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_pixel_aspect(&mut self, pixel_aspect: f32) {
+        self.tracing_state.config.write().pixel_aspect = pixel_aspect;
+        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_indirect_clamp(&mut self, clamp: f32) {
+        self.tracing_state.config.write().indirect_clamp = clamp;
+        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_clamp_mode(&mut self, clamp_mode: ClampMode) {
+        self.tracing_state.config.write().clamp_mode = clamp_mode.to_u32();
+        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_ambient(&mut self, ambient: Vec3) {
+        self.tracing_state.config.write().ambient = ambient.extend(0.0);
+        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+    }
+
+    // Replaces the procedural sky with a flat color, a skybox still takes precedence
+    pub fn set_background_color(&mut self, color: Vec3) {
+        self.tracing_state.config.write().background_color = color.extend(1.0);
+        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_spatial_splits(&mut self, spatial_splits: bool) {
+        self.tracing_state.spatial_splits.store(spatial_splits, Ordering::Relaxed);
+    }
+
+    pub fn set_frame_all(&mut self, frame_all: bool) {
+        self.tracing_state.frame_all.store(frame_all, Ordering::Relaxed);
+    }
+
+    // Look through the camera of the glTF scene called `name`, or the first one if it is empty, when a scene is loaded
+    pub fn set_gltf_camera(&mut self, name: Option<String>) {
+        *self.tracing_state.gltf_camera.write() = name;
+    }
+
+    pub fn set_trace_stats(&mut self, trace_stats: bool) {
+        self.tracing_state.trace_stats.store(trace_stats, Ordering::Relaxed);
+    }
+
+    pub fn set_print_timings(&mut self, print_timings: bool) {
+        self.tracing_state.print_timings.store(print_timings, Ordering::Relaxed);
+    }
+
+    // Trace with a compiled kernel from disk rather than the embedded one. Kernels which can't be read or don't
+    // validate are an error, rather than silently tracing with a kernel that wasn't asked for.
+    pub fn set_kernel_path(&mut self, path: &str) -> Result<(), String> {
+        crate::kernel::load_kernel(path).map_err(|error| format!("Not using {}, {}", path, error))?;
+        *self.tracing_state.kernel_path.write() = Some(path.to_string());
+        Ok(())
+    }
+
+    pub fn set_hot_reload(&mut self, hot_reload: bool) {
+        self.tracing_state.hot_reload.store(hot_reload, Ordering::Relaxed);
+    }
+
+    pub fn set_denoiser(&mut self, denoiser: Denoiser) {
+        self.tracing_state.denoiser.store(denoiser.to_u32(), Ordering::Relaxed);
+    }
+
+    pub fn set_denoise_interval(&mut self, interval: u32) {
+        self.tracing_state.denoise_interval.store(interval, Ordering::Relaxed);
+    }
+
+    pub fn set_output_path(&mut self, path: &str) {
+        self.output_path = Some(path.to_string());
+    }
+
+    pub fn set_time_budget(&mut self, seconds: u32) {
+        self.tracing_state.time_budget.store(seconds, Ordering::Relaxed);
+    }
+
+    pub fn set_target_fps(&mut self, fps: u32) {
+        self.tracing_state.target_fps.store(fps, Ordering::Relaxed);
+    }
+
+    pub fn set_bloom(&mut self, threshold: f32, strength: f32) {
+        let mut post_config = self.tracing_state.post_config.write();
+        post_config.bloom_threshold = threshold;
+        post_config.bloom_strength = strength;
+    }
+
+    pub fn set_chromatic_aberration(&mut self, strength: f32) {
+        self.tracing_state.post_config.write().chromatic_aberration = strength;
+    }
+
+    pub fn set_vignette(&mut self, strength: f32) {
+        self.tracing_state.post_config.write().vignette = strength;
+    }
+
+    pub fn set_despeckle_threshold(&mut self, threshold: f32) {
+        self.tracing_state.post_config.write().despeckle_threshold = threshold;
+    }
+
+    pub fn set_white_balance(&mut self, kelvin: f32) {
+        self.tracing_state.post_config.write().white_balance = kelvin;
+    }
+
+    // Tint alone balances for 6500K, which is close to neutral
+    pub fn set_tint(&mut self, tint: f32) {
+        let mut post_config = self.tracing_state.post_config.write();
+        post_config.tint = tint;
+        if post_config.white_balance == 0.0 {
+            post_config.white_balance = 6500.0;
+        }
+    }
+
+    pub fn set_aperture_blades(&mut self, blades: u32) {
+        self.tracing_state.config.write().aperture_blades = blades;
+        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_aovs(&mut self, aovs: Vec<Aov>) {
+        self.tracing_state.aovs_enabled.store(!aovs.is_empty(), Ordering::Relaxed);
+        self.aovs = aovs;
+    }
+
+    pub fn set_exposure(&mut self, exposure: f32) {
+        self.exposure = exposure;
+    }
+
+    pub fn set_tonemapping(&mut self, tonemapping: Tonemapping) {
+        self.tonemapping = tonemapping;
+    }
+
+    pub fn set_dither(&mut self, dither: bool) {
+        self.dither = dither;
+    }
+
+    pub fn set_dither_seed(&mut self, seed: u32) {
+        self.dither_seed = seed;
+    }
+
+    pub fn set_alpha_mode(&mut self, alpha_mode: AlphaMode) {
+        self.alpha_mode = alpha_mode;
+    }
+
+    // Only takes effect when the scene is loaded, so a running render is restarted
+    pub fn set_coordinate_system(&mut self, coordinate_system: CoordinateSystem) {
+        self.coordinate_system = coordinate_system;
+        self.restart_current_render(false);
+    }
+
+    // EXR and Radiance HDR files get the linear image with exposure applied, anything else gets what is on screen
+    fn save_image(&self, path: &str) {
+        let encode_start = std::time::Instant::now();
+        let config = *self.tracing_state.config.read();
+        let alpha = self.tracing_state.alpha.read();
+        let alpha = (config.transparent_background != 0 && alpha.len() == (config.width * config.height) as usize)
+            .then_some((alpha.as_slice(), self.alpha_mode));
+        let linear_image = || {
+            let mut image = self.tracing_state.read_framebuffer().clone();
+            apply_exposure(&mut image, self.exposure);
+            image
+        };
+        let res = if path.to_lowercase().ends_with(".exr") {
+            save_exr(path, config.width, config.height, &linear_image(), alpha).map_err(|err| err.to_string())
+        } else if path.to_lowercase().ends_with(".hdr") {
+            save_hdr(path, config.width, config.height, &linear_image()).map_err(|err| err.to_string())
+        } else if let Some(resources) = self.egui_renderer.paint_callback_resources.get::<PaintCallbackResources>() {
+            resources.save_render(path, config.width, config.height, alpha, self.surface_format, &self.device, &self.queue)
+                .map_err(|err| err.to_string())
+        } else {
+            // Tonemapped images are drawn with the resources of the window, which are created when rendering starts
+            Err("nothing has been rendered yet".to_string())
+        };
+        if let Err(err) = res {
+            println!("Error: Failed to save {}: {}", path, err);
+        }
+
+        // Passes are linear data for compositing, so they are always saved at full precision
+        let stem = std::path::Path::new(path).with_extension("");
+        for aov in self.aovs.iter() {
+            let aov_path = format!("{}_{}.exr", stem.display(), aov.name());
+            let pass = self.tracing_state.read_aov(*aov);
+            if pass.len() != (config.width * config.height * 3) as usize {
+                println!("Warning: The {} pass isn't ready yet, so it wasn't saved", aov.name());
+                continue;
+            }
+            if let Err(err) = save_exr(&aov_path, config.width, config.height, &pass, None) {
+                println!("Failed to save {}: {:?}", aov_path, err);
+            }
+        }
+
+        let encode_time = encode_start.elapsed();
+        self.tracing_state.timings.write().encode += encode_time;
+        if self.tracing_state.print_timings.load(Ordering::Relaxed) {
+            println!("Saved {} in {:.2?}", path, encode_time);
+        }
+    }
+
+    pub fn set_scene(&mut self, scene: &str) {
+        if let Some(builtin) = crate::scenes::find_builtin_scene(scene) {
+            let mut config = self.tracing_state.config.write();
+            config.cam_position = builtin.camera_position;
+            config.cam_rotation = builtin.camera_rotation;
+        }
+        self.set_scene_files(vec![SceneFile::new(scene)]);
+    }
+
+    // Render several scene files merged into one scene
+    pub fn set_scene_files(&mut self, files: Vec<SceneFile>) {
+        self.selected_scene = files;
         self.start_render(false);
     }
 
     fn on_gui(&mut self, egui_ctx: &egui::Context) {
         self.on_settings_gui(egui_ctx);
         self.on_environment_gui(egui_ctx);
+        self.on_post_process_gui(egui_ctx);
     }
 
     fn on_settings_gui(&mut self, egui_ctx: &egui::Context) {
@@ -208,7 +531,8 @@ impl App {
             .striped(true)
             .show(ui, |ui| {
                 ui.vertical(|ui| {
-                    ui.label(format!("Selected scene: {}", self.selected_scene));
+                    let scene_paths = self.selected_scene.iter().map(|file| file.path.as_str()).collect::<Vec<_>>();
+                    ui.label(format!("Selected scene: {}", scene_paths.join(", ")));
                     ui.horizontal(|ui| {
                         if self.compute_join_handle.as_ref().map_or(false, |t| !t.is_finished()) {
                             if ui.button("Stop").clicked() {
@@ -230,11 +554,18 @@ impl App {
                             }
                         }
 
+                        ui.menu_button("Built-in scene", |ui| {
+                            for builtin in crate::scenes::BUILTIN_SCENES {
+                                if ui.button(builtin.name).on_hover_text(builtin.description).clicked() {
+                                    self.set_scene(builtin.name);
+                                    ui.close_menu();
+                                }
+                            }
+                        });
+
                         if ui.button("Save image").clicked() {
-                            if let Some(resources) = self.egui_renderer.paint_callback_resources.get::<PaintCallbackResources>() {
-                                let width = self.tracing_state.config.read().width;
-                                let height = self.tracing_state.config.read().height;
-                                resources.save_render(width, height, self.surface_format, &self.device, &self.queue);
+                            if let Some(path) = tinyfiledialogs::save_file_dialog("Save render", "") {
+                                self.save_image(&path);
                             }
                         }
                     });
@@ -242,12 +573,27 @@ impl App {
                 ui.end_row();
                 
                 ui.horizontal(|ui| {
-                    #[cfg(feature = "oidn")]
+                    let prev_denoiser = Denoiser::from_u32(self.tracing_state.denoiser.load(Ordering::Relaxed));
+                    let mut denoiser = prev_denoiser;
+                    egui::ComboBox::from_label("Denoiser")
+                        .selected_text(format!("{:?}", denoiser))
+                        .show_ui(ui, |ui| {
+                            for option in [Denoiser::None, Denoiser::ATrous, Denoiser::Oidn, Denoiser::OidnPrefiltered] {
+                                if option.is_available() {
+                                    ui.selectable_value(&mut denoiser, option, format!("{:?}", option));
+                                }
+                            }
+                        });
+                    if denoiser != prev_denoiser {
+                        self.tracing_state.denoiser.store(denoiser.to_u32(), Ordering::Relaxed);
+                    }
+
+                    let mut denoise_interval = self.tracing_state.denoise_interval.load(Ordering::Relaxed).max(1);
+                    if ui.add(egui::DragValue::new(&mut denoise_interval).clamp_range(1..=120).prefix("every ").suffix(" frames"))
+                        .on_hover_text("Show the raw image in between, which keeps slow denoisers responsive")
+                        .changed()
                     {
-                        let mut denoise_checked = self.tracing_state.denoise.load(Ordering::Relaxed);
-                        if ui.checkbox(&mut denoise_checked, "Denoise").changed() {
-                            self.tracing_state.denoise.store(denoise_checked, Ordering::Relaxed);
-                        }
+                        self.tracing_state.denoise_interval.store(denoise_interval, Ordering::Relaxed);
                     }
     
                     let mut use_blue_noise = self.tracing_state.use_blue_noise.load(Ordering::Relaxed);
@@ -255,6 +601,70 @@ impl App {
                         self.tracing_state.use_blue_noise.store(use_blue_noise, Ordering::Relaxed);
                         self.tracing_state.dirty.store(true, Ordering::Relaxed);
                     }
+
+                    let mut animated_noise = self.tracing_state.animated_noise.load(Ordering::Relaxed);
+                    if ui.checkbox(&mut animated_noise, "Animated noise")
+                        .on_hover_text("Vary the noise pattern every time rendering restarts")
+                        .changed() {
+                        self.tracing_state.animated_noise.store(animated_noise, Ordering::Relaxed);
+                    }
+
+                    let mut transparent_background = self.tracing_state.config.read().transparent_background != 0;
+                    if ui.checkbox(&mut transparent_background, "Transparent background")
+                        .on_hover_text("Save images with alpha, where the background is transparent")
+                        .changed() {
+                        self.set_transparent_background(transparent_background);
+                    }
+                    if transparent_background {
+                        egui::ComboBox::from_label("Alpha")
+                            .selected_text(format!("{:?}", self.alpha_mode))
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(&mut self.alpha_mode, AlphaMode::Straight, "Straight");
+                                ui.selectable_value(&mut self.alpha_mode, AlphaMode::Premultiplied, "Premultiplied");
+                            })
+                            .response
+                            .on_hover_text("Straight divides color by alpha, premultiplied keeps color darkened at partially covered edges");
+                    }
+
+                    let mut shadows = self.tracing_state.config.read().shadows != 0;
+                    if ui.checkbox(&mut shadows, "Shadows")
+                        .on_hover_text("Check that light samples aren't blocked. Turning this off makes direct light shine through everything, which is only meant as a speedup for previews")
+                        .changed() {
+                        self.set_shadows(shadows);
+                    }
+
+                    let mut temporal_reprojection = self.tracing_state.temporal_reprojection.load(Ordering::Relaxed);
+                    if ui.checkbox(&mut temporal_reprojection, "Temporal reprojection").changed() {
+                        self.tracing_state.temporal_reprojection.store(temporal_reprojection, Ordering::Relaxed);
+                    }
+
+                    let mut adaptive_resolution = self.tracing_state.adaptive_resolution.load(Ordering::Relaxed);
+                    if ui.checkbox(&mut adaptive_resolution, "Adaptive resolution")
+                        .on_hover_text("Render at reduced resolution while moving the camera")
+                        .changed() {
+                        self.tracing_state.adaptive_resolution.store(adaptive_resolution, Ordering::Relaxed);
+                    }
+
+                    let mut spatial_splits = self.tracing_state.spatial_splits.load(Ordering::Relaxed);
+                    if ui.checkbox(&mut spatial_splits, "Spatial splits")
+                        .on_hover_text("Build the BVH with spatial splits when a scene is loaded. Slower to load, but faster to trace scenes with large or long thin triangles")
+                        .changed() {
+                        self.tracing_state.spatial_splits.store(spatial_splits, Ordering::Relaxed);
+                    }
+
+                    let mut frame_all = self.tracing_state.frame_all.load(Ordering::Relaxed);
+                    if ui.checkbox(&mut frame_all, "Frame all")
+                        .on_hover_text("Move the camera back so the whole scene is in view when a scene is loaded")
+                        .changed() {
+                        self.set_frame_all(frame_all);
+                    }
+
+                    let mut gltf_camera = self.tracing_state.gltf_camera.read().is_some();
+                    if ui.checkbox(&mut gltf_camera, "glTF camera")
+                        .on_hover_text("Look through the camera of the glTF scene when a scene is loaded")
+                        .changed() {
+                        self.set_gltf_camera(gltf_camera.then(String::new));
+                    }
                 });
                 ui.end_row();
     
@@ -278,6 +688,62 @@ impl App {
                 });
                 ui.end_row();
 
+                ui.horizontal(|ui| {
+                    let mut config = self.tracing_state.config.write();
+                    let t_max = config.ray_t_max;
+                    if ui.add(egui::DragValue::new(&mut config.ray_t_min).speed(0.0001).clamp_range(0.0..=t_max)).changed() {
+                        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+                    }
+                    ui.label("Ray t min");
+
+                    let t_min = config.ray_t_min;
+                    if ui.add(egui::DragValue::new(&mut config.ray_t_max).speed(10.0).clamp_range(t_min..=f32::MAX)).changed() {
+                        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+                    }
+                    ui.label("Ray t max");
+                });
+                ui.end_row();
+
+                ui.horizontal(|ui| {
+                    let mut near_clip = self.tracing_state.config.read().near_clip;
+                    if ui.add(egui::DragValue::new(&mut near_clip).speed(0.01).clamp_range(0.0..=f32::MAX)).changed() {
+                        self.set_near_clip(near_clip);
+                    }
+                    ui.label("Near clip").on_hover_text("Geometry closer to the camera than this is cut away, 0 = off");
+                });
+                ui.end_row();
+
+                ui.horizontal(|ui| {
+                    let mut config = self.tracing_state.config.write();
+                    let mut fov = config.fov.to_degrees();
+                    if ui.add(egui::DragValue::new(&mut fov).speed(0.5).clamp_range(1.0..=179.0).suffix("°")).changed() {
+                        config.fov = fov.to_radians();
+                        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+                    }
+                    ui.label("FOV").on_hover_text("Horizontal field of view");
+
+                    if ui.add(egui::DragValue::new(&mut config.pixel_aspect).speed(0.01).clamp_range(0.1..=10.0)).changed() {
+                        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+                    }
+                    ui.label("Pixel aspect").on_hover_text("Width of a pixel relative to its height, for anamorphic formats");
+
+                    if ui.add(egui::DragValue::new(&mut config.aperture_radius).speed(0.001).clamp_range(0.0..=f32::MAX)).changed() {
+                        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+                    }
+                    ui.label("Aperture").on_hover_text("Radius of the lens, 0 disables depth of field");
+
+                    if ui.add(egui::DragValue::new(&mut config.focus_distance).speed(0.01).clamp_range(0.001..=f32::MAX)).changed() {
+                        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+                    }
+                    ui.label("Focus distance");
+
+                    if ui.add(egui::DragValue::new(&mut config.aperture_blades).clamp_range(0..=16)).changed() {
+                        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+                    }
+                    ui.label("Blades").on_hover_text("Out of focus highlights take the shape of a polygon with this many sides, below 3 they are round");
+                });
+                ui.end_row();
+
                 let prev_nee_mode = NextEventEstimation::from_u32(self.tracing_state.config.read().nee);
                 let mut nee_mode = prev_nee_mode;
                 egui::ComboBox::from_label("Next event estimation")
@@ -293,6 +759,81 @@ impl App {
                 }
                 ui.end_row();
 
+                if nee_mode.uses_mis() {
+                    let prev_mis_heuristic = MisHeuristic::from_u32(self.tracing_state.config.read().mis_heuristic);
+                    let mut mis_heuristic = prev_mis_heuristic;
+                    egui::ComboBox::from_label("MIS heuristic")
+                        .selected_text(format!("{:?}", mis_heuristic))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut mis_heuristic, MisHeuristic::Power, "Power");
+                            ui.selectable_value(&mut mis_heuristic, MisHeuristic::Balance, "Balance");
+                            ui.selectable_value(&mut mis_heuristic, MisHeuristic::Cutoff, "Cutoff");
+                            ui.selectable_value(&mut mis_heuristic, MisHeuristic::Maximum, "Maximum");
+                        })
+                        .response
+                        .on_hover_text("How light samples and BSDF samples are weighted against each other. All are unbiased, they only differ in noise");
+                    if mis_heuristic != prev_mis_heuristic {
+                        self.set_mis_heuristic(mis_heuristic);
+                    }
+                    ui.end_row();
+                }
+
+                let prev_light_sampling = LightSampling::from_u32(self.tracing_state.config.read().light_sampling);
+                let mut light_sampling = prev_light_sampling;
+                egui::ComboBox::from_label("Light sampling")
+                    .selected_text(format!("{:?}", light_sampling))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut light_sampling, LightSampling::Power, "Power");
+                        ui.selectable_value(&mut light_sampling, LightSampling::LightTree, "Light tree");
+                    })
+                    .response
+                    .on_hover_text("The light tree favors nearby lights, which helps in scenes with many lights");
+                if light_sampling != prev_light_sampling {
+                    self.tracing_state.config.write().light_sampling = light_sampling.to_u32();
+                    self.tracing_state.dirty.store(true, Ordering::Relaxed);
+                }
+                ui.end_row();
+
+                ui.horizontal(|ui| {
+                    let mut light_samples = self.tracing_state.config.read().light_samples;
+                    if ui.add(egui::DragValue::new(&mut light_samples).clamp_range(1..=64)).changed() {
+                        self.set_light_samples(light_samples);
+                    }
+                    ui.label("Light samples")
+                        .on_hover_text("Light samples per bounce. Each costs a shadow ray and only reduces noise in direct light, more samples per pixel reduce all noise");
+                });
+                ui.end_row();
+
+                let prev_russian_roulette = RussianRoulette::from_u32(self.tracing_state.config.read().russian_roulette);
+                let mut russian_roulette = prev_russian_roulette;
+                egui::ComboBox::from_label("Russian roulette")
+                    .selected_text(format!("{:?}", russian_roulette))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut russian_roulette, RussianRoulette::Throughput, "Throughput");
+                        ui.selectable_value(&mut russian_roulette, RussianRoulette::Albedo, "Albedo");
+                    })
+                    .response
+                    .on_hover_text("Albedo also ends paths sooner after dark surfaces, which casts fewer rays for the same noise in scenes with dark materials");
+                if russian_roulette != prev_russian_roulette {
+                    self.set_russian_roulette(russian_roulette);
+                }
+                ui.end_row();
+
+                let prev_working_space = WorkingSpace::from_u32(self.tracing_state.config.read().working_space);
+                let mut working_space = prev_working_space;
+                egui::ComboBox::from_label("Working space")
+                    .selected_text(format!("{:?}", working_space))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut working_space, WorkingSpace::LinearSrgb, "Linear sRGB");
+                        ui.selectable_value(&mut working_space, WorkingSpace::AcesCg, "ACEScg");
+                    })
+                    .response
+                    .on_hover_text("Color space light transport is computed in. ACEScg is wider, which changes how saturated colors bounce");
+                if working_space != prev_working_space {
+                    self.set_working_space(working_space);
+                }
+                ui.end_row();
+
                 {
                     let mut config = self.tracing_state.config.write();
                     if ui.add(egui::Slider::new(&mut config.specular_weight_clamp.x, 0.0..=1.0).text("Min specular")).changed() {
@@ -310,6 +851,40 @@ impl App {
                         self.tracing_state.dirty.store(true, Ordering::Relaxed);
                     }
                     ui.end_row();
+
+                    ui.horizontal(|ui| {
+                        if ui.add(egui::DragValue::new(&mut config.indirect_clamp).speed(0.1).clamp_range(0.0..=f32::MAX)).changed() {
+                            self.tracing_state.dirty.store(true, Ordering::Relaxed);
+                        }
+                        ui.label("Indirect clamp (0 = off)")
+                            .on_hover_text("Limit how bright bounced light can be per sample, which removes fireflies but loses some energy. Direct light is not clamped");
+
+                        let mut clamp_mode = ClampMode::from_u32(config.clamp_mode);
+                        egui::ComboBox::from_label("Clamp mode")
+                            .selected_text(format!("{:?}", clamp_mode))
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(&mut clamp_mode, ClampMode::Luminance, "Luminance");
+                                ui.selectable_value(&mut clamp_mode, ClampMode::Channel, "Per channel");
+                            })
+                            .response
+                            .on_hover_text("Luminance dims fireflies by how bright they look, without changing their color. Per channel limits every channel, which turns colored fireflies whiter");
+                        if clamp_mode.to_u32() != config.clamp_mode {
+                            config.clamp_mode = clamp_mode.to_u32();
+                            self.tracing_state.dirty.store(true, Ordering::Relaxed);
+                        }
+                    });
+                    ui.end_row();
+
+                    ui.horizontal(|ui| {
+                        let mut ambient = config.ambient.truncate().to_array();
+                        if ui.color_edit_button_rgb(&mut ambient).changed() {
+                            config.ambient = Vec3::from(ambient).extend(0.0);
+                            self.tracing_state.dirty.store(true, Ordering::Relaxed);
+                        }
+                        ui.label("Ambient (preview only)")
+                            .on_hover_text("Constant fill light added to every surface, so scenes without lights aren't black. Not physically based, turn it off for final renders");
+                    });
+                    ui.end_row();
                 }
 
                 egui::ComboBox::from_label("Tonemapping operator")
@@ -322,12 +897,28 @@ impl App {
                         ui.selectable_value(&mut self.tonemapping, Tonemapping::Neutral, "Neutral");
                         ui.selectable_value(&mut self.tonemapping, Tonemapping::Reinhard, "Reinhard");
                         ui.selectable_value(&mut self.tonemapping, Tonemapping::Uncharted, "Uncharted");
+                        ui.selectable_value(&mut self.tonemapping, Tonemapping::AgX, "AgX");
                     });
                 ui.end_row();
 
-                if ui.button("Environment settings").clicked() {
-                    self.show_environment_window = !self.show_environment_window;
-                }
+                ui.add(egui::Slider::new(&mut self.exposure, -10.0..=10.0).text("Exposure (EV)"));
+                ui.end_row();
+
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut self.dither, "Dither")
+                        .on_hover_text("Break up banding in smooth gradients of 8-bit output with a little noise");
+                    ui.add_enabled(self.dither, egui::DragValue::new(&mut self.dither_seed).prefix("seed "));
+                });
+                ui.end_row();
+
+                ui.horizontal(|ui| {
+                    if ui.button("Environment settings").clicked() {
+                        self.show_environment_window = !self.show_environment_window;
+                    }
+                    if ui.button("Post processing").clicked() {
+                        self.show_post_process_window = !self.show_post_process_window;
+                    }
+                });
                 ui.end_row();
 
                 ui.separator();
@@ -352,10 +943,47 @@ impl App {
                     self.tracing_state.sync_rate.store(sync_rate, Ordering::Relaxed);
                 }
                 ui.end_row();
+
+                let mut samples_per_dispatch = self.tracing_state.config.read().samples_per_dispatch;
+                if ui.add_enabled(!self.use_cpu, egui::Slider::new(&mut samples_per_dispatch, 1..=32).text("GPU samples per dispatch")).changed() {
+                    self.set_samples_per_dispatch(samples_per_dispatch);
+                }
+                ui.end_row();
+
+                ui.horizontal(|ui| {
+                    let mut time_budget = self.tracing_state.time_budget.load(Ordering::Relaxed);
+                    if ui.add(egui::DragValue::new(&mut time_budget).suffix(" s")).changed() {
+                        self.tracing_state.time_budget.store(time_budget, Ordering::Relaxed);
+                    }
+                    ui.label("Time budget (0 = unlimited)");
+                });
+                ui.end_row();
+
+                ui.horizontal(|ui| {
+                    ui.add(egui::DragValue::new(&mut self.priority_samples).clamp_range(2..=64).suffix(" spp"));
+                    ui.add(egui::DragValue::new(&mut self.priority_brush_radius).clamp_range(1.0..=512.0).suffix(" px"));
+                    ui.label("Priority brush")
+                        .on_hover_text("Drag over the image to sample that area faster, hold ctrl to erase");
+                    if ui.button("Clear").clicked() {
+                        self.tracing_state.clear_priority();
+                    }
+                });
+                ui.end_row();
+
+                ui.horizontal(|ui| {
+                    let mut target_fps = self.tracing_state.target_fps.load(Ordering::Relaxed);
+                    if ui.add(egui::DragValue::new(&mut target_fps).clamp_range(0..=240)).changed() {
+                        self.tracing_state.target_fps.store(target_fps, Ordering::Relaxed);
+                    }
+                    ui.label("Target FPS (0 = off)")
+                        .on_hover_text("Lower the bounce count while frames are slower than this, which is less accurate");
+                });
+                ui.end_row();
         
                 ui.label(format!(
-                    "Samples: {}",
-                    self.tracing_state.samples.load(Ordering::Relaxed)
+                    "Samples: {}, noise: {:.2e}",
+                    self.tracing_state.samples.load(Ordering::Relaxed),
+                    self.tracing_state.read_noise_estimate(),
                 ));
                 ui.end_row();
             });
@@ -382,6 +1010,28 @@ impl App {
                 }
             });
 
+            if self.selected_skybox.is_none() {
+                let background_color = self.tracing_state.config.read().background_color;
+                let mut use_background = background_color.w != 0.0;
+                let mut color = background_color.truncate().to_array();
+                ui.horizontal(|ui| {
+                    let mut changed = ui.checkbox(&mut use_background, "Flat background").changed();
+                    changed |= ui.color_edit_button_rgb(&mut color).changed();
+                    if changed {
+                        let w = if use_background { 1.0 } else { 0.0 };
+                        self.tracing_state.config.write().background_color = Vec3::from(color).extend(w);
+                        self.tracing_state.dirty.store(true, Ordering::Relaxed);
+                    }
+                });
+            }
+
+            if self.selected_skybox.is_some() {
+                let mut degrees = self.tracing_state.config.read().skybox_rotation.to_degrees();
+                if ui.add(egui::Slider::new(&mut degrees, -180.0..=180.0).text("Skybox rotation")).changed() {
+                    self.set_skybox_rotation(degrees);
+                }
+            }
+
             let mut sun_intensity = sun_direction.w;
             if ui.add(egui::Slider::new(&mut sun_intensity, 0.0..=50.0).text("Sun intensity")).changed() {
                 self.tracing_state.config.write().sun_direction.w = sun_intensity;
@@ -436,7 +1086,92 @@ impl App {
         self.show_environment_window = show_environment_window;
     }
 
+    fn on_post_process_gui(&mut self, egui_ctx: &egui::Context) {
+        let mut show_post_process_window = self.show_post_process_window;
+        egui::Window::new("Post processing").open(&mut show_post_process_window).show(egui_ctx, |ui| {
+            let mut post_config = self.tracing_state.post_config.write();
+            ui.add(egui::Slider::new(&mut post_config.bloom_strength, 0.0..=1.0).text("Bloom strength"));
+            ui.add(egui::Slider::new(&mut post_config.bloom_threshold, 0.0..=10.0).text("Bloom threshold"));
+            ui.add(egui::Slider::new(&mut post_config.chromatic_aberration, 0.0..=5.0).text("Chromatic aberration"));
+            ui.add(egui::Slider::new(&mut post_config.vignette, 0.0..=1.0).text("Vignette"));
+            ui.add(egui::Slider::new(&mut post_config.despeckle_threshold, 0.0..=20.0).text("Despeckle threshold"));
+            ui.add(egui::Slider::new(&mut post_config.white_balance, 0.0..=12000.0).text("White balance (K)"))
+                .on_hover_text("Color temperature of the light which should look white, 0 = off");
+            ui.add(egui::Slider::new(&mut post_config.tint, -1.0..=1.0).text("Tint"))
+                .on_hover_text("Positive is more magenta, negative more green. Only applies with white balance on");
+        });
+        self.show_post_process_window = show_post_process_window;
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        self.tracing_state.paused.store(paused, Ordering::Relaxed);
+        let suffix = if paused { " (paused)" } else { "" };
+        self.window.set_title(&format!("{}{}", WINDOW_TITLE, suffix));
+    }
+
+    // Save the render once the time budget has run out. Called at the end of the redraw which noticed, so the image on
+    // screen, which non-HDR formats are saved from, includes the last samples.
+    fn save_budget_render(&self) {
+        let path = self
+            .output_path
+            .clone()
+            .unwrap_or_else(|| format!("render_{}spp.png", self.tracing_state.samples.load(Ordering::Relaxed)));
+        println!("Saving render to {}", path);
+        self.save_image(&path);
+    }
+
+    // Save the frame as it is on screen, named after the time so repeated dumps don't overwrite each other
+    fn dump_frame(&self) {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        let path = format!("frame_{}_{}spp.png", timestamp, self.tracing_state.samples.load(Ordering::Relaxed));
+        println!("Saving frame to {}", path);
+        self.save_image(&path);
+    }
+
+    // Keys which control accumulation rather than the camera. These are handled on the frame they are pressed,
+    // so they don't go through the rate limiting of the camera controls.
+    fn handle_render_keys(&mut self, ui: &egui::Ui) {
+        if ui.ctx().wants_keyboard_input() {
+            return;
+        }
+        let input = ui.input();
+        let toggle_pause = input.key_pressed(egui::Key::Space);
+        let step = input.events.iter().any(|event| matches!(event, egui::Event::Text(text) if text == "."));
+        let dump = input.key_pressed(egui::Key::S) && !input.pointer.secondary_down(); // S moves while flying
+        drop(input);
+
+        if toggle_pause {
+            self.set_paused(!self.tracing_state.paused.load(Ordering::Relaxed));
+        }
+        if step {
+            self.set_paused(true);
+            self.tracing_state.step();
+        }
+        if dump {
+            self.dump_frame();
+        }
+    }
+
+    // Dragging over the image with the left mouse button paints the priority brush, holding ctrl erases, see
+    // `TracingState::paint_priority`. The image is stretched over `rect`, so the pointer is mapped back to its pixels.
+    fn paint_priority(&self, ui: &egui::Ui, response: &egui::Response, rect: egui::Rect, width: u32, height: u32) {
+        if !response.dragged_by(egui::PointerButton::Primary) {
+            return;
+        }
+        let Some(pointer) = response.interact_pointer_pos() else {
+            return;
+        };
+        let scale = glam::Vec2::new(width as f32 / rect.width(), height as f32 / rect.height());
+        let center = glam::Vec2::new(pointer.x - rect.min.x, pointer.y - rect.min.y) * scale;
+        let samples = if ui.input().modifiers.ctrl { 1 } else { self.priority_samples };
+        self.tracing_state.paint_priority(center, self.priority_brush_radius * scale.x, samples);
+    }
+
     fn handle_input(&mut self, ui: &egui::Ui) {
+        self.handle_render_keys(ui);
+
         if self.last_input.elapsed().as_millis() < 16 {
             return;
         }
@@ -448,9 +1183,11 @@ impl App {
         } else {
             self.tracing_state.interacting.store(false, Ordering::Relaxed);
             self.window.set_cursor_visible(true);
+            return; // The camera is only controlled while holding right click
         }
     
         let mut config = self.tracing_state.config.write();
+        let (previous_position, previous_rotation) = (config.cam_position, config.cam_rotation);
     
         let mut forward = Vec3::new(0.0, 0.0, 1.0);
         let mut right = Vec3::new(1.0, 0.0, 0.0);
@@ -489,6 +1226,13 @@ impl App {
         config.cam_rotation.x += self.mouse_delta.1 * 0.005;
         config.cam_rotation.y += self.mouse_delta.0 * 0.005;
         self.mouse_delta = (0.0, 0.0);
+
+        // The samples accumulated from the old viewpoint are thrown away, instead of being blended into the new one
+        let moved = config.cam_position != previous_position || config.cam_rotation != previous_rotation;
+        drop(config);
+        if moved {
+            self.tracing_state.reset();
+        }
     }
 
     pub fn redraw(&mut self, platform: &mut Platform, start_time: &Instant) {
@@ -504,6 +1248,9 @@ impl App {
             .texture
             .create_view(&wgpu::TextureViewDescriptor::default());
 
+        // The tracing thread publishes its last image before setting this, so the image drawn below is the final one
+        let budget_reached = self.tracing_state.budget_reached.swap(false, Ordering::Relaxed);
+
         // Begin to draw the UI frame.
         platform.begin_frame();
 
@@ -514,15 +1261,25 @@ impl App {
                 self.on_gui(&platform.context());
                 self.handle_input(ui);
 
-                let rect = ui.allocate_exact_size(ui.available_size(), egui::Sense::drag()).0;
-                let framebuffer = self.tracing_state.framebuffer.read().clone(); // TODO: clone is slow
+                let (rect, response) = ui.allocate_exact_size(ui.available_size(), egui::Sense::drag());
+                let tracing_state = self.tracing_state.clone();
                 let width = self.tracing_state.config.read().width;
                 let height = self.tracing_state.config.read().height;
-                let tonemapping = self.tonemapping;
+                self.paint_priority(ui, &response, rect, width, height);
+                let uniforms = RenderUniforms {
+                    width,
+                    height,
+                    tonemapping: self.tonemapping as u32,
+                    exposure: self.exposure,
+                    dither: self.dither as u32,
+                    dither_seed: self.dither_seed,
+                    srgb_target: self.surface_format.describe().srgb as u32,
+                    _padding: 0,
+                };
                 let cb = egui_wgpu::CallbackFn::new()
                     .prepare(move |_device, queue, _encoder, typemap| {
                         if let Some(resources) = typemap.get::<PaintCallbackResources>() {
-                            resources.prepare(queue, &framebuffer, width, height, tonemapping);
+                            resources.prepare(queue, &tracing_state.read_framebuffer(), &uniforms);
                         }
                         Default::default()
                     })
@@ -589,6 +1346,10 @@ impl App {
         for id in &tdelta.free {
             self.egui_renderer.free_texture(id);
         }
+
+        if budget_reached {
+            self.save_budget_render();
+        }
     }
 
     pub fn handle_mouse_motion(&mut self, delta: (f64, f64)) {
@@ -624,37 +1385,38 @@ impl App {
     }
 }
 
-struct PaintCallbackResources {
+// Draws the framebuffer with render.wgsl, which tonemaps it. Used for the window, saved images, and
+// `Renderer::render_to_texture`.
+pub(crate) struct PaintCallbackResources {
     pipeline: wgpu::RenderPipeline,
     bind_group: wgpu::BindGroup,
     uniform_buffer: wgpu::Buffer,
     render_buffer: wgpu::Buffer,
+    _dither_buffer: wgpu::Buffer, // Never written, but has to outlive the bind group
 }
 
 impl PaintCallbackResources {
-    fn prepare(
+    pub(crate) fn prepare(
         &self,
         queue: &wgpu::Queue,
-        framebuffer: &Vec<f32>,
-        width: u32,
-        height: u32,
-        tonemapping: Tonemapping,
+        framebuffer: &[f32],
+        uniforms: &RenderUniforms,
     ) {
         queue.write_buffer(&self.render_buffer, 0, bytemuck::cast_slice(framebuffer));
         queue.write_buffer(
             &self.uniform_buffer,
             0,
-            bytemuck::cast_slice(&[width, height, tonemapping as u32]),
+            bytemuck::bytes_of(uniforms),
         );
     }
 
-    fn paint<'rpass>(&'rpass self, rpass: &mut wgpu::RenderPass<'rpass>) {
+    pub(crate) fn paint<'rpass>(&'rpass self, rpass: &mut wgpu::RenderPass<'rpass>) {
         rpass.set_pipeline(&self.pipeline);
         rpass.set_bind_group(0, &self.bind_group, &[]);
         rpass.draw(0..6, 0..1);
     }
 
-    fn new(
+    pub(crate) fn new(
         device: &wgpu::Device,
         format: wgpu::TextureFormat,
         width: u32,
@@ -688,6 +1450,16 @@ impl PaintCallbackResources {
                     },
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
         });
     
@@ -722,7 +1494,7 @@ impl PaintCallbackResources {
     
         let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: None,
-            contents: bytemuck::cast_slice(&[0.0, 0.0, 0.0]),
+            contents: bytemuck::bytes_of(&<RenderUniforms as bytemuck::Zeroable>::zeroed()),
             usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
         });
     
@@ -732,6 +1504,12 @@ impl PaintCallbackResources {
             usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
             mapped_at_creation: false,
         });
+
+        let dither_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&dither_tile()),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
     
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: None,
@@ -745,6 +1523,10 @@ impl PaintCallbackResources {
                     binding: 1,
                     resource: render_buffer.as_entire_binding(),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: dither_buffer.as_entire_binding(),
+                },
             ],
         });
     
@@ -753,10 +1535,11 @@ impl PaintCallbackResources {
             bind_group,
             uniform_buffer,
             render_buffer,
+            _dither_buffer: dither_buffer,
         }
     }
 
-    fn save_render(&self, texture_width: u32, texture_height: u32, format: wgpu::TextureFormat, device: &wgpu::Device, queue: &wgpu::Queue) {
+    fn save_render(&self, path: &str, texture_width: u32, texture_height: u32, alpha: Option<(&[f32], AlphaMode)>, format: wgpu::TextureFormat, device: &wgpu::Device, queue: &wgpu::Queue) -> image::ImageResult<()> {
         let texture_desc = &wgpu::TextureDescriptor {
             label: None,
             size: wgpu::Extent3d {
@@ -822,24 +1605,26 @@ impl PaintCallbackResources {
         );
         queue.submit(Some(encoder.finish()));
     
-        {
+        let res = {
             let buffer_slice = output_buffer.slice(..);
         
             buffer_slice.map_async(wgpu::MapMode::Read, |_| {});
             device.poll(wgpu::Maintain::Wait);
             let mut data = buffer_slice.get_mapped_range().to_vec();
             data.chunks_exact_mut(4).for_each(|c| c.swap(0, 2)); // BGRA -> RGBA swizzle
-            let Some(buffer) = image::ImageBuffer::<image::Rgba<u8>, _>::from_raw(texture_width, texture_height, data) else {
-                return;
-            };
-            let image = image::DynamicImage::ImageRgba8(buffer).into_rgba8();
-            if let Some(path) = tinyfiledialogs::save_file_dialog("Save render", "") {
-                let res = image.save(path);
-                if res.is_err() {
-                    #[cfg(debug_assertions)] println!("Failed to save image: {:?}", res.err());
+            if let Some((alpha, alpha_mode)) = alpha {
+                apply_coverage_alpha(&mut data, alpha, alpha_mode);
+            }
+            if path.to_lowercase().ends_with(".png") {
+                save_png(path, texture_width, texture_height, &data).map_err(image::ImageError::IoError)
+            } else {
+                match image::RgbaImage::from_raw(texture_width, texture_height, data) {
+                    Some(image) => image.save(path),
+                    None => Ok(()),
                 }
             }
-        }
+        };
         output_buffer.unmap();
+        res
     }
 }
\ No newline at end of file
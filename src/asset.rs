@@ -1,10 +1,13 @@
-use glam::{UVec4, Vec4, Mat4, Vec2, Vec3};
+use std::{collections::HashMap, path::Path};
+
+use glam::{UVec4, Vec4, Mat3, Mat4, Vec2, Vec3, Vec4Swizzles};
 use gpgpu::{GpuBuffer, BufOps, GpuConstImage, primitives::{pixels::{Rgba8UintNorm, Rgba32Float}, PixelInfo}, ImgOps};
 use image::DynamicImage;
 use russimp::{scene::{Scene, PostProcess::*}, node::Node, material::{DataContent, TextureType, Texture, Material, PropertyTypeInfo}};
-use shared_structs::{MaterialData, PerVertexData, LightPickEntry};
+use kernels::intersection::BVHReference;
+use shared_structs::{TracingConfig, MaterialData, PerVertexData, LightPickEntry, LightTreeNode, PrimitiveType, CompactPrimitive, make_primitive, material_index, ATLAS_SIZE, ATLAS_MIP_LEVELS};
 
-use crate::{bvh::{BVH, BVHBuilder, GpuBVH}, trace::FW, light_pick};
+use crate::{bvh::{BVH, BVHBuilder, GpuBVH}, trace::FW, light_pick, light_tree, atlas::{AtlasFormat, is_hdr_image}};
 
 pub struct World {
     pub bvh: BVH,
@@ -13,15 +16,30 @@ pub struct World {
     pub atlas: DynamicImage,
     pub material_data_buffer: Vec<MaterialData>,  
     pub light_pick_buffer: Vec<LightPickEntry>,  
+    pub light_tree_buffer: Vec<LightTreeNode>,
+    pub build_time: std::time::Duration, // Spent in `build_acceleration_structures`, including rebuilds
 }
 
 pub struct GpuWorld<'fw> {
     pub bvh: GpuBVH<'fw>,
     pub per_vertex_buffer: GpuBuffer<'fw, PerVertexData>,
-    pub index_buffer: GpuBuffer<'fw, UVec4>,
-    pub atlas: GpuConstImage<'fw, Rgba8UintNorm>,
+    pub index_buffer: GpuIndexBuffer<'fw>,
+    pub atlas: GpuAtlas<'fw>,
     pub material_data_buffer: GpuBuffer<'fw, MaterialData>,
     pub light_pick_buffer: GpuBuffer<'fw, LightPickEntry>,
+    pub light_tree_buffer: GpuBuffer<'fw, LightTreeNode>,
+}
+
+// Scenes small enough for 16 bit indices upload a compact index buffer, which needs its own kernel entry point.
+pub enum GpuIndexBuffer<'fw> {
+    Wide(GpuBuffer<'fw, UVec4>),
+    Compact(GpuBuffer<'fw, CompactPrimitive>),
+}
+
+// The kernel samples both formats the same way, so this is only relevant on the host.
+pub enum GpuAtlas<'fw> {
+    Ldr(GpuConstImage<'fw, Rgba8UintNorm>),
+    Hdr(GpuConstImage<'fw, Rgba32Float>),
 }
 
 fn convert_texture(texture: &Texture) -> Option<DynamicImage> {
@@ -31,6 +49,9 @@ fn convert_texture(texture: &Texture) -> Option<DynamicImage> {
             let image_buffer = image::RgbaImage::from_vec(texture.width, texture.height, image_data)?;
             image::DynamicImage::ImageRgba8(image_buffer)
         },
+        DataContent::Bytes(bytes) if image::guess_format(bytes).ok() == Some(image::ImageFormat::Hdr) => {
+            decode_hdr(std::io::Cursor::new(bytes))?
+        }
         DataContent::Bytes(bytes) => {
             image::io::Reader::new(std::io::Cursor::new(bytes)).with_guessed_format().ok()?.decode().ok()?
         }
@@ -43,6 +64,244 @@ fn load_texture(material: &Material, texture_type: TextureType) -> Option<Dynami
     material.textures.get(&texture_type).and_then(|texture| convert_texture(&texture.borrow()))
 }
 
+// Albedo and emissive data is stored in gamma space, but we atlas it with all the other textures which are stored in
+// linear. Therefore, we convert here. HDR formats (.hdr, .exr) are already linear, so they are used as-is, which keeps
+// them in the float atlas with their full range.
+fn linearize_color_texture(texture: DynamicImage) -> DynamicImage {
+    if is_hdr_image(&texture) {
+        return texture;
+    }
+    let mut texture = texture.into_rgb8();
+    for pixel in texture.iter_mut() {
+        *pixel = ((*pixel as f32 / 255.0).powf(2.2) * 255.0) as u8;
+    }
+    DynamicImage::ImageRgb8(texture)
+}
+
+// Triangles whose edges are parallel (sine of the angle between them below this) are considered degenerate
+const DEGENERATE_TRIANGLE_SINE: f32 = 1e-6;
+
+// Zero-area triangles can never be hit, and triangles with non-finite vertices produce garbage bounds and
+// centroids in the BVH, so these are dropped. Quads are held to the same standard, spheres need a positive radius.
+// Returns the number of primitives removed.
+pub fn remove_degenerate_triangles(vertices: &[PerVertexData], indices: &mut Vec<UVec4>) -> usize {
+    let triangle_count = indices.len();
+    indices.retain(|triangle| {
+        if PrimitiveType::of(*triangle) == PrimitiveType::Sphere {
+            let sphere = vertices[triangle.x as usize];
+            return sphere.vertex.is_finite() && sphere.radius.is_finite() && sphere.radius > 0.0;
+        }
+        let a = vertices[triangle.x as usize].vertex;
+        let b = vertices[triangle.y as usize].vertex;
+        let c = vertices[triangle.z as usize].vertex;
+        if !a.is_finite() || !b.is_finite() || !c.is_finite() {
+            return false;
+        }
+        // Scale invariant, so it works for tiny and huge meshes alike. Also catches zero length edges.
+        let edge_a = b - a;
+        let edge_b = c - a;
+        let max_cross = DEGENERATE_TRIANGLE_SINE * DEGENERATE_TRIANGLE_SINE * edge_a.length_squared() * edge_b.length_squared();
+        edge_a.cross(edge_b).length_squared() > max_cross
+    });
+    triangle_count - indices.len()
+}
+
+// Primitives reference vertices by index, so vertices shared between them only need to be stored once. Scene files
+// are already deduplicated by assimp, but geometry built in code, such as the built-in scenes, often repeats vertices
+// for every triangle. Vertices are merged only if every attribute is bitwise identical, so shading is unchanged.
+// The first occurrence of each vertex is kept, in order. Returns the number of vertices removed.
+pub fn deduplicate_vertices(per_vertex_data: &mut Vec<PerVertexData>, indices: &mut [UVec4]) -> usize {
+    let vertex_count = per_vertex_data.len();
+    let mut unique_indices = HashMap::new();
+    let mut remap = Vec::with_capacity(vertex_count);
+    let mut unique = Vec::new();
+    for data in per_vertex_data.iter() {
+        let key = bytemuck::cast::<PerVertexData, [u32; 20]>(*data);
+        let index = *unique_indices.entry(key).or_insert_with(|| {
+            unique.push(*data);
+            unique.len() as u32 - 1
+        });
+        remap.push(index);
+    }
+    for primitive in indices.iter_mut() {
+        primitive.x = remap[primitive.x as usize];
+        primitive.y = remap[primitive.y as usize];
+        primitive.z = remap[primitive.z as usize];
+    }
+    *per_vertex_data = unique;
+    vertex_count - per_vertex_data.len()
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UpAxis {
+    Y,
+    Z,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Handedness {
+    Left,
+    Right,
+}
+
+// Convention used by the scene files being loaded. Geometry is converted into the convention of the renderer, which
+// is Y-up and left handed, with X to the right and Z forward. The default matches what the loader has always assumed.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct CoordinateSystem {
+    pub up: UpAxis,
+    pub handedness: Handedness,
+    // Fixes for meshes exported inside out. Flipping the winding turns triangles around, which decides which side
+    // emissive surfaces light. Flipping normals turns the shading normals around, which decides which side is lit.
+    // Files without normals get them generated from their winding, so inside out meshes usually need both.
+    pub flip_winding: bool,
+    pub flip_normals: bool,
+}
+
+impl Default for CoordinateSystem {
+    fn default() -> Self {
+        Self {
+            up: UpAxis::Z,
+            handedness: Handedness::Right,
+            flip_winding: false,
+            flip_normals: false,
+        }
+    }
+}
+
+impl UpAxis {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "y" => Some(UpAxis::Y),
+            "z" => Some(UpAxis::Z),
+            _ => None,
+        }
+    }
+}
+
+impl Handedness {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "left" => Some(Handedness::Left),
+            "right" => Some(Handedness::Right),
+            _ => None,
+        }
+    }
+}
+
+impl CoordinateSystem {
+    // Applies to both positions and directions, since the conversion is just a rotation or a mirroring
+    pub fn to_world(&self, v: Vec3) -> Vec3 {
+        match (self.up, self.handedness) {
+            (UpAxis::Z, Handedness::Right) => Vec3::new(v.x, v.z, v.y),
+            (UpAxis::Z, Handedness::Left) => Vec3::new(v.x, v.z, -v.y),
+            (UpAxis::Y, Handedness::Right) => Vec3::new(v.x, v.y, -v.z),
+            (UpAxis::Y, Handedness::Left) => v,
+        }
+    }
+
+    // Mirroring turns counter-clockwise triangles clockwise, so the winding must be flipped back, unless it was
+    // asked to be flipped as well
+    pub fn flips_winding(&self) -> bool {
+        (self.handedness == Handedness::Right) != self.flip_winding
+    }
+
+    // Multiplies loaded normals
+    pub fn normal_sign(&self) -> f32 {
+        if self.flip_normals { -1.0 } else { 1.0 }
+    }
+
+    // Express a world space transform in the space of the scene file, so it can be applied before conversion
+    fn to_file_space(&self, transform: Mat4) -> Mat4 {
+        let to_world = Mat4::from_mat3(Mat3::from_cols(
+            self.to_world(Vec3::X),
+            self.to_world(Vec3::Y),
+            self.to_world(Vec3::Z),
+        ));
+        to_world.transpose() * transform * to_world // The conversion is orthonormal, so the transpose is the inverse
+    }
+}
+
+// A scene file to load, or the name of a built-in scene, placed in the world with `transform`. Several files can be
+// loaded together, in which case they are merged into a single scene.
+#[derive(Clone, Debug)]
+pub struct SceneFile {
+    pub path: String,
+    pub transform: Mat4,
+}
+
+impl SceneFile {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            transform: Mat4::IDENTITY,
+        }
+    }
+}
+
+// Geometry and materials of one or more loaded files, in the layout `World::from_geometry` expects
+#[derive(Default)]
+struct LoadedGeometry {
+    vertices: Vec<Vec3>,
+    indices: Vec<UVec4>,
+    normals: Vec<Vec4>,
+    tangents: Vec<Vec4>,
+    uvs: Vec<Vec2>,
+    lightmap_uvs: Vec<Vec2>,
+    colors: Vec<Vec4>,
+    radii: Vec<f32>,
+    material_datas: Vec<MaterialData>,
+    textures: Vec<DynamicImage>,
+}
+
+impl LoadedGeometry {
+    // Vertex and material indices of `other` are offset past the ones already here. Textures stay in the order the
+    // materials reference them, since both are appended in order.
+    fn append(&mut self, other: LoadedGeometry) {
+        let vertex_offset = self.vertices.len() as u32;
+        let material_offset = self.material_datas.len() as u32;
+        self.indices.extend(other.indices.iter().map(|primitive| make_primitive(
+            primitive.x + vertex_offset,
+            primitive.y + vertex_offset,
+            primitive.z + vertex_offset,
+            material_index(*primitive) + material_offset,
+            PrimitiveType::of(*primitive),
+        )));
+
+        // Attributes can be missing from a file, so pad them to keep them lined up with the vertices
+        let vertex_count = self.vertices.len();
+        self.normals.resize(vertex_count, Vec4::ZERO);
+        self.tangents.resize(vertex_count, Vec4::ZERO);
+        self.uvs.resize(vertex_count, Vec2::ZERO);
+        self.lightmap_uvs.resize(vertex_count, Vec2::ZERO);
+        self.colors.resize(vertex_count, Vec4::ONE);
+        self.radii.resize(vertex_count, 0.0);
+        self.vertices.extend(other.vertices);
+        self.normals.extend(other.normals);
+        self.tangents.extend(other.tangents);
+        self.uvs.extend(other.uvs);
+        self.lightmap_uvs.extend(other.lightmap_uvs);
+        self.colors.extend(other.colors);
+        self.radii.extend(other.radii);
+        self.material_datas.extend(other.material_datas);
+        self.textures.extend(other.textures);
+    }
+
+    fn into_world(self) -> World {
+        World::from_geometry(
+            self.vertices,
+            self.indices,
+            self.normals,
+            self.tangents,
+            self.uvs,
+            self.lightmap_uvs,
+            self.colors,
+            self.radii,
+            self.material_datas,
+            self.textures,
+        )
+    }
+}
+
 fn load_float_array(material: &Material, name: &str) -> Option<Vec<f32>> {
     let prop = material.properties.iter().find(|p| p.key == name)?;
     match &prop.data {
@@ -51,8 +310,300 @@ fn load_float_array(material: &Material, name: &str) -> Option<Vec<f32>> {
     }
 }
 
+// Same as `load_float_array`, for properties of the texture of the given type, such as `$tex.scale`
+fn load_texture_float_array(material: &Material, name: &str, texture_type: TextureType) -> Option<Vec<f32>> {
+    let prop = material.properties.iter().find(|p| p.key == name && p.semantic == texture_type)?;
+    match &prop.data {
+        PropertyTypeInfo::FloatArray(col) => Some(col.clone()),
+        _ => None
+    }
+}
+
+fn load_string(material: &Material, name: &str) -> Option<String> {
+    let prop = material.properties.iter().find(|p| p.key == name)?;
+    match &prop.data {
+        PropertyTypeInfo::String(string) => Some(string.clone()),
+        _ => None
+    }
+}
+
+// Emissive strength used when a glTF file doesn't have the KHR_materials_emissive_strength extension, and for other
+// formats. Older files were authored expecting a fixed multiplier, since the extension wasn't supported.
+const DEFAULT_EMISSIVE_STRENGTH: f32 = 15.0;
+
+// Locate the JSON chunk of a binary glTF file
+fn glb_json_chunk(bytes: &[u8]) -> Option<&[u8]> {
+    let length = u32::from_le_bytes(bytes.get(12..16)?.try_into().ok()?) as usize;
+    if bytes.get(16..20)? != b"JSON" {
+        return None;
+    }
+    bytes.get(20..20 + length)
+}
+
+// The JSON of a glTF file, either on its own or inside a binary file. None for other formats.
+fn load_gltf_json(path: &str) -> Option<serde_json::Value> {
+    let extension = Path::new(path).extension().map(|e| e.to_ascii_lowercase());
+    if extension.as_ref().map_or(true, |e| e != "gltf" && e != "glb") {
+        return None;
+    }
+    let bytes = std::fs::read(path).ok()?;
+    let json = if bytes.starts_with(b"glTF") { glb_json_chunk(&bytes)? } else { &bytes[..] };
+    serde_json::from_slice::<serde_json::Value>(json).ok()
+}
+
+// The materials of a glTF file as JSON, indexed like the glTF materials, which assimp keeps in order. Used for what
+// assimp 5.2.5 doesn't import, such as the KHR_materials_emissive_strength extension. Empty for other formats.
+fn load_gltf_materials(path: &str) -> Vec<serde_json::Value> {
+    load_gltf_json(path).and_then(|json| json["materials"].as_array().cloned()).unwrap_or_default()
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CameraProjection {
+    Perspective { yfov: f32 }, // Vertical field of view in radians
+    Orthographic { ymag: f32 }, // Half the height of the view in scene units
+}
+
+// A camera placed in a glTF file, converted to world space like the geometry of the file
+#[derive(Clone, Debug, PartialEq)]
+pub struct GltfCamera {
+    pub name: String,
+    pub position: Vec3,
+    pub forward: Vec3,
+    pub projection: CameraProjection,
+    pub znear: f32,
+}
+
+impl GltfCamera {
+    // Look through this camera. The camera of the renderer can't roll, so any roll is lost. Its field of view is
+    // horizontal, so it is derived from the vertical one of the glTF camera for the aspect ratio of the image, which
+    // keeps the vertical framing the same even if the image is shaped differently than the camera was authored for.
+    // The near plane is kept too, since the authoring tool clips there as well.
+    pub fn apply(&self, config: &mut TracingConfig) {
+        config.cam_position = self.position.extend(0.0);
+        config.cam_rotation.x = (-self.forward.y).clamp(-1.0, 1.0).asin();
+        config.cam_rotation.y = self.forward.x.atan2(self.forward.z);
+        match self.projection {
+            CameraProjection::Perspective { yfov } => {
+                let aspect = config.width as f32 * config.pixel_aspect / config.height as f32;
+                config.fov = 2.0 * ((yfov * 0.5).tan() * aspect).atan();
+                config.ortho_height = 0.0;
+            }
+            CameraProjection::Orthographic { ymag } => config.ortho_height = ymag,
+        }
+        config.near_clip = self.znear;
+    }
+}
+
+// Local transform of a glTF node, given either as a matrix or as translation, rotation and scale
+fn gltf_node_transform(node: &serde_json::Value) -> Mat4 {
+    let numbers = |value: &serde_json::Value| value.as_array().map(|array| array.iter().filter_map(|n| n.as_f64().map(|n| n as f32)).collect::<Vec<_>>());
+    if let Some(matrix) = numbers(&node["matrix"]).filter(|matrix| matrix.len() == 16) {
+        return Mat4::from_cols_slice(&matrix);
+    }
+    let translation = numbers(&node["translation"]).filter(|t| t.len() == 3).map_or(Vec3::ZERO, |t| Vec3::from_slice(&t));
+    let rotation = numbers(&node["rotation"]).filter(|r| r.len() == 4).map_or(glam::Quat::IDENTITY, |r| glam::Quat::from_slice(&r).normalize());
+    let scale = numbers(&node["scale"]).filter(|s| s.len() == 3).map_or(Vec3::ONE, |s| Vec3::from_slice(&s));
+    Mat4::from_scale_rotation_translation(scale, rotation, translation)
+}
+
+// The first camera placed in the default scene of a glTF file, or the first one whose camera or node is called `name`,
+// along with the transform of its node in the space of the file
+fn find_gltf_camera(json: &serde_json::Value, name: Option<&str>) -> Option<(serde_json::Value, Mat4)> {
+    let empty = Vec::new();
+    let nodes = json["nodes"].as_array().unwrap_or(&empty);
+    let scene = &json["scenes"][json["scene"].as_u64().unwrap_or(0) as usize];
+    let roots = match scene["nodes"].as_array() {
+        Some(roots) => roots.iter().filter_map(|node| node.as_u64()).map(|node| node as usize).collect::<Vec<_>>(),
+        // Without scenes, every node that isn't a child of another is a root
+        None => (0..nodes.len())
+            .filter(|&index| !nodes.iter().any(|node| node["children"].as_array().map_or(false, |c| c.iter().any(|c| c.as_u64() == Some(index as u64)))))
+            .collect(),
+    };
+
+    // Depth first, in the order the nodes are listed. Nodes are visited at most once each, in case of broken files.
+    let mut stack = roots.into_iter().rev().map(|root| (root, Mat4::IDENTITY)).collect::<Vec<_>>();
+    let mut visits = 0;
+    while let Some((index, parent_transform)) = stack.pop() {
+        visits += 1;
+        let Some(node) = nodes.get(index).filter(|_| visits <= nodes.len()) else {
+            continue;
+        };
+        let transform = parent_transform * gltf_node_transform(node);
+        if let Some(camera) = node["camera"].as_u64().map(|camera| &json["cameras"][camera as usize]).filter(|camera| camera.is_object()) {
+            let named = |value: &serde_json::Value| name.map_or(true, |name| value["name"].as_str() == Some(name));
+            if named(camera) || named(node) {
+                return Some((camera.clone(), transform));
+            }
+        }
+        if let Some(children) = node["children"].as_array() {
+            stack.extend(children.iter().rev().filter_map(|child| child.as_u64()).map(|child| (child as usize, transform)));
+        }
+    }
+    None
+}
+
+// The camera of the first glTF file of `scene` that has one, see `find_gltf_camera`. Placed in the world like the
+// geometry of that file. glTF cameras look down their -Z axis, with Y up.
+pub fn load_gltf_camera(scene: &[SceneFile], coordinate_system: CoordinateSystem, name: Option<&str>) -> Option<GltfCamera> {
+    scene.iter().find_map(|file| {
+        let json = load_gltf_json(&file.path)?;
+        let (camera, node_transform) = find_gltf_camera(&json, name)?;
+        let transform = coordinate_system.to_file_space(file.transform) * node_transform;
+        let number = |value: &serde_json::Value| value.as_f64().map(|value| value as f32);
+        // Distances are in the space of the camera, so they are scaled along with it
+        let forward = transform.transform_vector3(-Vec3::Z);
+        let up_scale = transform.transform_vector3(Vec3::Y).length();
+        let (projection, znear) = match camera["type"].as_str() {
+            Some("orthographic") => (
+                CameraProjection::Orthographic { ymag: number(&camera["orthographic"]["ymag"])? * up_scale },
+                number(&camera["orthographic"]["znear"]),
+            ),
+            _ => (CameraProjection::Perspective { yfov: number(&camera["perspective"]["yfov"])? }, number(&camera["perspective"]["znear"])),
+        };
+        Some(GltfCamera {
+            name: camera["name"].as_str().unwrap_or_default().to_string(),
+            position: coordinate_system.to_world(transform.transform_point3(Vec3::ZERO)),
+            forward: coordinate_system.to_world(forward).normalize_or_zero(),
+            projection,
+            znear: znear.unwrap_or(0.0).max(0.0) * forward.length(),
+        })
+    })
+}
+
+// Disney principled parameters of a glTF material. The ones glTF has extensions for, which is what Blender exports,
+// are converted. Any of them can also be given by name in the extras of the material, such as
+// `"extras": { "sheen": 0.5 }`, which takes precedence.
+fn load_principled_parameters(material: &mut MaterialData, json: &serde_json::Value) {
+    let number = |value: &serde_json::Value| value.as_f64().map(|value| value as f32);
+    let extensions = &json["extensions"];
+    if let Some(factor) = number(&extensions["KHR_materials_specular"]["specularFactor"]) {
+        material.specular = factor * 0.5; // The factor scales the default reflectance, which is a specular of 0.5
+    }
+    if let Some(clearcoat) = number(&extensions["KHR_materials_clearcoat"]["clearcoatFactor"]) {
+        material.clearcoat = clearcoat;
+    }
+    if let Some(roughness) = number(&extensions["KHR_materials_clearcoat"]["clearcoatRoughnessFactor"]) {
+        material.clearcoat_gloss = 1.0 - roughness;
+    }
+    // glTF gives the sheen color directly, rather than as a tint of the base color
+    if let Some(color) = extensions["KHR_materials_sheen"]["sheenColorFactor"].as_array() {
+        material.sheen = color.iter().filter_map(|channel| channel.as_f64()).fold(0.0, f64::max) as f32;
+        material.sheen_tint = 0.0;
+    }
+    if let Some(anisotropy) = number(&extensions["KHR_materials_anisotropy"]["anisotropyStrength"]) {
+        material.anisotropy = anisotropy;
+    }
+
+    let extras = &json["extras"];
+    for (name, value) in [
+        ("specular", &mut material.specular),
+        ("specular_tint", &mut material.specular_tint),
+        ("sheen", &mut material.sheen),
+        ("sheen_tint", &mut material.sheen_tint),
+        ("clearcoat", &mut material.clearcoat),
+        ("clearcoat_gloss", &mut material.clearcoat_gloss),
+        ("subsurface", &mut material.subsurface),
+        ("anisotropy", &mut material.anisotropy),
+    ] {
+        if let Some(number) = number(&extras[name]) {
+            *value = number;
+        }
+    }
+    // Given as [r, g, b], in scene units
+    let radius = extras["subsurface_radius"].as_array().map(|radius| radius.iter().filter_map(number).collect::<Vec<_>>());
+    if let Some(&[r, g, b]) = radius.as_deref() {
+        material.subsurface_radius = Vec4::new(r, g, b, 0.0);
+    }
+}
+
+// Scene files have no way to mark a mesh as a sphere or quad, so it's done by the name of the node holding it. A mesh
+// on a node whose name starts with one of these is replaced by the exact primitive: spheres are fitted to the bounding
+// box of the mesh, which can be as coarse as it likes, and quads need exactly 4 vertices forming a parallelogram, whose
+// front face agrees with the mesh normals. Meshes that don't fit are kept as triangles, with a warning. Geometry
+// built in code can use `SceneBuilder::push_sphere` instead.
+pub const ANALYTIC_SPHERE_PREFIX: &str = "AnalyticSphere";
+pub const ANALYTIC_QUAD_PREFIX: &str = "AnalyticQuad";
+
+// Extra references spatial splits may add, relative to the number of primitives
+const SPATIAL_SPLIT_BUDGET: f32 = 0.5;
+
+// Build the BVH, which reorders `indices`, and the light sampling data, which refers to lights by their position in
+// `indices`. Lights are never split, so each of them stays in the index buffer exactly once.
+fn build_acceleration_structures(
+    vertices: &[PerVertexData],
+    indices: &mut Vec<UVec4>,
+    material_datas: &[MaterialData],
+    spatial_split_budget: f32,
+) -> (BVH, Vec<LightPickEntry>, Vec<LightTreeNode>) {
+    // BVH building
+    let now = std::time::Instant::now();
+    let keep_whole = light_pick::compute_emissive_mask(indices, material_datas);
+    let bvh = BVHBuilder::new(vertices, indices)
+        .sah_samples(128)
+        .spatial_splits(spatial_split_budget)
+        .keep_whole(keep_whole)
+        .build();
+    #[cfg(debug_assertions)] println!("BVH build time: {:?}", now.elapsed());
+
+    // Build light pick table
+    let now = std::time::Instant::now();
+    let emissive_mask = light_pick::compute_emissive_mask(indices, material_datas);
+    let light_pick_table = light_pick::build_light_pick_table(vertices, indices, &emissive_mask, material_datas);
+    #[cfg(debug_assertions)] println!("Light pick table build time: {:?}", now.elapsed());
+
+    // Build light tree
+    let now = std::time::Instant::now();
+    let light_tree = light_tree::build_light_tree(vertices, indices, &emissive_mask, material_datas);
+    #[cfg(debug_assertions)] println!("Light tree build time: {:?}", now.elapsed());
+
+    (bvh, light_pick_table, light_tree)
+}
+
 impl World {
     pub fn from_path(path: &str) -> Option<Self> {
+        Self::from_path_with_coordinate_system(path, CoordinateSystem::default())
+    }
+
+    pub fn from_path_with_coordinate_system(path: &str, coordinate_system: CoordinateSystem) -> Option<Self> {
+        Self::load_geometry(path, coordinate_system, Mat4::IDENTITY).map(LoadedGeometry::into_world)
+    }
+
+    // PLY files are read with our own loader, see `ply::load_ply`. They have no materials, so the mesh gets a plain
+    // diffuse one, which vertex colors tint.
+    fn load_ply_geometry(path: &str, coordinate_system: CoordinateSystem, transform: Mat4) -> Option<LoadedGeometry> {
+        let mesh = match crate::ply::load_ply(path) {
+            Ok(mesh) => mesh,
+            Err(error) => {
+                println!("Warning: {}", error);
+                return None;
+            }
+        };
+
+        let trs = coordinate_system.to_file_space(transform);
+        let normal_matrix = Mat3::from_mat4(trs).inverse().transpose();
+        let vertex_count = mesh.positions.len();
+        Some(LoadedGeometry {
+            vertices: mesh.positions.iter().map(|v| coordinate_system.to_world(trs.transform_point3(*v))).collect(),
+            indices: mesh.triangles.iter().map(|t| {
+                let (b, c) = if coordinate_system.flips_winding() { (t.z, t.y) } else { (t.y, t.z) };
+                UVec4::new(t.x, b, c, 0)
+            }).collect(),
+            normals: mesh.normals.iter().map(|n| coordinate_system.to_world((normal_matrix * *n).normalize_or_zero() * coordinate_system.normal_sign()).extend(0.0)).collect(),
+            tangents: vec![Vec4::ZERO; vertex_count],
+            uvs: vec![Vec2::ZERO; vertex_count],
+            lightmap_uvs: vec![Vec2::ZERO; vertex_count],
+            colors: mesh.colors.unwrap_or_default(),
+            radii: Vec::new(),
+            material_datas: vec![crate::scenes::diffuse(Vec3::splat(0.8))],
+            textures: Vec::new(),
+        })
+    }
+
+    // Load a scene file, with `transform` applied in world space
+    fn load_geometry(path: &str, coordinate_system: CoordinateSystem, transform: Mat4) -> Option<LoadedGeometry> {
+        if Path::new(path).extension().map_or(false, |extension| extension.eq_ignore_ascii_case("ply")) {
+            return Self::load_ply_geometry(path, coordinate_system, transform);
+        }
         let blend = Scene::from_file(
             path,
             vec![
@@ -74,16 +625,83 @@ impl World {
         let mut normals = Vec::new();
         let mut tangents = Vec::new();
         let mut uvs = Vec::new();
+        let mut lightmap_uvs = Vec::new();
+        let mut colors = Vec::new();
+        let mut radii = Vec::new();
+
+        // Returns false if the mesh doesn't have the right shape for the primitive
+        fn push_analytic_primitive(
+            primitive_type: PrimitiveType,
+            mesh_vertices: &[Vec3],
+            mesh_normal: Vec3,
+            material_index: u32,
+            vertices: &mut Vec<Vec3>,
+            indices: &mut Vec<UVec4>,
+            normals: &mut Vec<Vec4>,
+            tangents: &mut Vec<Vec4>,
+            uvs: &mut Vec<Vec2>,
+            radii: &mut Vec<f32>
+        ) -> bool {
+            let base = vertices.len() as u32;
+            match primitive_type {
+                PrimitiveType::Sphere => {
+                    // Fit to the bounding box, so the mesh can be as coarse as it likes
+                    let aabb_min = mesh_vertices.iter().fold(Vec3::splat(f32::INFINITY), |acc, v| acc.min(*v));
+                    let aabb_max = mesh_vertices.iter().fold(Vec3::splat(f32::NEG_INFINITY), |acc, v| acc.max(*v));
+                    let center = (aabb_min + aabb_max) * 0.5;
+                    let radius = ((aabb_max - aabb_min) * 0.5).max_element();
+                    vertices.push(center);
+                    // Like colors, radii are padded for the vertices before this one, which aren't spheres
+                    radii.resize(base as usize, 0.0);
+                    radii.push(radius);
+                    normals.push(Vec4::ZERO);
+                    tangents.push(Vec4::ZERO);
+                    uvs.push(Vec2::ZERO);
+                    indices.push(make_primitive(base, base, base, material_index, PrimitiveType::Sphere));
+                }
+                PrimitiveType::Quad => {
+                    if mesh_vertices.len() != 4 {
+                        return false;
+                    }
+                    // The corner furthest from the first one is opposite to it, the other two span the quad
+                    let a = mesh_vertices[0];
+                    let opposite = (1..4)
+                        .max_by(|&i, &j| a.distance_squared(mesh_vertices[i]).total_cmp(&a.distance_squared(mesh_vertices[j])))
+                        .unwrap();
+                    let mut others = (1..4).filter(|&i| i != opposite).map(|i| mesh_vertices[i]);
+                    let (mut b, mut c) = (others.next().unwrap(), others.next().unwrap());
+                    let size = (b - a).length().max((c - a).length());
+                    if (b + c - a).distance(mesh_vertices[opposite]) > 1e-3 * size {
+                        return false; // Not a parallelogram
+                    }
+                    if (b - a).cross(c - a).dot(mesh_normal) < 0.0 {
+                        std::mem::swap(&mut b, &mut c);
+                    }
+                    let normal = (b - a).cross(c - a).normalize();
+                    vertices.extend([a, b, c]);
+                    normals.extend([normal.extend(0.0); 3]);
+                    tangents.extend([(b - a).normalize().extend(0.0); 3]);
+                    uvs.extend([Vec2::ZERO, Vec2::X, Vec2::Y]);
+                    indices.push(make_primitive(base, base + 1, base + 2, material_index, PrimitiveType::Quad));
+                }
+                PrimitiveType::Triangle => return false,
+            }
+            true
+        }
 
         fn walk_node_graph(
             scene: &Scene,
             node: &Node,
             trs: Mat4,
-            vertices: &mut Vec<Vec4>,
+            coordinate_system: CoordinateSystem,
+            vertices: &mut Vec<Vec3>,
             indices: &mut Vec<UVec4>,
             normals: &mut Vec<Vec4>,
             tangents: &mut Vec<Vec4>,
-            uvs: &mut Vec<Vec2>
+            uvs: &mut Vec<Vec2>,
+            lightmap_uvs: &mut Vec<Vec2>,
+            colors: &mut Vec<Vec4>,
+            radii: &mut Vec<f32>
         ) {
             let node_trs = Mat4::from_cols_array_2d(&[
                 [node.transformation.a1, node.transformation.b1, node.transformation.c1, node.transformation.d1],
@@ -92,26 +710,54 @@ impl World {
                 [node.transformation.a4, node.transformation.b4, node.transformation.c4, node.transformation.d4],
             ]);
             let new_trs = trs * node_trs;
-            let (node_scale,node_quat,_) = new_trs.to_scale_rotation_translation();
+
+            // Tangents follow the surface, so they are transformed like positions, but normals need the inverse-transpose
+            // to stay perpendicular to it under non-uniform scale. Decomposing into scale and rotation isn't enough,
+            // since nested transforms can shear.
+            let tangent_matrix = Mat3::from_mat4(new_trs);
+            let normal_matrix = tangent_matrix.inverse().transpose();
+
+            // See `ANALYTIC_SPHERE_PREFIX`
+            let analytic_type = if node.name.starts_with(ANALYTIC_SPHERE_PREFIX) {
+                Some(PrimitiveType::Sphere)
+            } else if node.name.starts_with(ANALYTIC_QUAD_PREFIX) {
+                Some(PrimitiveType::Quad)
+            } else {
+                None
+            };
 
             for mesh_idx in node.meshes.iter() {
                 let mesh = &scene.meshes[*mesh_idx as usize];
+                if let Some(primitive_type) = analytic_type {
+                    let mesh_vertices = mesh.vertices.iter().map(|v| {
+                        coordinate_system.to_world(new_trs.transform_point3(Vec3::new(v.x, v.y, v.z)))
+                    }).collect::<Vec<_>>();
+                    let mesh_normal = mesh.normals.iter().fold(Vec3::ZERO, |acc, n| {
+                        acc + coordinate_system.to_world(normal_matrix * Vec3::new(n.x, n.y, n.z))
+                    }) * coordinate_system.normal_sign();
+                    if push_analytic_primitive(primitive_type, &mesh_vertices, mesh_normal, mesh.material_index, vertices, indices, normals, tangents, uvs, radii) {
+                        continue;
+                    }
+                    println!("Warning: Mesh on node {} can't be made into an analytic {:?}, keeping it as triangles", node.name, primitive_type);
+                }
+
                 let triangle_offset = vertices.len() as u32;
                 for v in &mesh.vertices {
-                    let vert = new_trs.mul_vec4(Vec4::new(v.x, v.y, v.z, 1.0));
-                    vertices.push(Vec4::new(vert.x, vert.z, vert.y, 1.0));
+                    let vert = new_trs.transform_point3(Vec3::new(v.x, v.y, v.z));
+                    vertices.push(coordinate_system.to_world(vert));
                 }
                 for f in &mesh.faces {
                     assert_eq!(f.0.len(), 3);
-                    indices.push(UVec4::new(triangle_offset + f.0[0], triangle_offset + f.0[2], triangle_offset + f.0[1], mesh.material_index));
+                    let (b, c) = if coordinate_system.flips_winding() { (f.0[2], f.0[1]) } else { (f.0[1], f.0[2]) };
+                    indices.push(UVec4::new(triangle_offset + f.0[0], triangle_offset + b, triangle_offset + c, mesh.material_index));
                 }
                 for n in &mesh.normals {
-                    let norm = (node_quat.mul_vec3(Vec3::new(n.x, n.y, n.z) / node_scale)).normalize();
-                    normals.push(Vec4::new(norm.x, norm.z, norm.y, 0.0));
+                    let norm = (normal_matrix * Vec3::new(n.x, n.y, n.z)).normalize() * coordinate_system.normal_sign();
+                    normals.push(coordinate_system.to_world(norm).extend(0.0));
                 }
                 for t in &mesh.tangents {
-                    let tan = (node_quat.mul_vec3(Vec3::new(t.x, t.y, t.z) / node_scale)).normalize();
-                    tangents.push(Vec4::new(tan.x, tan.z, tan.y, 0.0));
+                    let tan = (tangent_matrix * Vec3::new(t.x, t.y, t.z)).normalize();
+                    tangents.push(coordinate_system.to_world(tan).extend(0.0));
                 }
                 if let Some(Some(uv_set)) = mesh.texture_coords.first() {
                     for uv in uv_set {
@@ -120,31 +766,44 @@ impl World {
                 } else {
                     uvs.resize(vertices.len(), Vec2::ZERO);
                 }
+                // The second UV set is the lightmap unwrap, used for baking. Like colors, it is padded where it's missing.
+                lightmap_uvs.resize(triangle_offset as usize, Vec2::ZERO);
+                if let Some(Some(uv_set)) = mesh.texture_coords.get(1) {
+                    for uv in uv_set {
+                        lightmap_uvs.push(Vec2::new(uv.x, uv.y));
+                    }
+                }
+                lightmap_uvs.resize(vertices.len(), Vec2::ZERO);
+                // Vertex colors, such as glTF COLOR_0 or PLY red/green/blue. Meshes without them are white, so they
+                // leave the albedo as it is. Analytic primitives before this mesh are padded the same way.
+                colors.resize(triangle_offset as usize, Vec4::ONE);
+                if let Some(Some(color_set)) = mesh.colors.first() {
+                    for color in color_set {
+                        colors.push(Vec4::new(color.r, color.g, color.b, color.a));
+                    }
+                }
+                colors.resize(vertices.len(), Vec4::ONE);
             }
 
             for child in node.children.borrow().iter() {
-                walk_node_graph(scene, child, new_trs, vertices, indices, normals, tangents, uvs);
+                walk_node_graph(scene, child, new_trs, coordinate_system, vertices, indices, normals, tangents, uvs, lightmap_uvs, colors, radii);
             }
         }
 
         if let Some(root) = blend.root.as_ref() {
-            walk_node_graph(&blend, root, Mat4::IDENTITY, &mut vertices, &mut indices, &mut normals, &mut tangents, &mut uvs);
+            let root_trs = coordinate_system.to_file_space(transform);
+            walk_node_graph(&blend, root, root_trs, coordinate_system, &mut vertices, &mut indices, &mut normals, &mut tangents, &mut uvs, &mut lightmap_uvs, &mut colors, &mut radii);
         }
 
         // Gather material data
         let mut material_datas = vec![MaterialData::default(); blend.materials.len()];
 
+        let gltf_materials = load_gltf_materials(path);
         let mut textures = Vec::new();
         for (material_index, material) in blend.materials.iter().enumerate() {
             let current_material_data = &mut material_datas[material_index];
             if let Some(texture) = load_texture(material, TextureType::Diffuse) {
-                // Albedo data is stored in gamma space, but we atlas it with all the other textures
-                // which are stored in linear. Therefore, we convert here.
-                let mut texture = texture.into_rgb8();
-                for pixel in texture.iter_mut() {
-                    *pixel = ((*pixel as f32 / 255.0).powf(2.2) * 255.0) as u8;
-                }
-                textures.push(image::DynamicImage::ImageRgb8(texture));
+                textures.push(linearize_color_texture(texture));
                 current_material_data.set_has_albedo_texture(true);
             }
             if let Some(texture) = load_texture(material, TextureType::Metalness) {
@@ -159,12 +818,27 @@ impl World {
                 textures.push(texture);
                 current_material_data.set_has_normal_texture(true);
             }
+            // Assimp imports the glTF occlusion texture as a light map
+            if let Some(texture) = load_texture(material, TextureType::LightMap) {
+                textures.push(texture);
+                current_material_data.set_has_occlusion_texture(true);
+            }
+            if let Some(texture) = load_texture(material, TextureType::Emissive) {
+                textures.push(linearize_color_texture(texture));
+                current_material_data.set_has_emissive_texture(true);
+            }
+            if let Some(scale) = load_texture_float_array(material, "$tex.scale", TextureType::Normals) {
+                current_material_data.normal_scale = scale[0];
+            }
             if let Some(col) = load_float_array(material, "$clr.diffuse") {
                 current_material_data.albedo = Vec4::new(col[0], col[1], col[2], col[3]);
             }
             if let Some(col) = load_float_array(material, "$clr.emissive") {
-                // HACK: Multiply by 15 since assimp 5.2.5 doesn't support emissive strength :(
-                current_material_data.emissive = Vec4::new(col[0], col[1], col[2], col[3]) * 15.0;
+                let strength = gltf_materials
+                    .get(material_index)
+                    .and_then(|json| json["extensions"]["KHR_materials_emissive_strength"]["emissiveStrength"].as_f64())
+                    .map_or(DEFAULT_EMISSIVE_STRENGTH, |strength| strength as f32);
+                current_material_data.emissive = Vec4::new(col[0], col[1], col[2], strength);
             }
             if let Some(col) = load_float_array(material, "$mat.metallicFactor") {
                 current_material_data.metallic = Vec4::splat(col[0]);
@@ -172,9 +846,73 @@ impl World {
             if let Some(col) = load_float_array(material, "$mat.roughnessFactor") {
                 current_material_data.roughness = Vec4::splat(col[0]);
             }
+            if let Some(col) = load_float_array(material, "$mat.transmission.factor") {
+                current_material_data.transmission = Vec4::splat(col[0]);
+            }
+            if let Some(json) = gltf_materials.get(material_index) {
+                load_principled_parameters(current_material_data, json);
+                // No format has bounce budgets, so they are read from the extras of glTF materials, as `"max_bounces": n`
+                if let Some(max_bounces) = json["extras"]["max_bounces"].as_u64() {
+                    current_material_data.max_bounces = max_bounces.min(u32::MAX as u64) as u32;
+                }
+                // Likewise `"hidden_from_camera": true`, for lights that should light the scene without being seen
+                if let Some(hidden) = json["extras"]["hidden_from_camera"].as_bool() {
+                    current_material_data.set_hidden_from_camera(hidden);
+                }
+            }
+            // Like analytic primitives, shadow catchers are picked by name, since no format has a way to mark them
+            if load_string(material, "?mat.name").map_or(false, |name| name.starts_with("ShadowCatcher")) {
+                current_material_data.set_shadow_catcher(true);
+            }
+        }
+
+        Some(LoadedGeometry { vertices, indices, normals, tangents, uvs, lightmap_uvs, colors, radii, material_datas, textures })
+    }
+
+    // Load a built-in scene by name, or merge one or more scene files from disk. Built-in scenes are already in world
+    // space, and can't be merged with other files.
+    pub fn load(scene: &[SceneFile], coordinate_system: CoordinateSystem) -> Option<Self> {
+        if let [file] = scene {
+            if let Some(builtin) = crate::scenes::find_builtin_scene(&file.path) {
+                return Some((builtin.build)());
+            }
+        }
+
+        let mut geometry = LoadedGeometry::default();
+        for file in scene {
+            let Some(file_geometry) = Self::load_geometry(&file.path, coordinate_system, file.transform) else {
+                println!("Warning: Failed to load scene file {}", file.path);
+                return None;
+            };
+            geometry.append(file_geometry);
+        }
+        Some(geometry.into_world())
+    }
+
+    // Build the acceleration structures and light sampling data for loaded geometry. Textures are given in the order
+    // the materials reference them, and are packed into the atlas. Vertices without a color are white, vertices
+    // without lightmap UVs are at 0, 0, and vertices without a radius have none, which only spheres need.
+    pub fn from_geometry(
+        vertices: Vec<Vec3>,
+        mut indices: Vec<UVec4>,
+        normals: Vec<Vec4>,
+        tangents: Vec<Vec4>,
+        uvs: Vec<Vec2>,
+        lightmap_uvs: Vec<Vec2>,
+        colors: Vec<Vec4>,
+        radii: Vec<f32>,
+        mut material_datas: Vec<MaterialData>,
+        textures: Vec<DynamicImage>,
+    ) -> Self {
+        for (material_index, material_data) in material_datas.iter_mut().enumerate() {
+            if material_data.sanitize() {
+                println!("Warning: Material {} has invalid values, which were clamped to the valid range", material_index);
+            }
         }
 
-        let (atlas_raw, mut sts) = crate::atlas::pack_textures(&textures, 4096, 4096);
+        let atlas_format = AtlasFormat::for_textures(&textures);
+        let (atlas_raw, mut sts) = crate::atlas::pack_textures(&textures, ATLAS_SIZE, ATLAS_SIZE, atlas_format);
+        let atlas_raw = crate::atlas::build_mip_chain(&atlas_raw, ATLAS_MIP_LEVELS);
 
         for material_data in material_datas.iter_mut() {
             if material_data.has_albedo_texture() {
@@ -189,66 +927,190 @@ impl World {
             if material_data.has_normal_texture() {
                 material_data.normals = sts.remove(0);
             }
+            if material_data.has_occlusion_texture() {
+                material_data.occlusion = sts.remove(0);
+            }
+            if material_data.has_emissive_texture() {
+                material_data.emissive_texture = sts.remove(0);
+            }
         }
 
-        // BVH building
-        let now = std::time::Instant::now();
-        let bvh = BVHBuilder::new(&vertices, &mut indices).sah_samples(128).build();
-        #[cfg(debug_assertions)] println!("BVH build time: {:?}", now.elapsed());
-
-        // Build light pick table
-        let now = std::time::Instant::now();
-        let emissive_mask = light_pick::compute_emissive_mask(&indices, &material_datas);
-        let light_pick_table = light_pick::build_light_pick_table(&vertices, &indices, &emissive_mask, &material_datas);
-        #[cfg(debug_assertions)] println!("Light pick table build time: {:?}", now.elapsed());
-
         // Pack per-vertex data
         let mut per_vertex_data = Vec::new();
         for i in 0..vertices.len() {
             per_vertex_data.push(PerVertexData {
-                vertex: *vertices.get(i).unwrap_or(&Vec4::ZERO),
+                vertex: vertices[i],
+                radius: *radii.get(i).unwrap_or(&0.0),
                 normal: *normals.get(i).unwrap_or(&Vec4::ZERO),
                 tangent: *tangents.get(i).unwrap_or(&Vec4::ZERO),
                 uv0: *uvs.get(i).unwrap_or(&Vec2::ZERO),
+                uv1: *lightmap_uvs.get(i).unwrap_or(&Vec2::ZERO),
+                color: *colors.get(i).unwrap_or(&Vec4::ONE),
                 ..Default::default()
             });
         }
-        Some(Self {
+        deduplicate_vertices(&mut per_vertex_data, &mut indices);
+
+        let degenerate_count = remove_degenerate_triangles(&per_vertex_data, &mut indices);
+        if degenerate_count > 0 {
+            println!("Warning: Skipped {} degenerate triangles", degenerate_count);
+        }
+
+        let build_start = std::time::Instant::now();
+        let (bvh, light_pick_table, light_tree) = build_acceleration_structures(&per_vertex_data, &mut indices, &material_datas, 0.0);
+        let build_time = build_start.elapsed();
+
+        Self {
             bvh,
             per_vertex_buffer: per_vertex_data,
             index_buffer: indices,
             atlas: atlas_raw,
             material_data_buffer: material_datas,
             light_pick_buffer: light_pick_table,
-        })
+            light_tree_buffer: light_tree,
+            build_time,
+        }
+    }
+
+    // Rebuild the BVH with spatial splits, see `BVHBuilder::spatial_splits`. This takes longer to build, but traces
+    // faster in scenes with large or long thin triangles, such as terrain and ground planes.
+    pub fn rebuild_with_spatial_splits(&mut self) {
+        let build_start = std::time::Instant::now();
+        let (bvh, light_pick_table, light_tree) = build_acceleration_structures(
+            &self.per_vertex_buffer,
+            &mut self.index_buffer,
+            &self.material_data_buffer,
+            SPATIAL_SPLIT_BUDGET,
+        );
+        self.bvh = bvh;
+        self.light_pick_buffer = light_pick_table;
+        self.light_tree_buffer = light_tree;
+        self.build_time += build_start.elapsed();
+    }
+
+    // Fraction of the triangles seen by the camera whose shading normals face away from it, from a coarse grid of
+    // primary rays, or None if the camera sees no triangles. Surfaces are seen from the side their normals are on,
+    // even from inside a room, so most of them facing away means the scene was probably imported inside out.
+    pub fn inward_facing_fraction(&self, config: &TracingConfig) -> Option<f32> {
+        const GRID_SIZE: u32 = 16;
+        let bvh = BVHReference {
+            nodes: &self.bvh.nodes,
+            t_min: config.ray_t_min,
+            t_max: config.ray_t_max,
+        };
+        let mut seen = 0;
+        let mut inward = 0;
+        for y in 0..GRID_SIZE {
+            for x in 0..GRID_SIZE {
+                let pixel = (Vec2::new(x as f32, y as f32) + 0.5) / GRID_SIZE as f32 * Vec2::new(config.width as f32, config.height as f32);
+                let direction = crate::reproject::primary_ray_direction(config, pixel);
+                let result = bvh.intersect_nearest(&self.per_vertex_buffer, &self.index_buffer, config.cam_position.xyz(), direction);
+                if !result.hit || PrimitiveType::of(result.triangle) != PrimitiveType::Triangle {
+                    continue; // Spheres and quads are exact, so they can't be inside out
+                }
+                let normal = [result.triangle.x, result.triangle.y, result.triangle.z]
+                    .iter()
+                    .fold(Vec3::ZERO, |acc, &index| acc + self.per_vertex_buffer[index as usize].normal.xyz());
+                seen += 1;
+                if normal.dot(direction) > 0.0 {
+                    inward += 1;
+                }
+            }
+        }
+        (seen > 0).then(|| inward as f32 / seen as f32)
+    }
+
+    // Bounds of the whole scene, from the root of the BVH, or None if it is empty
+    pub fn bounds(&self) -> Option<(Vec3, Vec3)> {
+        let root = self.bvh.nodes.first()?;
+        let (min, max) = (root.aabb_min(), root.aabb_max());
+        (min.is_finite() && max.is_finite()).then_some((min, max))
+    }
+
+    // Move the camera back along its view direction until the bounding sphere of the scene fits in view, with `margin`
+    // as a fraction of its radius to spare. Fitting the sphere rather than the box keeps it independent of rotation.
+    pub fn frame_all(&self, config: &mut TracingConfig, margin: f32) -> bool {
+        let Some((min, max)) = self.bounds() else {
+            return false;
+        };
+        let center = (min + max) * 0.5;
+        let radius = ((max - min).length() * 0.5).max(1e-3) * (1.0 + margin);
+
+        // The narrower of the horizontal and vertical field of view decides, see `primary_ray_direction`
+        let aspect = config.height as f32 / (config.width as f32 * config.pixel_aspect);
+        let half_fov = ((config.fov * 0.5).tan() * aspect.min(1.0)).atan();
+        let distance = radius / half_fov.sin();
+        let forward = crate::reproject::primary_ray_direction(config, Vec2::new(config.width as f32, config.height as f32) * 0.5);
+        config.cam_position = (center - forward * distance).extend(0.0);
+        config.ray_t_max = config.ray_t_max.max((distance + radius) * 2.0);
+        true
+    }
+
+    // Whether any geometry emits light, which next event estimation needs to have anything to sample. Without any,
+    // the light pick table only holds a sentinel.
+    pub fn has_emitters(&self) -> bool {
+        !self.light_pick_buffer[0].is_sentinel()
+    }
+
+    // Number of emissive primitives, which the light pick table has an entry for each of
+    pub fn light_count(&self) -> usize {
+        if self.has_emitters() { self.light_pick_buffer.len() } else { 0 }
+    }
+
+    // The index buffer packed with 16 bit indices, if the scene is small enough, see `CompactPrimitive`
+    pub fn compact_index_buffer(&self) -> Option<Vec<CompactPrimitive>> {
+        if !CompactPrimitive::fits(self.per_vertex_buffer.len(), self.material_data_buffer.len()) {
+            return None;
+        }
+        Some(self.index_buffer.iter().map(|primitive| CompactPrimitive::pack(*primitive)).collect())
     }
 
     pub fn into_gpu<'fw>(self) -> GpuWorld<'fw> {
         GpuWorld {
-            per_vertex_buffer: GpuBuffer::from_slice(&FW, &self.per_vertex_buffer),
-            index_buffer: GpuBuffer::from_slice(&FW, &self.index_buffer),
+            per_vertex_buffer: non_empty_buffer(&self.per_vertex_buffer),
+            index_buffer: match self.compact_index_buffer() {
+                Some(compact) => GpuIndexBuffer::Compact(non_empty_buffer(&compact)),
+                None => GpuIndexBuffer::Wide(non_empty_buffer(&self.index_buffer)),
+            },
             bvh: self.bvh.into_gpu(),
-            atlas: GpuConstImage::from_bytes(&FW, &self.atlas.to_rgba8(), 4096, 4096),
-            material_data_buffer: GpuBuffer::from_slice(&FW, &self.material_data_buffer),
+            atlas: if is_hdr_image(&self.atlas) {
+                GpuAtlas::Hdr(dynamic_image_to_gpu_image(self.atlas))
+            } else {
+                GpuAtlas::Ldr(dynamic_image_to_gpu_image(self.atlas))
+            },
+            material_data_buffer: non_empty_buffer(&self.material_data_buffer),
             light_pick_buffer: GpuBuffer::from_slice(&FW, &self.light_pick_buffer),
+            light_tree_buffer: GpuBuffer::from_slice(&FW, &self.light_tree_buffer),
         }
     }
 }
 
+// wgpu doesn't allow 0-sized buffers. A scene without geometry uploads a single default element instead, which is
+// never read, since its BVH has no primitives.
+fn non_empty_buffer<'fw, T: bytemuck::Pod + Default>(data: &[T]) -> GpuBuffer<'fw, T> {
+    if data.is_empty() {
+        GpuBuffer::from_slice(&FW, &[T::default()])
+    } else {
+        GpuBuffer::from_slice(&FW, data)
+    }
+}
+
+// Image crate does not by default decode .hdr images as HDR for some reason
+fn decode_hdr(reader: impl std::io::BufRead) -> Option<DynamicImage> {
+    let hdr_decoder = image::codecs::hdr::HdrDecoder::new(reader).ok()?;
+    let width = hdr_decoder.metadata().width;
+    let height = hdr_decoder.metadata().height;
+    let buffer = hdr_decoder.read_image_hdr().ok()?;
+    Some(DynamicImage::ImageRgb32F(image::ImageBuffer::from_vec(
+        width,
+        height,
+        buffer.into_iter().flat_map(|c| vec![c[0], c[1], c[2]]).collect(),
+    )?))
+}
+
 pub fn load_dynamic_image(path: &str) -> Option<DynamicImage> {
-    // Image crate does not by default decode .hdr images as HDR for some reason
     if path.ends_with(".hdr") {
-        let hdr_decoder = image::codecs::hdr::HdrDecoder::new(std::io::BufReader::new(
-            std::fs::File::open(&path).unwrap(),
-        )).ok()?;
-        let width = hdr_decoder.metadata().width;
-        let height = hdr_decoder.metadata().height;
-        let buffer = hdr_decoder.read_image_hdr().ok()?;
-        return Some(DynamicImage::ImageRgb32F(image::ImageBuffer::from_vec(
-            width,
-            height,
-            buffer.into_iter().flat_map(|c| vec![c[0], c[1], c[2]]).collect(),
-        )?));
+        return decode_hdr(std::io::BufReader::new(std::fs::File::open(&path).unwrap()));
     }
 
     image::io::Reader::open(path).ok()?.decode().ok()
@@ -266,8 +1128,8 @@ pub fn dynamic_image_to_gpu_image<'fw, P: PixelInfo>(img: DynamicImage) -> GpuCo
 pub fn dynamic_image_to_cpu_buffer<'img>(img: DynamicImage) -> Vec<Vec4> {
     let width = img.width();
     let height = img.height();
-    let data = img.into_rgb8();
-    let cpu_data: Vec<Vec4> = data.chunks(3).map(|f| Vec4::new(f[0] as f32, f[1] as f32, f[2] as f32, 255.0) / 255.0).collect();
+    let data = img.into_rgb32f(); // keep HDR data intact, LDR data is normalized to [0; 1]
+    let cpu_data: Vec<Vec4> = data.chunks(3).map(|f| Vec4::new(f[0], f[1], f[2], 1.0)).collect();
     assert_eq!(cpu_data.len(), width as usize * height as usize);
     cpu_data
 }
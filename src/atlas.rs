@@ -4,6 +4,29 @@ use glam::Vec4;
 use image::{DynamicImage, GenericImage};
 use fast_image_resize as fr;
 
+// Storage format of the texture atlas. With its mip chain, the atlas is 6144x4096, which costs 96 MiB as RGBA8,
+// but 384 MiB as RGBA32F, so we only use the latter when some texture needs it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AtlasFormat {
+    Rgba8,
+    Rgba32Float,
+}
+
+impl AtlasFormat {
+    // Pick the cheapest format that doesn't lose precision for any of the given textures.
+    pub fn for_textures(textures: &[DynamicImage]) -> Self {
+        if textures.iter().any(is_hdr_image) {
+            AtlasFormat::Rgba32Float
+        } else {
+            AtlasFormat::Rgba8
+        }
+    }
+}
+
+pub fn is_hdr_image(image: &DynamicImage) -> bool {
+    matches!(image, DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_))
+}
+
 #[derive(Clone, Copy)]
 pub struct PackingRect {
     pub x: u32,
@@ -23,7 +46,16 @@ impl PackingRect {
     }
 }
 
-pub fn pack_textures(textures: &[DynamicImage], atlas_width: u32, atlas_height: u32) -> (DynamicImage, Vec<Vec4>) {
+// DynamicImage copies by way of 8 bit pixels, so copy float data between the underlying buffers
+fn copy_image(dst: &mut DynamicImage, src: &DynamicImage, x: u32, y: u32) {
+    let copy_result = match (dst, src) {
+        (DynamicImage::ImageRgba32F(dst), DynamicImage::ImageRgba32F(src)) => dst.copy_from(src, x, y),
+        (dst, src) => dst.copy_from(src, x, y),
+    };
+    copy_result.expect("Texture didn't fit in atlas.");
+}
+
+pub fn pack_textures(textures: &[DynamicImage], atlas_width: u32, atlas_height: u32, format: AtlasFormat) -> (DynamicImage, Vec<Vec4>) {
     let root = PackingRect {
         x: 0,
         y: 0,
@@ -69,20 +101,32 @@ pub fn pack_textures(textures: &[DynamicImage], atlas_width: u32, atlas_height:
     leafs.truncate(textures.len());
 
     let mut resizer = fr::Resizer::new(fr::ResizeAlg::Convolution(fr::FilterType::Lanczos3));
-    let mut atlas = DynamicImage::new_rgba8(atlas_width, atlas_height);
+    let mut atlas = match format {
+        AtlasFormat::Rgba8 => DynamicImage::new_rgba8(atlas_width, atlas_height),
+        AtlasFormat::Rgba32Float => DynamicImage::new_rgba32f(atlas_width, atlas_height),
+    };
     for (i, leaf) in leafs.iter().enumerate() {
         let tex = &textures[i];
-        let width = NonZeroU32::new(tex.width()).unwrap();
-        let height = NonZeroU32::new(tex.height()).unwrap();
+        let resized_tex = match format {
+            AtlasFormat::Rgba8 => {
+                let width = NonZeroU32::new(tex.width()).unwrap();
+                let height = NonZeroU32::new(tex.height()).unwrap();
 
-        let desired_width = NonZeroU32::new(leaf.width).unwrap();
-        let desired_height = NonZeroU32::new(leaf.height).unwrap();
-        let fr_img_src = fr::Image::from_vec_u8(width, height, tex.to_rgba8().into_raw(), fr::PixelType::U8x4).unwrap();
-        let mut fr_img_dst = fr::Image::new(desired_width, desired_height, fr::PixelType::U8x4);
-        resizer.resize(&fr_img_src.view(), &mut fr_img_dst.view_mut()).unwrap();
+                let desired_width = NonZeroU32::new(leaf.width).unwrap();
+                let desired_height = NonZeroU32::new(leaf.height).unwrap();
+                let fr_img_src = fr::Image::from_vec_u8(width, height, tex.to_rgba8().into_raw(), fr::PixelType::U8x4).unwrap();
+                let mut fr_img_dst = fr::Image::new(desired_width, desired_height, fr::PixelType::U8x4);
+                resizer.resize(&fr_img_src.view(), &mut fr_img_dst.view_mut()).unwrap();
 
-        let resized_tex = DynamicImage::ImageRgba8(image::RgbaImage::from_raw(desired_width.get(), desired_height.get(), fr_img_dst.into_vec()).unwrap());
-        atlas.copy_from(&resized_tex.flipv(), leaf.x, leaf.y).unwrap();
+                DynamicImage::ImageRgba8(image::RgbaImage::from_raw(desired_width.get(), desired_height.get(), fr_img_dst.into_vec()).unwrap())
+            }
+            AtlasFormat::Rgba32Float => {
+                // fast_image_resize has no 4 channel float pixel type, so use the slower resize from image for these
+                let resized = image::imageops::resize(&tex.to_rgba32f(), leaf.width, leaf.height, image::imageops::FilterType::Lanczos3);
+                DynamicImage::ImageRgba32F(resized)
+            }
+        };
+        copy_image(&mut atlas, &resized_tex.flipv(), leaf.x, leaf.y);
     }
 
     let sts = leafs.iter().map(|x| x.to_uvst(atlas_width, atlas_height)).collect::<Vec<_>>();
@@ -90,3 +134,35 @@ pub fn pack_textures(textures: &[DynamicImage], atlas_width: u32, atlas_height:
 }
 
 
+
+// Builds an image containing the atlas along with its mip chain. The base level is kept as-is on the left,
+// the remaining levels are stacked on top of each other in a strip to the right, making the image 1.5x as wide:
+// ```
+// +---------+----+
+// |         | 1  |
+// |    0    +--+-+
+// |         |2 |
+// +---------+--+
+// ```
+// Since atlas entries are power-of-two sized and aligned, each level is a valid atlas for the same
+// UV rectangles, which the kernel remaps to the right strip position.
+pub fn build_mip_chain(atlas: &DynamicImage, levels: u32) -> DynamicImage {
+    let size = atlas.width();
+    let mut chain = if is_hdr_image(atlas) {
+        DynamicImage::new_rgba32f(size + size / 2, size)
+    } else {
+        DynamicImage::new_rgba8(size + size / 2, size)
+    };
+    copy_image(&mut chain, atlas, 0, 0);
+
+    let mut prev = atlas.clone();
+    let mut y = 0;
+    for level in 1..levels {
+        let level_size = size >> level;
+        let next = prev.resize_exact(level_size, level_size, image::imageops::FilterType::Triangle);
+        copy_image(&mut chain, &next, size, y);
+        y += level_size;
+        prev = next;
+    }
+    chain
+}
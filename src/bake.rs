@@ -0,0 +1,186 @@
+// Lightmap baking. Rather than starting at the camera, the path of each lightmap texel starts at the point of the
+// surface the texel covers in the lightmap UVs, heading straight into the surface. The bounces are traced the same way
+// as for the camera, so each texel holds the light leaving its point along the normal, which for diffuse surfaces is
+// what the camera sees there from any direction.
+
+use glam::{UVec2, Vec2, Vec3, Vec4Swizzles};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::prelude::*;
+use shared_structs::{CpuImage, PerVertexData, PrimitiveType, TracingConfig};
+
+use crate::asset::{World, dynamic_image_to_cpu_buffer, fallback_cpu_buffer, load_dynamic_image};
+
+pub const DEFAULT_BAKE_RESOLUTION: u32 = 1024;
+pub const DEFAULT_BAKE_SAMPLES: u32 = 256;
+
+// Texels outside every triangle are filled from their neighbours this many texels out, so filtering along the edges
+// of UV islands doesn't blend in black
+const DILATION_TEXELS: u32 = 2;
+
+// The point of a surface at the center of a lightmap texel
+#[derive(Copy, Clone, Debug)]
+pub struct LightmapTexel {
+    pub position: Vec3,
+    pub normal: Vec3,
+}
+
+pub struct Lightmap {
+    pub resolution: u32,
+    pub rgb: Vec<f32>,
+    pub covered: Vec<bool>, // Whether each texel is on a surface, rather than dilated or empty
+}
+
+// Meshes without a second UV set are baked into their first one, which works as long as it doesn't overlap itself
+pub fn has_lightmap_uvs(world: &World) -> bool {
+    world.per_vertex_buffer.iter().any(|data| data.uv1 != Vec2::ZERO)
+}
+
+// Position, normal and lightmap UV of a corner
+type Corner = (Vec3, Vec3, Vec2);
+
+// The surface point of each texel of a square lightmap, in rows from v = 0, or None where no primitive covers it.
+// Where primitives overlap in UV space, the last one wins. Spheres have no lightmap UVs, so they are left out.
+pub fn lightmap_texels(world: &World, resolution: u32) -> Vec<Option<LightmapTexel>> {
+    let use_lightmap_uvs = has_lightmap_uvs(world);
+    let corner = |index: u32| {
+        let data: &PerVertexData = &world.per_vertex_buffer[index as usize];
+        (data.vertex, data.normal.xyz(), if use_lightmap_uvs { data.uv1 } else { data.uv0 })
+    };
+
+    let mut texels = vec![None; (resolution * resolution) as usize];
+    for &primitive in world.index_buffer.iter() {
+        let (a, b, c) = (corner(primitive.x), corner(primitive.y), corner(primitive.z));
+        match PrimitiveType::of(primitive) {
+            PrimitiveType::Triangle => rasterize_triangle([a, b, c], resolution, &mut texels),
+            PrimitiveType::Quad => {
+                // The fourth corner of the parallelogram, extrapolated like the kernel does for the far half
+                let d = (b.0 + c.0 - a.0, b.1 + c.1 - a.1, b.2 + c.2 - a.2);
+                rasterize_triangle([a, b, c], resolution, &mut texels);
+                rasterize_triangle([b, d, c], resolution, &mut texels);
+            }
+            PrimitiveType::Sphere => {}
+        }
+    }
+    texels
+}
+
+fn rasterize_triangle(corners: [Corner; 3], resolution: u32, texels: &mut [Option<LightmapTexel>]) {
+    let [(pa, na, ta), (pb, nb, tb), (pc, nc, tc)] = corners;
+    let [ta, tb, tc] = [ta, tb, tc].map(|uv| uv * resolution as f32);
+    let area = (tb - ta).perp_dot(tc - ta);
+    if area == 0.0 {
+        return;
+    }
+    let geometric_normal = (pb - pa).cross(pc - pa).normalize_or_zero();
+
+    let min = ta.min(tb).min(tc).floor().max(Vec2::ZERO);
+    let max = ta.max(tb).max(tc).ceil().min(Vec2::splat(resolution as f32));
+    for y in min.y as u32..max.y as u32 {
+        for x in min.x as u32..max.x as u32 {
+            // Barycentrics from the areas of the triangles the texel center makes with each edge, which have the same
+            // sign as the whole triangle when it is inside, whichever way it is wound
+            let p = Vec2::new(x as f32, y as f32) + 0.5;
+            let wa = (tc - tb).perp_dot(p - tb) / area;
+            let wb = (ta - tc).perp_dot(p - tc) / area;
+            let wc = 1.0 - wa - wb;
+            if wa < 0.0 || wb < 0.0 || wc < 0.0 {
+                continue;
+            }
+            let normal = (na * wa + nb * wb + nc * wc).normalize_or_zero();
+            texels[(y * resolution + x) as usize] = Some(LightmapTexel {
+                position: pa * wa + pb * wb + pc * wc,
+                normal: if normal == Vec3::ZERO { geometric_normal } else { normal },
+            });
+        }
+    }
+}
+
+// Trace `samples` paths from each texel on the CPU. The config decides everything but the camera, such as the
+// bounce counts, next event estimation and the background.
+pub fn bake_lightmap(world: &World, skybox_path: Option<&str>, config: &TracingConfig, resolution: u32, samples: u32) -> Lightmap {
+    if !has_lightmap_uvs(world) {
+        println!("Warning: The scene has no lightmap UVs, so the lightmap is baked into its first UV set");
+    }
+    let texels = lightmap_texels(world, resolution);
+
+    let mut skybox_buffer = fallback_cpu_buffer();
+    let mut skybox_size = (2, 2);
+    if let Some(skybox_source) = skybox_path.and_then(load_dynamic_image) {
+        skybox_size = (skybox_source.width(), skybox_source.height());
+        skybox_buffer = dynamic_image_to_cpu_buffer(skybox_source);
+    }
+    let skybox = CpuImage::new(&skybox_buffer, skybox_size.0, skybox_size.1);
+    let atlas_buffer = dynamic_image_to_cpu_buffer(world.atlas.clone());
+    let atlas = CpuImage::new(&atlas_buffer, world.atlas.width(), world.atlas.height());
+
+    // Texture filtering starts out with the footprint of a texel rather than a pixel
+    let config = crate::trace::dispatch_config(TracingConfig { width: resolution, height: resolution, ..*config }, world.has_emitters(), skybox_size);
+
+    let mut seed_rng = StdRng::seed_from_u64(0);
+    let seeds = texels.iter().map(|_| seed_rng.gen::<u32>()).collect::<Vec<_>>();
+    let mut rgb = vec![0.0; texels.len() * 3];
+    rgb.par_chunks_mut(3).zip(texels.par_iter()).zip(seeds.par_iter()).for_each(|((color, texel), seed)| {
+        let Some(texel) = texel else {
+            return;
+        };
+        // Start just far enough above the surface that the ray hits it past `ray_t_min`
+        let offset = (config.ray_t_min * 4.0).max(texel.position.abs().max_element() * 1e-5);
+        let mut rng = UVec2::new(0, *seed);
+        let mut sum = Vec3::ZERO;
+        for _ in 0..samples {
+            let (radiance, _, _, _, next_rng) = kernels::trace_ray(
+                &config,
+                rng,
+                texel.position + texel.normal * offset,
+                -texel.normal,
+                &world.per_vertex_buffer,
+                &world.index_buffer,
+                &world.bvh.nodes,
+                &world.material_data_buffer,
+                &world.light_pick_buffer,
+                &world.light_tree_buffer,
+                &shared_structs::Sampler,
+                &atlas,
+                &skybox,
+                &mut kernels::stats::NoBounceStats,
+            );
+            sum += radiance.xyz();
+            rng = next_rng;
+        }
+        color.copy_from_slice(&(sum / samples.max(1) as f32).to_array());
+    });
+
+    let covered = texels.iter().map(Option::is_some).collect::<Vec<_>>();
+    dilate(&mut rgb, &covered, resolution, DILATION_TEXELS);
+    Lightmap { resolution, rgb, covered }
+}
+
+// Fill empty texels with the average of their filled neighbours, growing the filled area by a texel each pass
+fn dilate(rgb: &mut [f32], covered: &[bool], resolution: u32, passes: u32) {
+    let size = resolution as i32;
+    let mut filled = covered.to_vec();
+    for _ in 0..passes {
+        let (previous_rgb, previous_filled) = (rgb.to_vec(), filled.clone());
+        for y in 0..size {
+            for x in 0..size {
+                let index = (y * size + x) as usize;
+                if previous_filled[index] {
+                    continue;
+                }
+                let mut sum = Vec3::ZERO;
+                let mut count = 0;
+                for (nx, ny) in [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)] {
+                    let neighbour = (ny * size + nx) as usize;
+                    if nx >= 0 && ny >= 0 && nx < size && ny < size && previous_filled[neighbour] {
+                        sum += Vec3::from_slice(&previous_rgb[neighbour * 3..neighbour * 3 + 3]);
+                        count += 1;
+                    }
+                }
+                if count > 0 {
+                    rgb[index * 3..index * 3 + 3].copy_from_slice(&(sum / count as f32).to_array());
+                    filled[index] = true;
+                }
+            }
+        }
+    }
+}
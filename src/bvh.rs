@@ -1,8 +1,8 @@
-use glam::{UVec4, Vec3, Vec4, Vec4Swizzles};
+use glam::{UVec4, Vec3};
 use gpgpu::{GpuBuffer, BufOps};
-use shared_structs::{BVHNode};
+use shared_structs::{BVHNode, PerVertexData};
 
-use crate::trace::FW;
+use crate::{trace::FW, primitive::{primitive_bounds, primitive_centroid, clipped_primitive_bounds}};
 
 // TODO: Use triangle buffer directly instead of 2 indirections
 
@@ -37,6 +37,14 @@ pub struct BVH {
 }
 
 impl BVH {
+    // A BVH no ray can hit, for scenes without geometry. Traversal treats a node without primitives as an inner node,
+    // so the root points at two children with inverted bounds, which every ray misses.
+    pub fn empty() -> Self {
+        let mut root = BVHNode::default();
+        root.set_left_node_index(1);
+        Self { nodes: vec![root, BVHNode::default(), BVHNode::default()] }
+    }
+
     pub fn into_gpu<'fw>(self) -> GpuBVH<'fw> {
         let nodes_buffer = GpuBuffer::from_slice(&FW, &self.nodes);
         GpuBVH { nodes_buffer }
@@ -47,29 +55,68 @@ pub struct GpuBVH<'fw> {
     pub nodes_buffer: GpuBuffer<'fw, BVHNode>,
 }
 
+// Spatial splits, as in the SBVH (https://www.nvidia.com/docs/IO/77714/sbvh.pdf), split the primitives themselves
+// rather than only sorting them into children. A long thin triangle spanning a ground plane gets a reference in each
+// child it crosses, each with bounds clipped to that child, instead of one box overlapping everything around it.
+// Duplicated references are stored as copies of the primitive in the index buffer, so the node layout is unchanged.
+const SPATIAL_SPLIT_BINS: usize = 32;
+// Spatial splits are only tried where the children of the best object split overlap by more than this fraction of
+// the surface area of the root, since they mostly help where boxes overlap
+const SPATIAL_SPLIT_MIN_OVERLAP: f32 = 1e-5;
+
+// A primitive, or the part of it inside a node when building with spatial splits
+#[derive(Clone, Copy)]
+struct Reference {
+    primitive: u32,
+    aabb: BVHNode,
+}
+
+impl Reference {
+    fn bounds(&self) -> (Vec3, Vec3) {
+        (self.aabb.aabb_min(), self.aabb.aabb_max())
+    }
+
+    fn centroid(&self) -> Vec3 {
+        (self.aabb.aabb_min() + self.aabb.aabb_max()) * 0.5
+    }
+}
+
+fn references_aabb(references: &[Reference]) -> BVHNode {
+    let mut aabb = BVHNode::default();
+    for reference in references {
+        aabb.encapsulate_node(&reference.aabb);
+    }
+    aabb
+}
+
+#[derive(Clone, Copy)]
+enum Split {
+    Object { axis: usize, position: f32 },
+    Spatial { axis: usize, position: f32 },
+}
+
 pub struct BVHBuilder<'a> {
     sah_samples: usize,
-    vertices: &'a [Vec4],
-    indices: &'a mut [UVec4],
+    spatial_split_budget: f32,
+    keep_whole: Vec<bool>,
+    vertices: &'a [PerVertexData],
+    indices: &'a mut Vec<UVec4>,
     centroids: Vec<Vec3>,
     nodes: Vec<BVHNode>,
 }
 
 impl<'a> BVHBuilder<'a> {
-    pub fn new(vertices: &'a [Vec4], indices: &'a mut [UVec4]) -> Self {
+    pub fn new(vertices: &'a [PerVertexData], indices: &'a mut Vec<UVec4>) -> Self {
         let centroids = indices
             .iter()
-            .map(|ind| {
-                let v0 = vertices[ind.x as usize].xyz();
-                let v1 = vertices[ind.y as usize].xyz();
-                let v2 = vertices[ind.z as usize].xyz();
-                (v0 + v1 + v2) / 3.0
-            })
+            .map(|ind| primitive_centroid(vertices, *ind))
             .collect::<Vec<_>>();
-        let nodes = vec![BVHNode::default(); indices.len() * 2 - 1];
+        let nodes = vec![BVHNode::default(); (indices.len() * 2).saturating_sub(1)];
 
         Self {
             sah_samples: 128,
+            spatial_split_budget: 0.0,
+            keep_whole: Vec::new(),
             vertices,
             indices,
             centroids,
@@ -82,6 +129,20 @@ impl<'a> BVHBuilder<'a> {
         self
     }
 
+    // Allow spatial splits, adding up to `budget` times as many extra references as there are primitives. The index
+    // buffer grows by the duplicated references. 0 disables them, giving a regular SAH BVH.
+    pub fn spatial_splits(mut self, budget: f32) -> Self {
+        self.spatial_split_budget = budget;
+        self
+    }
+
+    // Primitives which must be referenced exactly once, such as lights, whose sampling relies on their index in the
+    // index buffer. Indexed like the primitives passed to `new`.
+    pub fn keep_whole(mut self, keep_whole: Vec<bool>) -> Self {
+        self.keep_whole = keep_whole;
+        self
+    }
+
     fn update_node_aabb(&mut self, node_idx: usize) {
         let node = &mut self.nodes[node_idx];
         let mut aabb_min = Vec3::splat(f32::INFINITY);
@@ -89,13 +150,9 @@ impl<'a> BVHBuilder<'a> {
 
         for i in 0..node.triangle_count() {
             let triangle_index = (node.first_triangle_index() + i) as usize;
-            let index = self.indices[triangle_index];
-            let v0 = self.vertices[index.x as usize].xyz();
-            let v1 = self.vertices[index.y as usize].xyz();
-            let v2 = self.vertices[index.z as usize].xyz();
-
-            aabb_min = aabb_min.min(v0.min(v1).min(v2));
-            aabb_max = aabb_max.max(v0.max(v1).max(v2));
+            let (primitive_min, primitive_max) = primitive_bounds(self.vertices, self.indices[triangle_index]);
+            aabb_min = aabb_min.min(primitive_min);
+            aabb_max = aabb_max.max(primitive_max);
         }
 
         node.set_aabb_min(&aabb_min);
@@ -110,21 +167,16 @@ impl<'a> BVHBuilder<'a> {
     
         for i in 0..node.triangle_count() {
             let triangle_index = (node.left_node_index() + i) as usize;
-            let index = self.indices[triangle_index];
-            let v0 = self.vertices[index.x as usize].xyz();
-            let v1 = self.vertices[index.y as usize].xyz();
-            let v2 = self.vertices[index.z as usize].xyz();
+            let (primitive_min, primitive_max) = primitive_bounds(self.vertices, self.indices[triangle_index]);
             let centroid = self.centroids[triangle_index];
     
             if centroid[axis] < split {
-                left_box.encapsulate(&v0);
-                left_box.encapsulate(&v1);
-                left_box.encapsulate(&v2);
+                left_box.encapsulate(&primitive_min);
+                left_box.encapsulate(&primitive_max);
                 left_tri_count += 1;
             } else {
-                right_box.encapsulate(&v0);
-                right_box.encapsulate(&v1);
-                right_box.encapsulate(&v2);
+                right_box.encapsulate(&primitive_min);
+                right_box.encapsulate(&primitive_max);
                 right_tri_count += 1;
             }
         }
@@ -206,14 +258,10 @@ impl<'a> BVHBuilder<'a> {
             let scale = self.sah_samples as f32 / (bounds_max - bounds_min);
             for i in 0..node.triangle_count() {
                 let triangle_index = (node.first_triangle_index() + i) as usize;
-                let index = self.indices[triangle_index];
-                let v0 = self.vertices[index.x as usize].xyz();
-                let v1 = self.vertices[index.y as usize].xyz();
-                let v2 = self.vertices[index.z as usize].xyz();
+                let (primitive_min, primitive_max) = primitive_bounds(self.vertices, self.indices[triangle_index]);
                 let segment_index = (((self.centroids[triangle_index][axis] - bounds_min) * scale) as usize).min(self.sah_samples - 1);
-                segments[segment_index].aabb.encapsulate(&v0);
-                segments[segment_index].aabb.encapsulate(&v1);
-                segments[segment_index].aabb.encapsulate(&v2);
+                segments[segment_index].aabb.encapsulate(&primitive_min);
+                segments[segment_index].aabb.encapsulate(&primitive_max);
                 segments[segment_index].triangle_count += 1;
             }
 
@@ -255,6 +303,13 @@ impl<'a> BVHBuilder<'a> {
     }
 
     pub fn build(&mut self) -> BVH {
+        if self.indices.is_empty() {
+            return BVH::empty();
+        }
+        if self.spatial_split_budget > 0.0 {
+            return self.build_with_spatial_splits();
+        }
+
         let mut node_count = 1;
 
         let root = &mut self.nodes[0];
@@ -322,4 +377,234 @@ impl<'a> BVHBuilder<'a> {
             nodes: self.nodes.clone(),
         }
     }
-}
\ No newline at end of file
+
+    fn is_kept_whole(&self, reference: &Reference) -> bool {
+        self.keep_whole.get(reference.primitive as usize).copied().unwrap_or(false)
+    }
+
+    // Binned SAH over the centroids of the references, like `find_best_split_segmented`. Returns the axis, position
+    // and cost of the best split, or None if the centroids all coincide.
+    fn find_object_split(&self, references: &[Reference]) -> Option<(usize, f32, f32)> {
+        let mut best: Option<(usize, f32, f32)> = None;
+        for axis in 0..3 {
+            let bounds_min = references.iter().map(|r| r.centroid()[axis]).fold(f32::INFINITY, f32::min);
+            let bounds_max = references.iter().map(|r| r.centroid()[axis]).fold(f32::NEG_INFINITY, f32::max);
+            if bounds_min == bounds_max {
+                continue;
+            }
+
+            let mut segments = vec![(BVHNode::default(), 0u32); self.sah_samples];
+            let scale = self.sah_samples as f32 / (bounds_max - bounds_min);
+            for reference in references {
+                let segment_index = (((reference.centroid()[axis] - bounds_min) * scale) as usize).min(self.sah_samples - 1);
+                segments[segment_index].0.encapsulate_node(&reference.aabb);
+                segments[segment_index].1 += 1;
+            }
+
+            // Sweep from the right to get the cost of everything past each plane, then from the left to evaluate them
+            let mut right_costs = vec![0.0; self.sah_samples];
+            let mut right_box = BVHNode::default();
+            let mut right_count = 0;
+            for i in (1..self.sah_samples).rev() {
+                right_box.encapsulate_node(&segments[i].0);
+                right_count += segments[i].1;
+                right_costs[i] = right_box.area() * right_count as f32;
+            }
+            let mut left_box = BVHNode::default();
+            let mut left_count = 0;
+            let step = (bounds_max - bounds_min) / self.sah_samples as f32;
+            for i in 1..self.sah_samples {
+                left_box.encapsulate_node(&segments[i - 1].0);
+                left_count += segments[i - 1].1;
+                let cost = left_box.area() * left_count as f32 + right_costs[i];
+                if left_count > 0 && left_count < references.len() as u32 && best.map_or(true, |(_, _, best_cost)| cost < best_cost) {
+                    best = Some((axis, bounds_min + step * i as f32, cost));
+                }
+            }
+        }
+        best
+    }
+
+    // Split the node into equally sized bins, clipping each reference to the bins it crosses, and sweep over the planes
+    // between them. Planes which would duplicate more references than `max_duplicates` are skipped. Returns the axis,
+    // position and cost of the best plane.
+    fn find_spatial_split(&self, node: &BVHNode, references: &[Reference], max_duplicates: usize) -> Option<(usize, f32, f32)> {
+        let mut best: Option<(usize, f32, f32)> = None;
+        for axis in 0..3 {
+            let bounds_min = node.aabb_min()[axis];
+            let bounds_max = node.aabb_max()[axis];
+            if bounds_min >= bounds_max {
+                continue;
+            }
+
+            // Each bin counts the references starting and ending in it
+            #[derive(Default, Clone, Copy)]
+            struct Bin {
+                aabb: BVHNode,
+                entries: u32,
+                exits: u32,
+            }
+            let mut bins = [Bin::default(); SPATIAL_SPLIT_BINS];
+            let step = (bounds_max - bounds_min) / SPATIAL_SPLIT_BINS as f32;
+            let bin_of = |position: f32| (((position - bounds_min) / step).max(0.0) as usize).min(SPATIAL_SPLIT_BINS - 1);
+            for reference in references {
+                if self.is_kept_whole(reference) {
+                    // Goes wherever its centroid is, like with an object split
+                    let bin = bin_of(reference.centroid()[axis]);
+                    bins[bin].aabb.encapsulate_node(&reference.aabb);
+                    bins[bin].entries += 1;
+                    bins[bin].exits += 1;
+                    continue;
+                }
+                let first_bin = bin_of(reference.aabb.aabb_min()[axis]);
+                let last_bin = bin_of(reference.aabb.aabb_max()[axis]);
+                for bin in first_bin..=last_bin {
+                    let bin_min = if bin == 0 { f32::NEG_INFINITY } else { bounds_min + step * bin as f32 };
+                    let bin_max = if bin == SPATIAL_SPLIT_BINS - 1 { f32::INFINITY } else { bounds_min + step * (bin + 1) as f32 };
+                    let primitive = self.indices[reference.primitive as usize];
+                    if let Some((clipped_min, clipped_max)) = clipped_primitive_bounds(self.vertices, primitive, reference.bounds(), axis, bin_min, bin_max) {
+                        bins[bin].aabb.encapsulate(&clipped_min);
+                        bins[bin].aabb.encapsulate(&clipped_max);
+                    }
+                }
+                bins[first_bin].entries += 1;
+                bins[last_bin].exits += 1;
+            }
+
+            let mut right_costs = [(0.0, 0); SPATIAL_SPLIT_BINS];
+            let mut right_box = BVHNode::default();
+            let mut right_count = 0;
+            for i in (1..SPATIAL_SPLIT_BINS).rev() {
+                right_box.encapsulate_node(&bins[i].aabb);
+                right_count += bins[i].exits;
+                right_costs[i] = (right_box.area() * right_count as f32, right_count);
+            }
+            let mut left_box = BVHNode::default();
+            let mut left_count = 0;
+            for i in 1..SPATIAL_SPLIT_BINS {
+                left_box.encapsulate_node(&bins[i - 1].aabb);
+                left_count += bins[i - 1].entries;
+                let (right_cost, right_count) = right_costs[i];
+                let duplicates = (left_count + right_count) as usize - references.len();
+                if left_count == 0 || right_count == 0 || duplicates > max_duplicates {
+                    continue;
+                }
+                let cost = left_box.area() * left_count as f32 + right_cost;
+                if best.map_or(true, |(_, _, best_cost)| cost < best_cost) {
+                    best = Some((axis, bounds_min + step * i as f32, cost));
+                }
+            }
+        }
+        best
+    }
+
+    // Sort references into the children of a split. Spatial splits clip the references crossing the plane into a
+    // part for each side.
+    fn split_references(&self, references: Vec<Reference>, split: Split) -> (Vec<Reference>, Vec<Reference>) {
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        for reference in references {
+            match split {
+                Split::Object { axis, position } => {
+                    if reference.centroid()[axis] < position { left.push(reference) } else { right.push(reference) }
+                }
+                Split::Spatial { axis, position } => {
+                    let (aabb_min, aabb_max) = reference.bounds();
+                    if self.is_kept_whole(&reference) {
+                        if reference.centroid()[axis] < position { left.push(reference) } else { right.push(reference) }
+                    } else if aabb_max[axis] <= position {
+                        left.push(reference);
+                    } else if aabb_min[axis] >= position {
+                        right.push(reference);
+                    } else {
+                        let primitive = self.indices[reference.primitive as usize];
+                        let sides = [(&mut left, f32::NEG_INFINITY, position), (&mut right, position, f32::INFINITY)];
+                        for (side, min, max) in sides {
+                            if let Some((clipped_min, clipped_max)) = clipped_primitive_bounds(self.vertices, primitive, (aabb_min, aabb_max), axis, min, max) {
+                                let mut aabb = BVHNode::default();
+                                aabb.encapsulate(&clipped_min);
+                                aabb.encapsulate(&clipped_max);
+                                side.push(Reference { primitive: reference.primitive, aabb });
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        (left, right)
+    }
+
+    // Top down build like `build`, but each node owns its references, since spatial splits can put a primitive in both
+    // children. Leaves copy their primitives into the index buffer as they are created.
+    fn build_with_spatial_splits(&mut self) -> BVH {
+        let primitive_count = self.indices.len();
+        let references = (0..primitive_count)
+            .map(|i| {
+                let (primitive_min, primitive_max) = primitive_bounds(self.vertices, self.indices[i]);
+                let mut aabb = BVHNode::default();
+                aabb.encapsulate(&primitive_min);
+                aabb.encapsulate(&primitive_max);
+                Reference { primitive: i as u32, aabb }
+            })
+            .collect::<Vec<_>>();
+        let max_references = primitive_count + (primitive_count as f32 * self.spatial_split_budget) as usize;
+        let mut reference_count = primitive_count;
+
+        let mut nodes = vec![references_aabb(&references)];
+        let root_area = nodes[0].area();
+        let mut leaf_primitives = Vec::with_capacity(primitive_count);
+        let mut stack = vec![(0, references)];
+        while let Some((node_idx, references)) = stack.pop() {
+            let node = nodes[node_idx];
+            let leaf_cost = node.area() * references.len() as f32;
+
+            // Try a spatial split where the best object split leaves overlapping children, as long as there is budget
+            let object_split = self.find_object_split(&references);
+            let mut best = object_split.map(|(axis, position, cost)| (Split::Object { axis, position }, cost));
+            let overlap = match object_split {
+                Some((axis, position, _)) => {
+                    let (left, right) = self.split_references(references.clone(), Split::Object { axis, position });
+                    let (left_aabb, right_aabb) = (references_aabb(&left), references_aabb(&right));
+                    let mut overlap = BVHNode::default();
+                    overlap.set_aabb_min(&left_aabb.aabb_min().max(right_aabb.aabb_min()));
+                    overlap.set_aabb_max(&left_aabb.aabb_max().min(right_aabb.aabb_max()));
+                    if overlap.aabb_min().cmple(overlap.aabb_max()).all() { overlap.area() } else { 0.0 }
+                }
+                None => root_area, // Every centroid is in the same place, so only a spatial split can separate them
+            };
+            if reference_count < max_references && overlap > root_area * SPATIAL_SPLIT_MIN_OVERLAP {
+                if let Some((axis, position, cost)) = self.find_spatial_split(&node, &references, max_references - reference_count) {
+                    if best.map_or(true, |(_, best_cost)| cost < best_cost) {
+                        best = Some((Split::Spatial { axis, position }, cost));
+                    }
+                }
+            }
+
+            // If the parent node is cheaper, or a side ended up empty, make a leaf
+            let children = match best {
+                Some((split, cost)) if cost < leaf_cost => Some(self.split_references(references.clone(), split)),
+                _ => None,
+            };
+            match children {
+                Some((left, right)) if !left.is_empty() && !right.is_empty() => {
+                    reference_count += left.len() + right.len() - references.len();
+                    let left_idx = nodes.len();
+                    nodes.push(references_aabb(&left));
+                    nodes.push(references_aabb(&right));
+                    nodes[node_idx].set_left_node_index(left_idx as u32);
+                    nodes[node_idx].set_triangle_count(0);
+                    stack.push((left_idx + 1, right));
+                    stack.push((left_idx, left));
+                }
+                _ => {
+                    nodes[node_idx].set_first_triangle_index(leaf_primitives.len() as u32);
+                    nodes[node_idx].set_triangle_count(references.len() as u32);
+                    leaf_primitives.extend(references.iter().map(|reference| self.indices[reference.primitive as usize]));
+                }
+            }
+        }
+
+        *self.indices = leaf_primitives;
+        BVH { nodes }
+    }
+}
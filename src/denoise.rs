@@ -0,0 +1,220 @@
+// Denoising of the averaged HDR image. All buffers are tightly packed RGB f32 triplets, like the framebuffer.
+// The albedo and normal buffers are the first hit guides written by the kernel.
+
+use glam::Vec4;
+use gpgpu::{BufOps, DescriptorSet, GpuBuffer, GpuBufferUsage, GpuUniformBuffer, Kernel, Program, Shader};
+use kernels::denoise::{atrous_config, atrous_pixel, ATROUS_ITERATIONS};
+use rayon::prelude::*;
+use shared_structs::ATrousConfig;
+
+use crate::{adapter::WORKGROUP_SIZE, kernel::EntryPoint, trace::FW};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Denoiser {
+    None,
+    ATrous,
+    Oidn,
+    OidnPrefiltered, // OIDN guided by the albedo and normal buffers, which are denoised first, see `oidn_denoise`
+}
+
+impl std::fmt::Debug for Denoiser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Denoiser::None => write!(f, "None"),
+            Denoiser::ATrous => write!(f, "À-Trous"),
+            Denoiser::Oidn => write!(f, "OIDN"),
+            Denoiser::OidnPrefiltered => write!(f, "OIDN, prefiltered guides"),
+        }
+    }
+}
+
+impl Denoiser {
+    pub fn to_u32(self) -> u32 {
+        match self {
+            Denoiser::None => 0,
+            Denoiser::ATrous => 1,
+            Denoiser::Oidn => 2,
+            Denoiser::OidnPrefiltered => 3,
+        }
+    }
+
+    pub fn from_u32(value: u32) -> Self {
+        match value {
+            0 => Denoiser::None,
+            1 => Denoiser::ATrous,
+            2 => Denoiser::Oidn,
+            3 => Denoiser::OidnPrefiltered,
+            _ => Denoiser::None,
+        }
+    }
+
+    // Names used on the command line, see `--denoiser`. The prefiltered variant has its own flag.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Denoiser::None),
+            "atrous" => Some(Denoiser::ATrous),
+            "oidn" => Some(Denoiser::Oidn),
+            _ => None,
+        }
+    }
+
+    // Whether the denoiser reads the albedo and normal buffers
+    pub fn uses_guides(&self) -> bool {
+        matches!(self, Denoiser::ATrous | Denoiser::OidnPrefiltered)
+    }
+
+    pub fn is_available(&self) -> bool {
+        match self {
+            Denoiser::Oidn | Denoiser::OidnPrefiltered => cfg!(feature = "oidn"),
+            _ => true,
+        }
+    }
+}
+
+pub fn denoise(denoiser: Denoiser, width: usize, height: usize, color: &mut [f32], albedo: &[f32], normal: &[f32]) {
+    match denoiser {
+        Denoiser::None => {}
+        Denoiser::ATrous => atrous_denoise(width, height, color, albedo, normal),
+        Denoiser::Oidn => {
+            #[cfg(feature = "oidn")]
+            oidn_denoise(width, height, color);
+        }
+        Denoiser::OidnPrefiltered => {
+            #[cfg(feature = "oidn")]
+            oidn_denoise_prefiltered(width, height, color, albedo, normal);
+        }
+    }
+}
+
+#[cfg(feature = "oidn")]
+pub fn oidn_denoise(width: usize, height: usize, input: &mut [f32]) {
+    let device = oidn::Device::new();
+    oidn::RayTracing::new(&device)
+        .hdr(true)
+        .srgb(false)
+        .image_dimensions(width, height)
+        .filter_in_place(input)
+        .expect("Filter config error!");
+}
+
+// OIDN's recommended workflow for noisy guides, such as those of scenes with textures, depth of field or motion: the
+// albedo and normal buffers are denoised on their own first, and the image is then denoised with the clean guides.
+// Without the prefilter, noise in the guides is treated as detail and survives in the image.
+#[cfg(feature = "oidn")]
+pub fn oidn_denoise_prefiltered(width: usize, height: usize, input: &mut [f32], albedo: &[f32], normal: &[f32]) {
+    let device = oidn::Device::new();
+    let prefilter = |guide: &mut [f32]| {
+        oidn::RayTracing::new(&device)
+            .hdr(false)
+            .srgb(false)
+            .image_dimensions(width, height)
+            .filter_in_place(guide)
+            .expect("Filter config error!");
+    };
+    let mut albedo = albedo.to_vec();
+    prefilter(&mut albedo);
+    // Filtered like an LDR image, so the normals are moved into [0, 1] and back
+    let mut normal = normal.iter().map(|n| n * 0.5 + 0.5).collect::<Vec<_>>();
+    prefilter(&mut normal);
+    normal.iter_mut().for_each(|n| *n = *n * 2.0 - 1.0);
+
+    oidn::RayTracing::new(&device)
+        .albedo_normal(&albedo, &normal)
+        .clean_aux(true)
+        .hdr(true)
+        .srgb(false)
+        .image_dimensions(width, height)
+        .filter_in_place(input)
+        .expect("Filter config error!");
+}
+
+// Edge-avoiding À-Trous wavelet filter, see `kernels::denoise::atrous_pixel`. This is the CPU fallback, which runs
+// the same passes as `GpuATrous`.
+pub fn atrous_denoise(width: usize, height: usize, color: &mut [f32], albedo: &[f32], normal: &[f32]) {
+    let mut ping = to_vec4s(color);
+    let mut pong = vec![Vec4::ZERO; ping.len()];
+    let (albedo, normal) = (to_vec4s(albedo), to_vec4s(normal));
+    for iteration in 0..ATROUS_ITERATIONS {
+        let config = atrous_config(width as u32, height as u32, iteration);
+        pong.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+            for (x, pixel) in row.iter_mut().enumerate() {
+                *pixel = atrous_pixel(x as u32, y as u32, &config, &ping, &albedo, &normal);
+            }
+        });
+        std::mem::swap(&mut ping, &mut pong);
+    }
+    from_vec4s(&ping, color);
+}
+
+// The À-Trous passes as compute dispatches, for denoising images rendered on the GPU. The image and guides are
+// uploaded after they are resolved on the host, so the image can be reprojected first, like with the other denoisers.
+// The passes ping-pong between two buffers, so there is a kernel for each direction.
+pub struct GpuATrous<'fw> {
+    width: u32,
+    height: u32,
+    config_buffer: GpuUniformBuffer<'fw, ATrousConfig>,
+    ping_buffer: GpuBuffer<'fw, Vec4>,
+    pong_buffer: GpuBuffer<'fw, Vec4>,
+    albedo_buffer: GpuBuffer<'fw, Vec4>,
+    normal_buffer: GpuBuffer<'fw, Vec4>,
+    passes: [Kernel<'fw>; 2],
+    staging: Vec<Vec4>,
+}
+
+impl<'fw> GpuATrous<'fw> {
+    pub fn new(spirv: &[u8], width: u32, height: u32) -> Self {
+        let pixel_count = (width * height) as usize;
+        let config_buffer = GpuUniformBuffer::from_slice(&FW, &[ATrousConfig::default()]);
+        let ping_buffer = GpuBuffer::from_slice(&FW, &vec![Vec4::ZERO; pixel_count]);
+        let pong_buffer = GpuBuffer::from_slice(&FW, &vec![Vec4::ZERO; pixel_count]);
+        let albedo_buffer = GpuBuffer::from_slice(&FW, &vec![Vec4::ZERO; pixel_count]);
+        let normal_buffer = GpuBuffer::from_slice(&FW, &vec![Vec4::ZERO; pixel_count]);
+        let shader = Shader::from_spirv_bytes(&FW, spirv, Some("compute"));
+        let pass = |input: &GpuBuffer<'fw, Vec4>, output: &GpuBuffer<'fw, Vec4>| {
+            let bindings = DescriptorSet::default()
+                .bind_uniform_buffer(&config_buffer)
+                .bind_buffer(input, GpuBufferUsage::ReadOnly)
+                .bind_buffer(output, GpuBufferUsage::ReadWrite)
+                .bind_buffer(&albedo_buffer, GpuBufferUsage::ReadOnly)
+                .bind_buffer(&normal_buffer, GpuBufferUsage::ReadOnly);
+            Kernel::new(&FW, Program::new(&shader, EntryPoint::ATrous.name()).add_descriptor_set(bindings))
+        };
+        let passes = [pass(&ping_buffer, &pong_buffer), pass(&pong_buffer, &ping_buffer)];
+        Self {
+            width,
+            height,
+            config_buffer,
+            ping_buffer,
+            pong_buffer,
+            albedo_buffer,
+            normal_buffer,
+            passes,
+            staging: vec![Vec4::ZERO; pixel_count],
+        }
+    }
+
+    pub fn denoise(&mut self, color: &mut [f32], albedo: &[f32], normal: &[f32]) {
+        let _ = self.ping_buffer.write(&to_vec4s(color));
+        let _ = self.albedo_buffer.write(&to_vec4s(albedo));
+        let _ = self.normal_buffer.write(&to_vec4s(normal));
+        for iteration in 0..ATROUS_ITERATIONS {
+            // The config is written before each dispatch is submitted, and each one is waited on, so every pass sees its own
+            let _ = self.config_buffer.write(&[atrous_config(self.width, self.height, iteration)]);
+            self.passes[iteration as usize % 2].enqueue(self.width.div_ceil(WORKGROUP_SIZE), self.height.div_ceil(WORKGROUP_SIZE), 1);
+            FW.poll_blocking();
+        }
+        let result = if ATROUS_ITERATIONS % 2 == 0 { &self.ping_buffer } else { &self.pong_buffer };
+        let _ = result.read_blocking(&mut self.staging);
+        from_vec4s(&self.staging, color);
+    }
+}
+
+fn to_vec4s(buffer: &[f32]) -> Vec<Vec4> {
+    buffer.chunks_exact(3).map(|c| Vec4::new(c[0], c[1], c[2], 0.0)).collect()
+}
+
+fn from_vec4s(buffer: &[Vec4], output: &mut [f32]) {
+    for (pixel, value) in output.chunks_exact_mut(3).zip(buffer) {
+        pixel.copy_from_slice(&value.truncate().to_array());
+    }
+}
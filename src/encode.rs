@@ -0,0 +1,314 @@
+// Parallel PNG encoding. The image is split into horizontal strips which are filtered and deflated on separate
+// threads. Every strip but the last ends with a sync flush, which byte-aligns the deflate stream, so the
+// compressed strips can be concatenated into a single zlib stream. Each strip gets its own compressor, so no
+// back-references cross strip boundaries, at the cost of slightly worse compression.
+
+use std::io::Write;
+
+use flate2::{Compress, Compression, FlushCompress, Status};
+use rayon::prelude::*;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const MIN_ROWS_PER_STRIP: usize = 32;
+const BYTES_PER_PIXEL: usize = 4;
+
+// Encode tightly packed 8-bit RGBA pixels as a PNG file, using one strip per thread
+pub fn encode_png(width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+    let rows_per_strip = (height as usize).div_ceil(rayon::current_num_threads()).max(MIN_ROWS_PER_STRIP);
+    encode_png_strips(width, height, rgba, rows_per_strip)
+}
+
+pub fn encode_png_strips(width: u32, height: u32, rgba: &[u8], rows_per_strip: usize) -> Vec<u8> {
+    let stride = width as usize * BYTES_PER_PIXEL;
+    assert_eq!(rgba.len(), stride * height as usize);
+
+    let rows = rgba.chunks(stride).collect::<Vec<_>>();
+    let strip_count = rows.len().div_ceil(rows_per_strip);
+    let strips = (0..strip_count)
+        .into_par_iter()
+        .map(|strip| {
+            let first_row = strip * rows_per_strip;
+            let last_row = (first_row + rows_per_strip).min(rows.len());
+            let mut filtered = Vec::with_capacity((last_row - first_row) * (stride + 1));
+            for y in first_row..last_row {
+                let prev_row = if y == 0 { None } else { Some(rows[y - 1]) };
+                filter_row_paeth(rows[y], prev_row, &mut filtered);
+            }
+            let compressed = deflate_strip(&filtered, strip == strip_count - 1);
+            (filtered, compressed)
+        })
+        .collect::<Vec<_>>();
+
+    // The checksum is cheap compared to compression, so just compute it serially
+    let mut checksum = adler::Adler32::new();
+    let mut zlib_stream = vec![0x78, 0x9C]; // zlib header, 32K window, default compression
+    for (filtered, compressed) in strips.iter() {
+        checksum.write_slice(filtered);
+        zlib_stream.extend_from_slice(compressed);
+    }
+    zlib_stream.extend_from_slice(&checksum.checksum().to_be_bytes());
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    header.extend_from_slice(&[8, 6, 0, 0, 0]); // 8 bit depth, RGBA, deflate, adaptive filtering, no interlacing
+
+    let mut png = Vec::with_capacity(zlib_stream.len() + 64);
+    png.extend_from_slice(&PNG_SIGNATURE);
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib_stream);
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+pub fn save_png(path: &str, width: u32, height: u32, rgba: &[u8]) -> std::io::Result<()> {
+    let png = encode_png(width, height, rgba);
+    std::fs::File::create(path)?.write_all(&png)
+}
+
+fn write_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(chunk_type);
+    png.extend_from_slice(data);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(chunk_type);
+    crc.update(data);
+    png.extend_from_slice(&crc.finalize().to_be_bytes());
+}
+
+// Filtering only depends on the unfiltered previous row, so strips can be filtered independently.
+// Paeth is usually the best single filter for rendered images.
+fn filter_row_paeth(row: &[u8], prev_row: Option<&[u8]>, output: &mut Vec<u8>) {
+    output.push(4); // Paeth filter type
+    for i in 0..row.len() {
+        let left = if i >= BYTES_PER_PIXEL { row[i - BYTES_PER_PIXEL] } else { 0 };
+        let up = prev_row.map_or(0, |prev| prev[i]);
+        let up_left = match prev_row {
+            Some(prev) if i >= BYTES_PER_PIXEL => prev[i - BYTES_PER_PIXEL],
+            _ => 0,
+        };
+        output.push(row[i].wrapping_sub(paeth_predictor(left, up, up_left)));
+    }
+}
+
+fn paeth_predictor(left: u8, up: u8, up_left: u8) -> u8 {
+    let p = left as i16 + up as i16 - up_left as i16;
+    let pa = (p - left as i16).abs();
+    let pb = (p - up as i16).abs();
+    let pc = (p - up_left as i16).abs();
+    if pa <= pb && pa <= pc {
+        left
+    } else if pb <= pc {
+        up
+    } else {
+        up_left
+    }
+}
+
+// Raw deflate of a single strip. Only the last strip finishes the stream.
+fn deflate_strip(data: &[u8], last: bool) -> Vec<u8> {
+    let mut compress = Compress::new(Compression::default(), false);
+    let flush = if last { FlushCompress::Finish } else { FlushCompress::Sync };
+    let mut output = Vec::with_capacity(data.len() / 2 + 64);
+    loop {
+        let consumed = compress.total_in() as usize;
+        let status = compress
+            .compress_vec(&data[consumed..], &mut output, flush)
+            .expect("Deflate failed");
+        let done = if last {
+            status == Status::StreamEnd
+        } else {
+            // The flush is complete once all input is consumed without filling the output
+            compress.total_in() as usize == data.len() && output.len() < output.capacity()
+        };
+        if done {
+            return output;
+        }
+        output.reserve(output.capacity());
+    }
+}
+
+// How color relates to alpha in saved images. The tracer leaves the background empty, so rendered color is
+// premultiplied: a pixel half covered by a white object is half as bright. Straight alpha divides the color by
+// coverage, giving the object's own color, which is what PNG expects and most viewers assume, so it is the default.
+// Premultiplied leaves the color as rendered, which compositors usually prefer for EXR.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum AlphaMode {
+    #[default]
+    Straight,
+    Premultiplied,
+}
+
+impl AlphaMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "straight" => Some(AlphaMode::Straight),
+            "premultiplied" => Some(AlphaMode::Premultiplied),
+            _ => None,
+        }
+    }
+}
+
+// Replace the alpha channel of tightly packed 8-bit RGBA pixels with per-pixel coverage. The color is expected to be
+// premultiplied, and is converted to `mode`.
+pub fn apply_coverage_alpha(rgba: &mut [u8], alpha: &[f32], mode: AlphaMode) {
+    for (pixel, coverage) in rgba.chunks_exact_mut(BYTES_PER_PIXEL).zip(alpha) {
+        let coverage = coverage.clamp(0.0, 1.0);
+        if mode == AlphaMode::Straight && coverage > 0.0 {
+            for channel in pixel[..3].iter_mut() {
+                *channel = (*channel as f32 / coverage).round().min(255.0) as u8;
+            }
+        }
+        pixel[3] = (coverage * 255.0).round() as u8;
+    }
+}
+
+// Dithering for 8-bit output. Smooth gradients quantize into visible bands, so each value is offset by up to half a
+// step before rounding, using a tile of blue noise shifted by a seed. The display shader does the same, so it must
+// match `dither` in render.wgsl.
+pub const DITHER_TILE_SIZE: u32 = 256;
+
+// The noise tile, row by row, with values in (0, 1)
+pub fn dither_tile() -> Vec<f32> {
+    let texture = &*crate::trace::BLUE_TEXTURE;
+    (0..DITHER_TILE_SIZE * DITHER_TILE_SIZE)
+        .map(|i| {
+            let (x, y) = (i % DITHER_TILE_SIZE, i / DITHER_TILE_SIZE);
+            (texture.get_pixel(x % texture.width(), y % texture.height())[0] as f32 + 0.5) / 256.0
+        })
+        .collect()
+}
+
+// Offset of a pixel before quantizing, in steps of the output, in [-0.5, 0.5]. Different seeds shift the tile, so
+// renders dithered with different seeds don't share a pattern.
+pub fn dither_offset(tile: &[f32], x: u32, y: u32, seed: u32) -> f32 {
+    let hash = seed.wrapping_mul(0x9E3779B9);
+    let tile_x = (x + hash % DITHER_TILE_SIZE) % DITHER_TILE_SIZE;
+    let tile_y = (y + (hash >> 8) % DITHER_TILE_SIZE) % DITHER_TILE_SIZE;
+    tile[(tile_y * DITHER_TILE_SIZE + tile_x) as usize] - 0.5
+}
+
+// Quantize a display encoded value in [0, 1] to 8 bits, dithered with the tile
+pub fn quantize_dithered(value: f32, x: u32, y: u32, tile: &[f32], seed: u32) -> u8 {
+    (value * 255.0 + dither_offset(tile, x, y, seed)).round().clamp(0.0, 255.0) as u8
+}
+
+// Same as `apply_coverage_alpha`, but for linear RGB, which is converted in place
+pub fn convert_alpha(rgb: &mut [f32], alpha: &[f32], mode: AlphaMode) {
+    if mode == AlphaMode::Premultiplied {
+        return;
+    }
+    for (pixel, &coverage) in rgb.chunks_exact_mut(3).zip(alpha) {
+        if coverage > 0.0 {
+            pixel.iter_mut().for_each(|channel| *channel /= coverage);
+        }
+    }
+}
+
+// Save linear RGB as a floating point EXR, keeping the full dynamic range. Without coverage, alpha is opaque.
+pub fn save_exr(path: &str, width: u32, height: u32, rgb: &[f32], alpha: Option<(&[f32], AlphaMode)>) -> image::ImageResult<()> {
+    let mut rgb = rgb.to_vec();
+    if let Some((alpha, mode)) = alpha {
+        convert_alpha(&mut rgb, alpha, mode);
+    }
+    let rgba = rgb
+        .chunks(3)
+        .enumerate()
+        .flat_map(|(i, c)| [c[0], c[1], c[2], alpha.map_or(1.0, |(alpha, _)| alpha[i])])
+        .collect::<Vec<_>>();
+    let Some(image) = image::Rgba32FImage::from_raw(width, height, rgba) else {
+        return Err(image::ImageError::Parameter(image::error::ParameterError::from_kind(
+            image::error::ParameterErrorKind::DimensionMismatch,
+        )));
+    };
+    image::DynamicImage::ImageRgba32F(image).save(path)
+}
+
+// Radiance RGBE, a compact HDR format. Each pixel is 4 bytes: an 8 bit mantissa per channel and an exponent shared by
+// all three, so channels much dimmer than the brightest one lose precision, but relative error stays below 1/128 of
+// the brightest channel. About half the size of a float EXR before compression, and readable by most HDR tools.
+const RGBE_MIN_RLE_WIDTH: usize = 8; // Run length encoded scanlines must be 8 to 32767 pixels wide
+const RGBE_MAX_RLE_WIDTH: usize = 0x7FFF;
+const RGBE_MAX_RUN: usize = 127;
+const RGBE_MAX_LITERAL: usize = 128;
+
+// Negative and non-finite values can't be represented, so they become black
+pub fn rgb_to_rgbe(rgb: [f32; 3]) -> [u8; 4] {
+    let rgb = rgb.map(|c| if c.is_finite() { c.max(0.0) } else { 0.0 });
+    let max = rgb[0].max(rgb[1]).max(rgb[2]);
+    if max < 1e-32 {
+        return [0; 4];
+    }
+    // Exponent such that the brightest channel divided by 2^exponent is in [0.5, 1)
+    let exponent = ((max.to_bits() >> 23) & 0xFF) as i32 - 126;
+    let scale = 2f32.powi(8 - exponent);
+    let [r, g, b] = rgb.map(|c| (c * scale).min(255.0) as u8);
+    [r, g, b, (exponent + 128) as u8]
+}
+
+// Channels are reconstructed at the middle of their quantization step
+pub fn rgbe_to_rgb(rgbe: [u8; 4]) -> [f32; 3] {
+    if rgbe[3] == 0 {
+        return [0.0; 3];
+    }
+    let scale = 2f32.powi(rgbe[3] as i32 - 136);
+    [rgbe[0], rgbe[1], rgbe[2]].map(|c| (c as f32 + 0.5) * scale)
+}
+
+// Encode linear RGB as a Radiance .hdr file. Scanlines are run length encoded per channel when their width allows it.
+pub fn encode_rgbe(width: u32, height: u32, rgb: &[f32]) -> Vec<u8> {
+    let width = width as usize;
+    assert_eq!(rgb.len(), width * height as usize * 3);
+
+    let mut hdr = format!("#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", height, width).into_bytes();
+    let scanlines = rgb
+        .par_chunks(width * 3)
+        .map(|row| {
+            let pixels = row.chunks_exact(3).map(|c| rgb_to_rgbe([c[0], c[1], c[2]])).collect::<Vec<_>>();
+            if (RGBE_MIN_RLE_WIDTH..=RGBE_MAX_RLE_WIDTH).contains(&width) {
+                encode_rgbe_scanline(&pixels)
+            } else {
+                pixels.concat()
+            }
+        })
+        .collect::<Vec<_>>();
+    for scanline in scanlines {
+        hdr.extend_from_slice(&scanline);
+    }
+    hdr
+}
+
+// A scanline header, followed by each of the 4 components of every pixel in turn, as runs of a repeated byte and
+// literal spans
+fn encode_rgbe_scanline(pixels: &[[u8; 4]]) -> Vec<u8> {
+    let mut output = vec![2, 2, (pixels.len() >> 8) as u8, pixels.len() as u8];
+    for component in 0..4 {
+        let bytes = pixels.iter().map(|pixel| pixel[component]).collect::<Vec<_>>();
+        let mut i = 0;
+        while i < bytes.len() {
+            let run = bytes[i..].iter().take(RGBE_MAX_RUN).take_while(|&&byte| byte == bytes[i]).count();
+            if run >= 3 {
+                output.extend_from_slice(&[128 + run as u8, bytes[i]]);
+                i += run;
+                continue;
+            }
+            // Literals continue until a run long enough to be worth encoding starts
+            let start = i;
+            while i < bytes.len() && i - start < RGBE_MAX_LITERAL {
+                if i + 2 < bytes.len() && bytes[i] == bytes[i + 1] && bytes[i] == bytes[i + 2] {
+                    break;
+                }
+                i += 1;
+            }
+            output.push((i - start) as u8);
+            output.extend_from_slice(&bytes[start..i]);
+        }
+    }
+    output
+}
+
+// Radiance files have no alpha channel, so transparent backgrounds are saved as rendered, with premultiplied color
+pub fn save_hdr(path: &str, width: u32, height: u32, rgb: &[f32]) -> std::io::Result<()> {
+    let hdr = encode_rgbe(width, height, rgb);
+    std::fs::File::create(path)?.write_all(&hdr)
+}
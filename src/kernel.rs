@@ -0,0 +1,171 @@
+// The compiled kernels, and the entry points the host dispatches. Names are checked against the functions marked with
+// `#[spirv(compute(...))]` in kernels/src/lib.rs by the tests, so a renamed kernel is caught before it fails to load.
+
+use std::{path::PathBuf, time::SystemTime};
+
+use rspirv::{dr::{Module, Operand}, spirv::{Decoration, Op}};
+
+pub const KERNEL: &[u8] = include_bytes!(env!("kernels.spv"));
+// Where the kernel was compiled to, which is watched when hot reloading
+pub const KERNEL_PATH: &str = env!("kernels.spv");
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum EntryPoint {
+    Trace, // Reads the full width index buffer
+    TraceCompact, // Reads the index buffer packed with 16 bit indices
+    ATrous, // One iteration of the À-Trous denoiser, which has bindings of its own
+}
+
+impl EntryPoint {
+    pub const ALL: [EntryPoint; 3] = [EntryPoint::Trace, EntryPoint::TraceCompact, EntryPoint::ATrous];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EntryPoint::Trace => "trace_kernel",
+            EntryPoint::TraceCompact => "trace_kernel_compact",
+            EntryPoint::ATrous => "atrous_kernel",
+        }
+    }
+}
+
+// Bindings of descriptor set 0 shared by the trace entry points, indexed by binding number. Must match the parameters of
+// the entry points, and the order `PathTracingKernel` binds resources in.
+pub const BINDINGS: [&str; 18] = [
+    "config",
+    "rng",
+    "output",
+    "per_vertex_buffer",
+    "index_buffer",
+    "nodes_buffer",
+    "material_data_buffer",
+    "light_pick_buffer",
+    "sampler",
+    "atlas",
+    "skybox",
+    "albedo_output",
+    "normal_output",
+    "light_tree_buffer",
+    "variance_output",
+    "specular_output",
+    "stats",
+    "sample_mask",
+];
+
+fn load_module(spirv: &[u8]) -> Result<Module, String> {
+    rspirv::dr::load_bytes(spirv).map_err(|error| format!("the kernel is not a valid SPIR-V module ({:?})", error))
+}
+
+fn entry_point_names(module: &Module) -> Vec<String> {
+    module
+        .entry_points
+        .iter()
+        .filter_map(|entry_point| match entry_point.operands.get(2) {
+            Some(Operand::LiteralString(name)) => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+// Values of a decoration on any variable, such as the binding numbers used
+fn decoration_values(module: &Module, decoration: Decoration) -> Vec<(u32, u32)> {
+    module
+        .annotations
+        .iter()
+        .filter(|annotation| annotation.class.opcode == Op::Decorate)
+        .filter_map(|annotation| match annotation.operands[..] {
+            [Operand::IdRef(target), Operand::Decoration(found), Operand::LiteralInt32(value)] if found == decoration => Some((target, value)),
+            _ => None,
+        })
+        .collect()
+}
+
+// Names of the entry points declared in a SPIR-V module, or None if it isn't a valid module
+pub fn spirv_entry_point_names(spirv: &[u8]) -> Option<Vec<String>> {
+    load_module(spirv).ok().map(|module| entry_point_names(&module))
+}
+
+// Check that a SPIR-V module has every entry point and binding the host expects, so a kernel built from mismatched
+// sources is reported clearly up front, rather than failing obscurely when the pipeline is created or dispatched.
+// The error lists everything that is missing.
+pub fn validate_kernel(spirv: &[u8]) -> Result<(), String> {
+    let module = load_module(spirv)?;
+    let names = entry_point_names(&module);
+    let missing_entry_points = EntryPoint::ALL
+        .iter()
+        .map(|entry_point| entry_point.name())
+        .filter(|name| !names.iter().any(|found| found == name))
+        .collect::<Vec<_>>();
+
+    let descriptor_sets = decoration_values(&module, Decoration::DescriptorSet);
+    let bindings = decoration_values(&module, Decoration::Binding)
+        .into_iter()
+        .filter(|(target, _)| descriptor_sets.contains(&(*target, 0)))
+        .map(|(_, binding)| binding)
+        .collect::<Vec<_>>();
+    let missing_bindings = BINDINGS
+        .iter()
+        .enumerate()
+        .filter(|(binding, _)| !bindings.contains(&(*binding as u32)))
+        .map(|(binding, name)| format!("{} ({})", binding, name))
+        .collect::<Vec<_>>();
+
+    let mut problems = Vec::new();
+    if !missing_entry_points.is_empty() {
+        problems.push(format!("missing entry points: {}", missing_entry_points.join(", ")));
+    }
+    if !missing_bindings.is_empty() {
+        problems.push(format!("missing bindings in descriptor set 0: {}", missing_bindings.join(", ")));
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("the kernel doesn't match the host, {}", problems.join("; ")))
+    }
+}
+
+// Read a compiled kernel from disk, such as one given with `--kernel`, checking it the same way as the embedded one
+pub fn load_kernel(path: &str) -> Result<Vec<u8>, String> {
+    let spirv = std::fs::read(path).map_err(|error| format!("couldn't read {} ({})", path, error))?;
+    validate_kernel(&spirv)?;
+    Ok(spirv)
+}
+
+// Watches a compiled kernel for changes, so kernels can be iterated on without restarting. Rebuilding the kernels
+// crate rewrites the file. The render loop already polls, so this checks the modification time and size of the file
+// when asked, rather than subscribing to file system events.
+pub struct KernelWatcher {
+    path: PathBuf,
+    stamp: Option<(SystemTime, u64)>,
+}
+
+impl KernelWatcher {
+    pub fn new(path: &str) -> Self {
+        let path = PathBuf::from(path);
+        let stamp = Self::stamp(&path);
+        Self { path, stamp }
+    }
+
+    fn stamp(path: &PathBuf) -> Option<(SystemTime, u64)> {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+
+    // The new kernel if the file changed since the last call. Kernels which fail to validate are reported and skipped,
+    // so the caller keeps using the previous one. A kernel caught halfway through being written is picked up once the
+    // write finishes, since that changes the file again.
+    pub fn poll(&mut self) -> Option<Vec<u8>> {
+        let stamp = Self::stamp(&self.path);
+        if stamp.is_none() || stamp == self.stamp {
+            return None;
+        }
+        self.stamp = stamp;
+        let spirv = std::fs::read(&self.path).ok()?;
+        match validate_kernel(&spirv) {
+            Ok(()) => Some(spirv),
+            Err(error) => {
+                println!("Warning: Not reloading {}, {}", self.path.display(), error);
+                None
+            }
+        }
+    }
+}
@@ -2,7 +2,23 @@
 
 pub mod app;
 pub mod trace;
+pub mod kernel;
+pub mod adapter;
+pub mod timer;
+pub mod render;
 pub mod bvh;
+pub mod primitive;
 pub mod atlas;
 pub mod asset;
-pub mod light_pick;
\ No newline at end of file
+pub mod ply;
+pub mod scenes;
+pub mod light_pick;
+pub mod light_tree;
+pub mod post;
+pub mod denoise;
+pub mod reproject;
+pub mod bake;
+pub mod encode;
+pub mod tonemap;
+#[cfg(feature = "pyo3")]
+pub mod python;
\ No newline at end of file
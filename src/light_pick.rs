@@ -1,19 +1,18 @@
-use glam::{UVec4, Vec3, Vec4, Vec4Swizzles};
+use glam::{UVec4, Vec3};
 use rand::Rng;
-use shared_structs::{LightPickEntry, MaterialData};
+use shared_structs::{LightPickEntry, MaterialData, PerVertexData, material_index};
 
-fn triangle_area(a: Vec3, b: Vec3, c: Vec3) -> f32 {
-    let side_a = b - a;
-    let side_b = c - b;
-    let side_c = a - c;
-    let s = (side_a.length() + side_b.length() + side_c.length()) / 2.0;
-    (s * (s - side_a.length()) * (s - side_b.length()) * (s - side_c.length())).sqrt()
+use crate::primitive::primitive_area;
+
+// Heron's formula loses most of its precision on long thin triangles, the cross product doesn't
+pub(crate) fn triangle_area(a: Vec3, b: Vec3, c: Vec3) -> f32 {
+    (b - a).cross(c - a).length() * 0.5
 }
 
 pub fn compute_emissive_mask(indices: &[UVec4], material_datas: &[MaterialData]) -> Vec<bool> {
     let mut emissive_mask = vec![false; indices.len()];
     for i in 0..indices.len() {
-        if material_datas[indices[i].w as usize].emissive.xyz() != Vec3::ZERO {
+        if material_datas[material_index(indices[i]) as usize].emission() != Vec3::ZERO {
             emissive_mask[i] = true;
         }
     }
@@ -22,7 +21,7 @@ pub fn compute_emissive_mask(indices: &[UVec4], material_datas: &[MaterialData])
 
 // NOTE: `mask` indicates which triangles are valid for picking
 pub fn build_light_pick_table(
-    vertices: &[Vec4],
+    vertices: &[PerVertexData],
     indices: &[UVec4],
     mask: &[bool],
     material_datas: &[MaterialData],
@@ -39,14 +38,10 @@ pub fn build_light_pick_table(
         total_tris += 1;
 
         let triangle = indices[i];
-        let a = vertices[triangle.x as usize].xyz();
-        let b = vertices[triangle.y as usize].xyz();
-        let c = vertices[triangle.z as usize].xyz();
-
-        let triangle_area = triangle_area(a, b, c);
+        let triangle_area = primitive_area(vertices, triangle);
         triangle_areas[i] = triangle_area;
 
-        let triangle_power = material_datas[triangle.w as usize].emissive.xyz().dot(Vec3::ONE) * triangle_area;
+        let triangle_power = material_datas[material_index(triangle) as usize].emission().dot(Vec3::ONE) * triangle_area;
         triangle_powers[i] = triangle_power;
         total_power += triangle_power;
     }
@@ -61,69 +56,58 @@ pub fn build_light_pick_table(
     for i in 0..indices.len() {
         triangle_probabilities[i] = triangle_powers[i] / total_power;
     }
-    let average_probability = triangle_probabilities.iter().sum::<f32>() / total_tris as f32;
     // Build histogram bins. Each entry contains 2 discrete outcomes.
-    #[derive(Debug)]
-    struct TriangleBin {
-        index_a: usize,
-        probability_a: f32,
-        index_b: usize,
-        probability_b: f32,
-    }
-    let mut bins = triangle_probabilities
-        .iter()
-        .enumerate()
-        .map(|x| TriangleBin {
-            index_a: x.0,
-            probability_a: *x.1,
-            index_b: 0,
-            probability_b: 0.0,
-        })
-        .filter(|x| x.probability_a != 0.0)
+    // Probabilities are scaled such that each bin should contain exactly 1.0 worth of probability.
+    let candidates = (0..indices.len())
+        .filter(|&i| triangle_probabilities[i] != 0.0)
         .collect::<Vec<_>>();
-    bins.sort_by(|a, b| {
-        a.probability_a
-            .partial_cmp(&b.probability_a)
-            .unwrap_or(std::cmp::Ordering::Equal)
-    });
-
-    // Robin hood - take from the most probable and give to the least probable
-    let num_bins = bins.len();
-    let mut most_probable = num_bins - 1;
-    for i in 0..num_bins {
-        let needed = average_probability - bins[i].probability_a;
-        if needed <= 0.0 {
-            break;
+    let num_bins = candidates.len();
+    let mut scaled_probabilities = vec![0.0; indices.len()];
+    let mut small = Vec::new();
+    let mut large = Vec::new();
+    for &i in candidates.iter() {
+        scaled_probabilities[i] = triangle_probabilities[i] * num_bins as f32;
+        if scaled_probabilities[i] < 1.0 {
+            small.push(i);
+        } else {
+            large.push(i);
         }
+    }
 
-        bins[i].index_b = bins[most_probable].index_a;
-        bins[i].probability_b = needed;
-        bins[most_probable].probability_a -= needed;
-        if bins[most_probable].probability_a <= average_probability {
-            most_probable -= 1;
+    // Robin hood (Vose's alias method) - take from the most probable and give to the least probable.
+    // Each underfull bin is topped off by a single overfull one, which may then become underfull itself.
+    let mut table = Vec::with_capacity(num_bins);
+    let make_entry = |index_a: usize, index_b: usize, ratio: f32| LightPickEntry {
+        triangle_index_a: index_a as u32,
+        triangle_index_b: index_b as u32,
+        triangle_pick_pdf_a: triangle_probabilities[index_a],
+        triangle_area_a: triangle_areas[index_a],
+        triangle_area_b: triangle_areas[index_b],
+        triangle_pick_pdf_b: triangle_probabilities[index_b],
+        ratio,
+    };
+    while !small.is_empty() && !large.is_empty() {
+        let less = small.pop().unwrap();
+        let more = large.pop().unwrap();
+        table.push(make_entry(less, more, scaled_probabilities[less]));
+        scaled_probabilities[more] -= 1.0 - scaled_probabilities[less];
+        if scaled_probabilities[more] < 1.0 {
+            small.push(more);
+        } else {
+            large.push(more);
         }
     }
 
-    // Build the table
-    let table = bins
-        .iter()
-        .map(|x| LightPickEntry {
-            triangle_index_a: x.index_a as u32,
-            triangle_index_b: x.index_b as u32,
-            triangle_pick_pdf_a: triangle_probabilities[x.index_a],
-            triangle_area_a: triangle_areas[x.index_a],
-            triangle_area_b: triangle_areas[x.index_b],
-            triangle_pick_pdf_b: triangle_probabilities[x.index_b],
-            ratio: x.probability_a / (x.probability_a + x.probability_b),
-        })
-        .collect::<Vec<_>>();
+    // Whatever is left is full up to floating point error
+    for i in small.into_iter().chain(large) {
+        table.push(make_entry(i, i, 1.0));
+    }
 
     table
 }
 
-// Just for reference
-#[allow(dead_code)]
-fn pick_light(table: &[LightPickEntry]) -> u32 {
+// Host-side reference of the picking done in the kernel, useful for testing
+pub fn pick_light(table: &[LightPickEntry]) -> u32 {
     let rng = rand::thread_rng().gen_range(0..table.len());
     let entry = table[rng];
     let rng = rand::thread_rng().gen_range(0.0..1.0);
@@ -135,7 +119,7 @@ fn pick_light(table: &[LightPickEntry]) -> u32 {
 }
 
 #[allow(dead_code)]
-fn build_light_cdf_table(vertices: &[Vec4], indices: &[UVec4], mask: &[bool]) -> Vec<f32> {
+fn build_light_cdf_table(vertices: &[PerVertexData], indices: &[UVec4], mask: &[bool]) -> Vec<f32> {
     // Calculate areas and probabilities of picking each triangle
     let mut triangle_areas = vec![0.0; indices.len()];
     let mut total_area = 0.0;
@@ -144,9 +128,9 @@ fn build_light_cdf_table(vertices: &[Vec4], indices: &[UVec4], mask: &[bool]) ->
             continue;
         }
         let triangle = indices[i];
-        let a = vertices[triangle.x as usize].xyz();
-        let b = vertices[triangle.y as usize].xyz();
-        let c = vertices[triangle.z as usize].xyz();
+        let a = vertices[triangle.x as usize].vertex;
+        let b = vertices[triangle.y as usize].vertex;
+        let c = vertices[triangle.z as usize].vertex;
         let triangle_area = triangle_area(a, b, c);
         total_area += triangle_area;
         triangle_areas[i] = triangle_area;
@@ -162,7 +146,7 @@ fn build_light_cdf_table(vertices: &[Vec4], indices: &[UVec4], mask: &[bool]) ->
 }
 
 /*
-pub fn compare_approaches(vertices: &[Vec4], indices: &[UVec4], mask: &[bool]) {
+pub fn compare_approaches(vertices: &[PerVertexData], indices: &[UVec4], mask: &[bool]) {
     let table = build_light_pick_table(vertices, indices, mask);
     let cdf_table = build_light_cdf_table(vertices, indices, mask);
     let root = BitMapBackend::new("bla.png", (640, 480)).into_drawing_area();
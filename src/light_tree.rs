@@ -0,0 +1,141 @@
+use std::f32::consts::PI;
+
+use glam::{UVec4, Vec3};
+use shared_structs::{LightTreeNode, MaterialData, PerVertexData, PrimitiveType, material_index};
+
+use crate::primitive::{primitive_area, primitive_bounds, primitive_centroid};
+
+#[derive(Copy, Clone)]
+struct Cone {
+    axis: Vec3,
+    theta_o: f32,
+}
+
+impl Cone {
+    // Smallest cone containing both cones, see "Importance Sampling of Many Lights with Adaptive Tree Splitting"
+    fn union(self, other: Cone) -> Cone {
+        let (a, b) = if self.theta_o >= other.theta_o { (self, other) } else { (other, self) };
+        let theta_d = a.axis.dot(b.axis).clamp(-1.0, 1.0).acos();
+        if (theta_d + b.theta_o).min(PI) <= a.theta_o {
+            return a;
+        }
+        let theta_o = (a.theta_o + theta_d + b.theta_o) * 0.5;
+        if theta_o >= PI {
+            return Cone { axis: a.axis, theta_o: PI };
+        }
+
+        // Rotate the axis of the wider cone towards the other one
+        let theta_r = theta_o - a.theta_o;
+        let perpendicular = (b.axis - a.axis * a.axis.dot(b.axis)).normalize_or_zero();
+        if perpendicular == Vec3::ZERO {
+            return Cone { axis: a.axis, theta_o: PI }; // Opposing axes
+        }
+        Cone {
+            axis: (a.axis * theta_r.cos() + perpendicular * theta_r.sin()).normalize(),
+            theta_o,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct LightPrimitive {
+    triangle_index: u32,
+    aabb_min: Vec3,
+    aabb_max: Vec3,
+    centroid: Vec3,
+    power: f32,
+    area: f32,
+    cone: Cone,
+}
+
+// NOTE: `mask` indicates which triangles are valid for picking
+pub fn build_light_tree(
+    vertices: &[PerVertexData],
+    indices: &[UVec4],
+    mask: &[bool],
+    material_datas: &[MaterialData],
+) -> Vec<LightTreeNode> {
+    let mut primitives = Vec::new();
+    for i in 0..indices.len() {
+        if !mask[i] {
+            continue;
+        }
+        let triangle = indices[i];
+        let area = primitive_area(vertices, triangle);
+        let power = material_datas[material_index(triangle) as usize].emission().dot(Vec3::ONE) * area;
+        if power <= 0.0 {
+            continue;
+        }
+
+        let cone = if PrimitiveType::of(triangle) == PrimitiveType::Sphere {
+            Cone { axis: Vec3::Y, theta_o: PI } // Emits in every direction
+        } else {
+            // Match the kernel, which samples lights with their geometric normal
+            let a = vertices[triangle.x as usize].vertex;
+            let b = vertices[triangle.y as usize].vertex;
+            let c = vertices[triangle.z as usize].vertex;
+            Cone { axis: (b - a).cross(c - a).normalize(), theta_o: 0.0 }
+        };
+        let (aabb_min, aabb_max) = primitive_bounds(vertices, triangle);
+        primitives.push(LightPrimitive {
+            triangle_index: i as u32,
+            aabb_min,
+            aabb_max,
+            centroid: primitive_centroid(vertices, triangle),
+            power,
+            area,
+            cone,
+        });
+    }
+
+    if primitives.is_empty() {
+        // wgpu doesn't allow 0-sized buffers. Without lights, the light pick table is a sentinel, so this is never read.
+        return vec![LightTreeNode::default()];
+    }
+
+    let mut nodes = vec![LightTreeNode::default(); primitives.len() * 2 - 1];
+    let mut next_node_index = 1;
+    build_node(&mut nodes, &mut next_node_index, 0, &mut primitives);
+    nodes
+}
+
+fn build_node(nodes: &mut [LightTreeNode], next_node_index: &mut usize, node_index: usize, primitives: &mut [LightPrimitive]) {
+    let mut aabb_min = Vec3::splat(f32::INFINITY);
+    let mut aabb_max = Vec3::splat(f32::NEG_INFINITY);
+    let mut centroid_min = Vec3::splat(f32::INFINITY);
+    let mut centroid_max = Vec3::splat(f32::NEG_INFINITY);
+    let mut power = 0.0;
+    let mut cone = primitives[0].cone;
+    for primitive in primitives.iter() {
+        aabb_min = aabb_min.min(primitive.aabb_min);
+        aabb_max = aabb_max.max(primitive.aabb_max);
+        centroid_min = centroid_min.min(primitive.centroid);
+        centroid_max = centroid_max.max(primitive.centroid);
+        power += primitive.power;
+        cone = cone.union(primitive.cone);
+    }
+
+    let node = &mut nodes[node_index];
+    node.aabb_min = aabb_min.extend(power);
+    node.aabb_max = aabb_max.extend(cone.theta_o);
+    node.cone_axis = cone.axis.extend(0.0);
+    if primitives.len() == 1 {
+        node.cone_axis.w = primitives[0].area;
+        node.left_node_index = primitives[0].triangle_index;
+        node.leaf = 1;
+        return;
+    }
+
+    // Median split along the longest axis of the centroid bounds
+    let extent = centroid_max - centroid_min;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+    let mid = primitives.len() / 2;
+    primitives.select_nth_unstable_by(mid, |a, b| a.centroid[axis].total_cmp(&b.centroid[axis]));
+
+    let left_node_index = *next_node_index;
+    *next_node_index += 2;
+    node.left_node_index = left_node_index as u32;
+    let (left, right) = primitives.split_at_mut(mid);
+    build_node(nodes, next_node_index, left_node_index, left);
+    build_node(nodes, next_node_index, left_node_index + 1, right);
+}
@@ -2,10 +2,154 @@ use std::time::Instant;
 use egui::FontDefinitions;
 use egui_winit_platform::{Platform, PlatformDescriptor};
 use winit::event::Event::{DeviceEvent, WindowEvent, MainEventsCleared, RedrawRequested};
-use rustic::app::App;
+use rustic::app::{App, Tonemapping};
+use glam::{Mat4, Vec3};
+use rustic::asset::{CoordinateSystem, Handedness, SceneFile, UpAxis};
+use rustic::denoise::Denoiser;
+use rustic::encode::AlphaMode;
+use rustic::post::{PostProcessConfig, DEFAULT_BLOOM_STRENGTH, DEFAULT_DESPECKLE_THRESHOLD};
+use shared_structs::TracingConfig;
+use rustic::trace::Aov;
+use shared_structs::{ClampMode, MisHeuristic, RussianRoulette, WorkingSpace};
 use winit::event_loop::ControlFlow;
 
+// Parses `x,y,z`
+fn parse_vec3(text: &str) -> Option<Vec3> {
+    let components = text.split(',').map(|c| c.trim().parse().ok()).collect::<Option<Vec<f32>>>()?;
+    match components[..] {
+        [x, y, z] => Some(Vec3::new(x, y, z)),
+        _ => None,
+    }
+}
+
+// For the modes that run without a window, which look their options up rather than going through them in order
+fn value_of<'a>(arguments: &'a [String], flag: &str) -> Option<&'a String> {
+    arguments.iter().position(|arg| arg == flag).and_then(|position| arguments.get(position + 1))
+}
+
+fn positive_number(arguments: &[String], flag: &str, default: u32) -> u32 {
+    match value_of(arguments, flag).map(|value| value.parse::<u32>().ok().filter(|n| *n > 0)) {
+        None => default,
+        Some(Some(n)) => n,
+        Some(None) => {
+            println!("Warning: {} needs a positive number", flag);
+            default
+        }
+    }
+}
+
+// Bake the lighting of the scene given with `--scene` into a lightmap on the CPU, and save it as lightmap.exr
+fn bake(arguments: &[String]) {
+    use rustic::bake::{bake_lightmap, DEFAULT_BAKE_RESOLUTION, DEFAULT_BAKE_SAMPLES};
+
+    let resolution = positive_number(arguments, "--bake-res", DEFAULT_BAKE_RESOLUTION);
+    let samples = positive_number(arguments, "--bake-samples", DEFAULT_BAKE_SAMPLES);
+    let Some(scene) = value_of(arguments, "--scene") else {
+        println!("Error: --bake needs a scene to bake, given with --scene");
+        return;
+    };
+    let Some(world) = rustic::asset::World::load(&[SceneFile::new(scene)], CoordinateSystem::default()) else {
+        return;
+    };
+
+    let config = shared_structs::TracingConfig {
+        nee: shared_structs::NextEventEstimation::MultipleImportanceSampling.to_u32(),
+        has_skybox: value_of(arguments, "--skybox").is_some() as u32,
+        ..Default::default()
+    };
+    let start = Instant::now();
+    let lightmap = bake_lightmap(&world, value_of(arguments, "--skybox").map(String::as_str), &config, resolution, samples);
+    match rustic::encode::save_exr("lightmap.exr", resolution, resolution, &lightmap.rgb, None) {
+        Ok(()) => println!("Baked lightmap.exr in {:.2?}", start.elapsed()),
+        Err(err) => println!("Warning: Failed to save lightmap.exr, {}", err),
+    }
+}
+
+// Render the scene given with `--scene` as independent strata, and save the mean, the variance between them and the
+// 95% confidence interval of the mean as bootstrap_mean.exr, bootstrap_variance.exr and bootstrap_confidence.exr
+fn bootstrap(arguments: &[String]) {
+    use rustic::render::{Renderer, DEFAULT_BOOTSTRAP_SAMPLES};
+
+    let strata = positive_number(arguments, "--bootstrap", 0);
+    if strata < 2 {
+        println!("Error: --bootstrap needs at least 2 strata to estimate the variance between them");
+        return;
+    }
+    let samples = positive_number(arguments, "--bootstrap-samples", DEFAULT_BOOTSTRAP_SAMPLES);
+    let Some(scene) = value_of(arguments, "--scene") else {
+        println!("Error: --bootstrap needs a scene to render, given with --scene");
+        return;
+    };
+
+    let (width, height) = (1280, 720);
+    let mut renderer = Renderer::new(vec![SceneFile::new(scene)], width, height);
+    renderer.skybox = value_of(arguments, "--skybox").cloned();
+    {
+        let mut config = renderer.state.config.write();
+        config.nee = shared_structs::NextEventEstimation::MultipleImportanceSampling.to_u32();
+        config.has_skybox = renderer.skybox.is_some() as u32;
+        if let Some(builtin) = rustic::scenes::find_builtin_scene(scene) {
+            config.cam_position = builtin.camera_position;
+            config.cam_rotation = builtin.camera_rotation;
+        }
+    }
+
+    let start = Instant::now();
+    let Some(bootstrap) = renderer.render_strata(strata, samples, |done| println!("Rendered stratum {} of {}", done, strata)) else {
+        println!("Error: Failed to render {}", scene);
+        return;
+    };
+    for (path, image) in [
+        ("bootstrap_mean.exr", &bootstrap.mean),
+        ("bootstrap_variance.exr", &bootstrap.variance),
+        ("bootstrap_confidence.exr", &bootstrap.confidence),
+    ] {
+        if let Err(err) = rustic::encode::save_exr(path, width, height, image, None) {
+            println!("Warning: Failed to save {}, {}", path, err);
+        }
+    }
+    let relative = bootstrap.confidence.iter().sum::<f32>() / bootstrap.mean.iter().sum::<f32>().max(f32::MIN_POSITIVE);
+    println!("Rendered {} strata of {} samples in {:.2?}, the 95% confidence interval is ±{:.2}% of the mean on average", strata, samples, start.elapsed(), relative * 100.0);
+}
+
 fn main() {
+    // `--list-adapters` prints the GPUs that can be traced on, and exits without opening a window
+    if std::env::args().skip(1).any(|arg| arg == "--list-adapters") {
+        rustic::adapter::print_adapters();
+        return;
+    }
+
+    // `--adapter <index|name>` traces on the adapter with that index in the list, or the first one whose name contains
+    // the text. It has to be picked before anything is traced, so it is handled ahead of the other arguments.
+    let arguments = std::env::args().skip(1).collect::<Vec<_>>();
+    if let Some(position) = arguments.iter().position(|arg| arg == "--adapter") {
+        match arguments.get(position + 1).map(|selector| rustic::adapter::select_adapter(selector)) {
+            Some(Ok(())) => {}
+            Some(Err(error)) => {
+                println!("Error: {}", error);
+                return;
+            }
+            None => println!("Warning: --adapter needs an index or a name"),
+        }
+    }
+
+    // `--bake` bakes the lighting of the `--scene` into a lightmap, in its second UV set, and saves it as lightmap.exr
+    // without opening a window. `--bake-res <n>` sets the width and height of the lightmap, 1024 by default, and
+    // `--bake-samples <n>` the paths traced per texel, 256 by default. `--skybox` lights it like when rendering.
+    if arguments.iter().any(|arg| arg == "--bake") {
+        bake(&arguments);
+        return;
+    }
+
+    // `--bootstrap <m>` renders the `--scene` m times, each from its own seed stream, and saves the mean, the variance
+    // between the renders and the 95% confidence interval of the mean as EXR files, without opening a window. This is
+    // the error of the estimator as a whole, rather than the variance of the samples within a pixel, for comparing
+    // samplers. `--bootstrap-samples <n>` sets the samples per render, 64 by default. `--skybox` lights it as usual.
+    if arguments.iter().any(|arg| arg == "--bootstrap") {
+        bootstrap(&arguments);
+        return;
+    }
+
     let width = 1280;
     let height = 720;
 
@@ -14,7 +158,7 @@ fn main() {
         .with_decorations(true)
         .with_resizable(true)
         .with_transparent(false)
-        .with_title("rust-path-tracer")
+        .with_title(rustic::app::WINDOW_TITLE)
         .with_inner_size(winit::dpi::PhysicalSize {
             width,
             height,
@@ -32,6 +176,341 @@ fn main() {
 
     let mut app = App::new(window);
 
+    // `--scene <path>` starts rendering a scene right away. The path can also be the name of a built-in scene.
+    // `--transparent` leaves the background empty, so saved images get alpha.
+    // `--dither` adds a little blue noise to 8-bit output before it is quantized, so smooth gradients don't band.
+    // `--dither-seed <n>` shifts that noise, and turns dithering on.
+    // `--alpha straight|premultiplied` picks how color relates to alpha in saved images, straight by default.
+    // `--aperture-blades <n>` gives the aperture n sides, so out of focus highlights become polygons.
+    // `--up y|z` and `--handedness left|right` describe the convention of the scene file, Z-up and right handed by default.
+    // `--flip-winding` and `--flip-normals` turn the triangles or the normals of scene files around, for meshes that were
+    // exported inside out. Files without normals get them from the winding, so these usually need both.
+    // `--mesh <path>` adds a scene file to a scene merged from several files, and can be repeated. It can be followed by
+    // `--mesh-scale <s>`, `--mesh-rotate <degrees>` (around the up axis) and `--mesh-offset <x,y,z>` to place that file,
+    // which are applied in the order they are given.
+    // `--working-space linear-srgb|acescg` picks the color space light transport is computed in.
+    // `--skybox <path>` uses an image as the skybox instead of the procedural sky. With `--scene empty`, only the
+    // skybox is rendered, which is handy for checking its orientation.
+    // `--env-rotation <degrees>` rotates the skybox image around the up axis.
+    // `--aov diffuse,specular` also saves the listed passes as .exr files whenever an image is saved.
+    // `--near <distance>` cuts away everything closer to the camera than the distance, for cameras inside geometry.
+    // `--noise static` gives every frame of an animation the same noise pattern, so a static camera doesn't flicker.
+    // `--noise animated` varies it per frame, for grain that looks natural in motion. Static is the default.
+    // `--t-min <distance>` and `--t-max <distance>` set the range of distances along a ray at which hits count. Raise
+    // t-min for large scenes with self-intersection artifacts, lower it for small ones where thin gaps leak light.
+    // `--pixel-aspect <ratio>` renders pixels this many times wider than they are tall, for anamorphic formats.
+    // `--clamp-indirect <value>` limits how bright light which bounced more than once can be per sample, removing
+    // fireflies from indirect light without dimming direct light. `--clamp-mode luminance|channel` picks whether the
+    // color is scaled down as a whole until its luminance is at the limit, keeping its hue, which is the default, or
+    // each channel is clamped on its own.
+    // `--ambient r,g,b` adds a constant fill light to every surface, so scenes without lights can be previewed. Not
+    // physically based.
+    // `--bg r,g,b` replaces the procedural sky with a flat color, which lights the scene like the sky would. A skybox
+    // still takes precedence.
+    // `--no-shadows` stops light samples from checking whether they are blocked, so direct light is unoccluded. Only
+    // meant for fast previews, such as when iterating on materials.
+    // `--light-samples <n>` takes n light samples per bounce and averages them. Each costs a shadow ray, and only
+    // direct light gets less noisy, so it pays off in scenes with many lights, where more pixel samples would also
+    // resample the camera and indirect paths.
+    // `--russian-roulette throughput|albedo` picks what the chance of a path going on is based on. albedo also ends
+    // paths sooner after dark surfaces, which saves rays in scenes with dark materials.
+    // `--mis-heuristic power|balance|cutoff|maximum` picks how light samples and BSDF samples of the same light are
+    // weighted against each other with multiple importance sampling, for comparing their variance. power is the default.
+    // `--tonemap <name>` picks the tonemapping operator: none, reinhard, aces, aces-overexposed, aces-hill, neutral,
+    // uncharted or agx. AgX desaturates very bright colors towards white instead of clipping them to a primary.
+    // `--exposure <ev>` brightens the image by 2^ev before tonemapping, or darkens it when negative. Saved .exr and .hdr
+    // files get it too.
+    // `--white-balance <kelvin>` scales the colors of the image so light of that color temperature looks white, and
+    // `--tint <value>` shifts them towards magenta, or green when negative. Applied before tonemapping.
+    // `--despeckle` removes isolated fireflies from the image.
+    // `--bloom-strength <value>` blurs light brighter than `--bloom-threshold <value>`, 1 by default, over its
+    // surroundings, and blends it in with that weight. Either one turns bloom on, with a strength of 0.1 by default.
+    // `--ca-strength <value>` shifts red and blue apart towards the edges of the image, by value percent at the edge.
+    // `--vignette <value>` darkens the corners of the image by that fraction, between 0 and 1.
+    // `--sbvh` builds the BVH with spatial splits, which loads slower but traces thin triangles faster. It only affects
+    // scenes loaded after it, so it goes before `--scene`.
+    // `--frame-all` moves the camera back along its view direction until the whole scene fits in view, for meshes
+    // without a known good camera placement. Like `--sbvh`, it goes before `--scene`.
+    // `--use-gltf-camera` looks through the first camera of a glTF scene, and `--gltf-camera <name>` through the camera
+    // with that name, or on a node with that name. Perspective and orthographic cameras both work. Goes before `--scene`.
+    // `--denoiser none|atrous|oidn` picks the denoiser. atrous is an edge-avoiding wavelet filter guided by the albedo
+    // and normal buffers, which runs as compute passes when rendering on the GPU. oidn needs the `oidn` feature. How
+    // long denoising took is printed with `--timings`, to compare them.
+    // `--oidn-prefilter` denoises with OIDN, guided by albedo and normal buffers which are denoised first. Needs the
+    // `oidn` feature.
+    // `--denoise-interval <n>` only denoises every nth frame, showing the raw image in between. Paused frames are always denoised.
+    // `--time-budget <secs>` stops rendering once the image has accumulated for that many seconds, and saves it as
+    // render_<n>spp.png, where n is the number of samples it got to.
+    // `-o <path>` saves that render to the path instead. The extension picks the format: .exr and .hdr keep the linear
+    // HDR image, .png gets the image as it is on screen.
+    // `--target-fps <n>` lowers the bounce count while frames take longer than 1/n seconds, trading accuracy for speed.
+    // `--trace-stats` prints the mean throughput of the paths at each bounce when tracing stops, for debugging dark renders.
+    // `--timings` prints how long loading, building the BVH, uploading, rendering, reading back and saving took when
+    // tracing stops, to tell which stage a slow run is bound by. GPU rendering is timed with timestamp queries where
+    // the adapter supports them, and with the CPU clock otherwise.
+    // `--profile` prints both, which together give the rays cast, the primitives tested and the ray throughput next to
+    // the time each stage took.
+    // `--samples-per-dispatch <n>` has each GPU dispatch render n samples, so fewer dispatches are needed. GPU only.
+    // `--kernel <path.spv>` traces with a kernel compiled separately instead of the embedded one, so shaders can be
+    // iterated on without rebuilding the host. A kernel that can't be loaded is an error, nothing is traced. GPU only.
+    // `--hot-reload` reloads the kernel whenever it is rebuilt, for iterating on it without restarting. GPU only.
+    let mut coordinate_system = CoordinateSystem::default();
+    let mut meshes: Vec<SceneFile> = Vec::new();
+    let mut bloom_threshold = None;
+    let mut bloom_strength = None;
+    let mut ray_t_min = None;
+    let mut ray_t_max = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scene" => match args.next() {
+                Some(scene) => app.set_scene(&scene),
+                None => println!("Warning: --scene needs a scene path or name"),
+            },
+            "--transparent" => app.set_transparent_background(true),
+            "--dither" => app.set_dither(true),
+            "--dither-seed" => match args.next().and_then(|seed| seed.parse().ok()) {
+                Some(seed) => {
+                    app.set_dither(true);
+                    app.set_dither_seed(seed);
+                }
+                None => println!("Warning: --dither-seed needs a number"),
+            },
+            "--alpha" => match args.next().as_deref().and_then(AlphaMode::from_name) {
+                Some(alpha_mode) => app.set_alpha_mode(alpha_mode),
+                None => println!("Warning: --alpha needs to be straight or premultiplied"),
+            },
+            "--aperture-blades" => match args.next().and_then(|blades| blades.parse().ok()) {
+                Some(blades) => app.set_aperture_blades(blades),
+                None => println!("Warning: --aperture-blades needs a number"),
+            },
+            "--up" => match args.next().as_deref().and_then(UpAxis::from_name) {
+                Some(up) => {
+                    coordinate_system.up = up;
+                    app.set_coordinate_system(coordinate_system);
+                }
+                None => println!("Warning: --up needs to be y or z"),
+            },
+            "--handedness" => match args.next().as_deref().and_then(Handedness::from_name) {
+                Some(handedness) => {
+                    coordinate_system.handedness = handedness;
+                    app.set_coordinate_system(coordinate_system);
+                }
+                None => println!("Warning: --handedness needs to be left or right"),
+            },
+            "--flip-winding" => {
+                coordinate_system.flip_winding = true;
+                app.set_coordinate_system(coordinate_system);
+            }
+            "--flip-normals" => {
+                coordinate_system.flip_normals = true;
+                app.set_coordinate_system(coordinate_system);
+            }
+            "--working-space" => match args.next().as_deref().and_then(WorkingSpace::from_name) {
+                Some(working_space) => app.set_working_space(working_space),
+                None => println!("Warning: --working-space needs to be linear-srgb or acescg"),
+            },
+            "--skybox" => match args.next() {
+                Some(path) => app.set_skybox(&path),
+                None => println!("Warning: --skybox needs a path to an image"),
+            },
+            "--env-rotation" => match args.next().and_then(|degrees| degrees.parse().ok()) {
+                Some(degrees) => app.set_skybox_rotation(degrees),
+                None => println!("Warning: --env-rotation needs a number of degrees"),
+            },
+            "--aov" => match args.next().and_then(|names| names.split(',').map(|name| Aov::from_name(name.trim())).collect()) {
+                Some(aovs) => app.set_aovs(aovs),
+                None => println!("Warning: --aov needs a comma separated list of diffuse and specular"),
+            },
+            "--near" => match args.next().and_then(|distance| distance.parse::<f32>().ok()).filter(|distance| *distance >= 0.0) {
+                Some(distance) => app.set_near_clip(distance),
+                None => println!("Warning: --near needs a non-negative distance"),
+            },
+            "--noise" => match args.next().as_deref().and_then(|mode| match mode {
+                "static" => Some(false),
+                "animated" => Some(true),
+                _ => None,
+            }) {
+                Some(animated) => app.set_animated_noise(animated),
+                None => println!("Warning: --noise needs to be static or animated"),
+            },
+            "--t-min" => match args.next().and_then(|distance| distance.parse::<f32>().ok()).filter(|distance| *distance >= 0.0) {
+                Some(distance) => ray_t_min = Some(distance),
+                None => println!("Warning: --t-min needs a non-negative distance"),
+            },
+            "--t-max" => match args.next().and_then(|distance| distance.parse::<f32>().ok()).filter(|distance| *distance > 0.0) {
+                Some(distance) => ray_t_max = Some(distance),
+                None => println!("Warning: --t-max needs a positive distance"),
+            },
+            "--pixel-aspect" => match args.next().and_then(|ratio| ratio.parse::<f32>().ok()).filter(|ratio| *ratio > 0.0) {
+                Some(ratio) => app.set_pixel_aspect(ratio),
+                None => println!("Warning: --pixel-aspect needs a positive number"),
+            },
+            "--clamp-indirect" => match args.next().and_then(|clamp| clamp.parse().ok()) {
+                Some(clamp) => app.set_indirect_clamp(clamp),
+                None => println!("Warning: --clamp-indirect needs a number"),
+            },
+            "--clamp-mode" => match args.next().as_deref().and_then(ClampMode::from_name) {
+                Some(clamp_mode) => app.set_clamp_mode(clamp_mode),
+                None => println!("Warning: --clamp-mode needs to be luminance or channel"),
+            },
+            "--ambient" => match args.next().as_deref().and_then(parse_vec3).filter(|ambient| ambient.min_element() >= 0.0) {
+                Some(ambient) => {
+                    app.set_ambient(ambient);
+                    if ambient != Vec3::ZERO {
+                        println!("Note: Ambient light is a preview aid and not physically based, turn it off for final renders");
+                    }
+                }
+                None => println!("Warning: --ambient needs a non-negative color as r,g,b"),
+            },
+            "--bg" => match args.next().as_deref().and_then(parse_vec3).filter(|color| color.min_element() >= 0.0) {
+                Some(color) => app.set_background_color(color),
+                None => println!("Warning: --bg needs a non-negative color as r,g,b"),
+            },
+            "--no-shadows" => {
+                app.set_shadows(false);
+                println!("Note: Shadows are off, so direct light isn't occluded. This is a preview aid, turn it off for final renders");
+            }
+            "--light-samples" => match args.next().and_then(|samples| samples.parse().ok()).filter(|samples| *samples > 0) {
+                Some(samples) => app.set_light_samples(samples),
+                None => println!("Warning: --light-samples needs a positive number"),
+            },
+            "--russian-roulette" => match args.next().as_deref().and_then(RussianRoulette::from_name) {
+                Some(russian_roulette) => app.set_russian_roulette(russian_roulette),
+                None => println!("Warning: --russian-roulette needs to be throughput or albedo"),
+            },
+            "--mis-heuristic" => match args.next().as_deref().and_then(MisHeuristic::from_name) {
+                Some(heuristic) => app.set_mis_heuristic(heuristic),
+                None => println!("Warning: --mis-heuristic needs to be power, balance, cutoff or maximum"),
+            },
+            "--tonemap" => match args.next().as_deref().and_then(Tonemapping::from_name) {
+                Some(tonemapping) => app.set_tonemapping(tonemapping),
+                None => println!("Warning: --tonemap needs to be none, reinhard, aces, aces-overexposed, aces-hill, neutral, uncharted or agx"),
+            },
+            "--exposure" => match args.next().and_then(|exposure| exposure.parse().ok()) {
+                Some(exposure) => app.set_exposure(exposure),
+                None => println!("Warning: --exposure needs a number of stops"),
+            },
+            "--white-balance" => match args.next().and_then(|kelvin| kelvin.parse::<f32>().ok()).filter(|kelvin| *kelvin > 0.0) {
+                Some(kelvin) => app.set_white_balance(kelvin),
+                None => println!("Warning: --white-balance needs a positive temperature in kelvin"),
+            },
+            "--tint" => match args.next().and_then(|tint| tint.parse().ok()) {
+                Some(tint) => app.set_tint(tint),
+                None => println!("Warning: --tint needs a number"),
+            },
+            "--despeckle" => app.set_despeckle_threshold(DEFAULT_DESPECKLE_THRESHOLD),
+            "--ca-strength" => match args.next().and_then(|strength| strength.parse::<f32>().ok()).filter(|strength| *strength >= 0.0) {
+                Some(strength) => app.set_chromatic_aberration(strength),
+                None => println!("Warning: --ca-strength needs a number that is at least 0"),
+            },
+            "--vignette" => match args.next().and_then(|strength| strength.parse::<f32>().ok()).filter(|strength| (0.0..=1.0).contains(strength)) {
+                Some(strength) => app.set_vignette(strength),
+                None => println!("Warning: --vignette needs a number between 0 and 1"),
+            },
+            "--bloom-threshold" => match args.next().and_then(|threshold| threshold.parse::<f32>().ok()).filter(|threshold| *threshold >= 0.0) {
+                Some(threshold) => bloom_threshold = Some(threshold),
+                None => println!("Warning: --bloom-threshold needs a number that is at least 0"),
+            },
+            "--bloom-strength" => match args.next().and_then(|strength| strength.parse::<f32>().ok()).filter(|strength| *strength >= 0.0) {
+                Some(strength) => bloom_strength = Some(strength),
+                None => println!("Warning: --bloom-strength needs a number that is at least 0"),
+            },
+            "--sbvh" => app.set_spatial_splits(true),
+            "--frame-all" => app.set_frame_all(true),
+            "--use-gltf-camera" => app.set_gltf_camera(Some(String::new())),
+            "--gltf-camera" => match args.next() {
+                Some(name) => app.set_gltf_camera(Some(name)),
+                None => println!("Warning: --gltf-camera needs the name of a camera"),
+            },
+            "--hot-reload" => app.set_hot_reload(true),
+            "--kernel" => match args.next() {
+                Some(path) => if let Err(error) = app.set_kernel_path(&path) {
+                    println!("Error: {}", error);
+                    return;
+                },
+                None => println!("Warning: --kernel needs a path to a SPIR-V file"),
+            },
+            "--adapter" => {
+                args.next(); // Already picked above
+            }
+            "--trace-stats" => app.set_trace_stats(true),
+            "--timings" => app.set_print_timings(true),
+            "--profile" => {
+                app.set_trace_stats(true);
+                app.set_print_timings(true);
+            }
+            "--denoiser" => match args.next().as_deref().and_then(Denoiser::from_name) {
+                Some(denoiser) if denoiser.is_available() => app.set_denoiser(denoiser),
+                Some(_) => println!("Warning: --denoiser oidn needs the path tracer to be built with the oidn feature"),
+                None => println!("Warning: --denoiser needs to be none, atrous or oidn"),
+            },
+            "--oidn-prefilter" => {
+                if Denoiser::OidnPrefiltered.is_available() {
+                    app.set_denoiser(Denoiser::OidnPrefiltered);
+                } else {
+                    println!("Warning: --oidn-prefilter needs the path tracer to be built with the oidn feature");
+                }
+            }
+            "--denoise-interval" => match args.next().and_then(|interval| interval.parse().ok()) {
+                Some(interval) => app.set_denoise_interval(interval),
+                None => println!("Warning: --denoise-interval needs a number"),
+            },
+            "--samples-per-dispatch" => match args.next().and_then(|samples| samples.parse().ok()).filter(|samples| *samples > 0) {
+                Some(samples) => app.set_samples_per_dispatch(samples),
+                None => println!("Warning: --samples-per-dispatch needs a positive number"),
+            },
+            "--target-fps" => match args.next().and_then(|fps| fps.parse().ok()) {
+                Some(fps) => app.set_target_fps(fps),
+                None => println!("Warning: --target-fps needs a number"),
+            },
+            "-o" => match args.next().filter(|path| [".exr", ".hdr", ".png"].iter().any(|extension| path.to_lowercase().ends_with(extension))) {
+                Some(path) => app.set_output_path(&path),
+                None => println!("Warning: -o needs a path ending in .exr, .hdr or .png"),
+            },
+            "--time-budget" => match args.next().and_then(|seconds| seconds.parse().ok()).filter(|seconds| *seconds > 0) {
+                Some(seconds) => app.set_time_budget(seconds),
+                None => println!("Warning: --time-budget needs a positive number of seconds"),
+            },
+            "--mesh" => match args.next() {
+                Some(path) => meshes.push(SceneFile::new(&path)),
+                None => println!("Warning: --mesh needs a path"),
+            },
+            "--mesh-scale" | "--mesh-rotate" | "--mesh-offset" => {
+                let value = args.next();
+                let transform = match arg.as_str() {
+                    "--mesh-scale" => value.and_then(|s| s.parse().ok()).map(|s: f32| Mat4::from_scale(Vec3::splat(s))),
+                    "--mesh-rotate" => value.and_then(|s| s.parse().ok()).map(|d: f32| Mat4::from_rotation_y(d.to_radians())),
+                    _ => value.as_deref().and_then(parse_vec3).map(Mat4::from_translation),
+                };
+                match (meshes.last_mut(), transform) {
+                    (Some(mesh), Some(transform)) => mesh.transform = transform * mesh.transform,
+                    (None, _) => println!("Warning: {} must come after a --mesh", arg),
+                    (_, None) => println!("Warning: {} has an invalid value", arg),
+                }
+            }
+            _ => println!("Warning: Ignoring unknown argument {}", arg),
+        }
+    }
+    if !meshes.is_empty() {
+        app.set_scene_files(meshes);
+    }
+    if bloom_threshold.is_some() || bloom_strength.is_some() {
+        app.set_bloom(
+            bloom_threshold.unwrap_or(PostProcessConfig::default().bloom_threshold),
+            bloom_strength.unwrap_or(DEFAULT_BLOOM_STRENGTH),
+        );
+    }
+    if ray_t_min.is_some() || ray_t_max.is_some() {
+        let t_min = ray_t_min.unwrap_or(TracingConfig::default().ray_t_min);
+        let t_max = ray_t_max.unwrap_or(TracingConfig::default().ray_t_max);
+        if t_min < t_max {
+            app.set_ray_range(t_min, t_max);
+        } else {
+            println!("Warning: --t-min must be smaller than --t-max, ignoring both");
+        }
+    }
+
     let start_time = Instant::now();
     event_loop.run(move |event, _, control_flow| {
         // Pass the winit events to the platform integration.
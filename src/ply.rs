@@ -0,0 +1,279 @@
+// Loader for PLY meshes, which scanners and research datasets commonly produce. Supports the ASCII and binary little
+// endian encodings, with positions, optional normals and optional vertex colors. Polygons are triangulated as fans, and
+// elements other than vertices and faces are skipped.
+
+use glam::{UVec3, Vec3, Vec4};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum ScalarType {
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Float32,
+    Float64,
+}
+
+impl ScalarType {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "char" | "int8" => Some(ScalarType::Int8),
+            "uchar" | "uint8" => Some(ScalarType::UInt8),
+            "short" | "int16" => Some(ScalarType::Int16),
+            "ushort" | "uint16" => Some(ScalarType::UInt16),
+            "int" | "int32" => Some(ScalarType::Int32),
+            "uint" | "uint32" => Some(ScalarType::UInt32),
+            "float" | "float32" => Some(ScalarType::Float32),
+            "double" | "float64" => Some(ScalarType::Float64),
+            _ => None,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            ScalarType::Int8 | ScalarType::UInt8 => 1,
+            ScalarType::Int16 | ScalarType::UInt16 => 2,
+            ScalarType::Int32 | ScalarType::UInt32 | ScalarType::Float32 => 4,
+            ScalarType::Float64 => 8,
+        }
+    }
+
+    // Integer colors go from 0 to the largest value of their type, float colors from 0 to 1
+    fn color_scale(self) -> f64 {
+        match self {
+            ScalarType::Int8 => i8::MAX as f64,
+            ScalarType::UInt8 => u8::MAX as f64,
+            ScalarType::Int16 => i16::MAX as f64,
+            ScalarType::UInt16 => u16::MAX as f64,
+            ScalarType::Int32 => i32::MAX as f64,
+            ScalarType::UInt32 => u32::MAX as f64,
+            ScalarType::Float32 | ScalarType::Float64 => 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Property {
+    Scalar { name: String, value_type: ScalarType },
+    List { name: String, count_type: ScalarType, item_type: ScalarType },
+}
+
+impl Property {
+    fn name(&self) -> &str {
+        match self {
+            Property::Scalar { name, .. } | Property::List { name, .. } => name,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+}
+
+// The data after the header, read one value at a time
+enum Body<'a> {
+    Ascii(std::str::SplitAsciiWhitespace<'a>),
+    Binary(&'a [u8]),
+}
+
+impl<'a> Body<'a> {
+    fn read(&mut self, value_type: ScalarType) -> Result<f64, String> {
+        match self {
+            Body::Ascii(tokens) => {
+                let token = tokens.next().ok_or("the file ends before all elements are read")?;
+                token.parse::<f64>().map_err(|_| format!("\"{}\" is not a number", token))
+            }
+            Body::Binary(bytes) => {
+                let size = value_type.size();
+                if bytes.len() < size {
+                    return Err("the file ends before all elements are read".to_string());
+                }
+                let (value, rest) = (*bytes).split_at(size);
+                *bytes = rest;
+                Ok(match value_type {
+                    ScalarType::Int8 => value[0] as i8 as f64,
+                    ScalarType::UInt8 => value[0] as f64,
+                    ScalarType::Int16 => i16::from_le_bytes([value[0], value[1]]) as f64,
+                    ScalarType::UInt16 => u16::from_le_bytes([value[0], value[1]]) as f64,
+                    ScalarType::Int32 => i32::from_le_bytes([value[0], value[1], value[2], value[3]]) as f64,
+                    ScalarType::UInt32 => u32::from_le_bytes([value[0], value[1], value[2], value[3]]) as f64,
+                    ScalarType::Float32 => f32::from_le_bytes([value[0], value[1], value[2], value[3]]) as f64,
+                    ScalarType::Float64 => f64::from_le_bytes(value.try_into().unwrap()),
+                })
+            }
+        }
+    }
+
+    fn read_list(&mut self, count_type: ScalarType, item_type: ScalarType) -> Result<Vec<f64>, String> {
+        let count = self.read(count_type)?;
+        if count < 0.0 {
+            return Err(format!("a list has a negative length of {}", count));
+        }
+        (0..count as usize).map(|_| self.read(item_type)).collect()
+    }
+}
+
+// A mesh read from a PLY file. Normals are computed from the faces when the file has none. Colors are only there if
+// the file has them, and are normalized to [0, 1].
+#[derive(Clone, Debug, Default)]
+pub struct PlyMesh {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub colors: Option<Vec<Vec4>>,
+    pub triangles: Vec<UVec3>,
+}
+
+fn parse_header(header: &str) -> Result<(Format, Vec<Element>), String> {
+    let mut lines = header.lines().map(str::trim);
+    if lines.next() != Some("ply") {
+        return Err("not a PLY file".to_string());
+    }
+
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in lines {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        match words[..] {
+            ["format", "ascii", _] => format = Some(Format::Ascii),
+            ["format", "binary_little_endian", _] => format = Some(Format::BinaryLittleEndian),
+            ["format", other, _] => return Err(format!("the {} format is not supported", other)),
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count.parse().map_err(|_| format!("element {} has an invalid count", name))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count_type, item_type, name] => {
+                let element = elements.last_mut().ok_or("property outside of an element")?;
+                let count_type = ScalarType::from_name(count_type).ok_or(format!("unknown type {}", count_type))?;
+                let item_type = ScalarType::from_name(item_type).ok_or(format!("unknown type {}", item_type))?;
+                element.properties.push(Property::List { name: name.to_string(), count_type, item_type });
+            }
+            ["property", value_type, name] => {
+                let element = elements.last_mut().ok_or("property outside of an element")?;
+                let value_type = ScalarType::from_name(value_type).ok_or(format!("unknown type {}", value_type))?;
+                element.properties.push(Property::Scalar { name: name.to_string(), value_type });
+            }
+            ["comment", ..] | ["obj_info", ..] | ["end_header"] | [] => {}
+            _ => return Err(format!("unexpected header line \"{}\"", line)),
+        }
+    }
+    Ok((format.ok_or("the header has no format")?, elements))
+}
+
+// Area weighted average of the normals of the faces around each vertex
+fn compute_normals(positions: &[Vec3], triangles: &[UVec3]) -> Vec<Vec3> {
+    let mut normals = vec![Vec3::ZERO; positions.len()];
+    for triangle in triangles {
+        let [a, b, c] = triangle.to_array().map(|index| positions[index as usize]);
+        let face_normal = (b - a).cross(c - a);
+        for index in triangle.to_array() {
+            normals[index as usize] += face_normal;
+        }
+    }
+    normals.iter().map(|normal| normal.normalize_or_zero()).collect()
+}
+
+pub fn parse_ply(bytes: &[u8]) -> Result<PlyMesh, String> {
+    const END_HEADER: &[u8] = b"end_header";
+    let header_end = bytes
+        .windows(END_HEADER.len())
+        .position(|window| window == END_HEADER)
+        .ok_or("the header has no end_header")?;
+    let body_start = bytes[header_end..]
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .map_or(bytes.len(), |newline| header_end + newline + 1);
+    let header = std::str::from_utf8(&bytes[..body_start]).map_err(|_| "the header is not text")?;
+    let (format, elements) = parse_header(header)?;
+
+    let mut body = match format {
+        Format::Ascii => Body::Ascii(std::str::from_utf8(&bytes[body_start..]).map_err(|_| "the body is not text")?.split_ascii_whitespace()),
+        Format::BinaryLittleEndian => Body::Binary(&bytes[body_start..]),
+    };
+
+    let mut mesh = PlyMesh::default();
+    let mut normals = Vec::new();
+    let mut colors = Vec::new();
+    let mut has_normals = false;
+    let mut has_colors = false;
+    let mut faces = Vec::new();
+    for element in elements.iter() {
+        let position_of = |name: &str| element.properties.iter().position(|property| property.name() == name);
+        let position = [position_of("x"), position_of("y"), position_of("z")];
+        let normal = [position_of("nx"), position_of("ny"), position_of("nz")];
+        let color = [position_of("red"), position_of("green"), position_of("blue")];
+        let alpha = position_of("alpha");
+        let indices = position_of("vertex_indices").or_else(|| position_of("vertex_index"));
+        if element.name == "vertex" {
+            if position.iter().any(Option::is_none) {
+                return Err("vertices have no position".to_string());
+            }
+            has_normals = normal.iter().all(Option::is_some);
+            has_colors = color.iter().all(Option::is_some);
+        }
+
+        for _ in 0..element.count {
+            // Scalars are kept by property index, colors already scaled, lists are only kept for face indices
+            let mut values = vec![0.0; element.properties.len()];
+            let mut face = None;
+            for (property_index, property) in element.properties.iter().enumerate() {
+                match property {
+                    Property::Scalar { value_type, .. } => {
+                        let value = body.read(*value_type)?;
+                        let is_color = color.contains(&Some(property_index)) || alpha == Some(property_index);
+                        values[property_index] = if is_color { value / value_type.color_scale() } else { value };
+                    }
+                    Property::List { count_type, item_type, .. } => {
+                        let list = body.read_list(*count_type, *item_type)?;
+                        if Some(property_index) == indices {
+                            face = Some(list);
+                        }
+                    }
+                }
+            }
+
+            let get = |index: Option<usize>, default: f64| index.map_or(default, |index| values[index]) as f32;
+            match element.name.as_str() {
+                "vertex" => {
+                    mesh.positions.push(Vec3::new(get(position[0], 0.0), get(position[1], 0.0), get(position[2], 0.0)));
+                    if has_normals {
+                        normals.push(Vec3::new(get(normal[0], 0.0), get(normal[1], 0.0), get(normal[2], 0.0)).normalize_or_zero());
+                    }
+                    if has_colors {
+                        colors.push(Vec4::new(get(color[0], 1.0), get(color[1], 1.0), get(color[2], 1.0), get(alpha, 1.0)));
+                    }
+                }
+                "face" => faces.extend(face),
+                _ => {}
+            }
+        }
+    }
+
+    for face in faces {
+        if face.iter().any(|&index| index < 0.0 || index as usize >= mesh.positions.len()) {
+            return Err(format!("a face refers to a vertex past the {} there are", mesh.positions.len()));
+        }
+        for i in 2..face.len() {
+            mesh.triangles.push(UVec3::new(face[0] as u32, face[i - 1] as u32, face[i] as u32));
+        }
+    }
+    mesh.normals = if has_normals { normals } else { compute_normals(&mesh.positions, &mesh.triangles) };
+    mesh.colors = has_colors.then_some(colors);
+    Ok(mesh)
+}
+
+pub fn load_ply(path: &str) -> Result<PlyMesh, String> {
+    let bytes = std::fs::read(path).map_err(|error| format!("can't read {}, {}", path, error))?;
+    parse_ply(&bytes).map_err(|error| format!("can't load {}, {}", path, error))
+}
@@ -0,0 +1,314 @@
+// Post processing effects applied on the CPU to the averaged HDR image, before it is tonemapped for display.
+// All buffers are tightly packed RGB f32 triplets, like the framebuffer.
+
+use rayon::prelude::*;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PostProcessConfig {
+    pub bloom_threshold: f32,
+    pub bloom_strength: f32, // 0 = disabled
+    pub chromatic_aberration: f32, // 0 = disabled
+    pub vignette: f32, // 0 = disabled
+    pub despeckle_threshold: f32, // 0 = disabled
+    pub white_balance: f32, // Color temperature in kelvin of the light which should look white, 0 = disabled
+    pub tint: f32, // Green to magenta, see `white_balance_gain`
+}
+
+impl Default for PostProcessConfig {
+    fn default() -> Self {
+        Self {
+            bloom_threshold: 1.0,
+            bloom_strength: 0.0,
+            chromatic_aberration: 0.0,
+            vignette: 0.0,
+            despeckle_threshold: 0.0,
+            white_balance: 0.0,
+            tint: 0.0,
+        }
+    }
+}
+
+pub fn apply_post_processing(config: &PostProcessConfig, width: usize, height: usize, image: &mut [f32]) {
+    // A change to the light of the scene rather than an effect of the lens, so it goes before the rest
+    if config.white_balance > 0.0 {
+        white_balance(image, white_balance_gain(config.white_balance, config.tint));
+    }
+    // First, so fireflies aren't spread out by bloom
+    if config.despeckle_threshold > 0.0 {
+        despeckle(width, height, image, config.despeckle_threshold);
+    }
+    if config.bloom_strength > 0.0 {
+        bloom(width, height, image, config.bloom_threshold, config.bloom_strength);
+    }
+    if config.chromatic_aberration > 0.0 {
+        chromatic_aberration(width, height, image, config.chromatic_aberration);
+    }
+    if config.vignette > 0.0 {
+        vignette(width, height, image, config.vignette);
+    }
+}
+
+// Exposure compensation in EV, where each step doubles or halves the brightness. The window applies it in render.wgsl
+// before tonemapping, this is for images which skip that, like saved .exr and .hdr files.
+pub fn apply_exposure(image: &mut [f32], exposure: f32) {
+    if exposure == 0.0 {
+        return;
+    }
+    let scale = exposure.exp2();
+    image.par_iter_mut().for_each(|c| *c *= scale);
+}
+
+// Used when bloom is turned on with `--bloom-threshold` alone, subtle enough to not wash out the image
+pub const DEFAULT_BLOOM_STRENGTH: f32 = 0.1;
+
+// Used by `--despeckle`, low enough to catch most fireflies, high enough to leave sharp highlights alone
+pub const DEFAULT_DESPECKLE_THRESHOLD: f32 = 4.0;
+
+pub(crate) fn luminance(pixel: &[f32]) -> f32 {
+    pixel[0] * 0.2126 + pixel[1] * 0.7152 + pixel[2] * 0.0722
+}
+
+// Firefly removal. Pixels more than `threshold` times as bright as the median of their 3x3 neighbourhood are replaced
+// by the median pixel. Unlike clamping, this leaves the brightness of the rest of the image alone, and since most of
+// the neighbourhood has to be darker, edges survive.
+pub fn despeckle(width: usize, height: usize, image: &mut [f32], threshold: f32) {
+    let source = image.to_vec();
+    let pixel = |x: usize, y: usize| &source[(y * width + x) * 3..(y * width + x) * 3 + 3];
+    image.par_chunks_mut(width * 3).enumerate().for_each(|(y, row)| {
+        for x in 0..width {
+            // Clamp to the edges, so border pixels have a full neighbourhood too
+            let mut neighbourhood = [[0.0; 3]; 9];
+            for (i, neighbour) in neighbourhood.iter_mut().enumerate() {
+                let nx = (x + i % 3).saturating_sub(1).min(width - 1);
+                let ny = (y + i / 3).saturating_sub(1).min(height - 1);
+                neighbour.copy_from_slice(pixel(nx, ny));
+            }
+            neighbourhood.sort_unstable_by(|a, b| luminance(a).total_cmp(&luminance(b)));
+            let median = neighbourhood[4];
+            if luminance(pixel(x, y)) > luminance(&median) * threshold {
+                row[x * 3..x * 3 + 3].copy_from_slice(&median);
+            }
+        }
+    });
+}
+
+// Multiply each channel by a gain, see `white_balance_gain`
+pub fn white_balance(image: &mut [f32], gain: [f32; 3]) {
+    image.par_chunks_mut(3).for_each(|pixel| {
+        for c in 0..3 {
+            pixel[c] *= gain[c];
+        }
+    });
+}
+
+// Used by `--tint`, how far a tint of 1 moves the white point off the Planckian locus, in CIE 1960 uv
+const TINT_SCALE: f32 = 0.02;
+
+// Gain which makes light of a black body at `kelvin` white in linear sRGB, as a camera's white balance does. Light
+// warmer than the temperature turns orange and cooler light turns blue. The tint moves the white point perpendicular
+// to the Planckian locus, so positive values make the image more magenta and negative values more green. The gain
+// keeps the luminance of white, so brightness is unchanged. A black body at 6500K is a little pinker than the D65 white
+// of sRGB, so it is within a few percent of neutral.
+pub fn white_balance_gain(kelvin: f32, tint: f32) -> [f32; 3] {
+    let kelvin = kelvin.clamp(1667.0, 25000.0);
+    let (u, v) = planckian_uv(kelvin);
+    // Step along the locus the way that keeps within its range, and turn the step to point above it, towards green
+    let (u_next, v_next) = planckian_uv(if kelvin < 25000.0 { kelvin + 1.0 } else { kelvin - 1.0 });
+    let (du, dv) = if kelvin < 25000.0 { (u_next - u, v_next - v) } else { (u - u_next, v - v_next) };
+    let length = (du * du + dv * dv).sqrt();
+    let (u, v) = (u + dv / length * tint * TINT_SCALE, v - du / length * tint * TINT_SCALE);
+
+    // uv to xy, to XYZ with a luminance of 1, to linear sRGB
+    let denominator = 2.0 * u - 8.0 * v + 4.0;
+    let (x, y) = (3.0 * u / denominator, 2.0 * v / denominator);
+    let xyz = [x / y, 1.0, (1.0 - x - y) / y];
+    let rgb = [
+        3.240454 * xyz[0] - 1.537139 * xyz[1] - 0.4985314 * xyz[2],
+        -0.9692660 * xyz[0] + 1.876011 * xyz[1] + 0.0415560 * xyz[2],
+        0.0556434 * xyz[0] - 0.2040259 * xyz[1] + 1.057225 * xyz[2],
+    ];
+    let gain = rgb.map(|c| 1.0 / c.max(1e-3));
+    let white = luminance(&gain);
+    gain.map(|c| c / white)
+}
+
+// Chromaticity of a black body in CIE 1960 uv, from the cubic fit of the Planckian locus in xy by Kang et al. 2002,
+// which holds from 1667K to 25000K
+fn planckian_uv(kelvin: f32) -> (f32, f32) {
+    let t = kelvin;
+    let x = if t <= 4000.0 {
+        -0.2661239e9 / (t * t * t) - 0.2343589e6 / (t * t) + 0.8776956e3 / t + 0.179910
+    } else {
+        -3.025847e9 / (t * t * t) + 2.107038e6 / (t * t) + 0.2226347e3 / t + 0.240390
+    };
+    let y = if t <= 2222.0 {
+        -1.106381 * x * x * x - 1.348110 * x * x + 2.185558 * x - 0.2021968
+    } else if t <= 4000.0 {
+        -0.9549476 * x * x * x - 1.374186 * x * x + 2.091370 * x - 0.1674887
+    } else {
+        3.0817580 * x * x * x - 5.873387 * x * x + 3.751130 * x - 0.3700148
+    };
+    let denominator = -2.0 * x + 12.0 * y + 3.0;
+    (4.0 * x / denominator, 6.0 * y / denominator)
+}
+
+const BLOOM_LEVELS: usize = 5;
+const BLOOM_SIGMA: f32 = 1.5;
+
+// Multi-scale bloom. Pixels brighter than the threshold are repeatedly halved in resolution and blurred,
+// and the blurred levels are added back on top of the image. Blurring a small kernel at each level of the
+// pyramid is much cheaper than blurring with a wide kernel at full resolution.
+pub fn bloom(width: usize, height: usize, image: &mut [f32], threshold: f32, strength: f32) {
+    // Keep only the part of each pixel which exceeds the threshold
+    let bright = image
+        .iter()
+        .map(|c| (c - threshold).max(0.0))
+        .collect::<Vec<_>>();
+
+    let mut levels: Vec<(Vec<f32>, usize, usize)> = Vec::with_capacity(BLOOM_LEVELS);
+    for _ in 0..BLOOM_LEVELS {
+        let (prev, prev_width, prev_height) = levels
+            .last()
+            .map(|(level, level_width, level_height)| (level.as_slice(), *level_width, *level_height))
+            .unwrap_or((bright.as_slice(), width, height));
+        let (mut next, next_width, next_height) = downsample(prev, prev_width, prev_height);
+        gaussian_blur(&mut next, next_width, next_height, BLOOM_SIGMA);
+        levels.push((next, next_width, next_height));
+    }
+
+    let weight = strength / BLOOM_LEVELS as f32;
+    image.par_chunks_mut(width * 3).enumerate().for_each(|(y, row)| {
+        let v = (y as f32 + 0.5) / height as f32;
+        for x in 0..width {
+            let u = (x as f32 + 0.5) / width as f32;
+            for (level, level_width, level_height) in levels.iter() {
+                let color = sample_bilinear(level, *level_width, *level_height, u, v);
+                for c in 0..3 {
+                    row[x * 3 + c] += color[c] * weight;
+                }
+            }
+        }
+    });
+}
+
+// Lateral chromatic aberration. Red and blue are scaled radially outwards and inwards from the center of
+// the image respectively, imitating a lens which refracts each wavelength differently.
+pub fn chromatic_aberration(width: usize, height: usize, image: &mut [f32], strength: f32) {
+    let source = image.to_vec();
+    let scales = [1.0 - strength * 0.01, 1.0, 1.0 + strength * 0.01];
+    image.par_chunks_mut(width * 3).enumerate().for_each(|(y, row)| {
+        let v = (y as f32 + 0.5) / height as f32 - 0.5;
+        for x in 0..width {
+            let u = (x as f32 + 0.5) / width as f32 - 0.5;
+            for c in [0, 2] {
+                let sampled = sample_bilinear(&source, width, height, u * scales[c] + 0.5, v * scales[c] + 0.5);
+                row[x * 3 + c] = sampled[c];
+            }
+        }
+    });
+}
+
+// Radial darkening towards the corners. The corners are darkened by a factor of (1 - strength).
+pub fn vignette(width: usize, height: usize, image: &mut [f32], strength: f32) {
+    image.par_chunks_mut(width * 3).enumerate().for_each(|(y, row)| {
+        let v = (y as f32 + 0.5) / height as f32 * 2.0 - 1.0;
+        for x in 0..width {
+            let u = (x as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+            let distance_squared = (u * u + v * v) * 0.5; // 1 at the corners
+            let factor = (1.0 - strength * distance_squared).max(0.0);
+            for c in 0..3 {
+                row[x * 3 + c] *= factor;
+            }
+        }
+    });
+}
+
+// Bilinear resize, used for upscaling reduced resolution previews
+pub fn resize_bilinear(src: &[f32], src_width: usize, src_height: usize, dst: &mut [f32], dst_width: usize, dst_height: usize) {
+    dst.par_chunks_mut(dst_width * 3).enumerate().for_each(|(y, row)| {
+        let v = (y as f32 + 0.5) / dst_height as f32;
+        for x in 0..dst_width {
+            let u = (x as f32 + 0.5) / dst_width as f32;
+            row[x * 3..x * 3 + 3].copy_from_slice(&sample_bilinear(src, src_width, src_height, u, v));
+        }
+    });
+}
+
+// 2x2 box downsample
+fn downsample(image: &[f32], width: usize, height: usize) -> (Vec<f32>, usize, usize) {
+    let half_width = (width / 2).max(1);
+    let half_height = (height / 2).max(1);
+    let mut result = vec![0.0; half_width * half_height * 3];
+    result.par_chunks_mut(half_width * 3).enumerate().for_each(|(y, row)| {
+        for x in 0..half_width {
+            for c in 0..3 {
+                let mut sum = 0.0;
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let sx = (x * 2 + dx).min(width - 1);
+                    let sy = (y * 2 + dy).min(height - 1);
+                    sum += image[(sy * width + sx) * 3 + c];
+                }
+                row[x * 3 + c] = sum * 0.25;
+            }
+        }
+    });
+    (result, half_width, half_height)
+}
+
+// Separable gaussian blur with clamp-to-edge addressing
+pub fn gaussian_blur(image: &mut [f32], width: usize, height: usize, sigma: f32) {
+    let radius = (sigma * 3.0).ceil() as isize;
+    let mut weights = (-radius..=radius)
+        .map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect::<Vec<_>>();
+    let total = weights.iter().sum::<f32>();
+    weights.iter_mut().for_each(|w| *w /= total);
+
+    let blur_pass = |src: &[f32], dst: &mut [f32], horizontal: bool| {
+        dst.par_chunks_mut(width * 3).enumerate().for_each(|(y, row)| {
+            for x in 0..width {
+                let mut sum = [0.0; 3];
+                for (i, weight) in weights.iter().enumerate() {
+                    let offset = i as isize - radius;
+                    let (sx, sy) = if horizontal {
+                        ((x as isize + offset).clamp(0, width as isize - 1) as usize, y)
+                    } else {
+                        (x, (y as isize + offset).clamp(0, height as isize - 1) as usize)
+                    };
+                    for c in 0..3 {
+                        sum[c] += src[(sy * width + sx) * 3 + c] * weight;
+                    }
+                }
+                row[x * 3..x * 3 + 3].copy_from_slice(&sum);
+            }
+        });
+    };
+
+    let mut temp = vec![0.0; image.len()];
+    blur_pass(image, &mut temp, true);
+    blur_pass(&temp, image, false);
+}
+
+// Bilinear fetch at normalized coordinates with clamp-to-edge addressing
+fn sample_bilinear(image: &[f32], width: usize, height: usize, u: f32, v: f32) -> [f32; 3] {
+    let x = (u * width as f32 - 0.5).clamp(0.0, (width - 1) as f32);
+    let y = (v * height as f32 - 0.5).clamp(0.0, (height - 1) as f32);
+    let x0 = x.floor() as usize;
+    let y0 = y.floor() as usize;
+    let x1 = (x0 + 1).min(width - 1);
+    let y1 = (y0 + 1).min(height - 1);
+    let tx = x - x0 as f32;
+    let ty = y - y0 as f32;
+
+    let mut result = [0.0; 3];
+    for c in 0..3 {
+        let c00 = image[(y0 * width + x0) * 3 + c];
+        let c10 = image[(y0 * width + x1) * 3 + c];
+        let c01 = image[(y1 * width + x0) * 3 + c];
+        let c11 = image[(y1 * width + x1) * 3 + c];
+        let a = c00 + (c10 - c00) * tx;
+        let b = c01 + (c11 - c01) * tx;
+        result[c] = a + (b - a) * ty;
+    }
+    result
+}
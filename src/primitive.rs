@@ -0,0 +1,99 @@
+// Host side geometry queries for the primitives in the index buffer, see `PrimitiveType` for the layout.
+
+use std::f32::consts::PI;
+
+use glam::{UVec4, Vec3};
+use shared_structs::{PerVertexData, PrimitiveType};
+
+fn corners(vertices: &[PerVertexData], primitive: UVec4) -> (Vec3, Vec3, Vec3) {
+    (
+        vertices[primitive.x as usize].vertex,
+        vertices[primitive.y as usize].vertex,
+        vertices[primitive.z as usize].vertex,
+    )
+}
+
+pub fn primitive_bounds(vertices: &[PerVertexData], primitive: UVec4) -> (Vec3, Vec3) {
+    let (a, b, c) = corners(vertices, primitive);
+    match PrimitiveType::of(primitive) {
+        PrimitiveType::Triangle => (a.min(b).min(c), a.max(b).max(c)),
+        PrimitiveType::Quad => {
+            let d = b + c - a;
+            (a.min(b).min(c).min(d), a.max(b).max(c).max(d))
+        }
+        PrimitiveType::Sphere => {
+            let radius = vertices[primitive.x as usize].radius;
+            (a - radius, a + radius)
+        }
+    }
+}
+
+pub fn primitive_centroid(vertices: &[PerVertexData], primitive: UVec4) -> Vec3 {
+    let (a, b, c) = corners(vertices, primitive);
+    match PrimitiveType::of(primitive) {
+        PrimitiveType::Triangle => (a + b + c) / 3.0,
+        PrimitiveType::Quad => (b + c) * 0.5,
+        PrimitiveType::Sphere => a,
+    }
+}
+
+pub fn primitive_area(vertices: &[PerVertexData], primitive: UVec4) -> f32 {
+    let (a, b, c) = corners(vertices, primitive);
+    match PrimitiveType::of(primitive) {
+        PrimitiveType::Triangle => crate::light_pick::triangle_area(a, b, c),
+        PrimitiveType::Quad => (b - a).cross(c - a).length(),
+        PrimitiveType::Sphere => {
+            let radius = vertices[primitive.x as usize].radius;
+            4.0 * PI * radius * radius
+        }
+    }
+}
+
+// Bounds of the part of a primitive between `min` and `max` along `axis`, limited to the box it was already clipped to.
+// Triangles and quads are clipped exactly, spheres fall back to their bounding box. None if nothing is left.
+pub fn clipped_primitive_bounds(
+    vertices: &[PerVertexData],
+    primitive: UVec4,
+    (aabb_min, aabb_max): (Vec3, Vec3),
+    axis: usize,
+    min: f32,
+    max: f32,
+) -> Option<(Vec3, Vec3)> {
+    let (a, b, c) = corners(vertices, primitive);
+    let (polygon, corner_count) = match PrimitiveType::of(primitive) {
+        PrimitiveType::Triangle => ([a, b, c, c], 3),
+        PrimitiveType::Quad => ([a, b, b + c - a, c], 4),
+        PrimitiveType::Sphere => {
+            let (mut clipped_min, mut clipped_max) = (aabb_min, aabb_max);
+            clipped_min[axis] = clipped_min[axis].max(min);
+            clipped_max[axis] = clipped_max[axis].min(max);
+            return (clipped_min[axis] <= clipped_max[axis]).then_some((clipped_min, clipped_max));
+        }
+    };
+
+    // Walk the edges, keeping the corners inside the slab and the points where edges cross its planes
+    let mut clipped_min = Vec3::splat(f32::INFINITY);
+    let mut clipped_max = Vec3::splat(f32::NEG_INFINITY);
+    let mut include = |point: Vec3| {
+        clipped_min = clipped_min.min(point);
+        clipped_max = clipped_max.max(point);
+    };
+    for i in 0..corner_count {
+        let (p, q) = (polygon[i], polygon[(i + 1) % corner_count]);
+        if p[axis] >= min && p[axis] <= max {
+            include(p);
+        }
+        for plane in [min, max] {
+            if (p[axis] < plane && q[axis] > plane) || (p[axis] > plane && q[axis] < plane) {
+                let t = (plane - p[axis]) / (q[axis] - p[axis]);
+                let mut crossing = p.lerp(q, t);
+                crossing[axis] = plane; // Exactly on the plane, despite rounding
+                include(crossing);
+            }
+        }
+    }
+
+    let clipped_min = clipped_min.max(aabb_min);
+    let clipped_max = clipped_max.min(aabb_max);
+    (clipped_min.cmple(clipped_max).all()).then_some((clipped_min, clipped_max))
+}
@@ -0,0 +1,105 @@
+// Python bindings, for driving renders from scripts and notebooks. Build the module as a dynamic library with the
+// `extension-module` feature: `cargo rustc --release --lib --crate-type cdylib --features extension-module`, or with
+// maturin, then rename the library to `rustic.so` (`rustic.pyd` on Windows) and put it on the Python path. Other
+// builds only produce the Rust library. The `pyo3` feature alone links against Python, for running the tests.
+//
+// ```python
+// import rustic
+// image = rustic.render({"scene": "cornell", "width": 256, "height": 256, "samples": 128})
+// ```
+
+use glam::{Vec3, Vec4};
+use numpy::{IntoPyArray, PyArray3};
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use shared_structs::WorkingSpace;
+
+use crate::asset::{CoordinateSystem, Handedness, SceneFile, UpAxis};
+use crate::render::Renderer;
+use crate::scenes::find_builtin_scene;
+
+const DEFAULT_SIZE: u32 = 512;
+const DEFAULT_SAMPLES: u32 = 64;
+
+fn get<'py, T: FromPyObject<'py>>(config: &'py PyDict, key: &str) -> PyResult<Option<T>> {
+    config
+        .get_item(key)
+        .map(|value| value.extract().map_err(|_| PyValueError::new_err(format!("Invalid value for \"{}\"", key))))
+        .transpose()
+}
+
+// Same as `get`, for names parsed with `from_name`
+fn get_named<T>(config: &PyDict, key: &str, from_name: fn(&str) -> Option<T>) -> PyResult<Option<T>> {
+    get::<String>(config, key)?
+        .map(|name| from_name(&name).ok_or_else(|| PyValueError::new_err(format!("Unknown {} \"{}\"", key, name))))
+        .transpose()
+}
+
+// Reshape a framebuffer of tightly packed RGB triplets, top row first, into an (H, W, 3) array
+pub fn framebuffer_to_ndarray(py: Python, framebuffer: Vec<f32>, width: u32, height: u32) -> PyResult<&PyArray3<f32>> {
+    framebuffer.into_pyarray(py).reshape([height as usize, width as usize, 3])
+}
+
+// Render a scene and return the linear HDR image. The config dict takes:
+// - `scene`: path or built-in scene name, or a list of paths to merge. Required.
+// - `width`, `height`, `samples`: image size and sample count, 512x512 with 64 samples by default.
+// - `skybox`: path to an HDR image to light the scene with, instead of the procedural sky.
+// - `camera_position`: `[x, y, z]`, and `camera_rotation`: `[pitch, yaw]` in radians. Built-in scenes default to
+//   their own camera.
+// - `up`, `handedness`, `working_space`: same as the command line options.
+// - `use_cpu`: render on the CPU instead of the GPU.
+#[pyfunction]
+fn render<'py>(py: Python<'py>, config: &'py PyDict) -> PyResult<&'py PyArray3<f32>> {
+    let scene = match config.get_item("scene") {
+        Some(scene) => match scene.extract::<String>() {
+            Ok(path) => vec![path],
+            Err(_) => get::<Vec<String>>(config, "scene")?.unwrap_or_default(),
+        },
+        None => return Err(PyValueError::new_err("The config needs a \"scene\"")),
+    };
+    let width = get(config, "width")?.unwrap_or(DEFAULT_SIZE);
+    let height = get(config, "height")?.unwrap_or(DEFAULT_SIZE);
+    let samples = get(config, "samples")?.unwrap_or(DEFAULT_SAMPLES);
+    if width == 0 || height == 0 || samples == 0 {
+        return Err(PyValueError::new_err("\"width\", \"height\" and \"samples\" must be positive"));
+    }
+
+    let mut renderer = Renderer::new(scene.iter().map(|path| SceneFile::new(path)).collect(), width, height);
+    renderer.skybox = get(config, "skybox")?;
+    renderer.use_cpu = get(config, "use_cpu")?.unwrap_or(false);
+    let defaults = CoordinateSystem::default();
+    renderer.coordinate_system = CoordinateSystem {
+        up: get_named(config, "up", UpAxis::from_name)?.unwrap_or(defaults.up),
+        handedness: get_named(config, "handedness", Handedness::from_name)?.unwrap_or(defaults.handedness),
+        ..defaults
+    };
+    {
+        let mut tracing_config = renderer.state.config.write();
+        if let Some(builtin) = scene.first().and_then(|name| find_builtin_scene(name)) {
+            tracing_config.cam_position = builtin.camera_position;
+            tracing_config.cam_rotation = builtin.camera_rotation;
+        }
+        if let Some([x, y, z]) = get::<[f32; 3]>(config, "camera_position")? {
+            tracing_config.cam_position = Vec3::new(x, y, z).extend(0.0);
+        }
+        if let Some([pitch, yaw]) = get::<[f32; 2]>(config, "camera_rotation")? {
+            tracing_config.cam_rotation = Vec4::new(pitch, yaw, 0.0, 0.0);
+        }
+        if let Some(working_space) = get_named(config, "working_space", WorkingSpace::from_name)? {
+            tracing_config.working_space = working_space.to_u32();
+        }
+    }
+
+    // Tracing doesn't touch Python, so let other Python threads run in the meantime
+    let framebuffer = py
+        .allow_threads(|| renderer.render(samples))
+        .ok_or_else(|| PyRuntimeError::new_err("Rendering failed, check that the scene can be loaded"))?;
+    framebuffer_to_ndarray(py, framebuffer, width, height)
+}
+
+#[pymodule]
+fn rustic(_py: Python, module: &PyModule) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(render, module)?)?;
+    Ok(())
+}
@@ -0,0 +1,251 @@
+// Library entry point for rendering a scene to a fixed sample count, without the interactive app. Tracing runs on a
+// worker thread, and the calling thread waits for it, reporting progress along the way.
+
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::time::{Duration, Instant};
+
+use crate::app::{PaintCallbackResources, RenderUniforms, Tonemapping};
+use crate::asset::{CoordinateSystem, SceneFile};
+use crate::trace::{trace_cpu, trace_gpu, TracingState};
+
+// Samples per stratum of `Renderer::render_strata` when rendering with `--bootstrap`
+pub const DEFAULT_BOOTSTRAP_SAMPLES: u32 = 64;
+
+// How often the calling thread checks on the tracing thread
+const PROGRESS_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// Shared flag for stopping a render early from another thread, such as a GUI. Clones refer to the same flag.
+#[derive(Clone, Default, Debug)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct RenderProgress {
+    pub samples_done: u32,
+    pub samples_total: u32,
+    pub elapsed: Duration,
+    pub est_remaining: Duration, // Extrapolated from the samples so far, 0 until the first batch is done
+}
+
+// Independent renders of the same scene, for error bars on the estimator itself. Each stratum is rendered from its own
+// seed stream, so the spread between them is that of the whole render, including anything that correlates samples,
+// such as blue noise seeds or denoising, unlike the variance of the samples within a pixel. RGB images like the
+// framebuffer.
+pub struct Bootstrap {
+    pub strata: u32,
+    pub mean: Vec<f32>, // Of the strata, which is the best estimate of the image
+    pub variance: Vec<f32>, // Between the strata, that is of a single render with the samples of one stratum
+    pub confidence: Vec<f32>, // Half the width of the 95% confidence interval of `mean`
+}
+
+// Two sided 95% quantile of Student's t-distribution with `degrees` degrees of freedom. Few strata give a poor estimate of
+// the variance, which is what the wider interval makes up for. Past 30, the normal quantile is close enough.
+pub fn t_quantile_95(degrees: u32) -> f32 {
+    const QUANTILES: [f32; 30] = [
+        12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228,
+        2.201, 2.179, 2.160, 2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086,
+        2.080, 2.074, 2.069, 2.064, 2.060, 2.056, 2.052, 2.048, 2.045, 2.042,
+    ];
+    match degrees {
+        0 => f32::INFINITY,
+        1..=30 => QUANTILES[degrees as usize - 1],
+        _ => 1.96,
+    }
+}
+
+// Combine independent renders of the same size into a `Bootstrap`, using Welford's algorithm per channel
+pub fn combine_strata(strata: &[Vec<f32>]) -> Bootstrap {
+    let len = strata.first().map_or(0, Vec::len);
+    let mut mean = vec![0.0; len];
+    let mut squared_differences = vec![0.0; len];
+    for (count, stratum) in strata.iter().enumerate() {
+        for ((mean, squared_difference), value) in mean.iter_mut().zip(squared_differences.iter_mut()).zip(stratum.iter()) {
+            let delta = value - *mean;
+            *mean += delta / (count + 1) as f32;
+            *squared_difference += delta * (value - *mean);
+        }
+    }
+    let count = strata.len();
+    let variance = squared_differences.iter().map(|sum| if count < 2 { 0.0 } else { sum / (count - 1) as f32 }).collect::<Vec<_>>();
+    let t = t_quantile_95(count.saturating_sub(1) as u32);
+    let confidence = variance.iter().map(|variance| if count < 2 { f32::INFINITY } else { t * (variance / count as f32).sqrt() }).collect();
+    Bootstrap { strata: count as u32, mean, variance, confidence }
+}
+
+pub struct Renderer {
+    pub scene: Vec<SceneFile>,
+    pub skybox: Option<String>,
+    pub coordinate_system: CoordinateSystem,
+    pub use_cpu: bool,
+    pub state: Arc<TracingState>, // Set the camera and other options through the config
+    // Display transform applied by `render_to_texture`, the other render functions return linear radiance
+    pub tonemapping: Tonemapping,
+    pub exposure: f32, // In EV
+}
+
+impl Renderer {
+    pub fn new(scene: Vec<SceneFile>, width: u32, height: u32) -> Self {
+        Self {
+            scene,
+            skybox: None,
+            coordinate_system: CoordinateSystem::default(),
+            use_cpu: false,
+            state: Arc::new(TracingState::new(width, height)),
+            tonemapping: Tonemapping::None,
+            exposure: 0.0,
+        }
+    }
+
+    // Throw away the accumulated image, for example after moving the camera through the config. Every render starts
+    // with this, see `TracingState::reset`.
+    pub fn reset(&self) {
+        self.state.reset();
+    }
+
+    pub fn render(&self, samples: u32) -> Option<Vec<f32>> {
+        self.render_with_progress(samples, &CancellationToken::new(), |_| {})
+    }
+
+    // Render from scratch until there are at least `samples` samples, and return the averaged RGB image. `progress`
+    // is called whenever a batch of samples finishes. Cancelling `cancel` stops the render after the sample in
+    // progress, and returns the image so far. Returns `None` if no samples were rendered, for example because the
+    // scene failed to load or the render was cancelled right away.
+    pub fn render_with_progress(
+        &self,
+        samples: u32,
+        cancel: &CancellationToken,
+        mut progress: impl FnMut(RenderProgress),
+    ) -> Option<Vec<f32>> {
+        self.reset();
+        self.state.running.store(true, Ordering::Relaxed);
+        let tracing_thread = {
+            let state = self.state.clone();
+            let scene = self.scene.clone();
+            let skybox = self.skybox.clone();
+            let coordinate_system = self.coordinate_system;
+            let use_cpu = self.use_cpu;
+            std::thread::spawn(move || {
+                if use_cpu {
+                    trace_cpu(&scene, skybox.as_deref(), coordinate_system, state);
+                } else {
+                    trace_gpu(&scene, skybox.as_deref(), coordinate_system, state);
+                }
+            })
+        };
+
+        let start = Instant::now();
+        let mut samples_reported = 0;
+        loop {
+            let samples_done = self.state.samples.load(Ordering::Relaxed).min(samples);
+            if samples_done != samples_reported {
+                samples_reported = samples_done;
+                let elapsed = start.elapsed();
+                progress(RenderProgress {
+                    samples_done,
+                    samples_total: samples,
+                    elapsed,
+                    est_remaining: elapsed.mul_f64((samples - samples_done) as f64 / samples_done as f64),
+                });
+            }
+            // The tracing thread publishes a batch before checking whether it should stop, so nothing is lost
+            if samples_done >= samples || cancel.is_cancelled() {
+                self.state.running.store(false, Ordering::Relaxed);
+            }
+            if tracing_thread.is_finished() {
+                break;
+            }
+            std::thread::sleep(PROGRESS_POLL_INTERVAL);
+        }
+        self.state.running.store(false, Ordering::Relaxed);
+        tracing_thread.join().ok()?;
+
+        (self.state.samples.load(Ordering::Relaxed) > 0).then(|| self.state.read_framebuffer().clone())
+    }
+
+    // Render `strata` images of `samples` samples each, and combine them, see `Bootstrap`. Each stratum gets its own
+    // seed stream by rendering it as a different frame of animated noise. Returns `None` if any stratum failed to render.
+    pub fn render_strata(&self, strata: u32, samples: u32, mut progress: impl FnMut(u32)) -> Option<Bootstrap> {
+        let animated_noise = self.state.animated_noise.swap(true, Ordering::Relaxed);
+        let frame = self.state.frame.load(Ordering::Relaxed);
+        let mut images = Vec::with_capacity(strata as usize);
+        for stratum in 0..strata {
+            self.state.frame.store(frame.wrapping_add(stratum), Ordering::Relaxed);
+            let Some(image) = self.render(samples) else {
+                break;
+            };
+            images.push(image);
+            progress(stratum + 1);
+        }
+        self.state.animated_noise.store(animated_noise, Ordering::Relaxed);
+        self.state.frame.store(frame, Ordering::Relaxed);
+        (images.len() == strata as usize).then(|| combine_strata(&images))
+    }
+
+    // Render like `render`, then draw the image into a texture owned by the caller, tonemapped the same way as the
+    // window. The texture must be created on `device`, be exactly as large as the config, have one mip level and one
+    // sample, have `TextureUsages::RENDER_ATTACHMENT`, and be in a renderable `format`, which has to be passed in since
+    // textures don't know their own format. sRGB formats are written linear values which the GPU encodes, any other
+    // format gets values that are already display encoded, like a non-sRGB swapchain.
+    //
+    // Tracing runs on its own device, and samples are accumulated on the host either way, so the texture can live on any
+    // device, such as the one of an editor embedding the renderer. The image is uploaded once, saving the caller a
+    // readback and upload of their own. The draw is submitted to `queue` but not waited on.
+    pub fn render_to_texture(
+        &self,
+        samples: u32,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        texture: &wgpu::Texture,
+        format: wgpu::TextureFormat,
+    ) -> Option<()> {
+        let framebuffer = self.render(samples)?;
+        let (width, height) = {
+            let config = self.state.config.read();
+            (config.width, config.height)
+        };
+        let resources = PaintCallbackResources::new(device, format, width, height);
+        resources.prepare(queue, &framebuffer, &RenderUniforms {
+            width,
+            height,
+            tonemapping: self.tonemapping as u32,
+            exposure: self.exposure,
+            dither: 0,
+            dither_seed: 0,
+            srgb_target: format.describe().srgb as u32,
+            _padding: 0,
+        });
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: None,
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+            resources.paint(&mut render_pass);
+        }
+        queue.submit(Some(encoder.finish()));
+        Some(())
+    }
+}
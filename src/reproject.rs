@@ -0,0 +1,141 @@
+// Temporal reprojection of previous frames, used to keep the image stable while the camera moves instead of
+// starting from scratch every frame. Color and normal buffers are tightly packed RGB f32 triplets like the
+// framebuffer, depth buffers have one value per pixel. A depth of 0 means the primary ray missed the scene.
+
+use glam::{Mat3, Vec2, Vec3, Vec4Swizzles};
+use shared_structs::TracingConfig;
+
+// Upper bound on how many samples worth of weight history can have, so stale data fades out over time
+pub const MAX_HISTORY_SAMPLES: f32 = 16.0;
+// Relative depth difference at which history is considered disoccluded
+const DEPTH_TOLERANCE: f32 = 0.1;
+// Minimum cosine between normals for history to be reused
+const NORMAL_TOLERANCE: f32 = 0.9;
+
+fn camera_rotation(config: &TracingConfig) -> Mat3 {
+    Mat3::from_rotation_y(config.cam_rotation.y) * Mat3::from_rotation_x(config.cam_rotation.x)
+}
+
+// Must match the camera setup in `camera_ray`
+pub fn primary_ray_direction(config: &TracingConfig, pixel: Vec2) -> Vec3 {
+    let width = config.width as f32;
+    let height = config.height as f32;
+    let mut uv = Vec2::new(pixel.x / width, 1.0 - pixel.y / height) * 2.0 - 1.0;
+    uv.y *= height / (width * config.pixel_aspect);
+    uv *= (config.fov * 0.5).tan();
+    camera_rotation(config) * Vec3::new(uv.x, uv.y, 1.0).normalize()
+}
+
+// Inverse of `primary_ray_direction`. The direction doesn't need to be normalized.
+pub fn project_direction(config: &TracingConfig, direction: Vec3) -> Option<Vec2> {
+    let local = camera_rotation(config).transpose() * direction;
+    if local.z <= 0.0 {
+        return None; // Behind the camera
+    }
+    let width = config.width as f32;
+    let height = config.height as f32;
+    let uv = local.xy() / local.z / (config.fov * 0.5).tan();
+    Some(Vec2::new(
+        (uv.x + 1.0) * 0.5 * width,
+        (1.0 - (uv.y * width * config.pixel_aspect / height + 1.0) * 0.5) * height,
+    ))
+}
+
+pub struct History {
+    pub color: Vec<f32>,
+    pub depth: Vec<f32>,
+    pub normal: Vec<f32>,
+    pub weight: Vec<f32>, // 0 where nothing was reprojected
+}
+
+// Forward reprojection from the previous view into the new one. Each previous pixel is moved to wherever its
+// primary hit lands in the new view, keeping the nearest one if several land on the same pixel.
+pub fn reproject(
+    prev_config: &TracingConfig,
+    config: &TracingConfig,
+    color: &[f32],
+    depth: &[f32],
+    normal: &[f32],
+    weight: &[f32],
+) -> History {
+    let width = config.width as usize;
+    let height = config.height as usize;
+    let pixel_count = width * height;
+    let mut history = History {
+        color: vec![0.0; pixel_count * 3],
+        depth: vec![0.0; pixel_count],
+        normal: vec![0.0; pixel_count * 3],
+        weight: vec![0.0; pixel_count],
+    };
+    let mut nearest = vec![f32::INFINITY; pixel_count];
+
+    for y in 0..prev_config.height as usize {
+        for x in 0..prev_config.width as usize {
+            let i = y * prev_config.width as usize + x;
+            let direction = primary_ray_direction(prev_config, Vec2::new(x as f32 + 0.5, y as f32 + 0.5));
+
+            // Misses are infinitely far away, so only the rotation of the camera matters for them
+            let (new_direction, new_depth) = if depth[i] > 0.0 {
+                let world_pos = prev_config.cam_position.xyz() + direction * depth[i];
+                let to_point = world_pos - config.cam_position.xyz();
+                (to_point, to_point.length())
+            } else {
+                (direction, f32::INFINITY)
+            };
+
+            let Some(pixel) = project_direction(config, new_direction) else {
+                continue;
+            };
+            if pixel.x < 0.0 || pixel.y < 0.0 || pixel.x >= width as f32 || pixel.y >= height as f32 {
+                continue;
+            }
+            let j = pixel.y as usize * width + pixel.x as usize;
+            if new_depth > nearest[j] {
+                continue;
+            }
+            nearest[j] = new_depth;
+
+            history.color[j * 3..j * 3 + 3].copy_from_slice(&color[i * 3..i * 3 + 3]);
+            history.normal[j * 3..j * 3 + 3].copy_from_slice(&normal[i * 3..i * 3 + 3]);
+            history.depth[j] = if new_depth.is_finite() { new_depth } else { 0.0 };
+            history.weight[j] = weight[i].min(MAX_HISTORY_SAMPLES);
+        }
+    }
+
+    history
+}
+
+// Disocclusion test. History is only reused if it looks like it belongs to the same surface.
+fn is_consistent(history: &History, i: usize, depth: f32, normal: &[f32]) -> bool {
+    let history_depth = history.depth[i];
+    if (depth > 0.0) != (history_depth > 0.0) {
+        return false;
+    }
+    if depth > 0.0 && (depth - history_depth).abs() > DEPTH_TOLERANCE * depth {
+        return false;
+    }
+    let normal = Vec3::from_slice(&normal[i * 3..i * 3 + 3]).normalize_or_zero();
+    let history_normal = Vec3::from_slice(&history.normal[i * 3..i * 3 + 3]).normalize_or_zero();
+    if normal != Vec3::ZERO && history_normal != Vec3::ZERO && normal.dot(history_normal) < NORMAL_TOLERANCE {
+        return false;
+    }
+    true
+}
+
+// Blend reprojected history into the average of `sample_count` new samples. Returns how many samples worth of
+// data each pixel now holds, to be used as the weight for the next reprojection.
+pub fn blend_history(history: &History, sample_count: f32, color: &mut [f32], depth: &[f32], normal: &[f32]) -> Vec<f32> {
+    let mut weights = vec![sample_count; depth.len()];
+    for i in 0..depth.len() {
+        let history_weight = history.weight[i];
+        if history_weight == 0.0 || !is_consistent(history, i, depth[i], normal) {
+            continue;
+        }
+        let total_weight = sample_count + history_weight;
+        for c in 0..3 {
+            color[i * 3 + c] = (color[i * 3 + c] * sample_count + history.color[i * 3 + c] * history_weight) / total_weight;
+        }
+        weights[i] = total_weight;
+    }
+    weights
+}
@@ -7,6 +7,11 @@ struct Uniforms {
     width: u32,
     height: u32,
     tonemapping: u32,
+    exposure: f32,
+    dither: u32,
+    dither_seed: u32,
+    srgb_target: u32, // The target encodes to sRGB when written, so dithering has to happen in that encoding
+    _padding: u32,
 };
 
 @group(0) @binding(0)
@@ -15,6 +20,9 @@ var<uniform> uniforms: Uniforms;
 @group(0) @binding(1)
 var<storage> render_buffer: array<f32>;
 
+@group(0) @binding(2)
+var<storage> dither_tile: array<f32>;
+
 var<private> v_positions: array<vec2<f32>, 6> = array<vec2<f32>, 6>(
     vec2<f32>(-1.0, -1.0),
     vec2<f32>(-1.0, 1.0),
@@ -116,6 +124,57 @@ fn uncharted(v: vec3<f32>) -> vec3<f32> {
     return curr * white_scale;
 }
 
+// AgX https://iolite-engine.com/blog_posts/minimal_agx_implementation, must match tonemap.rs
+fn agxContrast(x: vec3<f32>) -> vec3<f32> {
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    return 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232;
+}
+
+fn agx(x: vec3<f32>) -> vec3<f32> {
+    let agxInset = mat3x3<f32>(
+        vec3<f32>(0.842479062253094, 0.0423282422610123, 0.0423756549057051),
+        vec3<f32>(0.0784335999999992, 0.878468636469772, 0.0784336),
+        vec3<f32>(0.0792237451477643, 0.0791661274605434, 0.879142973793104)
+    );
+    let agxOutset = mat3x3<f32>(
+        vec3<f32>(1.19687900512017, -0.0528968517574562, -0.0529716355144438),
+        vec3<f32>(-0.0980208811401368, 1.15190312990417, -0.0980434501171241),
+        vec3<f32>(-0.0990297440797205, -0.0989611768448433, 1.15107367264116)
+    );
+    let minEv = -12.47393;
+    let maxEv = 4.026069;
+
+    var color = max(agxInset * x, vec3<f32>(1e-10));
+    color = clamp((log2(color) - minEv) / (maxEv - minEv), vec3<f32>(0.0), vec3<f32>(1.0));
+    color = agxContrast(color);
+    color = agxOutset * color;
+
+    // Linearize, the curve outputs display encoded values
+    return min(pow(max(color, vec3<f32>(0.0)), vec3<f32>(2.2)), vec3<f32>(1.0));
+}
+
+fn linear_to_srgb(x: vec3<f32>) -> vec3<f32> {
+    let c = saturate(x);
+    return select(1.055 * pow(c, vec3<f32>(1.0 / 2.4)) - 0.055, c * 12.92, c <= vec3<f32>(0.0031308));
+}
+
+fn srgb_to_linear(x: vec3<f32>) -> vec3<f32> {
+    let c = max(x, vec3<f32>(0.0));
+    return select(pow((c + 0.055) / 1.055, vec3<f32>(2.4)), c / 12.92, c <= vec3<f32>(0.04045));
+}
+
+// Offset by up to half a step of 8-bit output before it is quantized, to break up banding. Must match encode.rs.
+fn dither(color: vec3<f32>, pixel: vec2<u32>) -> vec3<f32> {
+    let hash = uniforms.dither_seed * 0x9E3779B9u;
+    let tile = (pixel + vec2<u32>(hash, hash >> 8u) % 256u) % 256u;
+    let offset = (dither_tile[tile.y * 256u + tile.x] - 0.5) / 255.0;
+    if (uniforms.srgb_target != 0u) {
+        return srgb_to_linear(linear_to_srgb(color) + offset);
+    }
+    return color + offset;
+}
+
 @fragment
 fn fs_main(in: VertexOut) -> @location(0) vec4<f32> {
     var uv = in.uv;
@@ -127,7 +186,8 @@ fn fs_main(in: VertexOut) -> @location(0) vec4<f32> {
     color.g = render_buffer[idx*3u+1u];
     color.b = render_buffer[idx*3u+2u];
 
-    var tonemapped = color.rgb;
+    // Exposure compensation in EV
+    var tonemapped = color.rgb * exp2(uniforms.exposure);
     switch (uniforms.tonemapping) {
         case 1u: { // Reinhard
             tonemapped = reinhard(tonemapped);
@@ -147,10 +207,17 @@ fn fs_main(in: VertexOut) -> @location(0) vec4<f32> {
         case 6u: { // Uncharted
             tonemapped = uncharted(tonemapped);
         }
+        case 7u: { // AgX
+            tonemapped = agx(tonemapped);
+        }
         default: {
             // No tonemapping
         }
     }
 
+    if (uniforms.dither != 0u) {
+        tonemapped = dither(tonemapped, puv);
+    }
+
     return vec4<f32>(tonemapped, 1.0);
 }
\ No newline at end of file
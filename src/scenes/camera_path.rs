@@ -0,0 +1,144 @@
+// Camera paths for flythroughs, given as keyframes in a JSON file:
+//
+// { "keyframes": [
+//     { "time": 0.0, "position": [0, 1, -5], "target": [0, 1, 0], "fov": 90 },
+//     { "time": 2.5, "position": [3, 2, -3], "target": [0, 1, 0], "fov": 60 }
+// ] }
+//
+// Times are in seconds, and the field of view is horizontal, in degrees. It can be left out, in which case the
+// default of the renderer is used. Positions are interpolated with a Catmull-Rom spline, which passes through every
+// keyframe with a continuous velocity, orientations are slerped, and the field of view is interpolated linearly.
+// Before the first and after the last keyframe, the camera holds still.
+
+use glam::{Quat, Vec3, Vec4};
+use shared_structs::TracingConfig;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CameraKeyframe {
+    pub time: f32,
+    pub position: Vec3,
+    pub target: Vec3,
+    pub fov: f32, // Radians
+}
+
+// Where the camera is at some point along a path, in the form `TracingConfig` expects
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CameraPose {
+    pub position: Vec3,
+    pub pitch: f32,
+    pub yaw: f32,
+    pub fov: f32,
+}
+
+impl CameraPose {
+    pub fn apply(&self, config: &mut TracingConfig) {
+        config.cam_position = self.position.extend(0.0);
+        config.cam_rotation = Vec4::new(self.pitch, self.yaw, 0.0, 0.0);
+        config.fov = self.fov;
+    }
+
+    // The camera looks down +Z before it is rotated, by pitch around X and then yaw around Y
+    fn rotation(&self) -> Quat {
+        Quat::from_rotation_y(self.yaw) * Quat::from_rotation_x(self.pitch)
+    }
+}
+
+// Pitch and yaw of a camera looking along `direction`
+fn look_angles(direction: Vec3) -> (f32, f32) {
+    let direction = direction.normalize_or_zero();
+    ((-direction.y).clamp(-1.0, 1.0).asin(), direction.x.atan2(direction.z))
+}
+
+impl CameraKeyframe {
+    fn pose(&self) -> CameraPose {
+        let (pitch, yaw) = look_angles(self.target - self.position);
+        CameraPose { position: self.position, pitch, yaw, fov: self.fov }
+    }
+}
+
+// Uniform Catmull-Rom spline through p1 at s = 0 and p2 at s = 1
+fn catmull_rom(p0: Vec3, p1: Vec3, p2: Vec3, p3: Vec3, s: f32) -> Vec3 {
+    let s2 = s * s;
+    let s3 = s2 * s;
+    0.5 * (2.0 * p1 + (p2 - p0) * s + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * s2 + (3.0 * p1 - p0 - 3.0 * p2 + p3) * s3)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CameraPath {
+    keyframes: Vec<CameraKeyframe>, // Sorted by time
+}
+
+fn parse_vec3(value: &serde_json::Value) -> Option<Vec3> {
+    match value.as_array()?.as_slice() {
+        [x, y, z] => Some(Vec3::new(x.as_f64()? as f32, y.as_f64()? as f32, z.as_f64()? as f32)),
+        _ => None,
+    }
+}
+
+impl CameraPath {
+    // Keyframes can be given in any order. Returns None if there are none.
+    pub fn new(mut keyframes: Vec<CameraKeyframe>) -> Option<Self> {
+        if keyframes.is_empty() {
+            return None;
+        }
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        Some(Self { keyframes })
+    }
+
+    // Parse the format described at the top of this file. Returns None if it is malformed or has no keyframes.
+    pub fn from_json(json: &str) -> Option<Self> {
+        let json = serde_json::from_str::<serde_json::Value>(json).ok()?;
+        let default_fov = TracingConfig::default().fov;
+        let keyframes = json["keyframes"]
+            .as_array()?
+            .iter()
+            .map(|keyframe| Some(CameraKeyframe {
+                time: keyframe["time"].as_f64()? as f32,
+                position: parse_vec3(&keyframe["position"])?,
+                target: parse_vec3(&keyframe["target"])?,
+                fov: keyframe["fov"].as_f64().map_or(default_fov, |degrees| (degrees as f32).to_radians()),
+            }))
+            .collect::<Option<Vec<_>>>()?;
+        Self::new(keyframes)
+    }
+
+    pub fn from_path(path: &str) -> Option<Self> {
+        Self::from_json(&std::fs::read_to_string(path).ok()?)
+    }
+
+    pub fn keyframes(&self) -> &[CameraKeyframe] {
+        &self.keyframes
+    }
+
+    // Time of the last keyframe, after which the camera stops
+    pub fn duration(&self) -> f32 {
+        self.keyframes[self.keyframes.len() - 1].time
+    }
+
+    pub fn evaluate(&self, time: f32) -> CameraPose {
+        let last = self.keyframes.len() - 1;
+        if time <= self.keyframes[0].time {
+            return self.keyframes[0].pose();
+        }
+        if time >= self.keyframes[last].time {
+            return self.keyframes[last].pose();
+        }
+
+        // Segment from keyframe i to i + 1, with the neighbours on either side shaping the curve
+        let i = self.keyframes.partition_point(|keyframe| keyframe.time <= time) - 1;
+        let (start, end) = (&self.keyframes[i], &self.keyframes[i + 1]);
+        let s = (time - start.time) / (end.time - start.time);
+        if s == 0.0 {
+            return start.pose();
+        }
+        let before = &self.keyframes[i.saturating_sub(1)];
+        let after = &self.keyframes[(i + 2).min(last)];
+        let position = catmull_rom(before.position, start.position, end.position, after.position, s);
+
+        // Slerp can introduce a little roll, which the camera can't represent, so only its forward direction is kept
+        let rotation = start.pose().rotation().slerp(end.pose().rotation(), s);
+        let (pitch, yaw) = look_angles(rotation * Vec3::Z);
+        let fov = start.fov + (end.fov - start.fov) * s;
+        CameraPose { position, pitch, yaw, fov }
+    }
+}
@@ -0,0 +1,78 @@
+// Procedural Cornell box, built straight into the world buffers so it can be rendered without any asset files.
+// Geometry is the measured data from https://www.graphics.cornell.edu/online/box/data.html, converted from
+// millimeters to meters and mirrored in x, since the original data is left handed. Materials and emission are the
+// usual RGB approximations of the measured spectra, as used by most renderers.
+
+use glam::{Vec3, Vec4};
+
+use super::{SceneBuilder, diffuse};
+use crate::asset::World;
+
+pub const SCENE_NAME: &str = "cornell";
+
+// The original camera sits at z = -0.8 with a narrow field of view. The trace kernel always uses a 90 degree
+// horizontal field of view, so the camera is moved closer to keep the box framed at 16:9.
+pub const CAMERA_POSITION: Vec4 = Vec4::new(0.0, 0.273, -0.55, 0.0);
+pub const CAMERA_ROTATION: Vec4 = Vec4::ZERO;
+
+const LIGHT_RADIANCE: Vec3 = Vec3::new(17.0, 12.0, 4.0);
+
+// The light is coplanar with the ceiling in the original data, so nudge it down to avoid z-fighting
+const LIGHT_OFFSET: f32 = 0.1;
+
+type Quad = [[f32; 3]; 4];
+
+const FLOOR: Quad = [[552.8, 0.0, 0.0], [0.0, 0.0, 0.0], [0.0, 0.0, 559.2], [549.6, 0.0, 559.2]];
+const CEILING: Quad = [[556.0, 548.8, 0.0], [556.0, 548.8, 559.2], [0.0, 548.8, 559.2], [0.0, 548.8, 0.0]];
+const BACK_WALL: Quad = [[549.6, 0.0, 559.2], [0.0, 0.0, 559.2], [0.0, 548.8, 559.2], [556.0, 548.8, 559.2]];
+const GREEN_WALL: Quad = [[0.0, 0.0, 559.2], [0.0, 0.0, 0.0], [0.0, 548.8, 0.0], [0.0, 548.8, 559.2]];
+const RED_WALL: Quad = [[552.8, 0.0, 0.0], [549.6, 0.0, 559.2], [556.0, 548.8, 559.2], [556.0, 548.8, 0.0]];
+const LIGHT_QUAD: Quad = [
+    [343.0, 548.8 - LIGHT_OFFSET, 227.0],
+    [343.0, 548.8 - LIGHT_OFFSET, 332.0],
+    [213.0, 548.8 - LIGHT_OFFSET, 332.0],
+    [213.0, 548.8 - LIGHT_OFFSET, 227.0],
+];
+
+// Corners of the top face of each block, the sides extend down to the floor
+const SHORT_BLOCK: ([[f32; 2]; 4], f32) = ([[130.0, 65.0], [82.0, 225.0], [240.0, 272.0], [290.0, 114.0]], 165.0);
+const TALL_BLOCK: ([[f32; 2]; 4], f32) = ([[423.0, 247.0], [265.0, 296.0], [314.0, 456.0], [472.0, 406.0]], 330.0);
+
+fn to_world(point: [f32; 3]) -> Vec3 {
+    Vec3::new(278.0 - point[0], point[1], point[2]) * 0.001
+}
+
+fn push_block(builder: &mut SceneBuilder, (top, height): ([[f32; 2]; 4], f32), material_index: u32) {
+    let center = to_world([
+        top.iter().map(|c| c[0]).sum::<f32>() / 4.0,
+        height * 0.5,
+        top.iter().map(|c| c[1]).sum::<f32>() / 4.0,
+    ]);
+    builder.push_quad(top.map(|c| to_world([c[0], height, c[1]])), Vec3::Y, material_index);
+    for i in 0..4 {
+        let a = top[i];
+        let b = top[(i + 1) % 4];
+        let side = [[a[0], 0.0, a[1]], [a[0], height, a[1]], [b[0], height, b[1]], [b[0], 0.0, b[1]]].map(to_world);
+        builder.push_quad(side, side[0] - center, material_index);
+    }
+}
+
+pub fn build_world() -> World {
+    let mut builder = SceneBuilder::default();
+    let white = builder.push_material(diffuse(Vec3::new(0.725, 0.71, 0.68)));
+    let red = builder.push_material(diffuse(Vec3::new(0.63, 0.065, 0.05)));
+    let green = builder.push_material(diffuse(Vec3::new(0.14, 0.45, 0.091)));
+    let mut light = diffuse(Vec3::splat(0.78));
+    light.emissive = LIGHT_RADIANCE.extend(1.0);
+    let light = builder.push_material(light);
+
+    let inside = to_world([278.0, 274.4, 279.6]);
+    for (quad, material_index) in [(FLOOR, white), (CEILING, white), (BACK_WALL, white), (GREEN_WALL, green), (RED_WALL, red)] {
+        let corners = quad.map(to_world);
+        builder.push_quad(corners, inside - corners[0], material_index);
+    }
+    builder.push_quad(LIGHT_QUAD.map(to_world), -Vec3::Y, light);
+    push_block(&mut builder, SHORT_BLOCK, white);
+    push_block(&mut builder, TALL_BLOCK, white);
+    builder.build()
+}
@@ -0,0 +1,176 @@
+// Built-in scenes, generated in code rather than loaded from a file. These make it possible to test each
+// feature without any asset files, and are used as reference scenes by the tests.
+//
+// To add a new named scene, write a function which fills a `SceneBuilder` and returns `builder.build()`, then add
+// an entry to `BUILTIN_SCENES` with a unique name and a camera that frames it. The scene can then be loaded by
+// passing its name anywhere a scene path is accepted, such as `--scene <name>`, and shows up in the UI.
+
+use glam::{UVec4, Vec2, Vec3, Vec4};
+use image::DynamicImage;
+use shared_structs::{MaterialData, PrimitiveType, make_primitive};
+
+use crate::asset::World;
+
+pub mod cornell;
+pub mod showcase;
+pub mod camera_path;
+
+pub use camera_path::CameraPath;
+
+pub struct BuiltinScene {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub camera_position: Vec4,
+    pub camera_rotation: Vec4,
+    pub build: fn() -> World,
+}
+
+pub const BUILTIN_SCENES: &[BuiltinScene] = &[
+    BuiltinScene {
+        name: cornell::SCENE_NAME,
+        description: "The classic Cornell box, with colored walls, an area light and two boxes",
+        camera_position: cornell::CAMERA_POSITION,
+        camera_rotation: cornell::CAMERA_ROTATION,
+        build: cornell::build_world,
+    },
+    BuiltinScene {
+        name: "spheres",
+        description: "Grid of spheres with roughness increasing to the right and metalness increasing upwards",
+        camera_position: Vec4::new(0.0, 2.7, -5.0, 0.0),
+        camera_rotation: Vec4::ZERO,
+        build: showcase::build_sphere_grid,
+    },
+    BuiltinScene {
+        name: "textured-plane",
+        description: "A single plane with a UV test texture, for checking texture mapping and filtering",
+        camera_position: Vec4::new(0.0, 2.0, -3.0, 0.0),
+        camera_rotation: Vec4::new(0.6, 0.0, 0.0, 0.0),
+        build: showcase::build_textured_plane,
+    },
+    BuiltinScene {
+        name: "glass",
+        description: "Glass sphere on a checkerboard, for checking refraction",
+        camera_position: Vec4::new(0.0, 1.2, -3.0, 0.0),
+        camera_rotation: Vec4::new(0.15, 0.0, 0.0, 0.0),
+        build: showcase::build_glass_sphere,
+    },
+    BuiltinScene {
+        name: "normal-map",
+        description: "Strips sharing one tilted normal map, with the green channel flipped, at zero strength, and as-is",
+        camera_position: Vec4::new(0.0, 1.6, 0.0, 0.0),
+        camera_rotation: Vec4::new(std::f32::consts::FRAC_PI_2, 0.0, 0.0, 0.0),
+        build: showcase::build_normal_map_strips,
+    },
+    BuiltinScene {
+        name: "shadow-catcher",
+        description: "Sphere on a shadow catcher floor, which only shows the shadow, for compositing",
+        camera_position: Vec4::new(0.0, 1.2, -3.0, 0.0),
+        camera_rotation: Vec4::new(0.15, 0.0, 0.0, 0.0),
+        build: showcase::build_shadow_catcher,
+    },
+    BuiltinScene {
+        name: "shadow-catcher-lit",
+        description: "Sphere on a shadow catcher floor, lit by a hidden area light, so it casts a shadow to one side",
+        camera_position: Vec4::new(0.0, 1.2, -3.0, 0.0),
+        camera_rotation: Vec4::new(0.15, 0.0, 0.0, 0.0),
+        build: showcase::build_shadow_catcher_lit,
+    },
+    BuiltinScene {
+        name: "empty",
+        description: "No geometry, only the environment, for checking the orientation of a skybox",
+        camera_position: Vec4::ZERO,
+        camera_rotation: Vec4::ZERO,
+        build: showcase::build_empty,
+    },
+];
+
+pub fn find_builtin_scene(name: &str) -> Option<&'static BuiltinScene> {
+    BUILTIN_SCENES.iter().find(|scene| scene.name == name)
+}
+
+// Accumulates geometry and materials in the layout `World::from_geometry` expects
+#[derive(Default)]
+pub struct SceneBuilder {
+    vertices: Vec<Vec3>,
+    indices: Vec<UVec4>,
+    normals: Vec<Vec4>,
+    tangents: Vec<Vec4>,
+    uvs: Vec<Vec2>,
+    radii: Vec<f32>,
+    material_datas: Vec<MaterialData>,
+    textures: Vec<DynamicImage>,
+}
+
+impl SceneBuilder {
+    // Returns the material index to use for primitives
+    pub fn push_material(&mut self, material: MaterialData) -> u32 {
+        self.material_datas.push(material);
+        self.material_datas.len() as u32 - 1
+    }
+
+    // Same as `push_material`, but with an albedo texture, which must be in linear space
+    pub fn push_textured_material(&mut self, mut material: MaterialData, albedo: DynamicImage) -> u32 {
+        // The atlas hands out texture locations in material order, so this holds as long as materials only have one texture
+        material.set_has_albedo_texture(true);
+        self.textures.push(albedo);
+        self.push_material(material)
+    }
+
+    // Same as `push_material`, but with a tangent space normal map
+    pub fn push_normal_mapped_material(&mut self, mut material: MaterialData, normal_map: DynamicImage) -> u32 {
+        material.set_has_normal_texture(true);
+        self.textures.push(normal_map);
+        self.push_material(material)
+    }
+
+    // Push a quad as 2 triangles, wound so the front face points along `facing`. Every quad gets its own
+    // vertices, so the shading normals stay flat. UVs go from 0 to 1 across the quad.
+    pub fn push_quad(&mut self, mut corners: [Vec3; 4], facing: Vec3, material_index: u32) {
+        if (corners[1] - corners[0]).cross(corners[2] - corners[0]).dot(facing) < 0.0 {
+            corners.reverse();
+        }
+        let normal = (corners[1] - corners[0]).cross(corners[2] - corners[0]).normalize();
+        let tangent = (corners[1] - corners[0]).normalize();
+
+        let base = self.vertices.len() as u32;
+        self.vertices.extend(corners);
+        self.normals.extend([normal.extend(0.0); 4]);
+        self.tangents.extend([tangent.extend(0.0); 4]);
+        self.uvs.extend([Vec2::new(0.0, 0.0), Vec2::new(1.0, 0.0), Vec2::new(1.0, 1.0), Vec2::new(0.0, 1.0)]);
+        self.indices.push(UVec4::new(base, base + 1, base + 2, material_index));
+        self.indices.push(UVec4::new(base, base + 2, base + 3, material_index));
+    }
+
+    pub fn push_sphere(&mut self, center: Vec3, radius: f32, material_index: u32) {
+        let base = self.vertices.len() as u32;
+        self.vertices.push(center);
+        self.radii.resize(base as usize, 0.0);
+        self.radii.push(radius);
+        self.normals.push(Vec4::ZERO);
+        self.tangents.push(Vec4::ZERO);
+        self.uvs.push(Vec2::ZERO);
+        self.indices.push(make_primitive(base, base, base, material_index, PrimitiveType::Sphere));
+    }
+
+    pub fn build(self) -> World {
+        World::from_geometry(
+            self.vertices,
+            self.indices,
+            self.normals,
+            self.tangents,
+            self.uvs,
+            Vec::new(),
+            Vec::new(),
+            self.radii,
+            self.material_datas,
+            self.textures,
+        )
+    }
+}
+
+pub fn diffuse(albedo: Vec3) -> MaterialData {
+    let mut material = MaterialData::default();
+    material.albedo = albedo.extend(1.0);
+    material.roughness = Vec4::ONE;
+    material
+}
@@ -0,0 +1,161 @@
+// Small scenes which each show off one material feature, lit by the procedural sky
+
+use glam::{Vec3, Vec4};
+use image::{DynamicImage, RgbImage};
+use shared_structs::MaterialData;
+
+use super::{SceneBuilder, diffuse};
+use crate::asset::World;
+
+const GRID_SIZE: u32 = 5;
+const GRID_SPACING: f32 = 1.1;
+const GRID_RADIUS: f32 = 0.45;
+
+const TEXTURE_SIZE: u32 = 256;
+
+// Horizontal square centered on the origin, facing up
+fn floor_corners(half_size: f32) -> [Vec3; 4] {
+    [
+        Vec3::new(-half_size, 0.0, -half_size),
+        Vec3::new(half_size, 0.0, -half_size),
+        Vec3::new(half_size, 0.0, half_size),
+        Vec3::new(-half_size, 0.0, half_size),
+    ]
+}
+
+// Linear space checkerboard with `checks` squares along each side
+fn checkerboard(checks: u32, dark: [u8; 3], light: [u8; 3]) -> DynamicImage {
+    let check_size = TEXTURE_SIZE / checks;
+    DynamicImage::ImageRgb8(RgbImage::from_fn(TEXTURE_SIZE, TEXTURE_SIZE, |x, y| {
+        image::Rgb(if (x / check_size + y / check_size) % 2 == 0 { dark } else { light })
+    }))
+}
+
+// Checkerboard tinted red along u and green along v, so both the orientation and the filtering are easy to judge
+fn uv_test_texture() -> DynamicImage {
+    let mut texture = checkerboard(8, [40, 40, 40], [200, 200, 200]).into_rgb8();
+    for (x, y, pixel) in texture.enumerate_pixels_mut() {
+        pixel[0] = pixel[0].saturating_add((x * 55 / TEXTURE_SIZE) as u8);
+        pixel[1] = pixel[1].saturating_add((y * 55 / TEXTURE_SIZE) as u8);
+    }
+    DynamicImage::ImageRgb8(texture)
+}
+
+// Every texel tilts the normal 30 degrees along the tangent space y axis
+fn tilted_normal_map() -> DynamicImage {
+    let (sin, cos) = 30f32.to_radians().sin_cos();
+    let texel = [0.5, 0.5 + sin * 0.5, 0.5 + cos * 0.5].map(|c| (c * 255.0).round() as u8);
+    DynamicImage::ImageRgb8(RgbImage::from_pixel(TEXTURE_SIZE, TEXTURE_SIZE, image::Rgb(texel)))
+}
+
+pub fn build_sphere_grid() -> World {
+    let mut builder = SceneBuilder::default();
+    let floor = builder.push_material(diffuse(Vec3::splat(0.5)));
+    builder.push_quad(floor_corners(10.0), Vec3::Y, floor);
+
+    // Roughness increases along x, metalness along y
+    for row in 0..GRID_SIZE {
+        for column in 0..GRID_SIZE {
+            let mut material = MaterialData::default();
+            material.albedo = Vec4::new(1.0, 0.78, 0.34, 1.0);
+            material.roughness = Vec4::splat(column as f32 / (GRID_SIZE - 1) as f32);
+            material.metallic = Vec4::splat(row as f32 / (GRID_SIZE - 1) as f32);
+            let material = builder.push_material(material);
+            let offset = (GRID_SIZE - 1) as f32 * 0.5;
+            let center = Vec3::new(
+                (column as f32 - offset) * GRID_SPACING,
+                GRID_RADIUS + 0.05 + row as f32 * GRID_SPACING,
+                0.0,
+            );
+            builder.push_sphere(center, GRID_RADIUS, material);
+        }
+    }
+    builder.build()
+}
+
+pub fn build_textured_plane() -> World {
+    let mut builder = SceneBuilder::default();
+    let plane = builder.push_textured_material(diffuse(Vec3::ONE), uv_test_texture());
+    builder.push_quad(floor_corners(2.0), Vec3::Y, plane);
+    builder.build()
+}
+
+pub fn build_glass_sphere() -> World {
+    let mut builder = SceneBuilder::default();
+    let floor = builder.push_textured_material(diffuse(Vec3::ONE), checkerboard(8, [20, 20, 20], [230, 230, 230]));
+    builder.push_quad(floor_corners(5.0), Vec3::Y, floor);
+
+    let mut glass = MaterialData::default();
+    glass.albedo = Vec4::ONE;
+    glass.roughness = Vec4::ZERO;
+    glass.transmission = Vec4::ONE;
+    let glass = builder.push_material(glass);
+    builder.push_sphere(Vec3::new(0.0, 0.75, 0.0), 0.75, glass);
+    builder.build()
+}
+
+// Seen from above, lit from the +z side by a wall light. The flipped and regular strips tilt towards and away from the
+// light, so one is brighter and the other darker than the flat strip in the middle.
+pub fn build_normal_map_strips() -> World {
+    let mut builder = SceneBuilder::default();
+    for (x, flip_green, normal_scale) in [(-1.0, true, 1.0), (0.0, false, 0.0), (1.0, false, 1.0)] {
+        let mut material = diffuse(Vec3::splat(0.8));
+        material.normal_scale = normal_scale;
+        material.set_flip_normal_green(flip_green);
+        let strip = builder.push_normal_mapped_material(material, tilted_normal_map());
+        let corners = floor_corners(0.5).map(|corner| corner + Vec3::new(x, 0.0, 0.0));
+        builder.push_quad(corners, Vec3::Y, strip);
+    }
+
+    let mut light = diffuse(Vec3::ONE);
+    light.emissive = Vec4::new(5.0, 5.0, 5.0, 1.0);
+    let light = builder.push_material(light);
+    builder.push_quad([
+        Vec3::new(-3.0, 0.0, 2.0),
+        Vec3::new(3.0, 0.0, 2.0),
+        Vec3::new(3.0, 2.0, 2.0),
+        Vec3::new(-3.0, 2.0, 2.0),
+    ], -Vec3::Z, light);
+    builder.build()
+}
+
+// Sphere resting on a shadow catcher floor, for compositing over a photo. Rendered with a transparent background,
+// only the sphere and its shadow are left.
+pub fn build_shadow_catcher() -> World {
+    let mut builder = SceneBuilder::default();
+    let mut catcher = diffuse(Vec3::splat(0.8));
+    catcher.set_shadow_catcher(true);
+    let floor = builder.push_material(catcher);
+    builder.push_quad(floor_corners(5.0), Vec3::Y, floor);
+
+    let sphere = builder.push_material(diffuse(Vec3::new(0.8, 0.3, 0.2)));
+    builder.push_sphere(Vec3::new(0.0, 0.75, 0.0), 0.75, sphere);
+    builder.build()
+}
+
+// The same sphere and shadow catcher floor, lit by an area light off to one side, which casts a shadow away from it.
+// The light is hidden from the camera, so only its shadow is seen.
+pub fn build_shadow_catcher_lit() -> World {
+    let mut builder = SceneBuilder::default();
+    let mut catcher = diffuse(Vec3::splat(0.8));
+    catcher.set_shadow_catcher(true);
+    let floor = builder.push_material(catcher);
+    builder.push_quad(floor_corners(5.0), Vec3::Y, floor);
+
+    let sphere = builder.push_material(diffuse(Vec3::new(0.8, 0.3, 0.2)));
+    builder.push_sphere(Vec3::new(0.0, 0.75, 0.0), 0.75, sphere);
+
+    let mut light = diffuse(Vec3::ONE);
+    light.emissive = Vec4::new(10.0, 10.0, 10.0, 1.0);
+    light.set_hidden_from_camera(true);
+    let light = builder.push_material(light);
+    let corners = floor_corners(0.5).map(|corner| corner + Vec3::new(2.0, 2.0, 0.0));
+    builder.push_quad(corners, -Vec3::Y, light);
+    builder.build()
+}
+
+// No geometry at all, so every ray escapes and the image is just the environment, for checking how an HDR skybox is
+// oriented
+pub fn build_empty() -> World {
+    SceneBuilder::default().build()
+}
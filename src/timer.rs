@@ -0,0 +1,96 @@
+// Timing of GPU work with timestamp queries, which measure how long the work ran on the GPU itself, leaving out the
+// time spent submitting it and waiting for it. Needs `Features::TIMESTAMP_QUERY`, which some backends lack, see
+// `adapter::tracing_supports_timestamps`. Without it the caller falls back to the CPU clock.
+
+use std::time::Duration;
+
+// Pairs of timestamps written before they have to be read back
+const TIMER_CAPACITY: u32 = 64;
+
+pub struct GpuTimer<'a> {
+    device: &'a wgpu::Device,
+    queue: &'a wgpu::Queue,
+    query_set: wgpu::QuerySet,
+    resolve_buffer: wgpu::Buffer,
+    readback_buffer: wgpu::Buffer,
+    pending: u32, // Pairs written since they were last read back
+    elapsed: Duration,
+}
+
+impl<'a> GpuTimer<'a> {
+    // None if the device can't write timestamps
+    pub fn new(device: &'a wgpu::Device, queue: &'a wgpu::Queue) -> Option<Self> {
+        if !device.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+            return None;
+        }
+        let query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
+            label: None,
+            ty: wgpu::QueryType::Timestamp,
+            count: TIMER_CAPACITY * 2,
+        });
+        let size = (TIMER_CAPACITY * 2) as u64 * std::mem::size_of::<u64>() as u64;
+        let resolve_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size,
+            usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        Some(Self { device, queue, query_set, resolve_buffer, readback_buffer, pending: 0, elapsed: Duration::ZERO })
+    }
+
+    // Time the work `submit` submits to the queue. Submissions run in order, so timestamps written in submissions
+    // right before and after it bracket it.
+    pub fn time(&mut self, submit: impl FnOnce()) {
+        if self.pending == TIMER_CAPACITY {
+            self.read_back();
+        }
+        self.write_timestamp(self.pending * 2);
+        submit();
+        self.write_timestamp(self.pending * 2 + 1);
+        self.pending += 1;
+    }
+
+    // Time the timed work ran for since the last call, waiting for it to finish
+    pub fn elapsed(&mut self) -> Duration {
+        self.read_back();
+        std::mem::take(&mut self.elapsed)
+    }
+
+    fn write_timestamp(&self, index: u32) {
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        encoder.write_timestamp(&self.query_set, index);
+        self.queue.submit(Some(encoder.finish()));
+    }
+
+    fn read_back(&mut self) {
+        if self.pending == 0 {
+            return;
+        }
+        let count = self.pending * 2;
+        let size = count as u64 * std::mem::size_of::<u64>() as u64;
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        encoder.resolve_query_set(&self.query_set, 0..count, &self.resolve_buffer, 0);
+        encoder.copy_buffer_to_buffer(&self.resolve_buffer, 0, &self.readback_buffer, 0, size);
+        self.queue.submit(Some(encoder.finish()));
+
+        let buffer_slice = self.readback_buffer.slice(..size);
+        buffer_slice.map_async(wgpu::MapMode::Read, |_| {});
+        self.device.poll(wgpu::Maintain::Wait);
+        {
+            let data = buffer_slice.get_mapped_range();
+            let ticks: &[u64] = bytemuck::cast_slice(&data);
+            // Timestamps count ticks of a length that depends on the GPU
+            let period = self.queue.get_timestamp_period() as f64;
+            let nanoseconds = ticks.chunks_exact(2).map(|pair| pair[1].saturating_sub(pair[0]) as f64 * period).sum::<f64>();
+            self.elapsed += Duration::from_nanos(nanoseconds as u64);
+        }
+        self.readback_buffer.unmap();
+        self.pending = 0;
+    }
+}
@@ -0,0 +1,37 @@
+// CPU reference implementations of tonemapping operators. The display path runs the equivalent code in
+// render.wgsl, so any change here must be mirrored there.
+
+use glam::{Mat3, Vec3};
+
+// AgX by Troy Sobotka, using the sRGB fitted matrices and sigmoid from https://iolite-engine.com/blog_posts/minimal_agx_implementation
+const AGX_INSET: Mat3 = Mat3::from_cols_array(&[
+    0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+    0.0784335999999992, 0.878468636469772, 0.0784336,
+    0.0792237451477643, 0.0791661274605434, 0.879142973793104,
+]);
+const AGX_OUTSET: Mat3 = Mat3::from_cols_array(&[
+    1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+    -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+    -0.0990297440797205, -0.0989611768448433, 1.15107367264116,
+]);
+// Log2 range of the encoding, middle gray is placed at -2.47393 EV
+const AGX_MIN_EV: f32 = -12.47393;
+const AGX_MAX_EV: f32 = 4.026069;
+
+// 6th order polynomial fit of the default AgX contrast curve
+fn agx_contrast(x: Vec3) -> Vec3 {
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232
+}
+
+pub fn agx(color: Vec3) -> Vec3 {
+    // The inset matrix pulls saturated colors towards the achromatic axis, so bright colors approach white
+    let inset = (AGX_INSET * color).max(Vec3::splat(1e-10));
+    let log = Vec3::new(inset.x.log2(), inset.y.log2(), inset.z.log2());
+    let encoded = (log - AGX_MIN_EV) / (AGX_MAX_EV - AGX_MIN_EV);
+    let curve = agx_contrast(encoded.clamp(Vec3::ZERO, Vec3::ONE));
+    let outset = AGX_OUTSET * curve;
+    // The curve outputs display encoded values, linearize them since the display applies its own transfer function
+    outset.max(Vec3::ZERO).powf(2.2).min(Vec3::ONE)
+}
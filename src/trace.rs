@@ -1,52 +1,105 @@
-const KERNEL: &[u8] = include_bytes!(env!("kernels.spv"));
 const BLUE_BYTES: &[u8] = include_bytes!("resources/bluenoise.png");
 lazy_static::lazy_static! {
     pub static ref FW: gpgpu::Framework = make_framework();
     pub static ref BLUE_TEXTURE: RgbaImage = Reader::new(Cursor::new(BLUE_BYTES)).with_guessed_format().unwrap().decode().unwrap().into_rgba8();
 }
 
-use glam::{UVec2, Vec4, UVec3};
+use glam::{UVec2, Vec2, Vec3, Vec4, UVec3};
 use gpgpu::{
     BufOps, DescriptorSet, GpuBuffer, GpuBufferUsage, GpuUniformBuffer, Kernel, Program, Shader, Sampler, SamplerWrapMode, SamplerFilterMode, GpuConstImage, primitives::pixels::Rgba32Float
 };
 use image::{RgbaImage, io::Reader, GenericImageView};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock, RwLockReadGuard};
 use pollster::FutureExt;
-use shared_structs::CpuImage;
+use shared_structs::{CpuImage, WorkingSpace, NextEventEstimation, welford_update, welford_variance_of_mean, bounce_stats_entry, bounce_stats_fixed_point, BOUNCE_STATS_BOUNCES, BOUNCE_STATS_LEN, BOUNCE_STATS_SCALE, BOUNCE_STATS_STRIDE, RAY_STATS_ENTRY};
 pub use shared_structs::TracingConfig;
 use std::{sync::{
     atomic::{Ordering, AtomicBool, AtomicU32},
     Arc,
-}, io::Cursor};
+}, io::Cursor, time::{Duration, Instant}};
 use rayon::prelude::*;
 
-use crate::{asset::{World, GpuWorld, dynamic_image_to_cpu_buffer, load_dynamic_image, dynamic_image_to_gpu_image, fallback_gpu_image, fallback_cpu_buffer}};
+use crate::{adapter::{WORKGROUP_SIZE, dispatch_size, tracing_limits, tracing_supports_timestamps}, kernel::{KERNEL, KERNEL_PATH, EntryPoint, KernelWatcher, load_kernel, validate_kernel}, asset::{World, CoordinateSystem, SceneFile, load_gltf_camera, GpuWorld, GpuAtlas, GpuIndexBuffer, dynamic_image_to_cpu_buffer, load_dynamic_image, dynamic_image_to_gpu_image, fallback_gpu_image, fallback_cpu_buffer}, post::{PostProcessConfig, apply_post_processing, luminance, resize_bilinear}, denoise::{Denoiser, GpuATrous, denoise}, reproject::{History, reproject, blend_history}, timer::GpuTimer};
 
 fn make_framework() -> gpgpu::Framework {
-    let backend = wgpu::util::backend_bits_from_env().unwrap_or(wgpu::Backends::PRIMARY);
     let power_preference = wgpu::util::power_preference_from_env()
         .unwrap_or(wgpu::PowerPreference::HighPerformance);
-    let instance = wgpu::Instance::new(backend);
-    let adapter = instance
-        .request_adapter(&wgpu::RequestAdapterOptions {
-            power_preference,
-            ..Default::default()
-        })
-        .block_on()
-        .expect("Failed at adapter creation.");
+    let instance = wgpu::Instance::new(crate::adapter::framework_backends());
+    let adapter = crate::adapter::selected_adapter().unwrap_or_else(|| {
+        instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference,
+                ..Default::default()
+            })
+            .block_on()
+            .expect("Failed at adapter creation.")
+    });
+    crate::adapter::set_tracing_limits(adapter.limits(), adapter.features());
     gpgpu::Framework::new(adapter, std::time::Duration::from_millis(1)).block_on()
 }
 
+// Extra output passes, which split the beauty pass by the lobe picked at the first bounce. Light from a specular
+// first bounce, such as reflections and refraction, goes to the specular pass, and everything else to the diffuse pass.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Aov {
+    Diffuse,
+    Specular,
+}
+
+impl Aov {
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Diffuse => "diffuse",
+            Aov::Specular => "specular",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "diffuse" => Some(Aov::Diffuse),
+            "specular" => Some(Aov::Specular),
+            _ => None,
+        }
+    }
+}
+
 pub struct TracingState {
     pub framebuffer: RwLock<Vec<f32>>,
+    pub alpha: RwLock<Vec<f32>>, // Fraction of primary rays which hit something, per pixel
     pub running: AtomicBool,
     pub samples: AtomicU32,
-    pub denoise: AtomicBool,
+    pub denoiser: AtomicU32,
+    pub denoise_interval: AtomicU32, // Denoise every nth frame of an accumulation, see `denoise_due`
     pub sync_rate: AtomicU32,
+    pub time_budget: AtomicU32, // seconds, 0 = unlimited
+    pub budget_reached: AtomicBool, // Set by the tracing thread when it stops because the time budget ran out
+    pub spatial_splits: AtomicBool, // Build the BVH with spatial splits, read when a scene is loaded
+    pub frame_all: AtomicBool, // Move the camera so the whole scene is in view, read when a scene is loaded
+    // Look through a camera of the glTF scene, by name, or the first one if the name is empty. Read when a scene is loaded.
+    pub gltf_camera: RwLock<Option<String>>,
+    pub target_fps: AtomicU32, // Frame rate to lower the bounce count for, 0 = off, see `BounceController`
     pub use_blue_noise: AtomicBool,
+    pub animated_noise: AtomicBool,
+    pub frame: AtomicU32,
+    pub temporal_reprojection: AtomicBool,
+    pub adaptive_resolution: AtomicBool,
     pub interacting: AtomicBool,
     pub dirty: AtomicBool,
+    pub paused: AtomicBool,
+    pub step_requests: AtomicU32, // Samples to render while paused
+    pub noise_estimate: AtomicU32, // Bits of an f32, see `noise_estimate`
+    pub aovs_enabled: AtomicBool, // Whether to resolve the diffuse and specular passes, which costs a readback
+    pub hot_reload: AtomicBool, // Reload the kernel when it is rebuilt, GPU only, read when tracing starts
+    pub kernel_path: RwLock<Option<String>>, // Compiled kernel to use instead of the embedded one, read when tracing starts
+    pub trace_stats: AtomicBool, // Print `ThroughputStats` when tracing stops, read when tracing starts
+    pub print_timings: AtomicBool, // Print `StageTimings` when tracing stops and images are saved
+    pub timings: RwLock<StageTimings>, // Of the current run, reset when a scene is loaded
+    pub priority_mask: RwLock<Vec<u32>>, // Samples per dispatch of each pixel, empty if none are prioritized
+    pub priority_version: AtomicU32, // Bumped whenever the priority mask changes, so tracing knows to pick it up
+    pub diffuse: RwLock<Vec<f32>>, // Empty until resolved, see `Aov`
+    pub specular: RwLock<Vec<f32>>,
     pub config: RwLock<TracingConfig>,
+    pub post_config: RwLock<PostProcessConfig>,
 }
 
 impl TracingState {
@@ -68,25 +121,217 @@ impl TracingState {
     pub fn new (width: u32, height: u32) -> Self {
         let (config, framebuffer) = Self::make_view_dependent_state(width, height, None);
         let config = RwLock::new(config);
+        let alpha = RwLock::new(vec![0.0; framebuffer.len() / 3]);
         let framebuffer = RwLock::new(framebuffer);
         let running = AtomicBool::new(false);
         let samples = AtomicU32::new(0);
-        let denoise = AtomicBool::new(false);
+        let denoiser = AtomicU32::new(Denoiser::None.to_u32());
+        let denoise_interval = AtomicU32::new(1);
         let sync_rate = AtomicU32::new(32);
+        let time_budget = AtomicU32::new(0);
+        let budget_reached = AtomicBool::new(false);
+        let spatial_splits = AtomicBool::new(false);
+        let frame_all = AtomicBool::new(false);
+        let gltf_camera = RwLock::new(None);
+        let target_fps = AtomicU32::new(0);
         let use_blue_noise = AtomicBool::new(true);
+        let animated_noise = AtomicBool::new(false);
+        let frame = AtomicU32::new(0);
+        let temporal_reprojection = AtomicBool::new(false);
+        let adaptive_resolution = AtomicBool::new(false);
         let interacting = AtomicBool::new(false);
         let dirty = AtomicBool::new(false);
+        let paused = AtomicBool::new(false);
+        let step_requests = AtomicU32::new(0);
+        let noise_estimate = AtomicU32::new(0);
+        let aovs_enabled = AtomicBool::new(false);
+        let hot_reload = AtomicBool::new(false);
+        let kernel_path = RwLock::new(None);
+        let trace_stats = AtomicBool::new(false);
+        let print_timings = AtomicBool::new(false);
+        let timings = RwLock::new(StageTimings::default());
+        let priority_mask = RwLock::new(Vec::new());
+        let priority_version = AtomicU32::new(0);
+        let diffuse = RwLock::new(Vec::new());
+        let specular = RwLock::new(Vec::new());
+        let post_config = RwLock::new(PostProcessConfig::default());
         
         Self {
             framebuffer,
+            alpha,
             running,
             samples,
-            denoise,
+            denoiser,
+            denoise_interval,
             sync_rate,
+            time_budget,
+            budget_reached,
+            spatial_splits,
+            frame_all,
+            gltf_camera,
+            target_fps,
             use_blue_noise,
+            animated_noise,
+            frame,
+            temporal_reprojection,
+            adaptive_resolution,
             interacting,
             dirty,
+            paused,
+            step_requests,
+            noise_estimate,
+            aovs_enabled,
+            hot_reload,
+            kernel_path,
+            trace_stats,
+            print_timings,
+            timings,
+            priority_mask,
+            priority_version,
+            diffuse,
+            specular,
             config,
+            post_config,
+        }
+    }
+
+    // Throw away all accumulated samples. A running render clears its accumulation buffers at the next flush,
+    // otherwise the framebuffer is cleared right away, so the next render doesn't continue from it.
+    pub fn reset(&self) {
+        if self.running.load(Ordering::Relaxed) {
+            self.dirty.store(true, Ordering::Relaxed);
+        } else {
+            self.framebuffer.write().fill(0.0);
+            self.alpha.write().fill(0.0);
+            self.samples.store(0, Ordering::Relaxed);
+            self.noise_estimate.store(0, Ordering::Relaxed);
+            self.diffuse.write().clear();
+            self.specular.write().clear();
+        }
+    }
+
+    // Render a single sample next time the tracing thread gets to it, if paused
+    pub fn step(&self) {
+        self.step_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // How many of up to `max` samples the tracing thread may render before publishing. While paused, that's only the
+    // samples requested by stepping, and the thread idles when there are none.
+    pub fn take_samples(&self, max: u32) -> u32 {
+        if !self.paused.load(Ordering::Relaxed) {
+            return max;
+        }
+        let mut taken = 0;
+        let _ = self.step_requests.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |steps| {
+            taken = steps.min(max);
+            Some(steps - taken)
+        });
+        taken
+    }
+
+    // Mean variance of the averaged pixels of the current render, i.e. how much noise is left. 0 until known.
+    pub fn read_noise_estimate(&self) -> f32 {
+        f32::from_bits(self.noise_estimate.load(Ordering::Relaxed))
+    }
+
+    // Whether accumulation started at `start` has run past the time budget, if there is one
+    pub fn over_time_budget(&self, start: Instant) -> bool {
+        let budget = self.time_budget.load(Ordering::Relaxed);
+        budget != 0 && start.elapsed().as_secs_f32() >= budget as f32
+    }
+
+    // Region priority, for look-dev on a specific spot. Pixels painted over are traced `samples` times per dispatch, and
+    // the average is accumulated as a single sample, so they converge that much faster while the rest of the image
+    // continues at the normal rate. Painting with 1 sample erases. Accumulation doesn't need to restart, since every
+    // pixel still gets one sample of the same image per dispatch. The brush is a circle, in pixels.
+    pub fn paint_priority(&self, center: Vec2, radius: f32, samples: u32) {
+        let config = *self.config.read();
+        let (width, height) = (config.width as usize, config.height as usize);
+        let mut mask = self.priority_mask.write();
+        if mask.len() != width * height {
+            *mask = vec![1; width * height];
+        }
+        let min = (center - radius).floor().max(Vec2::ZERO);
+        let max = (center + radius).ceil().min(Vec2::new(width as f32, height as f32));
+        for y in min.y as usize..max.y as usize {
+            for x in min.x as usize..max.x as usize {
+                if (Vec2::new(x as f32 + 0.5, y as f32 + 0.5) - center).length_squared() <= radius * radius {
+                    mask[y * width + x] = samples.max(1);
+                }
+            }
+        }
+        self.priority_version.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn clear_priority(&self) {
+        self.priority_mask.write().clear();
+        self.priority_version.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // The priority mask for a frame with `pixel_count` pixels. Masks painted at another resolution don't apply.
+    pub fn read_priority_mask(&self, pixel_count: usize) -> Vec<u32> {
+        let mask = self.priority_mask.read();
+        if mask.len() == pixel_count {
+            mask.clone()
+        } else {
+            vec![1; pixel_count]
+        }
+    }
+
+    // Whether to denoise a frame, given how many frames have been accumulated before it. With an interval of n, the
+    // first frame after rendering restarts and every nth frame after it are denoised, and the raw accumulation is shown
+    // in between. This keeps the image updating quickly while a slow denoiser like OIDN runs, and still gives a clean
+    // image as soon as the camera stops. Frames rendered while paused are always denoised, so the image left on screen
+    // is clean. 0 and 1 denoise every frame.
+    pub fn denoise_due(&self, accumulated_frames: u32) -> bool {
+        let interval = self.denoise_interval.load(Ordering::Relaxed).max(1);
+        accumulated_frames % interval == 0 || self.paused.load(Ordering::Relaxed)
+    }
+
+    // Borrow the latest averaged image without copying it. GPU results are read back into a persistent
+    // staging buffer by the tracing thread, so nothing is allocated per frame. The tracing thread can't publish
+    // a new frame while the guard is alive, so drop it as soon as possible.
+    pub fn read_framebuffer(&self) -> RwLockReadGuard<'_, Vec<f32>> {
+        self.framebuffer.read()
+    }
+
+    // Borrow the latest averaged pass, before denoising and post processing. Empty unless `aovs_enabled` is set and
+    // a full resolution frame has been rendered since.
+    pub fn read_aov(&self, aov: Aov) -> RwLockReadGuard<'_, Vec<f32>> {
+        match aov {
+            Aov::Diffuse => self.diffuse.read(),
+            Aov::Specular => self.specular.read(),
+        }
+    }
+
+    // Copy the latest averaged image into a caller-owned buffer, which must be width * height * 3 floats.
+    // Useful for reusing the same buffer across previews without holding the lock.
+    pub fn copy_framebuffer_into(&self, output: &mut [f32]) {
+        output.copy_from_slice(&self.framebuffer.read());
+    }
+
+    // Publish a finished frame to the render thread. The alpha buffer is resized along with the framebuffer.
+    fn publish(&self, image: &[f32], alpha: &[f32]) {
+        self.framebuffer.write().copy_from_slice(image);
+        let mut state_alpha = self.alpha.write();
+        state_alpha.resize(alpha.len(), 0.0);
+        state_alpha.copy_from_slice(alpha);
+    }
+
+    // Split the averaged beauty pass, which is still in the working space, into the diffuse and specular passes. Only
+    // specular light is accumulated separately and the rest is diffuse, so the passes add up to the beauty pass.
+    fn publish_aovs(&self, working_space: WorkingSpace, beauty: &[f32], specular_accumulated: &[Vec4], sample_count: f32) {
+        let mut specular = self.specular.write();
+        specular.resize(beauty.len(), 0.0);
+        resolve_accumulation(specular_accumulated, sample_count, &mut specular);
+        working_space_to_linear_srgb(working_space, &mut specular);
+
+        let mut diffuse = self.diffuse.write();
+        diffuse.clear();
+        diffuse.extend_from_slice(beauty);
+        working_space_to_linear_srgb(working_space, &mut diffuse);
+        for (diffuse, specular) in diffuse.iter_mut().zip(specular.iter()) {
+            *diffuse -= specular;
         }
     }
 }
@@ -95,53 +340,596 @@ struct PathTracingKernel<'fw>(Kernel<'fw>);
 
 impl<'fw> PathTracingKernel<'fw> {
     fn new(
+        spirv: &[u8],
         config_buffer: &GpuUniformBuffer<'fw, TracingConfig>,
         rng_buffer: &GpuBuffer<'fw, UVec2>,
         output_buffer: &GpuBuffer<'fw, Vec4>,
+        albedo_buffer: &GpuBuffer<'fw, Vec4>,
+        normal_buffer: &GpuBuffer<'fw, Vec4>,
+        variance_buffer: &GpuBuffer<'fw, Vec4>,
+        specular_buffer: &GpuBuffer<'fw, Vec4>,
+        stats_buffer: &GpuBuffer<'fw, u32>,
+        sample_mask_buffer: &GpuBuffer<'fw, u32>,
         world: &GpuWorld<'fw>,
         skybox: &GpuConstImage<'fw, Rgba32Float>,
     ) -> Self {
-        let shader = Shader::from_spirv_bytes(&FW, KERNEL, Some("compute"));
+        let shader = Shader::from_spirv_bytes(&FW, spirv, Some("compute"));
         let sampler = Sampler::new(&FW, SamplerWrapMode::ClampToEdge, SamplerFilterMode::Linear);
         let bindings = DescriptorSet::default()
             .bind_uniform_buffer(config_buffer)
             .bind_buffer(rng_buffer, GpuBufferUsage::ReadWrite)
             .bind_buffer(output_buffer, GpuBufferUsage::ReadWrite)
-            .bind_buffer(&world.per_vertex_buffer, GpuBufferUsage::ReadOnly)
-            .bind_buffer(&world.index_buffer, GpuBufferUsage::ReadOnly)
+            .bind_buffer(&world.per_vertex_buffer, GpuBufferUsage::ReadOnly);
+        let (bindings, entry_point) = match &world.index_buffer {
+            GpuIndexBuffer::Wide(index_buffer) => (bindings.bind_buffer(index_buffer, GpuBufferUsage::ReadOnly), EntryPoint::Trace),
+            GpuIndexBuffer::Compact(index_buffer) => (bindings.bind_buffer(index_buffer, GpuBufferUsage::ReadOnly), EntryPoint::TraceCompact),
+        };
+        let bindings = bindings
             .bind_buffer(&world.bvh.nodes_buffer, GpuBufferUsage::ReadOnly)
             .bind_buffer(&world.material_data_buffer, GpuBufferUsage::ReadOnly)
             .bind_buffer(&world.light_pick_buffer, GpuBufferUsage::ReadOnly)
-            .bind_sampler(&sampler)
-            .bind_const_image(&world.atlas)
-            .bind_const_image(&skybox);
-        let program = Program::new(&shader, "trace_kernel").add_descriptor_set(bindings);
+            .bind_sampler(&sampler);
+        let bindings = match &world.atlas {
+            GpuAtlas::Ldr(atlas) => bindings.bind_const_image(atlas),
+            GpuAtlas::Hdr(atlas) => bindings.bind_const_image(atlas),
+        };
+        let bindings = bindings
+            .bind_const_image(&skybox)
+            .bind_buffer(albedo_buffer, GpuBufferUsage::ReadWrite)
+            .bind_buffer(normal_buffer, GpuBufferUsage::ReadWrite)
+            .bind_buffer(&world.light_tree_buffer, GpuBufferUsage::ReadOnly)
+            .bind_buffer(variance_buffer, GpuBufferUsage::ReadWrite)
+            .bind_buffer(specular_buffer, GpuBufferUsage::ReadWrite)
+            .bind_buffer(stats_buffer, GpuBufferUsage::ReadWrite)
+            .bind_buffer(sample_mask_buffer, GpuBufferUsage::ReadOnly);
+        let program = Program::new(&shader, entry_point.name()).add_descriptor_set(bindings);
         let kernel = Kernel::new(&FW, program);
 
         Self(kernel)
     }
 }
 
-#[cfg(feature = "oidn")]
-fn denoise_image(width: usize, height: usize, input: &mut [f32]) {
-    let device = oidn::Device::new();
-    oidn::RayTracing::new(&device)
-        .hdr(true)
-        .srgb(false)
-        .image_dimensions(width, height)
-        .filter_in_place(input)
-        .expect("Filter config error!");
+// With adaptive resolution, the camera is rendered at reduced resolution while it moves and the result is upscaled,
+// which keeps navigation smooth in heavy scenes. Once there has been no camera movement for `PREVIEW_SETTLE_TIME`,
+// accumulation restarts at full resolution. The delay avoids throwing away samples during short pauses.
+// Previews are not denoised or reprojected, since they are replaced almost immediately anyway. They also don't count
+// towards the denoise interval, so the first full resolution frame after the camera settles is denoised.
+const PREVIEW_RESOLUTION_DIVISOR: u32 = 2; // Half width and height, so a quarter of the pixels
+const PREVIEW_SETTLE_TIME: Duration = Duration::from_millis(200);
+
+// How often a paused tracing thread checks whether it should render a step, resume or stop
+const PAUSED_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+fn preview_config(config: &TracingConfig) -> TracingConfig {
+    TracingConfig {
+        width: (config.width / PREVIEW_RESOLUTION_DIVISOR).max(1),
+        height: (config.height / PREVIEW_RESOLUTION_DIVISOR).max(1),
+        ..*config
+    }
+}
+
+// With a target frame rate, the bounce count is lowered while frames take too long, and raised again once there is
+// time to spare, up to the configured maximum. This keeps the interactive view responsive in heavy scenes, at the
+// cost of accuracy, since light that needs more bounces is missing. Samples with different bounce counts are
+// accumulated together, so the image doesn't converge to the right result while the bounce count is lowered.
+// Frame times are measured per sample, since that's what is displayed while the camera moves. Only the interactive
+// app sets a target, a render to a fixed sample count always uses the configured bounces.
+const BOUNCE_CONTROL_SMOOTHING: f32 = 0.25; // Weight of the latest frame in the running average
+const BOUNCE_CONTROL_HEADROOM: f32 = 0.75; // Fraction of the frame budget below which another bounce is added
+
+#[derive(Default, Debug)]
+pub struct BounceController {
+    bounces: Option<u32>, // None while there is no target
+    average_frame_time: Option<f32>,
+}
+
+impl BounceController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The config to render with, which never has more bounces than it already asks for
+    pub fn apply(&self, config: TracingConfig) -> TracingConfig {
+        match self.bounces {
+            Some(bounces) => TracingConfig { max_bounces: bounces.min(config.max_bounces), ..config },
+            None => config,
+        }
+    }
+
+    // Record how long the last sample took, and pick the bounce count for the next one, between 1 and `max_bounces`.
+    // Returns whether the bounce count changed.
+    pub fn update(&mut self, sample_time: Duration, target_fps: u32, max_bounces: u32) -> bool {
+        let previous = self.bounces;
+        if target_fps == 0 {
+            *self = Self::default();
+            return previous.is_some();
+        }
+
+        let sample_time = sample_time.as_secs_f32();
+        let average = match self.average_frame_time {
+            Some(average) => average + (sample_time - average) * BOUNCE_CONTROL_SMOOTHING,
+            None => sample_time,
+        };
+        let budget = 1.0 / target_fps as f32;
+        let max_bounces = max_bounces.max(1);
+        let bounces = previous.unwrap_or(max_bounces).clamp(1, max_bounces);
+        let bounces = if average > budget && bounces > 1 {
+            bounces - 1
+        } else if average < budget * BOUNCE_CONTROL_HEADROOM && bounces < max_bounces {
+            bounces + 1
+        } else {
+            bounces
+        };
+
+        // Frames with the old bounce count say little about the new one, so start measuring again
+        self.average_frame_time = if Some(bounces) == previous { Some(average) } else { None };
+        self.bounces = Some(bounces);
+        Some(bounces) != previous
+    }
+}
+
+// Per-pixel RNG seeds for a frame, where a frame starts whenever accumulation is restarted. With static noise every
+// frame uses the same seeds, so a sequence rendered from a static camera has an identical noise pattern. With animated
+// noise each frame is offset into the low-discrepancy sequence by a different amount, decorrelating the noise.
+pub fn frame_rng_seeds(rng_data: &[UVec2], frame: u32, animated: bool) -> Vec<UVec2> {
+    if !animated {
+        return rng_data.to_vec();
+    }
+    let offset = frame.wrapping_mul(0x9E3779B9); // Golden ratio, spreads consecutive frames far apart
+    rng_data.iter().map(|seed| UVec2::new(seed.x, seed.y.wrapping_add(offset))).collect()
+}
+
+// Accumulation buffers hold the sum of all samples so far. The kernel adds each new sample on top, so they are only
+// cleared when accumulation restarts. To continue a previous render, its averaged image is scaled back up by its
+// sample count. Without previous samples the buffer is zeroed explicitly, since scaling by zero would keep any NaNs.
+// Coverage is accumulated in the w component.
+pub fn restore_accumulation(framebuffer: &[f32], alpha: &[f32], samples: u32) -> Vec<Vec4> {
+    if samples == 0 {
+        return vec![Vec4::ZERO; framebuffer.len() / 3];
+    }
+    framebuffer
+        .chunks(3)
+        .enumerate()
+        .map(|(i, c)| Vec4::new(c[0], c[1], c[2], alpha.get(i).copied().unwrap_or(0.0)) * samples as f32)
+        .collect()
+}
+
+// Average accumulated samples into a tightly packed RGB buffer
+fn resolve_accumulation(accumulated: &[Vec4], sample_count: f32, output: &mut [f32]) {
+    for (i, col) in accumulated.iter().enumerate() {
+        output[i * 3] = col.x / sample_count;
+        output[i * 3 + 1] = col.y / sample_count;
+        output[i * 3 + 2] = col.z / sample_count;
+    }
+}
+
+// Per-pixel luminance statistics are kept with Welford's algorithm, see `welford_update`. Averaging the variance of
+// every pixel gives a single number for how converged the image is, which keeps shrinking as samples are added.
+pub fn noise_estimate(welford: &[Vec4]) -> f32 {
+    if welford.is_empty() {
+        return 0.0;
+    }
+    welford.iter().map(|state| welford_variance_of_mean(*state)).sum::<f32>() / welford.len() as f32
+}
+
+// Throughput of the paths of a render per bounce, for finding where energy is lost when a render comes out too dark.
+// Paths which ended count as 0 towards the mean over all paths, so a bounce where it drops a lot is where energy goes
+// missing, for example to a BSDF which absorbs too much. See `BOUNCE_STATS_BOUNCES` for how the kernels gather them.
+// Also counts the rays cast and primitives tested, which measure the work done regardless of how paths end, and with
+// the time spent tracing give the ray throughput.
+#[derive(Clone, Default, Debug)]
+pub struct ThroughputStats {
+    sums: [u64; BOUNCE_STATS_BOUNCES], // Fixed point, scaled by `BOUNCE_STATS_SCALE`
+    paths: [u64; BOUNCE_STATS_BOUNCES],
+    rays: u64,
+    primitive_tests: u64,
+    trace_time: Duration,
+}
+
+impl ThroughputStats {
+    // Add the statistics a kernel gathered in a buffer
+    pub fn add_buffer(&mut self, buffer: &[u32]) {
+        for (bounce, entry) in buffer.chunks_exact(BOUNCE_STATS_STRIDE).take(BOUNCE_STATS_BOUNCES).enumerate() {
+            self.sums[bounce] += entry[0] as u64 | (entry[1] as u64) << 32;
+            self.paths[bounce] += entry[2] as u64;
+        }
+        let rays = &buffer[RAY_STATS_ENTRY..RAY_STATS_ENTRY + BOUNCE_STATS_STRIDE];
+        self.rays += rays[0] as u64 | (rays[1] as u64) << 32;
+        self.primitive_tests += rays[2] as u64 | (rays[3] as u64) << 32;
+    }
+
+    pub fn merge(&mut self, other: &ThroughputStats) {
+        for bounce in 0..BOUNCE_STATS_BOUNCES {
+            self.sums[bounce] += other.sums[bounce];
+            self.paths[bounce] += other.paths[bounce];
+        }
+        self.rays += other.rays;
+        self.primitive_tests += other.primitive_tests;
+        self.trace_time += other.trace_time;
+    }
+
+    // Time spent in the kernels, which the ray throughput is measured over
+    pub fn add_trace_time(&mut self, time: Duration) {
+        self.trace_time += time;
+    }
+
+    pub fn rays(&self) -> u64 {
+        self.rays
+    }
+
+    pub fn primitive_tests(&self) -> u64 {
+        self.primitive_tests
+    }
+
+    // Millions of rays per second, or 0 if no time was recorded
+    pub fn mrays_per_second(&self) -> f64 {
+        if self.trace_time.is_zero() {
+            return 0.0;
+        }
+        self.rays as f64 / self.trace_time.as_secs_f64() / 1e6
+    }
+
+    // Paths which reached a bounce. The last bounce includes all later ones.
+    pub fn paths(&self, bounce: usize) -> u64 {
+        self.paths[bounce]
+    }
+
+    // Mean throughput over every path, including those which ended before the bounce
+    pub fn mean_throughput(&self, bounce: usize) -> f32 {
+        if self.paths[0] == 0 {
+            return 0.0;
+        }
+        (self.sums[bounce] as f64 / BOUNCE_STATS_SCALE as f64 / self.paths[0] as f64) as f32
+    }
+
+    // Mean throughput of the paths which reached the bounce
+    pub fn mean_surviving_throughput(&self, bounce: usize) -> f32 {
+        if self.paths[bounce] == 0 {
+            return 0.0;
+        }
+        (self.sums[bounce] as f64 / BOUNCE_STATS_SCALE as f64 / self.paths[bounce] as f64) as f32
+    }
+}
+
+impl kernels::stats::BounceStats for ThroughputStats {
+    fn record(&mut self, bounce: u32, throughput: Vec3) {
+        let bounce = bounce_stats_entry(bounce) / BOUNCE_STATS_STRIDE;
+        self.sums[bounce] += bounce_stats_fixed_point(throughput) as u64;
+        self.paths[bounce] += 1;
+    }
+
+    fn record_rays(&mut self, counts: kernels::stats::RayCounts) {
+        self.rays += counts.rays as u64;
+        self.primitive_tests += counts.primitive_tests as u64;
+    }
+}
+
+impl std::fmt::Display for ThroughputStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Throughput per bounce over {} paths:", self.paths[0])?;
+        writeln!(f, "{:>7} {:>9} {:>14} {:>14}", "bounce", "alive", "mean (all)", "mean (alive)")?;
+        for bounce in (0..BOUNCE_STATS_BOUNCES).filter(|&bounce| self.paths[bounce] > 0) {
+            let label = if bounce == BOUNCE_STATS_BOUNCES - 1 { format!("{}+", bounce) } else { bounce.to_string() };
+            let alive = self.paths[bounce] as f64 / self.paths[0] as f64 * 100.0;
+            writeln!(f, "{:>7} {:>8.2}% {:>14.4} {:>14.4}", label, alive, self.mean_throughput(bounce), self.mean_surviving_throughput(bounce))?;
+        }
+        let per_ray = if self.rays > 0 { self.primitive_tests as f64 / self.rays as f64 } else { 0.0 };
+        writeln!(f, "Rays cast: {}, primitive tests: {} ({:.1} per ray)", self.rays, self.primitive_tests, per_ray)?;
+        writeln!(f, "Ray throughput: {:.2} Mrays/s over {:.2?}", self.mrays_per_second(), self.trace_time)?;
+        Ok(())
+    }
+}
+
+// How the render stage of `StageTimings` was measured. The other stages always use the CPU clock.
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub enum TimingMethod {
+    #[default]
+    CpuClock, // Rendering on the CPU
+    GpuTimestamps, // Timestamp queries, which only cover the time the dispatches ran on the GPU
+    // The CPU clock around each dispatch, on adapters without timestamp queries. This also covers submitting the
+    // dispatch and waiting for it.
+    CpuFallback,
+}
+
+// Time spent in each stage of a run, to tell whether it is bound by loading, building or tracing. Building
+// covers the BVH and light sampling data, upload the scene and skybox going to the GPU, render the dispatches, and
+// readback copying the accumulation back from the GPU. Denoise covers every denoised frame, including moving the image
+// to and from the GPU for the GPU À-Trous passes, to compare denoisers by. Encode is time spent saving images. Stages which don't apply,
+// like upload and readback on the CPU, stay at 0.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct StageTimings {
+    pub load: Duration,
+    pub build: Duration,
+    pub upload: Duration,
+    pub render: Duration,
+    pub readback: Duration,
+    pub denoise: Duration,
+    pub encode: Duration,
+    pub render_method: TimingMethod,
+}
+
+impl std::fmt::Display for StageTimings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Time per stage:")?;
+        let stages = [
+            ("load", self.load),
+            ("build", self.build),
+            ("upload", self.upload),
+            ("render", self.render),
+            ("readback", self.readback),
+            ("denoise", self.denoise),
+            ("encode", self.encode),
+        ];
+        for (name, time) in stages {
+            writeln!(f, "{:>9} {:>10.2?}", name, time)?;
+        }
+        match self.render_method {
+            TimingMethod::CpuClock => writeln!(f, "Timed with the CPU clock"),
+            TimingMethod::GpuTimestamps => writeln!(f, "Render timed on the GPU with timestamp queries, everything else with the CPU clock"),
+            TimingMethod::CpuFallback => writeln!(f, "Timed with the CPU clock, since this adapter doesn't support timestamp queries, so render also covers waiting for the GPU"),
+        }
+    }
+}
+
+// Convert a tightly packed RGB image from the working space back to linear sRGB. Colors which end up outside of the
+// sRGB gamut are clipped to it, since negative values would break post processing and tonemapping.
+pub fn working_space_to_linear_srgb(working_space: WorkingSpace, image: &mut [f32]) {
+    if working_space == WorkingSpace::LinearSrgb {
+        return;
+    }
+    for pixel in image.chunks_exact_mut(3) {
+        let color = working_space.to_linear_srgb(glam::Vec3::new(pixel[0], pixel[1], pixel[2])).max(glam::Vec3::ZERO);
+        pixel.copy_from_slice(&color.to_array());
+    }
+}
+
+// Same as `resolve_accumulation`, but for the w component, which holds depth for the normal guide and coverage for
+// the radiance
+fn resolve_w(accumulated: &[Vec4], sample_count: f32, output: &mut [f32]) {
+    for (i, col) in accumulated.iter().enumerate() {
+        output[i] = col.w / sample_count;
+    }
+}
+
+// Accumulation buffers of a frame, as `FrameResolve::resolve` reads them. The GPU path reads them back when they are
+// asked for, so a frame only waits for the buffers it uses.
+trait AccumulationBuffers {
+    fn radiance(&mut self) -> &[Vec4];
+    fn variance(&mut self) -> &[Vec4];
+    fn specular(&mut self) -> &[Vec4];
+    fn albedo(&mut self) -> &[Vec4];
+    fn normal(&mut self) -> &[Vec4];
+}
+
+struct CpuAccumulation<'a> {
+    radiance: &'a [Vec4],
+    variance: &'a [Vec4],
+    specular: &'a [Vec4],
+    albedo: &'a [Vec4],
+    normal: &'a [Vec4],
+}
+
+impl AccumulationBuffers for CpuAccumulation<'_> {
+    fn radiance(&mut self) -> &[Vec4] {
+        self.radiance
+    }
+
+    fn variance(&mut self) -> &[Vec4] {
+        self.variance
+    }
+
+    fn specular(&mut self) -> &[Vec4] {
+        self.specular
+    }
+
+    fn albedo(&mut self) -> &[Vec4] {
+        self.albedo
+    }
+
+    fn normal(&mut self) -> &[Vec4] {
+        self.normal
+    }
+}
+
+// Every buffer is read back into `readback`, so a slice is only valid until the next buffer is asked for
+struct GpuAccumulation<'a, 'fw> {
+    radiance: &'a GpuBuffer<'fw, Vec4>,
+    variance: &'a GpuBuffer<'fw, Vec4>,
+    specular: &'a GpuBuffer<'fw, Vec4>,
+    albedo: &'a GpuBuffer<'fw, Vec4>,
+    normal: &'a GpuBuffer<'fw, Vec4>,
+    readback: &'a mut Vec<Vec4>,
+    readback_time: Duration,
+}
+
+impl GpuAccumulation<'_, '_> {
+    fn read(&mut self, buffer: &GpuBuffer<Vec4>) -> &[Vec4] {
+        let readback_start = Instant::now();
+        let _ = buffer.read_blocking(&mut self.readback[..]);
+        self.readback_time += readback_start.elapsed();
+        &self.readback[..]
+    }
+}
+
+impl AccumulationBuffers for GpuAccumulation<'_, '_> {
+    fn radiance(&mut self) -> &[Vec4] {
+        let buffer = self.radiance;
+        self.read(buffer)
+    }
+
+    fn variance(&mut self) -> &[Vec4] {
+        let buffer = self.variance;
+        self.read(buffer)
+    }
+
+    fn specular(&mut self) -> &[Vec4] {
+        let buffer = self.specular;
+        self.read(buffer)
+    }
+
+    fn albedo(&mut self) -> &[Vec4] {
+        let buffer = self.albedo;
+        self.read(buffer)
+    }
+
+    fn normal(&mut self) -> &[Vec4] {
+        let buffer = self.normal;
+        self.read(buffer)
+    }
+}
+
+// The host side of a frame, shared by the GPU and CPU paths: the images the accumulation is resolved into, and the
+// reprojected previous frames blended into them
+struct FrameResolve {
+    width: u32,
+    height: u32,
+    image: Vec<f32>,
+    preview: Vec<f32>, // At the preview resolution, before it is upscaled into `image`
+    alpha: Vec<f32>,
+    albedo: Vec<f32>,
+    normal: Vec<f32>,
+    depth: Vec<f32>,
+    history: Option<History>,
+}
+
+impl FrameResolve {
+    fn new(width: u32, height: u32) -> Self {
+        let pixel_count = (width * height) as usize;
+        Self {
+            width,
+            height,
+            image: vec![0.0; pixel_count * 3],
+            preview: vec![0.0; pixel_count * 3],
+            alpha: vec![0.0; pixel_count],
+            albedo: vec![0.0; pixel_count * 3],
+            normal: vec![0.0; pixel_count * 3],
+            depth: vec![0.0; pixel_count],
+            history: None,
+        }
+    }
+
+    // Average the accumulated samples, reproject and denoise them as far as that is turned on, post process them and
+    // publish them. `frame_config` is what the accumulation was started with, and `next_config` what the next frame
+    // renders with, which the history is reprojected to when the frame is flushed. Guides, variance and the specular
+    // pass have `guide_samples` samples, see `restore_accumulation`. `denoise_image` runs the given denoiser on the
+    // image, with the albedo and normal guides.
+    fn resolve(
+        &mut self,
+        state: &TracingState,
+        buffers: &mut impl AccumulationBuffers,
+        render_config: &TracingConfig,
+        frame_config: &TracingConfig,
+        next_config: &TracingConfig,
+        guide_samples: u32,
+        accumulated_frames: u32,
+        preview: bool,
+        flush: bool,
+        denoise_image: impl FnOnce(Denoiser, &mut [f32], &[f32], &[f32]),
+    ) {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let working_space = WorkingSpace::from_u32(render_config.working_space);
+        let sample_count = state.samples.load(Ordering::Relaxed) as f32;
+        let guide_samples = guide_samples as f32;
+        if preview {
+            let (render_width, render_height) = (render_config.width as usize, render_config.height as usize);
+            let preview_pixels = &mut self.preview[..render_width * render_height * 3];
+            resolve_accumulation(&buffers.radiance()[..render_width * render_height], sample_count, preview_pixels);
+            resize_bilinear(preview_pixels, render_width, render_height, &mut self.image, width, height);
+        } else {
+            let radiance = buffers.radiance();
+            resolve_accumulation(radiance, sample_count, &mut self.image);
+            resolve_w(radiance, sample_count, &mut self.alpha); // Previews keep the last full resolution alpha
+            state.noise_estimate.store(noise_estimate(buffers.variance()).to_bits(), Ordering::Relaxed);
+            if state.aovs_enabled.load(Ordering::Relaxed) {
+                state.publish_aovs(working_space, &self.image, buffers.specular(), guide_samples);
+            }
+        }
+
+        let denoiser = Denoiser::from_u32(state.denoiser.load(Ordering::Relaxed));
+        let denoising = denoiser != Denoiser::None && !flush && !preview && state.denoise_due(accumulated_frames);
+        // Reprojection assumes a perspective camera
+        let reprojecting = state.temporal_reprojection.load(Ordering::Relaxed) && !preview && render_config.ortho_height == 0.0;
+        if (denoising && denoiser.uses_guides()) || reprojecting {
+            resolve_accumulation(buffers.albedo(), guide_samples, &mut self.albedo);
+            let normal = buffers.normal();
+            resolve_accumulation(normal, guide_samples, &mut self.normal);
+            resolve_w(normal, guide_samples, &mut self.depth);
+        }
+
+        // Temporal reprojection
+        if reprojecting {
+            let sample_weights = match &self.history {
+                Some(history) => blend_history(history, guide_samples, &mut self.image, &self.depth, &self.normal),
+                None => vec![guide_samples; width * height],
+            };
+            // Only camera movement can be reprojected, other changes invalidate the history
+            if flush {
+                self.history = (!state.dirty.load(Ordering::Relaxed)).then(|| {
+                    reproject(frame_config, next_config, &self.image, &self.depth, &self.normal, &sample_weights)
+                });
+            }
+        } else {
+            self.history = None;
+        }
+
+        if denoising {
+            let denoise_start = Instant::now();
+            denoise_image(denoiser, &mut self.image, &self.albedo, &self.normal);
+            state.timings.write().denoise += denoise_start.elapsed();
+        }
+
+        // Post processing, which like display expects linear sRGB
+        working_space_to_linear_srgb(working_space, &mut self.image);
+        apply_post_processing(&state.post_config.read(), width, height, &mut self.image);
+
+        // Push to render thread
+        state.publish(&self.image, &self.alpha);
+    }
 }
 
 pub fn trace_gpu(
-    scene_path: &str,
+    scene: &[SceneFile],
     skybox_path: Option<&str>,
+    coordinate_system: CoordinateSystem,
     state: Arc<TracingState>,
 ) {
-    let Some(world) = World::from_path(scene_path).map(|w| w.into_gpu()) else {
+    // The embedded kernel is only used when no kernel was given. One from disk that stopped loading since it was
+    // picked is an error, since tracing with another kernel would go unnoticed.
+    let kernel_path = state.kernel_path.read().clone();
+    let mut kernel = match kernel_path.as_deref().map(load_kernel) {
+        Some(Ok(spirv)) => spirv,
+        Some(Err(error)) => {
+            println!("Error: Can't trace on the GPU, {}", error);
+            return;
+        }
+        None => KERNEL.to_vec(),
+    };
+    if let Err(error) = validate_kernel(&kernel) {
+        println!("Error: Can't trace on the GPU, {}", error);
+        return;
+    }
+    let (width, height) = (state.config.read().width, state.config.read().height);
+    if let Err(error) = dispatch_size(width, height, &tracing_limits()) {
+        println!("Error: Can't trace on the GPU, {}", error);
+        return;
+    }
+    let Some(world) = load_world(scene, coordinate_system, &state) else {
         return;
     };
-    let skybox = skybox_path.and_then(load_dynamic_image).map(dynamic_image_to_gpu_image).unwrap_or_else(|| fallback_gpu_image());
+    let has_emitters = world.has_emitters();
+    let scene_summary = SceneSummary::of(&world);
+    let upload_start = Instant::now();
+    let world = world.into_gpu();
+    let skybox_source = skybox_path.and_then(load_dynamic_image);
+    let skybox_size = skybox_source.as_ref().map_or((2, 2), |source| source.dimensions());
+    let skybox = skybox_source.map(dynamic_image_to_gpu_image).unwrap_or_else(|| fallback_gpu_image());
+    state.timings.write().upload = upload_start.elapsed();
+    // Dispatches are timed on the GPU where the adapter can, see `TimingMethod`
+    let mut gpu_timer = if tracing_supports_timestamps() { GpuTimer::new(FW.device(), FW.queue()) } else { None };
+    state.timings.write().render_method = if gpu_timer.is_some() { TimingMethod::GpuTimestamps } else { TimingMethod::CpuFallback };
+    if gpu_timer.is_none() && (state.print_timings.load(Ordering::Relaxed) || state.trace_stats.load(Ordering::Relaxed)) {
+        println!("Warning: This adapter doesn't support timestamp queries, so the GPU is timed with the CPU clock, which also counts waiting for it");
+    }
 
     let screen_width = state.config.read().width;
     let screen_height = state.config.read().height;
@@ -160,77 +948,365 @@ pub fn trace_gpu(
     }
 
     // Restore previous state, if there is any
-    let samples_init = state.samples.load(Ordering::Relaxed) as f32;
-    let output_buffer_init = state.framebuffer.read().chunks(3).map(|c| Vec4::new(c[0], c[1], c[2], 1.0) * samples_init).collect::<Vec<_>>();
+    let output_buffer_init = restore_accumulation(&state.framebuffer.read(), &state.alpha.read(), state.samples.load(Ordering::Relaxed));
 
     // Setup tracing state
     let pixel_count = (screen_width * screen_height) as u64;
-    let config_buffer = GpuUniformBuffer::from_slice(&FW, &[*state.config.read()]);
-    let rng_buffer = GpuBuffer::from_slice(&FW, if state.use_blue_noise.load(Ordering::Relaxed) { &rng_data_blue } else { &rng_data_uniform });
+    let config_buffer = GpuUniformBuffer::from_slice(&FW, &[dispatch_config(*state.config.read(), has_emitters, skybox_size)]);
+    let rng_seeds = |state: &TracingState| {
+        let rng_data = if state.use_blue_noise.load(Ordering::Relaxed) { &rng_data_blue } else { &rng_data_uniform };
+        frame_rng_seeds(rng_data, state.frame.load(Ordering::Relaxed), state.animated_noise.load(Ordering::Relaxed))
+    };
+    let rng_buffer = GpuBuffer::from_slice(&FW, &rng_seeds(&state));
     let output_buffer = GpuBuffer::from_slice(&FW, &output_buffer_init);
+    let albedo_buffer = GpuBuffer::from_slice(&FW, &vec![Vec4::ZERO; pixel_count as usize]);
+    let normal_buffer = GpuBuffer::from_slice(&FW, &vec![Vec4::ZERO; pixel_count as usize]);
+    let variance_buffer = GpuBuffer::from_slice(&FW, &vec![Vec4::ZERO; pixel_count as usize]);
+    let specular_buffer = GpuBuffer::from_slice(&FW, &vec![Vec4::ZERO; pixel_count as usize]);
+
+    // Throughput statistics of the current accumulation, gathered per frame. Without them the kernel gets a buffer
+    // too short to hold them, which turns them off.
+    let mut throughput_stats = state.trace_stats.load(Ordering::Relaxed).then(ThroughputStats::default);
+    let stats_buffer = GpuBuffer::from_slice(&FW, &vec![0u32; if throughput_stats.is_some() { BOUNCE_STATS_LEN } else { 1 }]);
+    let mut stats_buffer_raw: Vec<u32> = vec![0; BOUNCE_STATS_LEN];
+
+    // Samples per dispatch of each pixel, see `TracingState::paint_priority`
+    let mut priority_version = state.priority_version.load(Ordering::Relaxed);
+    let sample_mask_buffer = GpuBuffer::from_slice(&FW, &state.read_priority_mask(pixel_count as usize));
 
-    let mut image_buffer_raw: Vec<Vec4> = vec![Vec4::ZERO; pixel_count as usize];
-    let mut image_buffer: Vec<f32> = vec![0.0; pixel_count as usize * 3];
+    let mut readback_buffer_raw: Vec<Vec4> = vec![Vec4::ZERO; pixel_count as usize];
+    let mut frame = FrameResolve::new(screen_width, screen_height);
 
-    let rt = PathTracingKernel::new(&config_buffer, &rng_buffer, &output_buffer, &world, &skybox);
+    // Denoising and reprojection guides, noise statistics and the specular pass. These aren't restored with the
+    // framebuffer, so they keep their own sample count.
+    let mut guide_samples = 0;
+    let mut accumulated_frames = 0;
 
+    // The config the current frame was rendered with
+    let mut frame_config = *state.config.read();
+
+    let make_kernel = |spirv: &[u8]| {
+        PathTracingKernel::new(spirv, &config_buffer, &rng_buffer, &output_buffer, &albedo_buffer, &normal_buffer, &variance_buffer, &specular_buffer, &stats_buffer, &sample_mask_buffer, &world, &skybox)
+    };
+    let mut rt = make_kernel(&kernel);
+    // Created the first time À-Trous is used, from the same module as the trace kernel
+    let mut gpu_atrous: Option<GpuATrous> = None;
+    let watched_path = kernel_path.unwrap_or_else(|| KERNEL_PATH.to_string());
+    let mut kernel_watcher = state.hot_reload.load(Ordering::Relaxed).then(|| KernelWatcher::new(&watched_path));
+
+    let mut accumulation_start = Instant::now();
+    let mut preview = false;
+    let mut last_motion = Instant::now();
+    let mut bounce_controller = BounceController::new();
     while state.running.load(Ordering::Relaxed) {
+        // Switch back to full resolution once the camera has settled
+        let settled = preview && !state.interacting.load(Ordering::Relaxed) && last_motion.elapsed() >= PREVIEW_SETTLE_TIME;
+        let render_config = bounce_controller.apply(if preview { preview_config(&frame_config) } else { frame_config });
+
+        // Swap in the rebuilt kernel, restarting accumulation since the old samples no longer match. Pipeline creation
+        // can still fail on a kernel that validated, in which case the old kernel is kept.
+        if let Some(spirv) = kernel_watcher.as_mut().and_then(|watcher| watcher.poll()) {
+            match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| make_kernel(&spirv))) {
+                Ok(reloaded) => {
+                    rt = reloaded;
+                    kernel = spirv;
+                    gpu_atrous = None;
+                    state.dirty.store(true, Ordering::Relaxed);
+                    println!("Reloaded kernel from {}", watched_path);
+                }
+                Err(_) => println!("Warning: Failed to create a pipeline for the reloaded kernel, keeping the old one"),
+            }
+        }
+
+        // Pick up changes to the priority mask
+        let version = state.priority_version.load(Ordering::Relaxed);
+        if version != priority_version {
+            priority_version = version;
+            let _ = sample_mask_buffer.write(&state.read_priority_mask(pixel_count as usize));
+        }
+
         // Dispatch
-        let sync_rate = state.sync_rate.load(Ordering::Relaxed);
+        let sync_rate = state.take_samples(state.sync_rate.load(Ordering::Relaxed));
+        if sync_rate == 0 {
+            std::thread::sleep(PAUSED_POLL_INTERVAL);
+            continue;
+        }
+        let frame_start = Instant::now();
         let mut flush = false;
+        // Each dispatch renders a batch of samples, so the sync rate is rounded up to whole batches, and a step while
+        // paused renders one batch
+        let samples_per_dispatch = render_config.samples_per_dispatch.max(1);
         let mut finished_samples = 0;
-        for _ in 0..sync_rate {
-            rt.0.enqueue(screen_width.div_ceil(8), screen_height.div_ceil(8), 1);
+        // Time the dispatches were running, which the ray throughput is measured over. This leaves out the checks
+        // between dispatches and the readbacks after them, and with timestamp queries also the waiting for the GPU.
+        let mut trace_time = Duration::ZERO;
+        for _ in 0..sync_rate.div_ceil(samples_per_dispatch) {
+            // Each dispatch reads the random states and accumulation the previous one wrote. wgpu tracks how the buffers
+            // are used and inserts the barriers between dispatches, and waiting for each one to finish also keeps the
+            // readbacks below from racing the kernel.
+            let dispatch_start = Instant::now();
+            let dispatch = || rt.0.enqueue(render_config.width.div_ceil(WORKGROUP_SIZE), render_config.height.div_ceil(WORKGROUP_SIZE), 1);
+            match gpu_timer.as_mut() {
+                Some(gpu_timer) => gpu_timer.time(dispatch),
+                None => dispatch(),
+            }
             FW.poll_blocking();
-            finished_samples += 1;
+            if gpu_timer.is_none() {
+                trace_time += dispatch_start.elapsed();
+            }
+            finished_samples += samples_per_dispatch;
             
-            flush |= state.interacting.load(Ordering::Relaxed) || state.dirty.load(Ordering::Relaxed);
-            if flush {
+            flush |= state.interacting.load(Ordering::Relaxed) || state.dirty.load(Ordering::Relaxed) || settled;
+            if flush || state.over_time_budget(accumulation_start) {
                 break;
             }
             if !state.running.load(Ordering::Relaxed) {
-                return;
+                break; // Still publish the samples so far, so stopping early keeps them
             }
         }
+        if let Some(gpu_timer) = gpu_timer.as_mut() {
+            trace_time = gpu_timer.elapsed();
+        }
+        state.timings.write().render += trace_time;
         state.samples.fetch_add(finished_samples, Ordering::Relaxed);
-
-        // Readback from GPU
-        let _ = output_buffer.read_blocking(&mut image_buffer_raw);
-        let sample_count = state.samples.load(Ordering::Relaxed) as f32;
-        for (i, col) in image_buffer_raw.iter().enumerate() {
-            image_buffer[i * 3] = col.x / sample_count;
-            image_buffer[i * 3 + 1] = col.y / sample_count;
-            image_buffer[i * 3 + 2] = col.z / sample_count;
+        guide_samples += finished_samples;
+        if let Some(throughput_stats) = throughput_stats.as_mut() {
+            throughput_stats.add_trace_time(trace_time);
+            let _ = stats_buffer.read_blocking(&mut stats_buffer_raw);
+            let _ = stats_buffer.write(&vec![0; BOUNCE_STATS_LEN]);
+            throughput_stats.add_buffer(&stats_buffer_raw);
         }
 
-        // Denoise
-        #[cfg(feature = "oidn")]
-        if state.denoise.load(Ordering::Relaxed) && !flush {
-            denoise_image(screen_width as usize, screen_height as usize, &mut image_buffer);
-        }
+        // Readback from GPU
+        let next_config = *state.config.read();
+        let mut accumulation = GpuAccumulation {
+            radiance: &output_buffer,
+            variance: &variance_buffer,
+            specular: &specular_buffer,
+            albedo: &albedo_buffer,
+            normal: &normal_buffer,
+            readback: &mut readback_buffer_raw,
+            readback_time: Duration::ZERO,
+        };
+        frame.resolve(&state, &mut accumulation, &render_config, &frame_config, &next_config, guide_samples, accumulated_frames, preview, flush, |denoiser, image, albedo, normal| {
+            // À-Trous runs as compute passes on the GPU
+            if denoiser == Denoiser::ATrous {
+                let gpu_atrous = gpu_atrous.get_or_insert_with(|| GpuATrous::new(&kernel, screen_width, screen_height));
+                gpu_atrous.denoise(image, albedo, normal);
+            } else {
+                denoise(denoiser, screen_width as usize, screen_height as usize, image, albedo, normal);
+            }
+        });
+        state.timings.write().readback += accumulation.readback_time;
+        accumulated_frames += 1;
 
-        // Push to render thread
-        state.framebuffer.write().copy_from_slice(image_buffer.as_slice());
+        // Frame rate target
+        let sample_time = frame_start.elapsed() / finished_samples;
+        let bounces_changed = bounce_controller.update(sample_time, state.target_fps.load(Ordering::Relaxed), frame_config.max_bounces);
 
         // Interaction
         if flush {
             state.dirty.store(false, Ordering::Relaxed);
             state.samples.store(0, Ordering::Relaxed);
-            let _ = config_buffer.write(&[*state.config.read()]);
+            frame_config = next_config;
+            preview = state.adaptive_resolution.load(Ordering::Relaxed) && state.interacting.load(Ordering::Relaxed);
+            if preview {
+                last_motion = Instant::now();
+            }
+            let _ = config_buffer.write(&[dispatch_config(bounce_controller.apply(if preview { preview_config(&frame_config) } else { frame_config }), has_emitters, skybox_size)]);
             let _ = output_buffer.write(&vec![Vec4::ZERO; pixel_count as usize]);
-            let _ = rng_buffer.write(if state.use_blue_noise.load(Ordering::Relaxed) { &rng_data_blue } else { &rng_data_uniform });
+            let _ = albedo_buffer.write(&vec![Vec4::ZERO; pixel_count as usize]);
+            let _ = normal_buffer.write(&vec![Vec4::ZERO; pixel_count as usize]);
+            let _ = variance_buffer.write(&vec![Vec4::ZERO; pixel_count as usize]);
+            let _ = specular_buffer.write(&vec![Vec4::ZERO; pixel_count as usize]);
+            guide_samples = 0;
+            accumulated_frames = 0;
+            if let Some(throughput_stats) = throughput_stats.as_mut() {
+                *throughput_stats = ThroughputStats::default();
+            }
+            state.frame.fetch_add(1, Ordering::Relaxed);
+            let _ = rng_buffer.write(&rng_seeds(&state));
+            accumulation_start = Instant::now();
+        } else if state.over_time_budget(accumulation_start) {
+            println!("Time budget reached after {} samples", state.samples.load(Ordering::Relaxed));
+            state.budget_reached.store(true, Ordering::Relaxed);
+            state.running.store(false, Ordering::Relaxed);
+        } else if bounces_changed {
+            let _ = config_buffer.write(&[dispatch_config(bounce_controller.apply(if preview { preview_config(&frame_config) } else { frame_config }), has_emitters, skybox_size)]);
         }
     }
+
+    if let Some(throughput_stats) = throughput_stats {
+        print!("{}", throughput_stats);
+    }
+    if state.print_timings.load(Ordering::Relaxed) {
+        print!("{}", *state.timings.read());
+    }
+    warn_if_black(&state, &scene_summary);
+}
+
+// Fraction of the surfaces seen by the camera facing away from it, above which the scene is likely inside out
+const INSIDE_OUT_THRESHOLD: f32 = 0.5;
+// Space left around the scene when framing it, as a fraction of its bounding sphere's radius
+pub const FRAME_ALL_MARGIN: f32 = 0.05;
+
+fn load_world(scene: &[SceneFile], coordinate_system: CoordinateSystem, state: &TracingState) -> Option<World> {
+    let load_start = Instant::now();
+    let mut world = World::load(scene, coordinate_system)?;
+    if state.spatial_splits.load(Ordering::Relaxed) {
+        world.rebuild_with_spatial_splits();
+    }
+    // Building happens as part of loading, so its time is split off afterwards
+    *state.timings.write() = StageTimings {
+        load: load_start.elapsed().saturating_sub(world.build_time),
+        build: world.build_time,
+        ..Default::default()
+    };
+    if let Some(camera_name) = state.gltf_camera.read().as_deref() {
+        let name = (!camera_name.is_empty()).then_some(camera_name);
+        match load_gltf_camera(scene, coordinate_system, name) {
+            Some(camera) => camera.apply(&mut state.config.write()),
+            None => match name {
+                Some(name) => println!("Warning: The scene has no glTF camera called {}, so the camera is left where it is", name),
+                None => println!("Warning: The scene has no glTF camera, so the camera is left where it is"),
+            },
+        }
+    }
+    if state.frame_all.load(Ordering::Relaxed) && !world.frame_all(&mut state.config.write(), FRAME_ALL_MARGIN) {
+        println!("Warning: The scene is empty, so there is nothing to frame");
+    }
+    let config = *state.config.read();
+    if let Some(warning) = lighting_warning(world.has_emitters(), NextEventEstimation::from_u32(config.nee)) {
+        println!("Warning: {}", warning);
+    }
+    if world.inward_facing_fraction(&config).map_or(false, |fraction| fraction > INSIDE_OUT_THRESHOLD) {
+        println!("Warning: Most surfaces the camera sees have normals facing away from it, so the scene may be inside out. Try --flip-normals, or --flip-winding if lights shine the wrong way");
+    }
+    Some(world)
+}
+
+// Point out lighting setups that are likely to be mistakes, since they render black or noisy images rather than failing
+pub fn lighting_warning(has_emitters: bool, nee: NextEventEstimation) -> Option<&'static str> {
+    match (has_emitters, nee.uses_nee()) {
+        (false, true) => Some("The scene has no emissive geometry, so next event estimation is skipped, and all light comes from the background"),
+        (true, false) => Some("The scene has emissive geometry, but next event estimation is off, so it will be slow to converge"),
+        _ => None,
+    }
+}
+
+// What `black_render_diagnostics` needs to know about a scene, taken before the world is uploaded
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SceneSummary {
+    pub lights: usize, // Emissive primitives
+    pub bounds: Option<(Vec3, Vec3)>, // None if the scene is empty
+}
+
+impl SceneSummary {
+    pub fn of(world: &World) -> Self {
+        Self { lights: world.light_count(), bounds: world.bounds() }
+    }
+}
+
+// Mean luminance below which a render counts as black
+pub const BLACK_LUMINANCE: f32 = 1e-6;
+// The image is averaged down to at most this many cells on each side before checking it
+const BLACK_CHECK_SIZE: usize = 32;
+
+// A render that is black all over is more likely a mistake than a dark scene, such as missing lights, a camera looking
+// away from the scene or normals facing the wrong way. If the image is black, returns lines explaining what may have
+// caused it, otherwise None. `alpha` is the fraction of primary rays which hit something, per pixel.
+pub fn black_render_diagnostics(image: &[f32], alpha: &[f32], config: &TracingConfig, summary: &SceneSummary) -> Option<Vec<String>> {
+    let (width, height) = (config.width as usize, config.height as usize);
+    if image.len() < width * height * 3 || width * height == 0 {
+        return None;
+    }
+    // Box filter the luminance into cells, so a single bright spot still counts
+    let (cells_x, cells_y) = (width.min(BLACK_CHECK_SIZE), height.min(BLACK_CHECK_SIZE));
+    let mut cells = vec![(0.0, 0); cells_x * cells_y];
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = (y * width + x) * 3;
+            let cell = &mut cells[y * cells_y / height * cells_x + x * cells_x / width];
+            cell.0 += luminance(&image[pixel..pixel + 3]);
+            cell.1 += 1;
+        }
+    }
+    let cells = cells.iter().map(|(sum, count)| sum / *count as f32).collect::<Vec<_>>();
+    let mean = cells.iter().sum::<f32>() / cells.len() as f32;
+    if mean.is_nan() || mean >= BLACK_LUMINANCE {
+        return None;
+    }
+    let brightest = cells.iter().fold(0.0f32, |brightest, cell| brightest.max(*cell));
+
+    let mut lines = vec![format!("The render is black, with a mean luminance of {:.1e}, and {:.1e} in the brightest part", mean, brightest)];
+    match summary.lights {
+        0 => lines.push(String::from("The scene has no emissive geometry, so all light has to come from the background")),
+        lights => lines.push(format!("The scene has {} emissive primitives", lights)),
+    }
+    if config.has_skybox == 0 && config.background_color.w != 0.0 && config.background_color.truncate() == Vec3::ZERO {
+        lines.push(String::from("The background is black, so it doesn't light the scene either"));
+    }
+    if !alpha.is_empty() {
+        let hits = alpha.iter().filter(|coverage| **coverage > 0.0).count();
+        if hits == 0 {
+            lines.push(String::from("No primary rays hit geometry, the camera may be looking away from the scene"));
+        } else {
+            lines.push(format!("Primary rays hit geometry in {:.1}% of pixels", hits as f32 / alpha.len() as f32 * 100.0));
+        }
+    }
+    let center_direction = crate::reproject::primary_ray_direction(config, Vec2::new(config.width as f32, config.height as f32) * 0.5);
+    let camera = config.cam_position.truncate();
+    lines.push(format!("The camera is at {} looking along {}", camera, center_direction));
+    match summary.bounds {
+        Some((min, max)) => {
+            lines.push(format!("The scene spans from {} to {}", min, max));
+            let inside = camera.cmpge(min).all() && camera.cmple(max).all();
+            if !inside && ((min + max) * 0.5 - camera).dot(center_direction) < 0.0 {
+                lines.push(String::from("The center of the scene is behind the camera, try --frame-all"));
+            }
+        }
+        None => lines.push(String::from("The scene is empty")),
+    }
+    Some(lines)
+}
+
+// Check the finished render with `black_render_diagnostics`
+fn warn_if_black(state: &TracingState, summary: &SceneSummary) {
+    if state.samples.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let config = *state.config.read();
+    if let Some(lines) = black_render_diagnostics(&state.read_framebuffer(), &state.alpha.read(), &config, summary) {
+        println!("Warning: {}", lines[0]);
+        for line in lines[1..].iter() {
+            println!("  {}", line);
+        }
+    }
+}
+
+// Next event estimation has no lights to sample in a scene without emissive geometry, so it is turned off for the
+// dispatch, rather than having every bounce look up a light that isn't there. The configured mode is kept, so it is
+// used again if the scene is swapped for one with lights. The size of the skybox image the kernel reads is filled in.
+pub(crate) fn dispatch_config(config: TracingConfig, has_emitters: bool, skybox_size: (u32, u32)) -> TracingConfig {
+    let config = TracingConfig { skybox_width: skybox_size.0, skybox_height: skybox_size.1, ..config };
+    if has_emitters {
+        config
+    } else {
+        TracingConfig { nee: NextEventEstimation::None.to_u32(), ..config }
+    }
 }
 
 pub fn trace_cpu(
-    scene_path: &str,
+    scene: &[SceneFile],
     skybox_path: Option<&str>,
+    coordinate_system: CoordinateSystem,
     state: Arc<TracingState>,
 ) {
-    let Some(world) = World::from_path(scene_path) else {
+    let Some(world) = load_world(scene, coordinate_system, &state) else {
         return;
     };
+    let has_emitters = world.has_emitters();
+    let scene_summary = SceneSummary::of(&world);
     let mut skybox_image_buffer = fallback_cpu_buffer();
     let mut skybox_size = (2, 2);
     if let Some(skybox_source) = skybox_path.and_then(load_dynamic_image) {
@@ -255,81 +1331,181 @@ pub fn trace_cpu(
         }
     }
 
-    // Reset previous state, if there is any
-    let samples_init = state.samples.load(Ordering::Relaxed) as f32;
-    let mut output_buffer = state.framebuffer.read().chunks(3).map(|c| Vec4::new(c[0], c[1], c[2], 1.0) * samples_init).collect::<Vec<_>>();
+    // Restore previous state, if there is any
+    let mut output_buffer = restore_accumulation(&state.framebuffer.read(), &state.alpha.read(), state.samples.load(Ordering::Relaxed));
 
     // Setup tracing state
     let pixel_count = (screen_width * screen_height) as u64;
-    let mut rng_buffer = if state.use_blue_noise.load(Ordering::Relaxed) { &mut rng_data_blue } else { &mut rng_data_uniform };
+    let rng_seeds = |state: &TracingState| {
+        let rng_data = if state.use_blue_noise.load(Ordering::Relaxed) { &rng_data_blue } else { &rng_data_uniform };
+        frame_rng_seeds(rng_data, state.frame.load(Ordering::Relaxed), state.animated_noise.load(Ordering::Relaxed))
+    };
+    let mut rng_buffer = rng_seeds(&state);
+
+    let mut frame = FrameResolve::new(screen_width, screen_height);
+
+    // Throughput statistics of the current accumulation, gathered per row and merged
+    let mut throughput_stats = state.trace_stats.load(Ordering::Relaxed).then(ThroughputStats::default);
 
-    let mut image_buffer: Vec<f32> = vec![0.0; pixel_count as usize * 3];
+    // Samples per dispatch of each pixel, see `TracingState::paint_priority`
+    let mut priority_version = state.priority_version.load(Ordering::Relaxed);
+    let mut sample_mask = state.read_priority_mask(pixel_count as usize);
+
+    // Denoising and reprojection guides. These aren't restored with the framebuffer, so they keep their own sample count.
+    let mut guide_samples = 0;
+    let mut accumulated_frames = 0;
+    let mut albedo_buffer = vec![Vec4::ZERO; pixel_count as usize];
+    let mut normal_buffer = vec![Vec4::ZERO; pixel_count as usize];
+    let mut variance_buffer = vec![Vec4::ZERO; pixel_count as usize];
+    let mut specular_buffer = vec![Vec4::ZERO; pixel_count as usize]; // Uses the guide sample count, like the guides
+
+    // The config the current frame was rendered with
+    let mut frame_config = *state.config.read();
 
     let atlas_width = world.atlas.width();
     let atlas_height = world.atlas.height();
     let atlas_buffer = dynamic_image_to_cpu_buffer(world.atlas);
     let atlas_image = CpuImage::new(&atlas_buffer, atlas_width, atlas_height);
 
+    let mut accumulation_start = Instant::now();
+    let mut preview = false;
+    let mut last_motion = Instant::now();
+    let mut render_config;
+    let mut bounce_controller = BounceController::new();
     while state.running.load(Ordering::Relaxed) {
+        // Switch back to full resolution once the camera has settled
+        let settled = preview && !state.interacting.load(Ordering::Relaxed) && last_motion.elapsed() >= PREVIEW_SETTLE_TIME;
+
+        // Pick up changes to the priority mask
+        let version = state.priority_version.load(Ordering::Relaxed);
+        if version != priority_version {
+            priority_version = version;
+            sample_mask = state.read_priority_mask(pixel_count as usize);
+        }
+
         // Dispatch
-        let flush = state.interacting.load(Ordering::Relaxed) || state.dirty.load(Ordering::Relaxed);
+        if state.take_samples(1) == 0 {
+            std::thread::sleep(PAUSED_POLL_INTERVAL);
+            continue;
+        }
+        let flush = state.interacting.load(Ordering::Relaxed) || state.dirty.load(Ordering::Relaxed) || settled;
+        let frame_start = Instant::now();
         {
-            let config = state.config.read();
-            let outputs = output_buffer.par_chunks_mut(screen_width as usize).enumerate();
-            let rngs = rng_buffer.par_chunks_mut(screen_width as usize);
-            outputs.zip(rngs).for_each(|((y, output), rng)| {
-                for x in 0..screen_width {
-                    let (radiance, rng_state) = kernels::trace_pixel(
+            render_config = dispatch_config(bounce_controller.apply(if preview { preview_config(&frame_config) } else { frame_config }), has_emitters, skybox_size);
+            let render_width = render_config.width as usize;
+            let render_pixel_count = render_width * render_config.height as usize;
+            let outputs = output_buffer[..render_pixel_count].par_chunks_mut(render_width).enumerate();
+            let albedos = albedo_buffer[..render_pixel_count].par_chunks_mut(render_width);
+            let normals = normal_buffer[..render_pixel_count].par_chunks_mut(render_width);
+            let variances = variance_buffer[..render_pixel_count].par_chunks_mut(render_width);
+            let speculars = specular_buffer[..render_pixel_count].par_chunks_mut(render_width);
+            let rngs = rng_buffer[..render_pixel_count].par_chunks_mut(render_width);
+            let rows = outputs.zip(albedos).zip(normals).zip(variances).zip(speculars).zip(rngs);
+            let frame_stats = Mutex::new(ThroughputStats::default());
+            rows.for_each(|((((((y, output), albedo_output), normal_output), variance_output), specular_output), rng)| {
+                let mut row_stats = ThroughputStats::default();
+                for x in 0..render_config.width {
+                    let (radiance, specular, albedo, normal, rng_state) = kernels::trace_pixel_averaged(
+                        kernels::pixel_samples(&render_config, &sample_mask, y * render_width + x as usize),
                         UVec3::new(x, y as u32, 1),
-                        &config,
+                        &render_config,
                         rng[x as usize],
                         &world.per_vertex_buffer,
                         &world.index_buffer,
                         &world.bvh.nodes,
                         &world.material_data_buffer,
                         &world.light_pick_buffer,
+                        &world.light_tree_buffer,
                         &shared_structs::Sampler,
                         &atlas_image,
                         &skybox_image,
+                        &mut row_stats,
                     );
                     output[x as usize] += radiance;
+                    specular_output[x as usize] += specular;
+                    albedo_output[x as usize] += albedo;
+                    normal_output[x as usize] += normal;
+                    variance_output[x as usize] = welford_update(variance_output[x as usize], radiance.truncate());
                     rng[x as usize] = rng_state;
                 }
+                if throughput_stats.is_some() {
+                    frame_stats.lock().merge(&row_stats);
+                }
             });
+            if let Some(throughput_stats) = throughput_stats.as_mut() {
+                throughput_stats.merge(&frame_stats.into_inner());
+                throughput_stats.add_trace_time(frame_start.elapsed());
+            }
         }
+        state.timings.write().render += frame_start.elapsed();
         state.samples.fetch_add(1, Ordering::Relaxed);
+        guide_samples += 1;
 
-        // Readback from GPU
-        let sample_count = state.samples.load(Ordering::Relaxed) as f32;
-        for (i, col) in output_buffer.iter().enumerate() {
-            image_buffer[i * 3] = col.x / sample_count;
-            image_buffer[i * 3 + 1] = col.y / sample_count;
-            image_buffer[i * 3 + 2] = col.z / sample_count;
-        }
-
-        // Denoise
-        #[cfg(feature = "oidn")]
-        if state.denoise.load(Ordering::Relaxed) && !flush {
-            denoise_image(screen_width as usize, screen_height as usize, &mut image_buffer);
-        }
+        let next_config = *state.config.read();
+        let mut accumulation = CpuAccumulation {
+            radiance: &output_buffer,
+            variance: &variance_buffer,
+            specular: &specular_buffer,
+            albedo: &albedo_buffer,
+            normal: &normal_buffer,
+        };
+        frame.resolve(&state, &mut accumulation, &render_config, &frame_config, &next_config, guide_samples, accumulated_frames, preview, flush, |denoiser, image, albedo, normal| {
+            denoise(denoiser, screen_width as usize, screen_height as usize, image, albedo, normal);
+        });
+        accumulated_frames += 1;
 
-        // Push to render thread
-        state.framebuffer.write().copy_from_slice(image_buffer.as_slice());
+        // Frame rate target, the next sample picks up the new bounce count by itself
+        bounce_controller.update(frame_start.elapsed(), state.target_fps.load(Ordering::Relaxed), frame_config.max_bounces);
 
         // Interaction
         if flush {
             state.dirty.store(false, Ordering::Relaxed);
             state.samples.store(0, Ordering::Relaxed);
+            frame_config = next_config;
             output_buffer = vec![Vec4::ZERO; pixel_count as usize];
-            rng_buffer = if state.use_blue_noise.load(Ordering::Relaxed) { &mut rng_data_blue } else { &mut rng_data_uniform };
+            albedo_buffer = vec![Vec4::ZERO; pixel_count as usize];
+            normal_buffer = vec![Vec4::ZERO; pixel_count as usize];
+            variance_buffer = vec![Vec4::ZERO; pixel_count as usize];
+            specular_buffer = vec![Vec4::ZERO; pixel_count as usize];
+            guide_samples = 0;
+            accumulated_frames = 0;
+            if let Some(throughput_stats) = throughput_stats.as_mut() {
+                *throughput_stats = ThroughputStats::default();
+            }
+            preview = state.adaptive_resolution.load(Ordering::Relaxed) && state.interacting.load(Ordering::Relaxed);
+            if preview {
+                last_motion = Instant::now();
+            }
+            state.frame.fetch_add(1, Ordering::Relaxed);
+            rng_buffer = rng_seeds(&state);
+            accumulation_start = Instant::now();
+        } else if state.over_time_budget(accumulation_start) {
+            println!("Time budget reached after {} samples", state.samples.load(Ordering::Relaxed));
+            state.budget_reached.store(true, Ordering::Relaxed);
+            state.running.store(false, Ordering::Relaxed);
         }
     }
+
+    if let Some(throughput_stats) = throughput_stats {
+        print!("{}", throughput_stats);
+    }
+    if state.print_timings.load(Ordering::Relaxed) {
+        print!("{}", *state.timings.read());
+    }
+    warn_if_black(&state, &scene_summary);
 }
 
 // Harness for running syncronous tracing
 #[allow(dead_code)]
 pub fn setup_trace(width: u32, height: u32, samples: u32) -> Arc<TracingState> {
     let state = Arc::new(TracingState::new(width, height));
+    restart_trace(&state, samples);
+    state
+}
+
+// Let the next synchronous trace with `state` run until it has `samples` samples
+#[allow(dead_code)]
+pub fn restart_trace(state: &Arc<TracingState>, samples: u32) {
     state.running.store(true, Ordering::Relaxed);
     {
         let state = state.clone();
@@ -340,5 +1516,4 @@ pub fn setup_trace(width: u32, height: u32, samples: u32) -> Arc<TracingState> {
             state.running.store(false, Ordering::Relaxed);
         });
     }
-    state
 }
\ No newline at end of file
@@ -0,0 +1,38 @@
+use rustic::adapter::{describe_adapter, enumerate_adapters};
+
+#[test]
+fn adapters_are_listed_by_index_and_name() {
+    for (index, adapter) in enumerate_adapters().iter().enumerate() {
+        let info = adapter.get_info();
+        let description = describe_adapter(index, &info, &adapter.limits());
+        assert!(description.starts_with(&format!("{}: {} (", index, info.name)), "{}", description);
+        assert!(description.contains(&adapter.limits().max_storage_buffer_binding_size.to_string()));
+    }
+}
+
+#[test]
+fn adapters_are_found_by_index_or_name() {
+    use rustic::adapter::find_adapter;
+
+    let names = ["Intel(R) UHD Graphics 630".to_string(), "NVIDIA GeForce RTX 3060 Laptop GPU".to_string()];
+    assert_eq!(find_adapter(&names, "1"), Some(1));
+    assert_eq!(find_adapter(&names, "2"), None, "indices past the end don't exist");
+    assert_eq!(find_adapter(&names, "nvidia"), Some(1), "names are matched ignoring case");
+    assert_eq!(find_adapter(&names, "Graphics"), Some(0));
+    assert_eq!(find_adapter(&names, "radeon"), None);
+}
+
+#[test]
+fn dispatches_larger_than_the_device_allows_are_an_error() {
+    use rustic::adapter::{dispatch_size, max_resolution};
+
+    let limits = wgpu::Limits::default();
+    assert_eq!(max_resolution(&limits), 65535 * 8);
+    assert_eq!(dispatch_size(1280, 720, &limits), Ok((160, 90)));
+    assert_eq!(dispatch_size(15360, 16384, &limits), Ok((1920, 2048)), "16K renders fit");
+    assert_eq!(dispatch_size(13, 9, &limits), Ok((2, 2)), "partial workgroups are rounded up");
+
+    let small = wgpu::Limits { max_compute_workgroups_per_dimension: 1024, ..Default::default() };
+    assert_eq!(dispatch_size(8192, 8192, &small), Ok((1024, 1024)));
+    assert!(dispatch_size(1024, 8193, &small).unwrap_err().contains("8192"));
+}
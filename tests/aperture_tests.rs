@@ -0,0 +1,62 @@
+use glam::Vec2;
+use rand::Rng;
+use shared_structs::sample_aperture;
+
+fn corners(blades: u32, rotation: f32) -> Vec<Vec2> {
+    (0..blades)
+        .map(|i| {
+            let angle = rotation + i as f32 * 2.0 * std::f32::consts::PI / blades as f32;
+            Vec2::new(angle.cos(), angle.sin())
+        })
+        .collect()
+}
+
+#[test]
+fn polygon_samples_stay_inside() {
+    let mut rng = rand::thread_rng();
+    for blades in 3..=8 {
+        let rotation = rng.gen_range(0.0..std::f32::consts::TAU);
+        let corners = corners(blades, rotation);
+        let mut max_distance: f32 = 0.0;
+        for _ in 0..10000 {
+            let point = sample_aperture(blades, rotation, Vec2::new(rng.gen(), rng.gen()));
+            max_distance = max_distance.max(point.length());
+            // The corners go counter-clockwise, so the point must be to the left of every edge
+            for i in 0..blades as usize {
+                let a = corners[i];
+                let b = corners[(i + 1) % blades as usize];
+                assert!((b - a).perp_dot(point - a) >= -1e-5, "{} blades, {} is outside", blades, point);
+            }
+        }
+        // Samples should reach the corners, not just the middle
+        assert!(max_distance > 0.95);
+    }
+}
+
+#[test]
+fn polygon_samples_are_uniform() {
+    // Equal area sectors should get an equal share of the samples
+    let blades = 6;
+    let sample_count = 60000;
+    let mut counts = [0; 6];
+    let mut rng = rand::thread_rng();
+    for _ in 0..sample_count {
+        let point = sample_aperture(blades, 0.0, Vec2::new(rng.gen(), rng.gen()));
+        let angle = point.y.atan2(point.x).rem_euclid(std::f32::consts::TAU);
+        counts[((angle / std::f32::consts::TAU * blades as f32) as usize).min(5)] += 1;
+    }
+    for count in counts {
+        assert!((count as f32 - sample_count as f32 / 6.0).abs() < sample_count as f32 * 0.01);
+    }
+}
+
+#[test]
+fn disk_samples_stay_inside() {
+    let mut rng = rand::thread_rng();
+    for blades in [0, 1, 2] {
+        for _ in 0..10000 {
+            let point = sample_aperture(blades, 0.0, Vec2::new(rng.gen(), rng.gen()));
+            assert!(point.length() <= 1.0 + 1e-5);
+        }
+    }
+}
@@ -0,0 +1,700 @@
+use glam::{UVec4, Vec4};
+use rustic::{asset::remove_degenerate_triangles, bvh::BVHBuilder};
+use shared_structs::{PerVertexData, PrimitiveType, make_primitive};
+
+mod common;
+
+#[test]
+fn degenerate_triangles_are_excluded_from_bvh() {
+    let vertex = |x: f32, y: f32, z: f32, radius: f32| PerVertexData { vertex: glam::Vec3::new(x, y, z), radius, ..Default::default() };
+    let vertices = vec![
+        vertex(0.0, 0.0, 0.0, 0.0),
+        vertex(1.0, 0.0, 0.0, 0.0),
+        vertex(0.0, 1.0, 0.0, 0.0),
+        vertex(2.0, 0.0, 0.0, 0.0),
+        vertex(f32::NAN, 0.0, 0.0, 0.0),
+        vertex(0.0, f32::INFINITY, 0.0, 0.0),
+        vertex(0.0, 0.0, 1e-4, 0.0),
+        vertex(1e-4, 0.0, 1e-4, 0.0),
+        vertex(0.0, 1e-4, 1e-4, 0.0),
+        vertex(5.0, 0.0, 0.0, 0.5),
+    ];
+    let sphere = make_primitive(9, 9, 9, 0, PrimitiveType::Sphere);
+    let mut indices = vec![
+        UVec4::new(0, 1, 2, 0), // valid
+        UVec4::new(0, 1, 3, 0), // collinear
+        UVec4::new(0, 0, 2, 0), // repeated vertex
+        UVec4::new(0, 4, 2, 0), // NaN
+        UVec4::new(0, 1, 5, 0), // infinite
+        UVec4::new(6, 7, 8, 0), // tiny but valid
+        sphere, // valid
+        make_primitive(0, 0, 0, 0, PrimitiveType::Sphere), // no radius
+    ];
+
+    let removed = remove_degenerate_triangles(&vertices, &mut indices);
+    assert_eq!(removed, 5);
+    assert_eq!(indices, vec![UVec4::new(0, 1, 2, 0), UVec4::new(6, 7, 8, 0), sphere]);
+
+    let bvh = BVHBuilder::new(&vertices, &mut indices).build();
+    for node in bvh.nodes.iter().filter(|node| node.is_leaf() && node.triangle_count() > 0) {
+        assert!(node.aabb_min().is_finite());
+        assert!(node.aabb_max().is_finite());
+    }
+}
+
+#[test]
+fn analytic_sphere_is_fitted_to_mesh() {
+    let world = rustic::asset::World::from_path("scenes/SphereLight.glb").unwrap();
+    let spheres = world
+        .index_buffer
+        .iter()
+        .filter(|primitive| PrimitiveType::of(**primitive) == PrimitiveType::Sphere)
+        .collect::<Vec<_>>();
+    assert_eq!(spheres.len(), 1);
+    assert_eq!(world.index_buffer.len(), 3); // The sphere and 2 floor triangles
+
+    let sphere = world.per_vertex_buffer[spheres[0].x as usize];
+    assert!((sphere.vertex - glam::Vec3::new(0.0, 1.5, 3.0)).length() < 1e-4);
+    assert!((sphere.radius - 0.5).abs() < 1e-4);
+}
+
+#[test]
+fn cornell_box_faces_are_consistent() {
+    let world = rustic::asset::World::load(&[rustic::asset::SceneFile::new(rustic::scenes::cornell::SCENE_NAME)], Default::default()).unwrap();
+    assert_eq!(world.index_buffer.len(), 32); // 5 walls, the light and 2 blocks of 5 faces, as 2 triangles each
+
+    let mask = rustic::light_pick::compute_emissive_mask(&world.index_buffer, &world.material_data_buffer);
+    let lights = world.index_buffer.iter().zip(mask).filter(|(_, emissive)| *emissive).collect::<Vec<_>>();
+    assert_eq!(lights.len(), 2);
+
+    // Front faces must agree with the shading normals, and the light must shine down
+    for triangle in world.index_buffer.iter() {
+        let vertex = |index: u32| world.per_vertex_buffer[index as usize].vertex;
+        let geometric_normal = (vertex(triangle.y) - vertex(triangle.x)).cross(vertex(triangle.z) - vertex(triangle.x)).normalize();
+        let shading_normal = world.per_vertex_buffer[triangle.x as usize].normal.truncate();
+        assert!(geometric_normal.dot(shading_normal) > 0.999);
+    }
+    for (light, _) in lights {
+        assert!(world.per_vertex_buffer[light.x as usize].normal.y < -0.999);
+    }
+}
+
+#[test]
+fn builtin_scenes_load_by_name() {
+    for builtin in rustic::scenes::BUILTIN_SCENES {
+        let world = rustic::asset::World::load(&[rustic::asset::SceneFile::new(builtin.name)], Default::default()).unwrap();
+        assert!(!world.index_buffer.is_empty() || builtin.name == "empty", "{} is empty", builtin.name);
+        assert!(world.per_vertex_buffer.iter().all(|v| v.vertex.is_finite()), "{} has invalid vertices", builtin.name);
+    }
+    assert!(rustic::scenes::find_builtin_scene("not-a-scene").is_none());
+}
+
+#[test]
+fn z_up_mesh_is_oriented_y_up() {
+    use rustic::asset::{CoordinateSystem, Handedness, UpAxis, World};
+
+    // A ground triangle facing up, and a wall triangle 2 units tall facing -y, authored Z-up and counter-clockwise
+    let path = std::env::temp_dir().join("rustic_z_up_test.obj");
+    std::fs::write(&path, "\
+v 0 0 0\nv 1 0 0\nv 0 1 0\nv 0 0 2\n\
+vn 0 0 1\nvn 0 -1 0\n\
+f 1//1 2//1 3//1\nf 1//2 2//2 4//2\n").unwrap();
+
+    let systems = [
+        (UpAxis::Z, Handedness::Right, 2.0),
+        (UpAxis::Z, Handedness::Left, 2.0),
+        (UpAxis::Y, Handedness::Right, 1.0),
+        (UpAxis::Y, Handedness::Left, 1.0),
+    ];
+    for (up, handedness, height) in systems {
+        let world = World::from_path_with_coordinate_system(path.to_str().unwrap(), CoordinateSystem { up, handedness, ..Default::default() }).unwrap();
+        assert_eq!(world.index_buffer.len(), 2);
+
+        let top = world.per_vertex_buffer.iter().map(|v| v.vertex.y).fold(f32::NEG_INFINITY, f32::max);
+        assert!((top - height).abs() < 1e-4, "{:?} {:?} is {} tall", up, handedness, top);
+
+        // Converting must not turn any faces inside out
+        for triangle in world.index_buffer.iter() {
+            let vertex = |index: u32| world.per_vertex_buffer[index as usize].vertex;
+            let geometric_normal = (vertex(triangle.y) - vertex(triangle.x)).cross(vertex(triangle.z) - vertex(triangle.x)).normalize();
+            let shading_normal = world.per_vertex_buffer[triangle.x as usize].normal.truncate();
+            assert!(geometric_normal.dot(shading_normal) > 0.999, "{:?} {:?} flipped a face", up, handedness);
+        }
+    }
+
+    // With the default convention, the ground ends up facing up
+    let world = World::from_path(path.to_str().unwrap()).unwrap();
+    assert!(world.per_vertex_buffer.iter().any(|v| v.normal.y > 0.999));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn inside_out_mesh_is_fixed_by_flipping() {
+    use rustic::asset::{CoordinateSystem, Handedness, UpAxis, World};
+
+    // A quad in front of the camera, wound and with normals facing away from it
+    let path = std::env::temp_dir().join("rustic_inside_out_test.obj");
+    std::fs::write(&path, "\
+v -3 -3 0\nv 3 -3 0\nv 3 3 0\nv -3 3 0\n\
+vn 0 0 1\n\
+f 1//1 2//1 3//1\nf 1//1 3//1 4//1\n").unwrap();
+    let load = |flip: bool| {
+        let coordinate_system = CoordinateSystem { up: UpAxis::Y, handedness: Handedness::Left, flip_winding: flip, flip_normals: flip };
+        World::from_path_with_coordinate_system(path.to_str().unwrap(), coordinate_system).unwrap()
+    };
+    let inverted = load(false);
+    let flipped = load(true);
+    std::fs::remove_file(path).unwrap();
+
+    let config = shared_structs::TracingConfig {
+        width: 16,
+        height: 16,
+        cam_position: Vec4::new(0.0, 0.0, -2.0, 0.0),
+        ..Default::default()
+    };
+    assert_eq!(inverted.inward_facing_fraction(&config), Some(1.0));
+    assert_eq!(flipped.inward_facing_fraction(&config), Some(0.0));
+    for triangle in flipped.index_buffer.iter() {
+        let vertex = |index: u32| flipped.per_vertex_buffer[index as usize].vertex;
+        let geometric_normal = (vertex(triangle.y) - vertex(triangle.x)).cross(vertex(triangle.z) - vertex(triangle.x)).normalize();
+        assert!(geometric_normal.z < -0.999, "the flipped quad should face the camera");
+    }
+
+    // Lit by the sky, only the flipped quad reflects light towards the camera
+    let total = |image: &[Vec4]| image.iter().map(|pixel| pixel.truncate().element_sum()).sum::<f32>();
+    let inverted_light = total(&common::render_cpu(&inverted, &config, 4));
+    let flipped_light = total(&common::render_cpu(&flipped, &config, 4));
+    assert!(flipped_light > inverted_light, "flipped {} should be brighter than inverted {}", flipped_light, inverted_light);
+}
+
+#[test]
+fn scene_files_are_merged_with_their_own_materials() {
+    use glam::{Mat4, Vec3, Vec4Swizzles};
+    use rustic::asset::{SceneFile, World};
+
+    let light = World::from_path("scenes/SphereLight.glb").unwrap();
+    let sphere = World::from_path("scenes/LoneSphere.glb").unwrap();
+    let mut offset_sphere = SceneFile::new("scenes/LoneSphere.glb");
+    offset_sphere.transform = Mat4::from_translation(Vec3::new(30.0, 0.0, 0.0)) * Mat4::from_scale(Vec3::splat(2.0));
+    let merged = World::load(&[SceneFile::new("scenes/SphereLight.glb"), offset_sphere], Default::default()).unwrap();
+
+    assert_eq!(merged.index_buffer.len(), light.index_buffer.len() + sphere.index_buffer.len());
+    assert_eq!(merged.material_data_buffer.len(), light.material_data_buffer.len() + sphere.material_data_buffer.len());
+
+    // Each primitive keeps the material of the file it came from, which for the second file is offset past the first
+    let light_material_count = light.material_data_buffer.len() as u32;
+    for primitive in merged.index_buffer.iter() {
+        let position = merged.per_vertex_buffer[primitive.x as usize].vertex;
+        let material = shared_structs::material_index(*primitive);
+        let from_second_file = position.x > 20.0; // The floor of the first file spans -10 to 10
+        assert_eq!(material >= light_material_count, from_second_file);
+        if from_second_file {
+            assert!((position.distance(Vec3::new(30.0, 0.0, 0.0)) - 2.0).abs() < 1e-3); // On the scaled sphere
+            assert_eq!(merged.material_data_buffer[material as usize].emissive.xyz(), Vec3::ZERO);
+        }
+    }
+
+    // The light of the first file is the only one
+    let mask = rustic::light_pick::compute_emissive_mask(&merged.index_buffer, &merged.material_data_buffer);
+    assert_eq!(mask.iter().filter(|emissive| **emissive).count(), 1);
+}
+
+#[test]
+fn non_uniform_scale_keeps_normals_perpendicular() {
+    use glam::{Mat4, Vec3, Vec4Swizzles};
+    use rustic::asset::{SceneFile, World};
+
+    // Stretching the unit sphere gives an ellipsoid, where the surface normal at p is proportional to p / scale^2
+    let scale = Vec3::new(1.0, 3.0, 0.5);
+    let mut ellipsoid = SceneFile::new("scenes/LoneSphere.glb");
+    ellipsoid.transform = Mat4::from_rotation_y(0.7) * Mat4::from_scale(scale);
+    let world = World::load(&[ellipsoid], Default::default()).unwrap();
+
+    let to_local = Mat4::from_rotation_y(-0.7);
+    for vertex in world.per_vertex_buffer.iter() {
+        let local_position = to_local.transform_point3(vertex.vertex);
+        let expected = Mat4::from_rotation_y(0.7).transform_vector3(local_position / (scale * scale)).normalize();
+        assert!(vertex.normal.xyz().dot(expected) > 0.999, "normal {} should be {}", vertex.normal, expected);
+        if vertex.tangent.xyz() != Vec3::ZERO {
+            assert!(vertex.tangent.xyz().dot(vertex.normal.xyz()).abs() < 1e-3);
+        }
+    }
+}
+
+#[test]
+fn occlusion_texture_gets_its_own_atlas_slot() {
+    use glam::Vec4Swizzles;
+
+    let mut material = shared_structs::MaterialData::default();
+    material.set_has_albedo_texture(true);
+    material.set_has_occlusion_texture(true);
+    let solid = |color: [u8; 3]| image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(16, 16, image::Rgb(color)));
+    let world = rustic::asset::World::from_geometry(
+        vec![glam::Vec3::ZERO, glam::Vec3::X, glam::Vec3::Y],
+        vec![UVec4::new(0, 1, 2, 0)],
+        vec![Vec4::Z; 3],
+        vec![Vec4::X; 3],
+        vec![glam::Vec2::ZERO; 3],
+        Vec::new(),
+        Vec::new(),
+        Vec::new(),
+        vec![material],
+        vec![solid([255, 0, 0]), solid([64, 64, 64])],
+    );
+
+    // Textures are handed out in slot order, so the occlusion map comes after the albedo
+    let material = world.material_data_buffer[0];
+    let center_of = |location: Vec4| {
+        let center = (location.xy() + location.zw() * 0.5) * shared_structs::ATLAS_SIZE as f32;
+        image::GenericImageView::get_pixel(&world.atlas, center.x as u32, center.y as u32)
+    };
+    assert_eq!(center_of(material.albedo).0[..3], [255, 0, 0]);
+    assert_eq!(center_of(material.occlusion).0[..3], [64, 64, 64]);
+}
+
+#[test]
+fn emissive_strength_multiplies_emission() {
+    use glam::Vec3;
+
+    // Two triangles with the same emissive color, only the first using KHR_materials_emissive_strength
+    let path = std::env::temp_dir().join("rustic_emissive_strength_test.gltf");
+    std::fs::write(&path, r#"{
+        "asset": { "version": "2.0" },
+        "extensionsUsed": ["KHR_materials_emissive_strength"],
+        "scene": 0,
+        "scenes": [{ "nodes": [0, 1] }],
+        "nodes": [{ "mesh": 0 }, { "mesh": 1, "translation": [2, 0, 0] }],
+        "meshes": [
+            { "primitives": [{ "attributes": { "POSITION": 0 }, "material": 0 }] },
+            { "primitives": [{ "attributes": { "POSITION": 0 }, "material": 1 }] }
+        ],
+        "materials": [
+            { "emissiveFactor": [1.0, 0.5, 0.25], "extensions": { "KHR_materials_emissive_strength": { "emissiveStrength": 4.0 } } },
+            { "emissiveFactor": [1.0, 0.5, 0.25] }
+        ],
+        "buffers": [{ "byteLength": 36, "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAA" }],
+        "bufferViews": [{ "buffer": 0, "byteLength": 36 }],
+        "accessors": [{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0] }]
+    }"#).unwrap();
+
+    let world = rustic::asset::World::from_path(path.to_str().unwrap()).unwrap();
+    assert_eq!(world.material_data_buffer[0].emission(), Vec3::new(4.0, 2.0, 1.0));
+    // Without the extension, the old fixed multiplier is kept so existing scenes stay as bright
+    assert_eq!(world.material_data_buffer[1].emission(), Vec3::new(15.0, 7.5, 3.75));
+}
+
+#[test]
+fn compact_primitives_round_trip() {
+    use shared_structs::{CompactPrimitive, PackedPrimitive, make_primitive, COMPACT_PRIMITIVE_MAX_MATERIALS, COMPACT_PRIMITIVE_MAX_VERTICES};
+
+    let max_vertex = COMPACT_PRIMITIVE_MAX_VERTICES as u32 - 1;
+    let max_material = COMPACT_PRIMITIVE_MAX_MATERIALS as u32 - 1;
+    for primitive in [
+        make_primitive(0, 1, 2, 0, PrimitiveType::Triangle),
+        make_primitive(max_vertex, 0, max_vertex, max_material, PrimitiveType::Quad),
+        make_primitive(12345, 0, 0, 77, PrimitiveType::Sphere),
+    ] {
+        assert_eq!(CompactPrimitive::pack(primitive).unpack(), primitive);
+    }
+
+    assert!(CompactPrimitive::fits(COMPACT_PRIMITIVE_MAX_VERTICES, COMPACT_PRIMITIVE_MAX_MATERIALS));
+    assert!(!CompactPrimitive::fits(COMPACT_PRIMITIVE_MAX_VERTICES + 1, 1));
+    assert!(!CompactPrimitive::fits(3, COMPACT_PRIMITIVE_MAX_MATERIALS + 1));
+}
+
+#[test]
+fn compact_index_buffer_renders_identically() {
+    let world = rustic::asset::World::load(&[rustic::asset::SceneFile::new(rustic::scenes::cornell::SCENE_NAME)], Default::default()).unwrap();
+    let compact = world.compact_index_buffer().expect("the Cornell box fits in 16 bit indices");
+    assert_eq!(std::mem::size_of_val(compact.as_slice()) * 2, std::mem::size_of_val(world.index_buffer.as_slice()));
+
+    // Both renders use the same seeds, so they only differ if a primitive is read differently
+    let config = shared_structs::TracingConfig {
+        width: 32,
+        height: 32,
+        cam_position: rustic::scenes::cornell::CAMERA_POSITION,
+        cam_rotation: rustic::scenes::cornell::CAMERA_ROTATION,
+        nee: shared_structs::NextEventEstimation::MultipleImportanceSampling.to_u32(),
+        ..Default::default()
+    };
+    let wide = common::render_cpu(&world, &config, 1);
+    let narrow = common::render_cpu_with(&world, &compact, &config, 1, &mut kernels::stats::NoBounceStats);
+    assert!(wide == narrow);
+}
+
+#[test]
+fn material_bounce_budget_matches_global_cap() {
+    let load = || rustic::asset::World::load(&[rustic::asset::SceneFile::new(rustic::scenes::cornell::SCENE_NAME)], Default::default()).unwrap();
+    let world = load();
+    let config = shared_structs::TracingConfig {
+        width: 32,
+        height: 32,
+        cam_position: rustic::scenes::cornell::CAMERA_POSITION,
+        cam_rotation: rustic::scenes::cornell::CAMERA_ROTATION,
+        min_bounces: 8,
+        max_bounces: 8,
+        ..Default::default()
+    };
+    let short = common::render_cpu(&world, &shared_structs::TracingConfig { max_bounces: 2, ..config }, 1);
+    let long = common::render_cpu(&world, &config, 1);
+
+    // With every material limited to 2 bounces, paths end where a global maximum of 2 would end them
+    let mut budgeted = load();
+    for material in budgeted.material_data_buffer.iter_mut() {
+        material.max_bounces = 2;
+    }
+    let budgeted_render = common::render_cpu(&budgeted, &config, 1);
+    assert!(budgeted_render == short);
+    let total = |image: &[Vec4]| image.iter().map(|pixel| pixel.truncate().element_sum()).sum::<f32>();
+    assert!(total(&long) > total(&budgeted_render), "longer paths gather more light");
+}
+
+#[test]
+fn material_bounce_budget_is_read_from_gltf_extras() {
+    let path = std::env::temp_dir().join("rustic_bounce_budget_test.gltf");
+    std::fs::write(&path, r#"{
+        "asset": { "version": "2.0" },
+        "scene": 0,
+        "scenes": [{ "nodes": [0, 1] }],
+        "nodes": [{ "mesh": 0 }, { "mesh": 1, "translation": [2, 0, 0] }],
+        "meshes": [
+            { "primitives": [{ "attributes": { "POSITION": 0 }, "material": 0 }] },
+            { "primitives": [{ "attributes": { "POSITION": 0 }, "material": 1 }] }
+        ],
+        "materials": [
+            { "extras": { "max_bounces": 12 } },
+            { }
+        ],
+        "buffers": [{ "byteLength": 36, "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAA" }],
+        "bufferViews": [{ "buffer": 0, "byteLength": 36 }],
+        "accessors": [{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0] }]
+    }"#).unwrap();
+
+    let world = rustic::asset::World::from_path(path.to_str().unwrap()).unwrap();
+    assert_eq!(world.material_data_buffer[0].max_bounces, 12);
+    assert_eq!(world.material_data_buffer[1].max_bounces, 0);
+}
+
+#[test]
+fn principled_parameters_are_read_from_gltf() {
+    let path = std::env::temp_dir().join("rustic_principled_test.gltf");
+    std::fs::write(&path, r#"{
+        "asset": { "version": "2.0" },
+        "extensionsUsed": ["KHR_materials_clearcoat", "KHR_materials_sheen", "KHR_materials_specular"],
+        "scene": 0,
+        "scenes": [{ "nodes": [0, 1] }],
+        "nodes": [{ "mesh": 0 }, { "mesh": 1, "translation": [2, 0, 0] }],
+        "meshes": [
+            { "primitives": [{ "attributes": { "POSITION": 0 }, "material": 0 }] },
+            { "primitives": [{ "attributes": { "POSITION": 0 }, "material": 1 }] }
+        ],
+        "materials": [
+            {
+                "extensions": {
+                    "KHR_materials_clearcoat": { "clearcoatFactor": 1.0, "clearcoatRoughnessFactor": 0.25 },
+                    "KHR_materials_sheen": { "sheenColorFactor": [0.2, 0.6, 0.4] },
+                    "KHR_materials_specular": { "specularFactor": 0.5 }
+                },
+                "extras": { "subsurface": 0.75, "subsurface_radius": [1.0, 0.5, 0.25], "clearcoat": 0.5 }
+            },
+            { }
+        ],
+        "buffers": [{ "byteLength": 36, "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAA" }],
+        "bufferViews": [{ "buffer": 0, "byteLength": 36 }],
+        "accessors": [{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0] }]
+    }"#).unwrap();
+
+    let world = rustic::asset::World::from_path(path.to_str().unwrap()).unwrap();
+    let material = world.material_data_buffer[0];
+    assert_eq!(material.specular, 0.25);
+    assert_eq!(material.clearcoat, 0.5, "extras take precedence over extensions");
+    assert_eq!(material.clearcoat_gloss, 0.75);
+    assert_eq!((material.sheen, material.sheen_tint), (0.6, 0.0));
+    assert_eq!(material.subsurface, 0.75);
+    assert_eq!(material.subsurface_radius, Vec4::new(1.0, 0.5, 0.25, 0.0));
+
+    // Unset parameters keep their defaults
+    let material = world.material_data_buffer[1];
+    let default = shared_structs::MaterialData::default();
+    assert_eq!((material.specular, material.sheen, material.clearcoat), (default.specular, default.sheen, default.clearcoat));
+    assert!(!material.has_subsurface_scattering());
+}
+
+#[test]
+fn sheen_and_clearcoat_change_renders() {
+    let load = || rustic::asset::World::load(&[rustic::asset::SceneFile::new(rustic::scenes::cornell::SCENE_NAME)], Default::default()).unwrap();
+    let config = shared_structs::TracingConfig {
+        width: 32,
+        height: 32,
+        cam_position: rustic::scenes::cornell::CAMERA_POSITION,
+        cam_rotation: rustic::scenes::cornell::CAMERA_ROTATION,
+        ..Default::default()
+    };
+    let total = |image: &[Vec4]| image.iter().map(|pixel| pixel.truncate().element_sum()).sum::<f32>();
+    let render_with = |change: &dyn Fn(&mut shared_structs::MaterialData)| {
+        let mut world = load();
+        for material in world.material_data_buffer.iter_mut() {
+            change(material);
+        }
+        total(&common::render_cpu(&world, &config, 1))
+    };
+
+    // Sheen doesn't change how paths are sampled, so it can only add light. The clearcoat also changes which
+    // directions are sampled, so at this sample count it can only be expected to change the image.
+    let plain = render_with(&|_| {});
+    assert!(render_with(&|material| material.sheen = 1.0) > plain);
+    let coated = render_with(&|material| material.clearcoat = 1.0);
+    assert!(coated.is_finite() && coated != plain);
+}
+
+#[test]
+fn rough_metal_at_grazing_angles_has_little_noise() {
+    use glam::Vec3;
+    use rustic::scenes::SceneBuilder;
+
+    // A rough, white metal floor under a white sky, with the top row of the image on the horizon. Sampling only the
+    // microfacets visible from the camera keeps the weights of the reflected paths close to each other, even where the
+    // floor is seen at grazing angles.
+    let mut builder = SceneBuilder::default();
+    let mut metal = rustic::scenes::diffuse(Vec3::ONE);
+    metal.metallic = Vec4::ONE;
+    metal.roughness = Vec4::splat(0.6);
+    let metal = builder.push_material(metal);
+    let extent = 1000.0;
+    builder.push_quad([Vec3::new(-extent, 0.0, -extent), Vec3::new(extent, 0.0, -extent), Vec3::new(extent, 0.0, extent), Vec3::new(-extent, 0.0, extent)], Vec3::Y, metal);
+    let world = builder.build();
+    let config = shared_structs::TracingConfig {
+        width: 32,
+        height: 32,
+        cam_position: Vec4::new(0.0, 1.0, 0.0, 0.0),
+        cam_rotation: Vec4::new(std::f32::consts::FRAC_PI_4, 0.0, 0.0, 0.0),
+        background_color: Vec4::new(1.0, 1.0, 1.0, 1.0),
+        ..Default::default()
+    };
+
+    // Neighbouring pixels in a row see the floor at nearly the same angle, so they only differ by noise. The first row
+    // is skipped, since some of its samples see the sky.
+    let image = common::render_cpu(&world, &config, 16);
+    let grazing_rows = 1..config.height / 4;
+    let (mut difference, mut total) = (0.0, 0.0);
+    for y in grazing_rows {
+        for x in 0..config.width - 1 {
+            let pixel = image[(y * config.width + x) as usize].truncate().element_sum();
+            let neighbour = image[(y * config.width + x + 1) as usize].truncate().element_sum();
+            difference += (pixel - neighbour).abs();
+            total += pixel;
+        }
+    }
+    assert!(total > 0.0, "the floor should reflect the sky");
+    assert!(difference / total < 0.25, "relative noise at grazing angles is {}", difference / total);
+}
+
+#[test]
+fn lights_hidden_from_camera_still_light_the_scene() {
+    use glam::Vec3;
+    use rustic::scenes::SceneBuilder;
+
+    // A small light between the camera and a floor, looking straight down at both, against a black background
+    let build = |hidden: bool| {
+        let mut builder = SceneBuilder::default();
+        let floor = builder.push_material(rustic::scenes::diffuse(Vec3::splat(0.8)));
+        builder.push_quad([Vec3::new(-1.0, 0.0, -1.0), Vec3::new(1.0, 0.0, -1.0), Vec3::new(1.0, 0.0, 1.0), Vec3::new(-1.0, 0.0, 1.0)], Vec3::Y, floor);
+        let mut light = rustic::scenes::diffuse(Vec3::ZERO);
+        light.emissive = Vec4::new(1.0, 1.0, 1.0, 10.0);
+        light.set_hidden_from_camera(hidden);
+        let light = builder.push_material(light);
+        builder.push_sphere(Vec3::new(0.0, 0.5, 0.0), 0.1, light);
+        builder.build()
+    };
+    let config = shared_structs::TracingConfig {
+        width: 16,
+        height: 16,
+        cam_position: Vec4::new(0.0, 1.0, 0.0, 0.0),
+        cam_rotation: Vec4::new(std::f32::consts::FRAC_PI_2, 0.0, 0.0, 0.0),
+        background_color: Vec4::new(0.0, 0.0, 0.0, 1.0),
+        nee: shared_structs::NextEventEstimation::MultipleImportanceSampling.to_u32(),
+        ..Default::default()
+    };
+    let brightness = |pixel: &Vec4| pixel.truncate().max_element();
+
+    let visible = common::render_cpu(&build(false), &config, 64);
+    let hidden = common::render_cpu(&build(true), &config, 64);
+    let center = (8 * 16 + 8) as usize;
+    assert!(brightness(&visible[center]) > 5.0, "the light should be seen, got {}", visible[center]);
+    assert!(brightness(&hidden[center]) < 1.0, "the light should not be seen, got {}", hidden[center]);
+    assert!(brightness(&hidden[center]) > 0.05, "the floor under the light should be lit, got {}", hidden[center]);
+
+    // Away from the light, the floor is lit the same either way
+    let floor = |image: &[Vec4]| (0..image.len()).filter(|i| brightness(&visible[*i]) < 1.0).map(|i| image[i].truncate().element_sum()).sum::<f32>();
+    assert!(floor(&visible) > 0.0);
+    assert!((floor(&hidden) - floor(&visible)).abs() < 0.1 * floor(&visible), "floor got {} hidden but {} visible", floor(&hidden), floor(&visible));
+}
+
+#[test]
+fn vertex_colors_tint_the_albedo() {
+    use rustic::asset::World;
+
+    // A quad facing the camera, red on the left and blue on the right
+    let path = std::env::temp_dir().join("rustic_vertex_color_test.ply");
+    std::fs::write(&path, "\
+ply\nformat ascii 1.0\n\
+element vertex 4\nproperty float x\nproperty float y\nproperty float z\n\
+property uchar red\nproperty uchar green\nproperty uchar blue\n\
+element face 2\nproperty list uchar int vertex_indices\nend_header\n\
+-3 -3 0 255 0 0\n3 -3 0 0 0 255\n3 3 0 0 0 255\n-3 3 0 255 0 0\n\
+3 0 2 1\n3 0 3 2\n").unwrap();
+    let world = World::from_path(path.to_str().unwrap()).unwrap();
+    std::fs::remove_file(path).unwrap();
+
+    for vertex in world.per_vertex_buffer.iter() {
+        let expected = if vertex.vertex.x < 0.0 { glam::Vec3::X } else { glam::Vec3::Z };
+        assert!(vertex.color.truncate().abs_diff_eq(expected, 1e-3), "vertex at {} has color {}", vertex.vertex, vertex.color);
+    }
+
+    // Lit only by ambient light, each side takes on the color of its vertices
+    let config = shared_structs::TracingConfig {
+        width: 16,
+        height: 16,
+        cam_position: Vec4::new(0.0, 0.0, -2.0, 0.0),
+        background_color: Vec4::new(0.0, 0.0, 0.0, 1.0),
+        ambient: Vec4::ONE,
+        ..Default::default()
+    };
+    let image = common::render_cpu(&world, &config, 4);
+    let left = image[8 * 16 + 2];
+    let right = image[8 * 16 + 13];
+    assert!(left.x > left.z && left.y < 1e-3, "left side should be red, got {}", left);
+    assert!(right.z > right.x && right.y < 1e-3, "right side should be blue, got {}", right);
+}
+
+#[test]
+fn shared_vertices_are_stored_once() {
+    use glam::{UVec4, Vec2};
+    use rustic::asset::World;
+    use rustic::scenes::diffuse;
+
+    // A cube with smoothed normals, so each corner is the same vertex for all the faces that meet there
+    let corners = (0..8)
+        .map(|i| glam::Vec3::new(if i & 1 == 0 { -0.5 } else { 0.5 }, if i & 2 == 0 { 0.0 } else { 1.0 }, if i & 4 == 0 { -0.5 } else { 0.5 }))
+        .collect::<Vec<_>>();
+    let normals = corners.iter().map(|corner| (*corner - glam::Vec3::new(0.0, 0.5, 0.0)).normalize().extend(0.0)).collect::<Vec<_>>();
+    let faces = [[0, 2, 3, 1], [4, 5, 7, 6], [0, 1, 5, 4], [2, 6, 7, 3], [0, 4, 6, 2], [1, 3, 7, 5]];
+    let triangles = faces.iter().flat_map(|f| [UVec4::new(f[0], f[1], f[2], 0), UVec4::new(f[0], f[2], f[3], 0)]).collect::<Vec<_>>();
+    let build = |vertices: Vec<glam::Vec3>, normals: Vec<Vec4>, indices: Vec<UVec4>| {
+        let count = vertices.len();
+        World::from_geometry(vertices, indices, normals, vec![Vec4::ZERO; count], vec![Vec2::ZERO; count], Vec::new(), Vec::new(), Vec::new(), vec![diffuse(glam::Vec3::splat(0.8))], Vec::new())
+    };
+
+    let indexed = build(corners.clone(), normals.clone(), triangles.clone());
+
+    // The same cube as a triangle soup, with 3 vertices of its own per triangle
+    let soup_order = triangles.iter().flat_map(|t| [t.x, t.y, t.z]).collect::<Vec<_>>();
+    let soup = build(
+        soup_order.iter().map(|&i| corners[i as usize]).collect(),
+        soup_order.iter().map(|&i| normals[i as usize]).collect(),
+        (0..12).map(|i| UVec4::new(i * 3, i * 3 + 1, i * 3 + 2, 0)).collect(),
+    );
+
+    assert_eq!(soup_order.len(), 36);
+    assert_eq!(indexed.per_vertex_buffer.len(), 8);
+    assert_eq!(soup.per_vertex_buffer.len(), 8);
+    assert_eq!(soup.index_buffer.len(), 12);
+
+    let config = shared_structs::TracingConfig {
+        width: 32,
+        height: 32,
+        cam_position: Vec4::new(1.5, 2.0, -2.0, 0.0),
+        cam_rotation: Vec4::new(0.5, -0.6, 0.0, 0.0),
+        ..Default::default()
+    };
+    assert!(common::render_cpu(&indexed, &config, 1) == common::render_cpu(&soup, &config, 1));
+}
+
+#[test]
+fn frame_all_fits_the_scene_in_view() {
+    use glam::{Vec3, Vec4Swizzles};
+    use rustic::scenes::SceneBuilder;
+
+    // A long, off center box of a scene, framed from an arbitrary direction in a wide image
+    let mut builder = SceneBuilder::default();
+    let grey = builder.push_material(rustic::scenes::diffuse(Vec3::splat(0.8)));
+    builder.push_quad([Vec3::new(40.0, 0.0, 90.0), Vec3::new(60.0, 0.0, 90.0), Vec3::new(60.0, 0.0, 110.0), Vec3::new(40.0, 0.0, 110.0)], Vec3::Y, grey);
+    builder.push_sphere(Vec3::new(50.0, 5.0, 100.0), 3.0, grey);
+    let world = builder.build();
+    let (min, max) = world.bounds().unwrap();
+
+    let mut config = shared_structs::TracingConfig {
+        width: 64,
+        height: 32,
+        cam_rotation: Vec4::new(0.4, 2.5, 0.0, 0.0),
+        ..Default::default()
+    };
+    assert!(world.frame_all(&mut config, rustic::trace::FRAME_ALL_MARGIN));
+
+    // Every corner of the bounds projects into the image, away from its edges
+    let margin = 1.0;
+    for i in 0..8 {
+        let corner = Vec3::select(glam::BVec3::new(i & 1 != 0, i & 2 != 0, i & 4 != 0), max, min);
+        let pixel = rustic::reproject::project_direction(&config, corner - config.cam_position.xyz()).unwrap();
+        assert!(pixel.x > margin && pixel.x < config.width as f32 - margin, "corner {} lands at {}", corner, pixel);
+        assert!(pixel.y > margin && pixel.y < config.height as f32 - margin, "corner {} lands at {}", corner, pixel);
+    }
+}
+
+#[test]
+fn gltf_cameras_frame_the_scene_like_the_authoring_tool() {
+    use glam::{Vec3, Vec4Swizzles};
+    use rustic::asset::{load_gltf_camera, CameraProjection, CoordinateSystem, Handedness, SceneFile, UpAxis};
+
+    // A perspective camera 5 units in front of the origin, looking at it, and an orthographic one looking down from
+    // under a scaled parent
+    let path = std::env::temp_dir().join("rustic_gltf_camera_test.gltf");
+    std::fs::write(&path, r#"{
+        "asset": { "version": "2.0" },
+        "scene": 0,
+        "scenes": [{ "nodes": [0, 1] }],
+        "nodes": [
+            { "name": "Front", "camera": 0, "translation": [0, 1, 5] },
+            { "scale": [2, 2, 2], "children": [2] },
+            { "camera": 1, "translation": [0, 5, 0], "rotation": [-0.7071068, 0, 0, 0.7071068] }
+        ],
+        "cameras": [
+            { "type": "perspective", "perspective": { "yfov": 0.6, "znear": 0.1, "zfar": 100 } },
+            { "name": "Top", "type": "orthographic", "orthographic": { "xmag": 1.5, "ymag": 1.5, "znear": 0.5, "zfar": 100 } }
+        ]
+    }"#).unwrap();
+    let scene = [SceneFile::new(path.to_str().unwrap())];
+    let coordinate_system = CoordinateSystem { up: UpAxis::Y, handedness: Handedness::Right, ..Default::default() };
+    let to_world = |v: Vec3| coordinate_system.to_world(v);
+
+    let front = load_gltf_camera(&scene, coordinate_system, None).expect("the first camera");
+    assert_eq!(front, load_gltf_camera(&scene, coordinate_system, Some("Front")).unwrap(), "found by the name of its node");
+    assert!(front.position.abs_diff_eq(to_world(Vec3::new(0.0, 1.0, 5.0)), 1e-5));
+    assert!(front.forward.abs_diff_eq(to_world(-Vec3::Z), 1e-5));
+    assert_eq!(front.projection, CameraProjection::Perspective { yfov: 0.6 });
+
+    // In an image twice as wide as it is tall, the point ahead lands in the middle, and the edges of the authored
+    // vertical field of view on the top and bottom of the image
+    let mut config = shared_structs::TracingConfig { width: 64, height: 32, ..Default::default() };
+    front.apply(&mut config);
+    assert!((config.near_clip - 0.1).abs() < 1e-6);
+    let project = |config: &shared_structs::TracingConfig, point: Vec3| {
+        rustic::reproject::project_direction(config, to_world(point) - config.cam_position.xyz()).unwrap()
+    };
+    let half_height = 5.0 * (0.3f32).tan();
+    assert!(project(&config, Vec3::new(0.0, 1.0, 0.0)).abs_diff_eq(glam::Vec2::new(32.0, 16.0), 1e-3));
+    assert!(project(&config, Vec3::new(0.0, 1.0 + half_height, 0.0)).abs_diff_eq(glam::Vec2::new(32.0, 0.0), 1e-3));
+    assert!(project(&config, Vec3::new(half_height * 2.0, 1.0 - half_height, 0.0)).abs_diff_eq(glam::Vec2::new(64.0, 32.0), 1e-3));
+
+    // The parent's scale moves the camera and widens its view
+    let top = load_gltf_camera(&scene, coordinate_system, Some("Top")).expect("the camera called Top");
+    assert!(top.position.abs_diff_eq(to_world(Vec3::new(0.0, 10.0, 0.0)), 1e-5));
+    assert!(top.forward.abs_diff_eq(-Vec3::Y, 1e-5));
+    assert!((top.znear - 1.0).abs() < 1e-5);
+    top.apply(&mut config);
+    assert!((config.ortho_height - 3.0).abs() < 1e-5);
+    assert!((config.cam_rotation.x - std::f32::consts::FRAC_PI_2).abs() < 1e-5);
+
+    assert!(load_gltf_camera(&scene, coordinate_system, Some("Side")).is_none());
+}
+
@@ -0,0 +1,72 @@
+use glam::{Vec3, Vec4, Vec4Swizzles};
+use rustic::asset::World;
+use rustic::bake::{bake_lightmap, lightmap_texels};
+use shared_structs::{NextEventEstimation, TracingConfig};
+
+mod common;
+
+const RESOLUTION: u32 = 16;
+const SAMPLES: u32 = 256;
+const BLOCK_SIZE: u32 = 4;
+
+// The floor, lit by a light above and to the side of it, so the floor gets darker away from it. The light is a
+// sphere, which has no lightmap UVs, so the floor has the whole lightmap to itself.
+fn lit_floor() -> World {
+    common::lit_floor(Vec3::new(1.0, 2.5, 0.0), 0.3)
+}
+
+// Looking straight down from 1 unit above the floor, so the 90 degree field of view covers exactly the floor, below
+// the light
+fn camera_config() -> TracingConfig {
+    TracingConfig {
+        width: RESOLUTION,
+        height: RESOLUTION,
+        cam_position: Vec4::new(0.0, 1.0, 0.0, 0.0),
+        cam_rotation: Vec4::new(std::f32::consts::FRAC_PI_2, 0.0, 0.0, 0.0),
+        nee: NextEventEstimation::MultipleImportanceSampling.to_u32(),
+        background_color: Vec4::new(0.0, 0.0, 0.0, 1.0),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn floor_covers_the_whole_lightmap() {
+    let texels = lightmap_texels(&lit_floor(), RESOLUTION);
+    for texel in texels.iter() {
+        let texel = texel.expect("every texel is on the floor");
+        assert!(texel.position.y == 0.0 && texel.position.x.abs() < 1.0 && texel.position.z.abs() < 1.0, "{:?}", texel);
+        assert!(texel.normal.abs_diff_eq(Vec3::Y, 1e-5));
+    }
+}
+
+// The floor is diffuse, so the light leaving it towards the camera is the same as along its normal, which is what the
+// lightmap holds. Blocks of texels are compared, to average out the noise of both.
+#[test]
+fn baked_floor_matches_camera_render() {
+    let world = lit_floor();
+    let config = camera_config();
+    let render = common::render_cpu(&world, &config, SAMPLES);
+
+    let texels = lightmap_texels(&world, RESOLUTION);
+    let baked = bake_lightmap(&world, None, &config, RESOLUTION, SAMPLES);
+    assert!(baked.covered.iter().all(|covered| *covered));
+
+    let blocks = (RESOLUTION / BLOCK_SIZE) as usize;
+    let mut baked_blocks = vec![Vec3::ZERO; blocks * blocks];
+    let mut rendered_blocks = vec![Vec3::ZERO; blocks * blocks];
+    for (index, texel) in texels.iter().enumerate() {
+        let texel = texel.unwrap();
+        let pixel = rustic::reproject::project_direction(&config, texel.position - config.cam_position.xyz()).unwrap();
+        let pixel_index = pixel.y as usize * RESOLUTION as usize + pixel.x as usize;
+        let block = (index / RESOLUTION as usize / BLOCK_SIZE as usize) * blocks + (index % RESOLUTION as usize) / BLOCK_SIZE as usize;
+        rendered_blocks[block] += render[pixel_index].xyz();
+        baked_blocks[block] += Vec3::from_slice(&baked.rgb[index * 3..index * 3 + 3]);
+    }
+
+    for (rendered, baked) in rendered_blocks.iter().zip(baked_blocks.iter()) {
+        assert!((*baked - *rendered).abs().max_element() < 0.05 * rendered.max_element(), "baked {} but rendered {}", baked, rendered);
+    }
+    let brightest = rendered_blocks.iter().map(|block| block.x).fold(0.0, f32::max);
+    let darkest = rendered_blocks.iter().map(|block| block.x).fold(f32::INFINITY, f32::min);
+    assert!(brightest > darkest * 1.5, "the floor should be lit unevenly, from {} to {}", darkest, brightest);
+}
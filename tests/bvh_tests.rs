@@ -0,0 +1,145 @@
+use glam::{UVec4, Vec3};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rustic::bvh::BVHBuilder;
+use shared_structs::{BVHNode, PerVertexData};
+
+const BUILDS: usize = 4;
+
+fn vertex(position: Vec3) -> PerVertexData {
+    PerVertexData { vertex: position, ..Default::default() }
+}
+
+// Random triangles, clustered so splits are uneven, plus a grid of identical triangles stacked on top of each other.
+// The stacked ones have equal centroids, so any ordering that isn't stable shows up in how they're partitioned.
+fn random_mesh(seed: u64, triangle_count: usize) -> (Vec<PerVertexData>, Vec<UVec4>) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    let mut push_triangle = |vertices: &mut Vec<PerVertexData>, corners: [Vec3; 3]| {
+        let base = vertices.len() as u32;
+        vertices.extend(corners.map(vertex));
+        indices.push(UVec4::new(base, base + 1, base + 2, 0));
+    };
+    for _ in 0..triangle_count {
+        let cluster = Vec3::new(rng.gen_range(0..4) as f32, 0.0, rng.gen_range(0..4) as f32) * 10.0;
+        let center = cluster + Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 3.0;
+        let corners = [0, 1, 2].map(|_| center + Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 0.5);
+        push_triangle(&mut vertices, corners);
+    }
+    for x in 0..8 {
+        for _ in 0..8 {
+            let corner = Vec3::new(x as f32, -5.0, 0.0);
+            push_triangle(&mut vertices, [corner, corner + Vec3::X, corner + Vec3::Z]);
+        }
+    }
+    (vertices, indices)
+}
+
+// Build, returning the nodes and the reordered primitives
+fn build(vertices: &[PerVertexData], indices: &[UVec4]) -> (Vec<BVHNode>, Vec<UVec4>) {
+    let mut indices = indices.to_vec();
+    let bvh = BVHBuilder::new(vertices, &mut indices).sah_samples(128).build();
+    (bvh.nodes, indices)
+}
+
+#[test]
+fn bvh_build_is_deterministic() {
+    for (seed, triangle_count) in [(0, 1), (1, 100), (2, 5000)] {
+        let (vertices, indices) = random_mesh(seed, triangle_count);
+        let (reference_nodes, reference_indices) = build(&vertices, &indices);
+        for _ in 0..BUILDS {
+            let (nodes, reordered) = build(&vertices, &indices);
+            assert!(
+                bytemuck::cast_slice::<BVHNode, u8>(&nodes) == bytemuck::cast_slice::<BVHNode, u8>(&reference_nodes),
+                "nodes differ for seed {}", seed
+            );
+            assert!(reordered == reference_indices, "triangle order differs for seed {}", seed);
+        }
+    }
+}
+
+// A ground plane made of long diagonal slivers, whose bounding boxes all cover most of the plane, with some small
+// triangles scattered on top
+fn sliver_mesh() -> (Vec<PerVertexData>, Vec<UVec4>) {
+    let mut rng = StdRng::seed_from_u64(4);
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    for i in 0..200 {
+        let offset = i as f32 * 0.5 - 50.0;
+        let corners = [Vec3::new(-50.0, 0.0, offset), Vec3::new(50.0, 0.0, offset + 50.0), Vec3::new(-50.0, 0.0, offset + 0.5)];
+        let base = vertices.len() as u32;
+        vertices.extend(corners.map(vertex));
+        indices.push(UVec4::new(base, base + 1, base + 2, 0));
+    }
+    for _ in 0..200 {
+        let center = Vec3::new(rng.gen_range(-50.0..50.0), rng.gen_range(0.0..2.0), rng.gen_range(-50.0..50.0));
+        let base = vertices.len() as u32;
+        vertices.extend([0, 1, 2].map(|_| vertex(center + Vec3::new(rng.gen(), rng.gen(), rng.gen()))));
+        indices.push(UVec4::new(base, base + 1, base + 2, 0));
+    }
+    (vertices, indices)
+}
+
+fn area(min: Vec3, max: Vec3) -> f32 {
+    let extent = max - min;
+    extent.x * extent.y + extent.y * extent.z + extent.z * extent.x
+}
+
+// Expected cost of tracing a ray, relative to testing every primitive against it
+fn sah_cost(nodes: &[BVHNode]) -> f32 {
+    let cost = nodes
+        .iter()
+        .map(|node| area(node.aabb_min(), node.aabb_max()) * node.triangle_count().max(1) as f32)
+        .sum::<f32>();
+    cost / area(nodes[0].aabb_min(), nodes[0].aabb_max())
+}
+
+#[test]
+fn spatial_splits_cover_every_primitive() {
+    let (vertices, indices) = sliver_mesh();
+    let mut keep_whole = vec![false; indices.len()];
+    keep_whole[0] = true;
+    let mut references = indices.clone();
+    let bvh = BVHBuilder::new(&vertices, &mut references).spatial_splits(0.5).keep_whole(keep_whole).build();
+
+    // The slivers get split, within the budget, except for the one that has to stay whole
+    assert!(references.len() > indices.len());
+    assert!(references.len() <= indices.len() + indices.len() / 2);
+    assert_eq!(references.iter().filter(|primitive| **primitive == indices[0]).count(), 1);
+
+    // Children are inside their parents
+    for node in bvh.nodes.iter().filter(|node| !node.is_leaf()) {
+        for child in [&bvh.nodes[node.left_node_index() as usize], &bvh.nodes[node.right_node_index() as usize]] {
+            assert!(child.aabb_min().cmpge(node.aabb_min()).all() && child.aabb_max().cmple(node.aabb_max()).all());
+        }
+    }
+
+    // Together, the leaves referencing a primitive cover all of it
+    let mut covered = std::collections::HashMap::new();
+    for leaf in bvh.nodes.iter().filter(|node| node.is_leaf()) {
+        for i in 0..leaf.triangle_count() {
+            let primitive = references[(leaf.first_triangle_index() + i) as usize];
+            let (min, max) = covered.entry(primitive.to_array()).or_insert((Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)));
+            *min = min.min(leaf.aabb_min());
+            *max = max.max(leaf.aabb_max());
+        }
+    }
+    for primitive in &indices {
+        let corners = [primitive.x, primitive.y, primitive.z].map(|i| vertices[i as usize].vertex);
+        let (min, max) = covered[&primitive.to_array()];
+        for corner in corners {
+            assert!(corner.cmpge(min - 1e-3).all() && corner.cmple(max + 1e-3).all(), "{:?} isn't covered", corner);
+        }
+    }
+}
+
+#[test]
+fn spatial_splits_reduce_overlap() {
+    let (vertices, indices) = sliver_mesh();
+    let mut plain_indices = indices.clone();
+    let plain = BVHBuilder::new(&vertices, &mut plain_indices).build();
+    let mut split_indices = indices.clone();
+    let split = BVHBuilder::new(&vertices, &mut split_indices).spatial_splits(0.5).build();
+    let (plain_cost, split_cost) = (sah_cost(&plain.nodes), sah_cost(&split.nodes));
+    assert!(split_cost < plain_cost, "SAH cost is {} with spatial splits and {} without", split_cost, plain_cost);
+}
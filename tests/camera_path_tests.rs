@@ -0,0 +1,67 @@
+use glam::{Mat3, Vec3};
+use rustic::scenes::CameraPath;
+use shared_structs::TracingConfig;
+
+const PATH: &str = r#"{ "keyframes": [
+    { "time": 0.0, "position": [0, 1, -5], "target": [0, 1, 0], "fov": 90 },
+    { "time": 1.0, "position": [4, 2, -3], "target": [0, 0.5, 0], "fov": 60 },
+    { "time": 3.0, "position": [5, 1, 2], "target": [1, 1, 0] },
+    { "time": 2.0, "position": [3, 3, 4], "target": [0, 1, 0], "fov": 45 }
+] }"#;
+
+// Direction the renderer points the camera in, for a config the pose was applied to
+fn forward(config: &TracingConfig) -> Vec3 {
+    Mat3::from_rotation_y(config.cam_rotation.y) * Mat3::from_rotation_x(config.cam_rotation.x) * Vec3::Z
+}
+
+#[test]
+fn keyframes_are_hit_at_their_times() {
+    let path = CameraPath::from_json(PATH).unwrap();
+    assert_eq!(path.duration(), 3.0);
+    for keyframe in path.keyframes() {
+        let pose = path.evaluate(keyframe.time);
+        assert_eq!(pose.position, keyframe.position);
+        assert_eq!(pose.fov, keyframe.fov);
+
+        let mut config = TracingConfig::default();
+        pose.apply(&mut config);
+        let to_target = (keyframe.target - keyframe.position).normalize();
+        assert!(forward(&config).dot(to_target) > 1.0 - 1e-6);
+    }
+}
+
+#[test]
+fn keyframes_are_sorted_and_fov_defaults() {
+    let path = CameraPath::from_json(PATH).unwrap();
+    let times = path.keyframes().iter().map(|keyframe| keyframe.time).collect::<Vec<_>>();
+    assert_eq!(times, vec![0.0, 1.0, 2.0, 3.0]);
+    assert_eq!(path.keyframes()[3].fov, TracingConfig::default().fov);
+    assert_eq!(path.keyframes()[1].fov, 60f32.to_radians());
+}
+
+#[test]
+fn path_is_smooth_and_holds_at_the_ends() {
+    let path = CameraPath::from_json(PATH).unwrap();
+    assert_eq!(path.evaluate(-1.0), path.evaluate(0.0));
+    assert_eq!(path.evaluate(10.0), path.evaluate(3.0));
+
+    // Small steps in time give small steps in position and direction, also across keyframes
+    let step = 0.001;
+    let mut previous = path.evaluate(0.0);
+    let mut time = step;
+    while time <= 3.0 {
+        let pose = path.evaluate(time);
+        assert!((pose.position - previous.position).length() < 0.05, "jump in position at {}", time);
+        assert!((pose.yaw - previous.yaw).abs() < 0.05, "jump in yaw at {}", time);
+        assert!((pose.pitch - previous.pitch).abs() < 0.05, "jump in pitch at {}", time);
+        previous = pose;
+        time += step;
+    }
+}
+
+#[test]
+fn malformed_paths_are_rejected() {
+    assert!(CameraPath::from_json("").is_none());
+    assert!(CameraPath::from_json(r#"{ "keyframes": [] }"#).is_none());
+    assert!(CameraPath::from_json(r#"{ "keyframes": [{ "time": 0, "position": [0, 1], "target": [0, 0, 0] }] }"#).is_none());
+}
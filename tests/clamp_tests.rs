@@ -0,0 +1,29 @@
+use glam::Vec3;
+use shared_structs::{ClampMode, TracingConfig};
+
+// A saturated orange firefly, over the limit in red and green but not blue
+#[test]
+fn clamp_modes_treat_colored_fireflies_differently() {
+    let luminance = |color: Vec3| color.dot(Vec3::new(0.2126, 0.7152, 0.0722));
+    let firefly = Vec3::new(8.0, 2.0, 0.5);
+    let scaled = ClampMode::Luminance.apply(firefly, 1.0);
+    assert!((luminance(scaled) - 1.0).abs() < 1e-5, "{}", scaled);
+    assert!((scaled / scaled.x).abs_diff_eq(firefly / firefly.x, 1e-6), "the hue is kept");
+
+    let clamped = ClampMode::Channel.apply(firefly, 1.0);
+    assert_eq!(clamped, Vec3::new(1.0, 1.0, 0.5));
+    assert!(clamped.min_element() / clamped.max_element() > firefly.min_element() / firefly.max_element(), "clamping each channel makes it whiter");
+
+    // Deep blue looks dim, so it stays under a luminance limit, while its channel is over a per channel limit
+    let blue = Vec3::new(0.0, 0.0, 8.0);
+    assert!(luminance(blue) < 1.0);
+    assert_eq!(ClampMode::Luminance.apply(blue, 1.0), blue);
+    assert_eq!(ClampMode::Channel.apply(blue, 1.0), Vec3::new(0.0, 0.0, 1.0));
+
+    // Both leave samples under the limit alone
+    let dim = Vec3::new(0.9, 0.2, 0.1);
+    assert_eq!(ClampMode::Luminance.apply(dim, 1.0), dim);
+    assert_eq!(ClampMode::Channel.apply(dim, 1.0), dim);
+    assert_eq!(ClampMode::from_name("channel"), Some(ClampMode::Channel));
+    assert_eq!(ClampMode::from_u32(TracingConfig::default().clamp_mode), ClampMode::Luminance);
+}
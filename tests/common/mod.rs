@@ -0,0 +1,68 @@
+// Scenes and rendering shared by the tests that trace on the CPU, without a `TracingState`. Not every test uses all of
+// them.
+#![allow(dead_code)]
+
+use glam::{UVec2, UVec3, Vec3, Vec4};
+use kernels::stats::{BounceStats, NoBounceStats};
+use rustic::asset::{dynamic_image_to_cpu_buffer, fallback_cpu_buffer, World};
+use rustic::scenes::{diffuse, SceneBuilder};
+use shared_structs::{CpuImage, PackedPrimitive, TracingConfig};
+
+// Average of `samples` samples per pixel on the CPU, each pixel with its own seed
+pub fn render_cpu(world: &World, config: &TracingConfig, samples: u32) -> Vec<Vec4> {
+    render_cpu_with(world, &world.index_buffer, config, samples, &mut NoBounceStats)
+}
+
+// Same as `render_cpu`, but reading primitives from the given index buffer, and recording every path into `stats`
+pub fn render_cpu_with<P: PackedPrimitive, S: BounceStats>(
+    world: &World,
+    index_buffer: &[P],
+    config: &TracingConfig,
+    samples: u32,
+    stats: &mut S,
+) -> Vec<Vec4> {
+    let atlas_buffer = dynamic_image_to_cpu_buffer(world.atlas.clone());
+    let atlas = CpuImage::new(&atlas_buffer, world.atlas.width(), world.atlas.height());
+    let skybox_buffer = fallback_cpu_buffer();
+    let skybox = CpuImage::new(&skybox_buffer, 2, 2);
+    let mut image = Vec::new();
+    for y in 0..config.height {
+        for x in 0..config.width {
+            let (radiance, ..) = kernels::trace_pixel_averaged(
+                samples,
+                UVec3::new(x, y, 1),
+                config,
+                UVec2::new(0, (y * config.width + x).wrapping_mul(0x9E3779B9)),
+                &world.per_vertex_buffer,
+                index_buffer,
+                &world.bvh.nodes,
+                &world.material_data_buffer,
+                &world.light_pick_buffer,
+                &world.light_tree_buffer,
+                &shared_structs::Sampler,
+                &atlas,
+                &skybox,
+                stats,
+            );
+            image.push(radiance);
+        }
+    }
+    image
+}
+
+// A 2x2 floor centered on the origin, lit by an emissive sphere above it. The floor has no specular, so without
+// Fresnel it looks the same from every direction.
+pub fn lit_floor(light_center: Vec3, light_radius: f32) -> World {
+    let mut builder = SceneBuilder::default();
+    let mut floor = diffuse(Vec3::splat(0.8));
+    floor.specular = 0.0;
+    let floor = builder.push_material(floor);
+    let corners = [Vec3::new(-1.0, 0.0, -1.0), Vec3::new(1.0, 0.0, -1.0), Vec3::new(1.0, 0.0, 1.0), Vec3::new(-1.0, 0.0, 1.0)];
+    builder.push_quad(corners, Vec3::Y, floor);
+
+    let mut emitter = diffuse(Vec3::ZERO);
+    emitter.emissive = Vec4::new(1.0, 1.0, 1.0, 10.0);
+    let emitter = builder.push_material(emitter);
+    builder.push_sphere(light_center, light_radius, emitter);
+    builder.build()
+}
@@ -1,13 +1,14 @@
 use std::sync::Arc;
 
+use rustic::asset::SceneFile;
 use rustic::trace::*;
-use shared_structs::NextEventEstimation;
+use shared_structs::{NextEventEstimation, WorkingSpace};
 
 fn trace(use_cpu: bool, scene: &str, skybox: Option<&str>, state: &Arc<TracingState>) {
     if use_cpu {
-        trace_cpu(scene, skybox, state.clone());
+        trace_cpu(&[SceneFile::new(scene)], skybox, Default::default(), state.clone());
     } else {
-        trace_gpu(scene, skybox, state.clone());
+        trace_gpu(&[SceneFile::new(scene)], skybox, Default::default(), state.clone());
     }
 }
 
@@ -22,7 +23,7 @@ fn furnace_test(use_cpu: bool, use_mis: bool) {
         state.config.write().nee = NextEventEstimation::MultipleImportanceSampling.to_u32();
     }
     trace(use_cpu, "scenes/FurnaceTest.glb", None, &state);
-    let frame = state.framebuffer.read();
+    let frame = state.read_framebuffer();
 
     let pixel_r = frame[(size * 3) * coord.1 + coord.0 * 3 + 0].powf(1.0 / 2.2);
     let pixel_g = frame[(size * 3) * coord.1 + coord.0 * 3 + 1].powf(1.0 / 2.2);
@@ -50,4 +51,1091 @@ fn furnace_test_cpu_mis() {
 #[test]
 fn furnace_test_gpu_mis() {
     furnace_test(false, true);
-}
\ No newline at end of file
+}
+fn background_test(use_cpu: bool) {
+    let size = 32;
+    let background = glam::Vec3::new(0.2, 0.5, 0.9);
+
+    // Look away from the scene so every primary ray escapes
+    let state = setup_trace(size as u32, size as u32, 4);
+    {
+        let mut config = state.config.write();
+        config.cam_position = glam::Vec4::new(0.0, 0.0, -1000.0, 0.0);
+        config.cam_rotation = glam::Vec4::new(0.0, std::f32::consts::PI, 0.0, 0.0);
+        config.background_color = background.extend(1.0);
+    }
+    trace(use_cpu, "scenes/FurnaceTest.glb", None, &state);
+    let frame = state.read_framebuffer();
+
+    for pixel in frame.chunks(3) {
+        assert!((pixel[0] - background.x).abs() < 1e-5);
+        assert!((pixel[1] - background.y).abs() < 1e-5);
+        assert!((pixel[2] - background.z).abs() < 1e-5);
+    }
+}
+
+#[test]
+fn background_test_cpu() {
+    background_test(true);
+}
+
+#[test]
+fn background_test_gpu() {
+    background_test(false);
+}
+
+// Ambient light is added on top of the regular lighting, and is off by default
+fn ambient_test(use_cpu: bool) {
+    let size = 32;
+    let mean_brightness = |ambient: glam::Vec3| {
+        let state = setup_trace(size as u32, size as u32, 8);
+        state.config.write().ambient = ambient.extend(0.0);
+        trace(use_cpu, "scenes/FurnaceTest.glb", None, &state);
+        let frame = state.read_framebuffer();
+        frame.iter().sum::<f32>() / frame.len() as f32
+    };
+
+    assert_eq!(shared_structs::TracingConfig::default().ambient, glam::Vec4::ZERO);
+    let unlit = mean_brightness(glam::Vec3::ZERO);
+    let lit = mean_brightness(glam::Vec3::ONE);
+    assert!(lit > unlit + 0.1, "ambient light should brighten the image, {} vs {}", lit, unlit);
+}
+
+#[test]
+fn ambient_test_cpu() {
+    ambient_test(true);
+}
+
+#[test]
+fn ambient_test_gpu() {
+    ambient_test(false);
+}
+
+// Half a turn should put the other half of the skybox behind the camera
+fn skybox_rotation_test(use_cpu: bool) {
+    let size = 16;
+    let path = std::env::temp_dir().join(format!("rustic_skybox_rotation_test_{}.png", use_cpu));
+    image::RgbImage::from_fn(64, 32, |x, _| if x < 32 { image::Rgb([255, 0, 0]) } else { image::Rgb([0, 0, 255]) })
+        .save(&path)
+        .unwrap();
+
+    let mut centers = Vec::new();
+    for rotation in [0.0, std::f32::consts::PI] {
+        let state = setup_trace(size, size, 4);
+        {
+            let mut config = state.config.write();
+            config.cam_position = glam::Vec4::new(0.0, 0.0, -1000.0, 0.0);
+            config.cam_rotation = glam::Vec4::new(0.0, std::f32::consts::PI, 0.0, 0.0);
+            config.has_skybox = 1;
+            // The sun direction turns the skybox too, so point it along x where that rotation is 0
+            config.sun_direction = glam::Vec4::new(1.0, 0.0, 0.0, 15.0);
+            config.skybox_rotation = rotation;
+        }
+        trace(use_cpu, "scenes/FurnaceTest.glb", path.to_str(), &state);
+        let frame = state.read_framebuffer();
+        let i = (size as usize / 2 * size as usize + size as usize / 2) * 3;
+        centers.push(glam::Vec3::new(frame[i], frame[i + 1], frame[i + 2]));
+    }
+    assert!(centers[0].x > centers[0].z * 10.0, "unrotated center is {}", centers[0]);
+    assert!(centers[1].z > centers[1].x * 10.0, "rotated center is {}", centers[1]);
+}
+
+#[test]
+fn skybox_rotation_test_cpu() {
+    skybox_rotation_test(true);
+}
+
+#[test]
+fn skybox_rotation_test_gpu() {
+    skybox_rotation_test(false);
+}
+
+// Without geometry every ray escapes, so each pixel should be the skybox texel its ray points at. The skybox is made of
+// vertical bands of flat color, and pixels whose rays land near the edge of a band are skipped, since antialiasing
+// jitter can put their samples in either band.
+fn empty_scene_test(use_cpu: bool) {
+    let size = 32;
+    let bands = 8;
+    let band_color = |band: u32| glam::Vec3::new((band & 1) as f32, (band >> 1 & 1) as f32, (band >> 2 & 1) as f32);
+    let path = std::env::temp_dir().join(format!("rustic_empty_scene_test_{}.png", use_cpu));
+    image::RgbImage::from_fn(64, 32, |x, _| {
+        let color = band_color(x * bands / 64) * 255.0;
+        image::Rgb([color.x as u8, color.y as u8, color.z as u8])
+    })
+    .save(&path)
+    .unwrap();
+
+    let state = setup_trace(size, size, 4);
+    {
+        let mut config = state.config.write();
+        config.cam_position = glam::Vec4::ZERO;
+        config.cam_rotation = glam::Vec4::ZERO;
+        config.has_skybox = 1;
+        // Along x, the sun direction doesn't turn the skybox, and its intensity of 15 leaves the texels as they are
+        config.sun_direction = glam::Vec4::new(1.0, 0.0, 0.0, 15.0);
+    }
+    trace(use_cpu, "empty", path.to_str(), &state);
+    let frame = state.read_framebuffer();
+    let config = *state.config.read();
+
+    let mut checked = 0;
+    for y in 0..size {
+        for x in 0..size {
+            let direction = rustic::reproject::primary_ray_direction(&config, glam::Vec2::new(x as f32 + 0.5, y as f32 + 0.5));
+            let u = 0.5 + direction.z.atan2(direction.x) / (2.0 * std::f32::consts::PI);
+            let position_in_band = u * bands as f32;
+            if (position_in_band - position_in_band.round()).abs() < 0.15 {
+                continue;
+            }
+            let expected = band_color(position_in_band as u32 % bands);
+            let i = (y as usize * size as usize + x as usize) * 3;
+            let pixel = glam::Vec3::new(frame[i], frame[i + 1], frame[i + 2]);
+            assert!((pixel - expected).abs().max_element() < 1e-3, "pixel ({}, {}) is {} rather than {}", x, y, pixel, expected);
+            checked += 1;
+        }
+    }
+    assert!(checked > size * size / 2, "only {} pixels were far enough from a band edge", checked);
+}
+
+#[test]
+fn empty_scene_test_cpu() {
+    empty_scene_test(true);
+}
+
+#[test]
+fn empty_scene_test_gpu() {
+    empty_scene_test(false);
+}
+
+fn accumulation_test(use_cpu: bool) {
+    let size = 16;
+    let background = glam::Vec3::new(0.2, 0.5, 0.9);
+
+    // Every primary ray escapes, so every sample has exactly the background radiance
+    for samples in [1, 2] {
+        let state = setup_trace(size, size, samples);
+        {
+            let mut config = state.config.write();
+            config.cam_position = glam::Vec4::new(0.0, 0.0, -1000.0, 0.0);
+            config.cam_rotation = glam::Vec4::new(0.0, std::f32::consts::PI, 0.0, 0.0);
+            config.background_color = background.extend(1.0);
+        }
+        trace(use_cpu, "scenes/FurnaceTest.glb", None, &state);
+
+        // Samples must add up rather than overwrite each other, so the sum is N times the radiance before the divide
+        let sample_count = state.samples.load(std::sync::atomic::Ordering::Relaxed);
+        assert!(sample_count >= samples);
+        let accumulated = restore_accumulation(&state.read_framebuffer(), &state.alpha.read(), sample_count);
+        for pixel in accumulated {
+            assert!((pixel.truncate() - background * sample_count as f32).abs().max_element() < 1e-4);
+        }
+    }
+}
+
+#[test]
+fn accumulation_test_cpu() {
+    accumulation_test(true);
+}
+
+#[test]
+fn accumulation_test_gpu() {
+    accumulation_test(false);
+}
+
+fn timings_test(use_cpu: bool) {
+    let state = setup_trace(16, 16, 4);
+    trace(use_cpu, "scenes/FurnaceTest.glb", None, &state);
+
+    // Every stage of a run that applies is timed, on the GPU that includes moving data to and from it
+    let timings = *state.timings.read();
+    assert!(timings.load > std::time::Duration::ZERO);
+    assert!(timings.build > std::time::Duration::ZERO);
+    assert!(timings.render > std::time::Duration::ZERO);
+    assert_eq!(timings.encode, std::time::Duration::ZERO);
+    if use_cpu {
+        assert_eq!(timings.upload, std::time::Duration::ZERO);
+        assert_eq!(timings.readback, std::time::Duration::ZERO);
+    } else {
+        assert!(timings.upload > std::time::Duration::ZERO);
+        assert!(timings.readback > std::time::Duration::ZERO);
+    }
+
+    let printed = timings.to_string();
+    for stage in ["load", "build", "upload", "render", "readback", "denoise", "encode"] {
+        assert!(printed.contains(stage), "{} is missing from\n{}", stage, printed);
+    }
+}
+
+#[test]
+fn timings_test_cpu() {
+    timings_test(true);
+}
+
+#[test]
+fn timings_test_gpu() {
+    timings_test(false);
+}
+
+fn reset_test(use_cpu: bool) {
+    use std::sync::atomic::Ordering;
+    let size = 32;
+
+    // A noisy scene, so an image with fewer samples is visibly different. A furnace looks the same at any sample count.
+    let state = setup_trace(size, size, 8);
+    state.aovs_enabled.store(true, Ordering::Relaxed);
+    state.sync_rate.store(1, Ordering::Relaxed); // Publish every sample, so the second render stops short of the first
+    trace(use_cpu, "scenes/SphereLight.glb", None, &state);
+    let first = state.read_framebuffer().clone();
+    assert!(first.iter().any(|value| *value > 0.0));
+
+    state.reset();
+    assert_eq!(state.samples.load(Ordering::Relaxed), 0);
+    assert_eq!(state.noise_estimate.load(Ordering::Relaxed), 0);
+    assert!(state.framebuffer.read().iter().all(|value| *value == 0.0));
+    assert!(state.alpha.read().iter().all(|value| *value == 0.0));
+    assert!(state.diffuse.read().is_empty());
+    assert!(state.specular.read().is_empty());
+
+    // Without the reset, the trace would resume from the 8 samples above and stop right away, leaving the image as it was
+    restart_trace(&state, 1);
+    trace(use_cpu, "scenes/SphereLight.glb", None, &state);
+    assert!((1..8).contains(&state.samples.load(Ordering::Relaxed)));
+    assert_ne!(*state.read_framebuffer(), first);
+}
+
+#[test]
+fn reset_test_cpu() {
+    reset_test(true);
+}
+
+#[test]
+fn reset_test_gpu() {
+    reset_test(false);
+}
+
+#[test]
+fn fresh_accumulation_is_zeroed() {
+    let framebuffer = vec![f32::NAN; 4 * 3];
+    assert!(restore_accumulation(&framebuffer, &[f32::NAN; 4], 0).iter().all(|pixel| *pixel == glam::Vec4::ZERO));
+    assert!(restore_accumulation(&[0.5; 3], &[1.0], 2)[0] == glam::Vec4::new(1.0, 1.0, 1.0, 2.0));
+}
+
+fn transparent_background_test(use_cpu: bool) {
+    let size = 32;
+
+    // A lone diffuse sphere in the middle of the view, only lit by the background
+    let state = setup_trace(size as u32, size as u32, 16);
+    {
+        let mut config = state.config.write();
+        config.cam_position = glam::Vec4::new(0.0, 0.0, -5.0, 0.0);
+        config.background_color = glam::Vec4::new(0.2, 0.5, 0.9, 1.0);
+        config.transparent_background = 1;
+    }
+    trace(use_cpu, "scenes/LoneSphere.glb", None, &state);
+    let frame = state.read_framebuffer().clone();
+    let alpha = state.alpha.read().clone();
+
+    let center = size / 2 * size + size / 2;
+    assert_eq!(alpha[center], 1.0);
+    assert_eq!(alpha[0], 0.0);
+    assert_eq!(&frame[0..3], &[0.0; 3]);
+
+    // Export and read back the image, the surround must be fully transparent
+    let mut rgba = frame.chunks(3).flat_map(|c| [c[0], c[1], c[2], 1.0].map(|v| (v.clamp(0.0, 1.0) * 255.0) as u8)).collect::<Vec<_>>();
+    rustic::encode::apply_coverage_alpha(&mut rgba, &alpha, rustic::encode::AlphaMode::Premultiplied);
+    let png = rustic::encode::encode_png(size as u32, size as u32, &rgba);
+    let decoded = image::load_from_memory(&png).unwrap().into_rgba8();
+    assert_eq!(decoded.get_pixel(0, 0).0, [0, 0, 0, 0]);
+    assert_eq!(decoded.get_pixel(size as u32 - 1, size as u32 - 1).0[3], 0);
+    assert_eq!(decoded.get_pixel(size as u32 / 2, size as u32 / 2).0[3], 255);
+
+    // Antialiased edge pixels are partially covered. Straight color times alpha gives back the premultiplied color.
+    let edge = alpha.iter().position(|&a| a > 0.1 && a < 0.9).expect("No partially covered pixels");
+    let mut straight = frame.clone();
+    rustic::encode::convert_alpha(&mut straight, &alpha, rustic::encode::AlphaMode::Straight);
+    for channel in 0..3 {
+        let premultiplied = frame[edge * 3 + channel];
+        assert!(premultiplied > 0.0);
+        assert!((straight[edge * 3 + channel] * alpha[edge] - premultiplied).abs() < 1e-5);
+        assert!(straight[edge * 3 + channel] > premultiplied);
+    }
+}
+
+#[test]
+fn transparent_background_test_cpu() {
+    transparent_background_test(true);
+}
+
+#[test]
+fn transparent_background_test_gpu() {
+    transparent_background_test(false);
+}
+
+fn shadow_catcher_test(use_cpu: bool) {
+    let size = 64;
+    let background = glam::Vec4::new(0.2, 0.5, 0.9, 1.0);
+
+    // Look straight down at the sphere, so the floor fills the view around it
+    let render_scene = |scene: &str, background: glam::Vec4, transparent: bool| {
+        let state = setup_trace(size as u32, size as u32, 64);
+        {
+            let mut config = state.config.write();
+            config.cam_position = glam::Vec4::new(0.0, 4.0, 0.0, 0.0);
+            config.cam_rotation = glam::Vec4::new(std::f32::consts::FRAC_PI_2, 0.0, 0.0, 0.0);
+            config.background_color = background;
+            config.transparent_background = transparent as u32;
+        }
+        trace(use_cpu, scene, None, &state);
+        let frame = state.read_framebuffer().clone();
+        let alpha = state.alpha.read().clone();
+        (frame, alpha)
+    };
+    let render = |transparent: bool| render_scene("shadow-catcher", background, transparent);
+    // Pixels at a distance from the center of the image, the sphere covers about 8 pixels
+    let ring = |min: f32, max: f32| {
+        (0..size * size).filter(move |i| {
+            let offset = glam::Vec2::new((i % size) as f32 + 0.5, (i / size) as f32 + 0.5) - glam::Vec2::splat(size as f32 / 2.0);
+            offset.length() >= min && offset.length() < max
+        })
+    };
+    let average = |values: &[f32], pixels: Vec<usize>| pixels.iter().map(|&i| values[i]).sum::<f32>() / pixels.len() as f32;
+
+    // Over a transparent background, the floor is black, with alpha only where the sphere shadows it
+    let (frame, alpha) = render(true);
+    assert_eq!(alpha[size / 2 * size + size / 2], 1.0);
+    let contact_alpha = average(&alpha, ring(9.0, 11.0).collect());
+    let far_alpha = average(&alpha, ring(28.0, 64.0).collect());
+    assert!(contact_alpha > 0.1, "contact shadow alpha is {}", contact_alpha);
+    assert!(far_alpha < 0.03, "far floor alpha is {}", far_alpha);
+    for i in ring(9.0, 64.0) {
+        assert_eq!(&frame[i * 3..i * 3 + 3], &[0.0; 3]);
+    }
+
+    // Over an opaque background, the floor shows the background, darkened in the shadow
+    let (frame, alpha) = render(false);
+    let blue = frame.iter().skip(2).step_by(3).copied().collect::<Vec<_>>();
+    assert!(alpha.iter().all(|&a| a == 1.0));
+    let far_blue = average(&blue, ring(28.0, 64.0).collect());
+    assert!((far_blue - background.z).abs() < 0.03, "far floor is {}", far_blue);
+    assert!(average(&blue, ring(9.0, 11.0).collect()) < far_blue * 0.9);
+
+    // Under an area light and a black sky, the shadow falls on the side of the sphere away from the light. Occlusion
+    // of the sky alone would darken both sides alike.
+    let (_, alpha) = render_scene("shadow-catcher-lit", glam::Vec4::new(0.0, 0.0, 0.0, 1.0), true);
+    let (left, right): (Vec<_>, Vec<_>) = ring(9.0, 20.0).partition(|i| i % size < size / 2);
+    let (left_alpha, right_alpha) = (average(&alpha, left), average(&alpha, right));
+    let (lit_alpha, shadowed_alpha) = (left_alpha.min(right_alpha), left_alpha.max(right_alpha));
+    assert!(shadowed_alpha > 0.15, "shadow alpha is {}", shadowed_alpha);
+    assert!(lit_alpha < 0.05, "lit floor alpha is {}", lit_alpha);
+}
+
+#[test]
+fn shadow_catcher_test_cpu() {
+    shadow_catcher_test(true);
+}
+
+#[test]
+fn shadow_catcher_test_gpu() {
+    shadow_catcher_test(false);
+}
+
+fn spatial_split_test(use_cpu: bool) {
+    let size = 64;
+
+    // Spatial splits only change how the scene is traversed, so the render should stay the same. The Cornell box has
+    // large walls for them to split, and an area light which has to be left whole for light sampling.
+    let render = |spatial_splits: bool| {
+        let state = setup_trace(size as u32, size as u32, 16);
+        state.spatial_splits.store(spatial_splits, std::sync::atomic::Ordering::Relaxed);
+        {
+            let mut config = state.config.write();
+            config.cam_position = rustic::scenes::cornell::CAMERA_POSITION;
+            config.cam_rotation = rustic::scenes::cornell::CAMERA_ROTATION;
+            config.nee = NextEventEstimation::MultipleImportanceSampling.to_u32();
+        }
+        trace(use_cpu, rustic::scenes::cornell::SCENE_NAME, None, &state);
+        let frame = state.read_framebuffer().clone();
+        frame
+    };
+    let plain = render(false);
+    let split = render(true);
+    let difference = plain.iter().zip(split.iter()).map(|(a, b)| (a - b).abs()).sum::<f32>() / plain.len() as f32;
+    let brightness = plain.iter().sum::<f32>() / plain.len() as f32;
+    assert!(brightness > 0.0);
+    assert!(difference < brightness * 0.01, "average difference is {}", difference);
+}
+
+#[test]
+fn spatial_split_test_cpu() {
+    spatial_split_test(true);
+}
+
+#[test]
+fn spatial_split_test_gpu() {
+    spatial_split_test(false);
+}
+
+fn time_budget_test(use_cpu: bool) {
+    let state = setup_trace(32, 32, u32::MAX);
+    state.time_budget.store(1, std::sync::atomic::Ordering::Relaxed);
+
+    let start = std::time::Instant::now();
+    trace(use_cpu, "scenes/FurnaceTest.glb", None, &state);
+    let elapsed = start.elapsed().as_secs_f32();
+
+    assert!(!state.running.load(std::sync::atomic::Ordering::Relaxed));
+    assert!(state.samples.load(std::sync::atomic::Ordering::Relaxed) > 0);
+    // Scene loading isn't part of the budget, so allow some slack
+    assert!(elapsed < 10.0);
+}
+
+#[test]
+fn time_budget_test_cpu() {
+    time_budget_test(true);
+}
+
+#[test]
+fn time_budget_test_gpu() {
+    time_budget_test(false);
+}
+
+fn watertight_test(use_cpu: bool) {
+    let size = 256;
+
+    // Look along a finely tessellated plane at a grazing angle, against a white background. With a single bounce
+    // every ray that hits the plane contributes nothing, so any light below the horizon leaked through a seam.
+    let state = setup_trace(size as u32, size as u32, 16);
+    {
+        let mut config = state.config.write();
+        config.cam_position = glam::Vec4::new(0.0, 0.1, -49.0, 0.0);
+        config.cam_rotation = glam::Vec4::ZERO;
+        config.background_color = glam::Vec4::ONE;
+        config.min_bounces = 0;
+        config.max_bounces = 1;
+    }
+    trace(use_cpu, "scenes/TessellatedPlane.glb", None, &state);
+    let frame = state.read_framebuffer();
+
+    // Rows this far below the horizon hit the plane well within its bounds
+    for y in size / 2 + 2..size {
+        for x in 0..size {
+            let pixel = &frame[(y * size + x) * 3..(y * size + x) * 3 + 3];
+            assert_eq!(pixel, &[0.0, 0.0, 0.0], "Light leaked through at ({}, {})", x, y);
+        }
+    }
+}
+
+#[test]
+fn watertight_test_cpu() {
+    watertight_test(true);
+}
+
+#[test]
+fn watertight_test_gpu() {
+    watertight_test(false);
+}
+
+fn render_direct_lighting(use_cpu: bool, scene: &str, light_samples: u32) -> Vec<f32> {
+    let size = 64;
+    let state = setup_trace(size, size, 4);
+    state.use_blue_noise.store(false, std::sync::atomic::Ordering::Relaxed); // Independent noise between renders
+    {
+        let mut config = state.config.write();
+        config.nee = NextEventEstimation::DirectLightSampling.to_u32();
+        config.background_color = glam::Vec4::new(0.0, 0.0, 0.0, 1.0);
+        config.min_bounces = 0;
+        config.max_bounces = 1;
+        config.light_samples = light_samples;
+    }
+    trace(use_cpu, scene, None, &state);
+    let mut frame = vec![0.0; (size * size * 3) as usize];
+    state.copy_framebuffer_into(&mut frame);
+    frame
+}
+
+// Mean and relative squared difference between 2 renders of the floor below the sphere light
+fn sphere_light_noise(use_cpu: bool, scene: &str, light_samples: u32) -> (f32, f32) {
+    let size = 64;
+    let a = render_direct_lighting(use_cpu, scene, light_samples);
+    let b = render_direct_lighting(use_cpu, scene, light_samples);
+    let floor = (size * 3 / 4 * size * 3)..(size * size * 3);
+    let mean = a[floor.clone()].iter().sum::<f32>() / floor.len() as f32;
+    let noise = a[floor.clone()].iter().zip(b[floor.clone()].iter()).map(|(a, b)| (a - b) * (a - b)).sum::<f32>() / floor.len() as f32;
+    (mean, noise / (mean * mean))
+}
+
+fn sphere_light_test(use_cpu: bool) {
+    // The same sphere light, once as a tessellated mesh and once as an analytic sphere
+    let (analytic_mean, analytic_noise) = sphere_light_noise(use_cpu, "scenes/SphereLight.glb", 1);
+    let (tessellated_mean, tessellated_noise) = sphere_light_noise(use_cpu, "scenes/TessellatedSphereLight.glb", 1);
+    assert!(analytic_mean > 0.0);
+    assert!((analytic_mean - tessellated_mean).abs() < analytic_mean * 0.1, "analytic {}, tessellated {}", analytic_mean, tessellated_mean);
+    assert!(analytic_noise * 10.0 < tessellated_noise, "analytic {}, tessellated {}", analytic_noise, tessellated_noise);
+}
+
+#[test]
+fn sphere_light_test_cpu() {
+    sphere_light_test(true);
+}
+
+#[test]
+fn sphere_light_test_gpu() {
+    sphere_light_test(false);
+}
+
+fn light_samples_test(use_cpu: bool) {
+    // The tessellated sphere is made of many small lights, so a single light sample per bounce is noisy. Averaging 4
+    // should keep the brightness and cut the variance to about a quarter.
+    let (single_mean, single_noise) = sphere_light_noise(use_cpu, "scenes/TessellatedSphereLight.glb", 1);
+    let (multiple_mean, multiple_noise) = sphere_light_noise(use_cpu, "scenes/TessellatedSphereLight.glb", 4);
+    assert!(single_mean > 0.0);
+    assert!((single_mean - multiple_mean).abs() < single_mean * 0.1, "1 sample {}, 4 samples {}", single_mean, multiple_mean);
+    assert!(multiple_noise * 2.0 < single_noise, "1 sample {}, 4 samples {}", single_noise, multiple_noise);
+}
+
+#[test]
+fn light_samples_test_cpu() {
+    light_samples_test(true);
+}
+
+#[test]
+fn light_samples_test_gpu() {
+    light_samples_test(false);
+}
+
+fn cornell_box_test(use_cpu: bool) {
+    let size = 64;
+    let state = setup_trace(size as u32, size as u32, 32);
+    {
+        let mut config = state.config.write();
+        config.nee = NextEventEstimation::MultipleImportanceSampling.to_u32();
+        config.cam_position = rustic::scenes::cornell::CAMERA_POSITION;
+        config.cam_rotation = rustic::scenes::cornell::CAMERA_ROTATION;
+    }
+    trace(use_cpu, rustic::scenes::cornell::SCENE_NAME, None, &state);
+    let frame = state.read_framebuffer();
+
+    // The top of the white back wall, next to the red and green walls, should pick up some of their color
+    let average = |columns: std::ops::Range<usize>| {
+        let mut sum = glam::Vec3::ZERO;
+        for y in 25..29 {
+            for x in columns.clone() {
+                let i = (y * size + x) * 3;
+                sum += glam::Vec3::new(frame[i], frame[i + 1], frame[i + 2]);
+            }
+        }
+        sum
+    };
+    let near_red = average(25..28);
+    let near_green = average(37..40);
+    assert!(near_red.min_element() > 0.0 && near_green.min_element() > 0.0);
+    assert!(near_red.x / near_red.y > near_green.x / near_green.y, "near red {}, near green {}", near_red, near_green);
+}
+
+fn render_cornell_box(use_cpu: bool, max_bounces: u32, indirect_clamp: f32) -> Vec<f32> {
+    let size = 32;
+    let state = setup_trace(size, size, 4);
+    {
+        let mut config = state.config.write();
+        config.nee = NextEventEstimation::DirectLightSampling.to_u32();
+        config.cam_position = rustic::scenes::cornell::CAMERA_POSITION;
+        config.cam_rotation = rustic::scenes::cornell::CAMERA_ROTATION;
+        config.max_bounces = max_bounces;
+        config.indirect_clamp = indirect_clamp;
+    }
+    trace(use_cpu, rustic::scenes::cornell::SCENE_NAME, None, &state);
+    let mut frame = vec![0.0; (size * size * 3) as usize];
+    state.copy_framebuffer_into(&mut frame);
+    frame
+}
+
+fn indirect_clamp_test(use_cpu: bool) {
+    // Clamping doesn't change which random numbers are drawn, so renders can be compared pixel by pixel
+    let clamp = 0.05;
+    let direct = render_cornell_box(use_cpu, 1, 0.0);
+    assert_eq!(render_cornell_box(use_cpu, 1, clamp), direct, "direct light is never clamped");
+
+    let unclamped = render_cornell_box(use_cpu, 4, 0.0);
+    let clamped = render_cornell_box(use_cpu, 4, clamp);
+    for ((clamped, unclamped), direct) in clamped.iter().zip(unclamped.iter()).zip(direct.iter()) {
+        assert!(*clamped <= unclamped + 1e-5, "clamping only removes light, {} > {}", clamped, unclamped);
+        assert!(*clamped >= direct - 1e-5, "indirect light is still added, {} < {}", clamped, direct);
+    }
+    let total = |frame: &[f32]| frame.iter().sum::<f32>();
+    assert!(total(&clamped) < total(&unclamped), "bright indirect samples are clamped");
+}
+
+#[test]
+fn indirect_clamp_test_cpu() {
+    indirect_clamp_test(true);
+}
+
+#[test]
+fn indirect_clamp_test_gpu() {
+    indirect_clamp_test(false);
+}
+
+// Render the Cornell box on the GPU, reading back every `sync_rate` samples
+fn render_cornell_box_gpu(samples: u32, sync_rate: u32, samples_per_dispatch: u32) -> Vec<f32> {
+    let size = 32;
+    let state = setup_trace(size, size, samples);
+    state.sync_rate.store(sync_rate, std::sync::atomic::Ordering::Relaxed);
+    {
+        let mut config = state.config.write();
+        config.cam_position = rustic::scenes::cornell::CAMERA_POSITION;
+        config.cam_rotation = rustic::scenes::cornell::CAMERA_ROTATION;
+        config.samples_per_dispatch = samples_per_dispatch;
+    }
+    trace(false, rustic::scenes::cornell::SCENE_NAME, None, &state);
+    assert_eq!(state.samples.load(std::sync::atomic::Ordering::Relaxed), samples);
+    let mut frame = vec![0.0; (size * size * 3) as usize];
+    state.copy_framebuffer_into(&mut frame);
+    frame
+}
+
+#[test]
+fn samples_per_dispatch_test_gpu() {
+    // Batched samples draw the same random numbers and are accumulated in the same order as separate dispatches
+    let unbatched = render_cornell_box_gpu(8, 8, 1);
+    assert_eq!(render_cornell_box_gpu(8, 8, 4), unbatched);
+    assert_eq!(render_cornell_box_gpu(8, 8, 8), unbatched);
+}
+
+#[test]
+fn dispatch_ordering_stress_test_gpu() {
+    // Every dispatch reads the random states and accumulated radiance the previous one wrote. If a dispatch could
+    // start before the previous one finished writing, long runs of back to back dispatches would pick up stale data,
+    // and differ from run to run, or from a render that reads back after every sample.
+    let samples = 256;
+    let back_to_back = render_cornell_box_gpu(samples, samples, 1);
+    for _ in 0..3 {
+        assert_eq!(render_cornell_box_gpu(samples, samples, 1), back_to_back);
+    }
+    assert_eq!(render_cornell_box_gpu(samples, 1, 1), back_to_back);
+}
+
+fn cornell_box_noise(use_cpu: bool, priority_samples: u32) -> f32 {
+    let size = 32;
+    let state = setup_trace(size, size, 4);
+    {
+        let mut config = state.config.write();
+        config.cam_position = rustic::scenes::cornell::CAMERA_POSITION;
+        config.cam_rotation = rustic::scenes::cornell::CAMERA_ROTATION;
+    }
+    state.paint_priority(glam::Vec2::splat(size as f32 / 2.0), size as f32, priority_samples);
+    trace(use_cpu, rustic::scenes::cornell::SCENE_NAME, None, &state);
+    state.read_noise_estimate()
+}
+
+fn priority_region_test(use_cpu: bool) {
+    // Painting with 1 sample leaves the image as it is, so this compares the whole image with and without priority
+    let normal = cornell_box_noise(use_cpu, 1);
+    let prioritized = cornell_box_noise(use_cpu, 8);
+    assert!(normal > 0.0);
+    assert!(prioritized < normal * 0.5, "prioritized {}, normal {}", prioritized, normal);
+}
+
+#[test]
+fn priority_region_test_cpu() {
+    priority_region_test(true);
+}
+
+#[test]
+fn priority_region_test_gpu() {
+    priority_region_test(false);
+}
+
+#[test]
+fn cornell_box_test_cpu() {
+    cornell_box_test(true);
+}
+
+#[test]
+fn cornell_box_test_gpu() {
+    cornell_box_test(false);
+}
+
+// Every built-in scene should render something, without any NaNs or infinities leaking into the image
+fn builtin_scenes_test(use_cpu: bool) {
+    let size = 32;
+    for builtin in rustic::scenes::BUILTIN_SCENES {
+        let state = setup_trace(size, size, 4);
+        {
+            let mut config = state.config.write();
+            config.nee = NextEventEstimation::MultipleImportanceSampling.to_u32();
+            config.cam_position = builtin.camera_position;
+            config.cam_rotation = builtin.camera_rotation;
+        }
+        trace(use_cpu, builtin.name, None, &state);
+        let frame = state.read_framebuffer();
+        assert!(frame.iter().all(|c| c.is_finite()), "{} has non-finite pixels", builtin.name);
+        assert!(frame.iter().sum::<f32>() > 0.0, "{} is black", builtin.name);
+    }
+}
+
+#[test]
+fn builtin_scenes_test_cpu() {
+    builtin_scenes_test(true);
+}
+
+#[test]
+fn builtin_scenes_test_gpu() {
+    builtin_scenes_test(false);
+}
+
+// The strips share a normal map tilted towards the light, so flipping the green channel should tilt it away instead,
+// putting the flipped and regular strips on either side of the flat one
+fn normal_map_test(use_cpu: bool) {
+    let size = 64;
+    let scene = rustic::scenes::find_builtin_scene("normal-map").unwrap();
+    let state = setup_trace(size, size, 64);
+    {
+        let mut config = state.config.write();
+        config.nee = NextEventEstimation::MultipleImportanceSampling.to_u32();
+        config.cam_position = scene.camera_position;
+        config.cam_rotation = scene.camera_rotation;
+        config.background_color = glam::Vec4::new(0.0, 0.0, 0.0, 1.0);
+    }
+    trace(use_cpu, scene.name, None, &state);
+    let frame = state.read_framebuffer();
+
+    // Strips are centered on columns 12, 32 and 52, and span rows 22 to 42
+    let average = |column: usize| {
+        let mut sum = 0.0;
+        for y in 28..36 {
+            for x in column - 3..column + 3 {
+                let i = (y * size as usize + x) * 3;
+                sum += frame[i] + frame[i + 1] + frame[i + 2];
+            }
+        }
+        sum
+    };
+    let (flipped, flat, regular) = (average(12), average(32), average(52));
+    assert!(flat > 0.0);
+    assert!((flipped - flat) * (regular - flat) < 0.0, "flipped {}, flat {}, regular {}", flipped, flat, regular);
+    assert!((flipped - regular).abs() > flat * 0.1, "flipped {}, flat {}, regular {}", flipped, flat, regular);
+}
+
+#[test]
+fn normal_map_test_cpu() {
+    normal_map_test(true);
+}
+
+#[test]
+fn normal_map_test_gpu() {
+    normal_map_test(false);
+}
+
+// The diffuse and specular passes split the image between them, so they must add up to it
+fn aov_test(use_cpu: bool) {
+    let size = 32;
+    let scene = rustic::scenes::find_builtin_scene("spheres").unwrap();
+    let state = setup_trace(size, size, 32);
+    state.aovs_enabled.store(true, std::sync::atomic::Ordering::Relaxed);
+    {
+        let mut config = state.config.write();
+        config.cam_position = scene.camera_position;
+        config.cam_rotation = scene.camera_rotation;
+    }
+    trace(use_cpu, scene.name, None, &state);
+    let frame = state.read_framebuffer();
+    let diffuse = state.read_aov(Aov::Diffuse);
+    let specular = state.read_aov(Aov::Specular);
+    assert_eq!(diffuse.len(), frame.len());
+    assert_eq!(specular.len(), frame.len());
+    for i in 0..frame.len() {
+        let sum = diffuse[i] + specular[i];
+        assert!((sum - frame[i]).abs() <= frame[i].abs() * 1e-5 + 1e-6, "{} + {} != {}", diffuse[i], specular[i], frame[i]);
+    }
+
+    // The metallic spheres reflect the sky, while the floor is purely diffuse
+    assert!(specular.iter().sum::<f32>() > 0.0);
+    assert!(diffuse.iter().sum::<f32>() > 0.0);
+}
+
+#[test]
+fn aov_test_cpu() {
+    aov_test(true);
+}
+
+#[test]
+fn aov_test_gpu() {
+    aov_test(false);
+}
+
+// The noise estimate should be known once rendering has started, and shrink as samples are added
+fn noise_estimate_test(use_cpu: bool) {
+    let size = 32;
+    let mut estimates = Vec::new();
+    for samples in [32, 512] {
+        let state = setup_trace(size, size, samples);
+        {
+            let mut config = state.config.write();
+            config.cam_position = rustic::scenes::cornell::CAMERA_POSITION;
+            config.cam_rotation = rustic::scenes::cornell::CAMERA_ROTATION;
+        }
+        trace(use_cpu, rustic::scenes::cornell::SCENE_NAME, None, &state);
+        estimates.push(state.read_noise_estimate());
+    }
+    assert!(estimates[0] > 0.0);
+    assert!(estimates[1] < estimates[0] * 0.5, "noise went from {} to {}", estimates[0], estimates[1]);
+}
+
+#[test]
+fn noise_estimate_test_cpu() {
+    noise_estimate_test(true);
+}
+
+#[test]
+fn noise_estimate_test_gpu() {
+    noise_estimate_test(false);
+}
+
+// Progress should be reported as batches finish, ending at the requested sample count
+fn render_progress_test(use_cpu: bool) {
+    let size = 32;
+    let samples = 64;
+    let mut renderer = rustic::render::Renderer::new(vec![SceneFile::new(rustic::scenes::cornell::SCENE_NAME)], size, size);
+    renderer.use_cpu = use_cpu;
+    {
+        let mut config = renderer.state.config.write();
+        config.cam_position = rustic::scenes::cornell::CAMERA_POSITION;
+        config.cam_rotation = rustic::scenes::cornell::CAMERA_ROTATION;
+    }
+
+    let mut reports = Vec::new();
+    let cancel = rustic::render::CancellationToken::new();
+    let image = renderer.render_with_progress(samples, &cancel, |progress| reports.push(progress)).unwrap();
+    assert_eq!(image.len(), (size * size * 3) as usize);
+    assert!(image.iter().all(|c| c.is_finite()));
+
+    assert!(!reports.is_empty());
+    assert!(reports.windows(2).all(|pair| pair[0].samples_done < pair[1].samples_done && pair[0].elapsed <= pair[1].elapsed));
+    let last = reports.last().unwrap();
+    assert_eq!((last.samples_done, last.samples_total), (samples, samples));
+    assert_eq!(last.est_remaining, std::time::Duration::ZERO);
+
+    // Failing to load is reported rather than returning a black image
+    let missing = rustic::render::Renderer::new(vec![SceneFile::new("scenes/DoesNotExist.glb")], size, size);
+    assert!(missing.render(1).is_none());
+}
+
+#[test]
+fn render_progress_test_cpu() {
+    render_progress_test(true);
+}
+
+#[test]
+fn render_progress_test_gpu() {
+    render_progress_test(false);
+}
+
+// Cancelling should stop well before the requested sample count, and still give a usable image
+fn cancel_render_test(use_cpu: bool) {
+    let size = 32;
+    let samples = 1_000_000;
+    let mut renderer = rustic::render::Renderer::new(vec![SceneFile::new(rustic::scenes::cornell::SCENE_NAME)], size, size);
+    renderer.use_cpu = use_cpu;
+    {
+        let mut config = renderer.state.config.write();
+        config.cam_position = rustic::scenes::cornell::CAMERA_POSITION;
+        config.cam_rotation = rustic::scenes::cornell::CAMERA_ROTATION;
+    }
+
+    let cancel = rustic::render::CancellationToken::new();
+    let token = cancel.clone();
+    let image = renderer.render_with_progress(samples, &cancel, |_| token.cancel()).unwrap();
+    assert!(cancel.is_cancelled());
+    let samples_done = renderer.state.samples.load(std::sync::atomic::Ordering::Relaxed);
+    assert!(samples_done > 0 && samples_done < samples);
+    assert_eq!(image.len(), (size * size * 3) as usize);
+    assert!(image.iter().all(|c| c.is_finite()));
+    assert!(image.iter().sum::<f32>() > 0.0);
+}
+
+#[test]
+fn cancel_render_test_cpu() {
+    cancel_render_test(true);
+}
+
+#[test]
+fn cancel_render_test_gpu() {
+    cancel_render_test(false);
+}
+
+// The image drawn into a texture on another device should match the returned one, encoded for display
+fn render_to_texture_test(use_cpu: bool) {
+    use pollster::FutureExt;
+
+    let size = 64; // Rows of 8-bit RGBA are then 256 bytes, which is what texture copies need them to be a multiple of
+    let mut renderer = rustic::render::Renderer::new(vec![SceneFile::new(rustic::scenes::cornell::SCENE_NAME)], size, size);
+    renderer.use_cpu = use_cpu;
+    {
+        let mut config = renderer.state.config.write();
+        config.cam_position = rustic::scenes::cornell::CAMERA_POSITION;
+        config.cam_rotation = rustic::scenes::cornell::CAMERA_ROTATION;
+    }
+
+    let instance = wgpu::Instance::new(wgpu::Backends::PRIMARY);
+    let adapter = instance.request_adapter(&wgpu::RequestAdapterOptions::default()).block_on().unwrap();
+    let (device, queue) = adapter.request_device(&wgpu::DeviceDescriptor::default(), None).block_on().unwrap();
+    let format = wgpu::TextureFormat::Rgba8UnormSrgb;
+    let extent = wgpu::Extent3d { width: size, height: size, depth_or_array_layers: 1 };
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: None,
+        size: extent,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+    });
+    renderer.render_to_texture(4, &device, &queue, &texture, format).unwrap();
+
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size: (size * size * 4) as u64,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+    encoder.copy_texture_to_buffer(
+        texture.as_image_copy(),
+        wgpu::ImageCopyBuffer {
+            buffer: &buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(size * 4),
+                rows_per_image: std::num::NonZeroU32::new(size),
+            },
+        },
+        extent,
+    );
+    queue.submit(Some(encoder.finish()));
+    let slice = buffer.slice(..);
+    slice.map_async(wgpu::MapMode::Read, |_| {});
+    device.poll(wgpu::Maintain::Wait);
+    let pixels = slice.get_mapped_range().to_vec();
+
+    // Without tonemapping, the texture holds the clamped radiance, which the sRGB format encodes
+    let framebuffer = renderer.state.read_framebuffer();
+    for (pixel, radiance) in pixels.chunks(4).zip(framebuffer.chunks(3)) {
+        for channel in 0..3 {
+            let linear = (pixel[channel] as f32 / 255.0).powf(2.2);
+            assert!((linear - radiance[channel].clamp(0.0, 1.0)).abs() < 0.02, "texture has {} where the render has {}", linear, radiance[channel]);
+        }
+    }
+    assert!(pixels.iter().any(|&value| value > 0));
+}
+
+#[test]
+fn render_to_texture_test_cpu() {
+    render_to_texture_test(true);
+}
+
+#[test]
+fn render_to_texture_test_gpu() {
+    render_to_texture_test(false);
+}
+
+// A scene without any saturated colors should look the same in every working space
+fn working_space_test(use_cpu: bool) {
+    let size = 64;
+    let mut averages = Vec::new();
+    for working_space in [WorkingSpace::LinearSrgb, WorkingSpace::AcesCg] {
+        let state = setup_trace(size, size, 32);
+        state.config.write().working_space = working_space.to_u32();
+        trace(use_cpu, "scenes/FurnaceTest.glb", None, &state);
+        let frame = state.read_framebuffer();
+        averages.push(frame.iter().sum::<f32>() / frame.len() as f32);
+    }
+    assert!(averages[0] > 0.0);
+    assert!((averages[0] - averages[1]).abs() < averages[0] * 0.02, "{} vs {}", averages[0], averages[1]);
+}
+
+#[test]
+fn working_space_test_cpu() {
+    working_space_test(true);
+}
+
+#[test]
+fn working_space_test_gpu() {
+    working_space_test(false);
+}
+
+// Every stratum should get its own seeds, so they differ, and the options borrowed for it should be put back
+fn render_strata_test(use_cpu: bool) {
+    use std::sync::atomic::Ordering;
+
+    let size = 16;
+    let mut renderer = rustic::render::Renderer::new(vec![SceneFile::new(rustic::scenes::cornell::SCENE_NAME)], size, size);
+    renderer.use_cpu = use_cpu;
+    {
+        let mut config = renderer.state.config.write();
+        config.cam_position = rustic::scenes::cornell::CAMERA_POSITION;
+        config.cam_rotation = rustic::scenes::cornell::CAMERA_ROTATION;
+    }
+    let frame = renderer.state.frame.load(Ordering::Relaxed);
+
+    let mut progress = Vec::new();
+    let bootstrap = renderer.render_strata(4, 4, |done| progress.push(done)).unwrap();
+    assert_eq!(progress, vec![1, 2, 3, 4]);
+    assert_eq!(bootstrap.strata, 4);
+    assert_eq!(bootstrap.mean.len(), (size * size * 3) as usize);
+    assert!(bootstrap.mean.iter().sum::<f32>() > 0.0);
+    assert!(bootstrap.variance.iter().any(|variance| *variance > 0.0), "the strata should differ");
+    assert!(bootstrap.confidence.iter().all(|confidence| confidence.is_finite() && *confidence >= 0.0));
+    assert!(!renderer.state.animated_noise.load(Ordering::Relaxed));
+    assert_eq!(renderer.state.frame.load(Ordering::Relaxed), frame);
+}
+
+#[test]
+fn render_strata_test_cpu() {
+    render_strata_test(true);
+}
+
+#[test]
+fn render_strata_test_gpu() {
+    render_strata_test(false);
+}
+
+fn clamp_mode_test(use_cpu: bool) {
+    use shared_structs::ClampMode;
+
+    // Same random numbers in every render, so pixels can be compared. Either mode only ever removes light.
+    let render = |clamp: f32, clamp_mode: ClampMode| {
+        let size = 32;
+        let state = setup_trace(size, size, 4);
+        {
+            let mut config = state.config.write();
+            config.nee = NextEventEstimation::DirectLightSampling.to_u32();
+            config.cam_position = rustic::scenes::cornell::CAMERA_POSITION;
+            config.cam_rotation = rustic::scenes::cornell::CAMERA_ROTATION;
+            config.indirect_clamp = clamp;
+            config.clamp_mode = clamp_mode.to_u32();
+        }
+        trace(use_cpu, rustic::scenes::cornell::SCENE_NAME, None, &state);
+        let mut frame = vec![0.0; (size * size * 3) as usize];
+        state.copy_framebuffer_into(&mut frame);
+        frame
+    };
+    let unclamped = render(0.0, ClampMode::Luminance);
+    let scaled = render(0.05, ClampMode::Luminance);
+    let clamped = render(0.05, ClampMode::Channel);
+    for ((scaled, clamped), unclamped) in scaled.iter().zip(clamped.iter()).zip(unclamped.iter()) {
+        assert!(*scaled <= unclamped + 1e-5 && *clamped <= unclamped + 1e-5, "{} and {} <= {}", scaled, clamped, unclamped);
+    }
+    let total = |frame: &[f32]| frame.iter().sum::<f32>();
+    assert!(total(&scaled) < total(&unclamped) && total(&clamped) < total(&unclamped));
+    assert!(scaled != clamped, "the red and green walls bounce colored light, which the modes clamp differently");
+}
+
+#[test]
+fn clamp_mode_test_cpu() {
+    clamp_mode_test(true);
+}
+
+#[test]
+fn clamp_mode_test_gpu() {
+    clamp_mode_test(false);
+}
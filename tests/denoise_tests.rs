@@ -0,0 +1,119 @@
+use rand::Rng;
+use rustic::denoise::*;
+use rustic::trace::TracingState;
+
+const SIZE: usize = 64;
+
+fn noisy_image(mean: f32, amplitude: f32) -> Vec<f32> {
+    let mut rng = rand::thread_rng();
+    (0..SIZE * SIZE * 3)
+        .map(|_| mean + rng.gen_range(-amplitude..amplitude))
+        .collect()
+}
+
+fn variance(image: &[f32], mean: f32) -> f32 {
+    image.iter().map(|c| (c - mean) * (c - mean)).sum::<f32>() / image.len() as f32
+}
+
+#[test]
+fn atrous_reduces_noise_on_flat_surface() {
+    let mut color = noisy_image(0.5, 0.2);
+    let albedo = vec![0.8; SIZE * SIZE * 3];
+    let normal = vec![0.0; SIZE * SIZE * 3];
+
+    let before = variance(&color, 0.5);
+    atrous_denoise(SIZE, SIZE, &mut color, &albedo, &normal);
+    let after = variance(&color, 0.5);
+    assert!(after < before * 0.1);
+}
+
+#[test]
+fn atrous_preserves_normal_edges() {
+    // Left and right halves face different directions and have different brightness
+    let mut color = vec![0.0; SIZE * SIZE * 3];
+    let mut normal = vec![0.0; SIZE * SIZE * 3];
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            let i = (y * SIZE + x) * 3;
+            let left = x < SIZE / 2;
+            color[i..i + 3].copy_from_slice(&[if left { 0.1 } else { 1.0 }; 3]);
+            normal[i..i + 3].copy_from_slice(&if left { [1.0, 0.0, 0.0] } else { [0.0, 0.0, 1.0] });
+        }
+    }
+    let albedo = vec![0.8; SIZE * SIZE * 3];
+
+    atrous_denoise(SIZE, SIZE, &mut color, &albedo, &normal);
+    let row = SIZE / 2 * SIZE;
+    assert!((color[(row + SIZE / 2 - 1) * 3] - 0.1).abs() < 0.01);
+    assert!((color[(row + SIZE / 2) * 3] - 1.0).abs() < 0.01);
+}
+
+// The GPU passes run the same per pixel filter as the CPU fallback, so they only differ by floating point error
+#[test]
+fn gpu_atrous_matches_cpu() {
+    let noisy = noisy_image(0.5, 0.2);
+    let albedo = noisy_image(0.6, 0.1);
+    let normal = (0..SIZE * SIZE).flat_map(|i| if i % SIZE < SIZE / 2 { [0.0, 0.0, 1.0] } else { [1.0, 0.0, 0.0] }).collect::<Vec<f32>>();
+
+    let mut cpu = noisy.clone();
+    atrous_denoise(SIZE, SIZE, &mut cpu, &albedo, &normal);
+    let mut gpu = noisy.clone();
+    GpuATrous::new(rustic::kernel::KERNEL, SIZE as u32, SIZE as u32).denoise(&mut gpu, &albedo, &normal);
+    for (cpu, gpu) in cpu.iter().zip(gpu.iter()) {
+        assert!((cpu - gpu).abs() < 1e-4, "CPU {} but GPU {}", cpu, gpu);
+    }
+}
+
+#[test]
+fn denoiser_names() {
+    assert_eq!(Denoiser::from_name("none"), Some(Denoiser::None));
+    assert_eq!(Denoiser::from_name("atrous"), Some(Denoiser::ATrous));
+    assert_eq!(Denoiser::from_name("oidn"), Some(Denoiser::Oidn));
+    assert_eq!(Denoiser::from_name("bm3d"), None);
+}
+
+#[test]
+fn denoiser_none_is_identity() {
+    let original = noisy_image(0.5, 0.2);
+    let mut color = original.clone();
+    let guide = vec![0.0; SIZE * SIZE * 3];
+    denoise(Denoiser::None, SIZE, SIZE, &mut color, &guide, &guide);
+    assert_eq!(color, original);
+}
+
+// A checkerboard texture under even light, seen through noisy samples. The albedo guide is as noisy as a guide
+// averaged from few samples with antialiasing would be along the texture.
+#[cfg(feature = "oidn")]
+#[test]
+fn oidn_prefiltered_guides_keep_texture_detail() {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let texture = (0..SIZE * SIZE)
+        .flat_map(|i| [if (i % SIZE / 4 + i / SIZE / 4) % 2 == 0 { 0.2 } else { 0.8 }; 3])
+        .collect::<Vec<f32>>();
+    let noisy_color = texture.iter().map(|a| a * rng.gen_range(0.2..1.8)).collect::<Vec<_>>();
+    let noisy_albedo = texture.iter().map(|a| (a + rng.gen_range(-0.15..0.15)).clamp(0.0, 1.0)).collect::<Vec<_>>();
+    let normal = (0..SIZE * SIZE).flat_map(|_| [0.0, 0.0, 1.0]).collect::<Vec<f32>>();
+    let error = |image: &[f32]| image.iter().zip(texture.iter()).map(|(c, t)| (c - t) * (c - t)).sum::<f32>() / image.len() as f32;
+
+    let mut unguided = noisy_color.clone();
+    denoise(Denoiser::Oidn, SIZE, SIZE, &mut unguided, &noisy_albedo, &normal);
+    let mut prefiltered = noisy_color.clone();
+    denoise(Denoiser::OidnPrefiltered, SIZE, SIZE, &mut prefiltered, &noisy_albedo, &normal);
+    assert!(error(&unguided) < error(&noisy_color));
+    assert!(error(&prefiltered) < error(&unguided), "prefiltered error {} but unguided {}", error(&prefiltered), error(&unguided));
+}
+
+#[test]
+fn denoise_interval_skips_frames_until_paused() {
+    let state = TracingState::new(4, 4);
+    assert!((0..4).all(|frame| state.denoise_due(frame)), "denoises every frame by default");
+
+    state.denoise_interval.store(3, std::sync::atomic::Ordering::Relaxed);
+    let due = (0..7).map(|frame| state.denoise_due(frame)).collect::<Vec<_>>();
+    assert_eq!(due, [true, false, false, true, false, false, true]);
+
+    state.paused.store(true, std::sync::atomic::Ordering::Relaxed);
+    assert!(state.denoise_due(1));
+}
@@ -0,0 +1,129 @@
+use rand::Rng;
+use rustic::encode::*;
+
+fn random_image(width: u32, height: u32) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    // Mix of smooth and noisy regions, so both filtering and compression get exercised
+    (0..width * height * 4)
+        .map(|i| if (i / 4 / width) % 2 == 0 { (i % 251) as u8 } else { rng.gen() })
+        .collect()
+}
+
+fn decode(png: &[u8]) -> image::RgbaImage {
+    image::load_from_memory_with_format(png, image::ImageFormat::Png)
+        .expect("Encoded PNG should be valid")
+        .into_rgba8()
+}
+
+#[test]
+fn png_strips_roundtrip() {
+    let (width, height) = (53, 97);
+    let rgba = random_image(width, height);
+    for rows_per_strip in [1, 7, 32, 97, 1000] {
+        let decoded = decode(&encode_png_strips(width, height, &rgba, rows_per_strip));
+        assert_eq!(decoded.dimensions(), (width, height));
+        assert_eq!(decoded.as_raw(), &rgba);
+    }
+}
+
+#[test]
+fn png_default_strips_roundtrip() {
+    let (width, height) = (640, 360);
+    let rgba = random_image(width, height);
+    let decoded = decode(&encode_png(width, height, &rgba));
+    assert_eq!(decoded.as_raw(), &rgba);
+}
+
+#[test]
+fn coverage_alpha_modes() {
+    // A half covered edge pixel of a white object, as rendered: the color is darkened by the coverage
+    let premultiplied = [100, 100, 100, 255];
+    let alpha = [0.5];
+
+    let mut straight = premultiplied;
+    apply_coverage_alpha(&mut straight, &alpha, AlphaMode::Straight);
+    assert_eq!(straight, [200, 200, 200, 128]);
+
+    let mut kept = premultiplied;
+    apply_coverage_alpha(&mut kept, &alpha, AlphaMode::Premultiplied);
+    assert_eq!(kept, [100, 100, 100, 128]);
+
+    // Uncovered pixels can't be divided, and stay empty
+    let mut empty = [0, 0, 0, 255];
+    apply_coverage_alpha(&mut empty, &[0.0], AlphaMode::Straight);
+    assert_eq!(empty, [0, 0, 0, 0]);
+}
+
+fn random_hdr_image(width: u32, height: u32) -> Vec<f32> {
+    let mut rng = rand::thread_rng();
+    // Flat rows, which become runs, mixed with noise over a wide dynamic range
+    (0..width * height * 3)
+        .map(|i| if (i / 3 / width) % 3 == 0 { 0.25 } else { rng.gen::<f32>() * 10f32.powi(rng.gen_range(-4..4)) })
+        .collect()
+}
+
+// Error relative to the brightest channel of each pixel, since that sets the shared exponent
+fn assert_within_rgbe_precision(decoded: &[f32], rgb: &[f32], tolerance: f32) {
+    for (decoded, original) in decoded.chunks(3).zip(rgb.chunks(3)) {
+        let max = original.iter().fold(0.0f32, |a, &b| a.max(b));
+        for (d, o) in decoded.iter().zip(original) {
+            assert!((d - o).abs() <= max * tolerance, "{:?} decoded as {:?}", original, decoded);
+        }
+    }
+}
+
+#[test]
+fn rgbe_pixels_roundtrip() {
+    let rgb = random_hdr_image(64, 64);
+    let decoded = rgb.chunks(3).flat_map(|c| rgbe_to_rgb(rgb_to_rgbe([c[0], c[1], c[2]]))).collect::<Vec<_>>();
+    assert_within_rgbe_precision(&decoded, &rgb, 1.0 / 128.0);
+
+    assert_eq!(rgb_to_rgbe([0.0; 3]), [0; 4]);
+    assert_eq!(rgb_to_rgbe([-1.0, f32::NAN, f32::INFINITY]), [0; 4]);
+    assert_eq!(rgbe_to_rgb([0; 4]), [0.0; 3]);
+}
+
+#[test]
+fn rgbe_file_roundtrip() {
+    // Narrow images can't be run length encoded, so both kinds of scanline are covered
+    for (width, height) in [(53, 17), (5, 9)] {
+        let rgb = random_hdr_image(width, height);
+        let hdr = encode_rgbe(width, height, &rgb);
+        let decoded = image::load_from_memory_with_format(&hdr, image::ImageFormat::Hdr)
+            .expect("Encoded HDR should be valid")
+            .into_rgb32f();
+        assert_eq!(decoded.dimensions(), (width, height));
+        // The image crate reconstructs channels at the bottom of their quantization step, so allow a step more
+        assert_within_rgbe_precision(decoded.as_raw(), &rgb, 1.0 / 64.0);
+    }
+}
+
+#[test]
+fn dithering_removes_banding() {
+    // A dark gradient spanning a few 8-bit steps, where rounding alone gives wide flat bands
+    let (width, height) = (512, DITHER_TILE_SIZE);
+    let tile = dither_tile();
+    let value = |x: u32| x as f32 / width as f32 * 4.0 / 255.0;
+    let worst_error = |quantize: &dyn Fn(f32, u32, u32) -> u8| {
+        (0..width)
+            .map(|x| {
+                let mean = (0..height).map(|y| quantize(value(x), x, y) as f32).sum::<f32>() / height as f32;
+                (mean - value(x) * 255.0).abs()
+            })
+            .fold(0.0, f32::max)
+    };
+
+    let banded = worst_error(&|value, _, _| (value * 255.0).round() as u8);
+    let dithered = worst_error(&|value, x, y| quantize_dithered(value, x, y, &tile, 0));
+    assert!(banded > 0.45, "rounding should band, error {}", banded);
+    assert!(dithered < 0.1, "dithering should follow the gradient on average, error {}", dithered);
+}
+
+#[test]
+fn dither_seed_shifts_pattern() {
+    let tile = dither_tile();
+    let pattern = |seed| (0..64).map(|x| dither_offset(&tile, x, 0, seed)).collect::<Vec<_>>();
+    assert_eq!(pattern(7), pattern(7));
+    assert_ne!(pattern(0), pattern(1));
+    assert!(pattern(3).iter().all(|offset| offset.abs() <= 0.5));
+}
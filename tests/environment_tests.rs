@@ -0,0 +1,82 @@
+use glam::{UVec2, Vec2, Vec4};
+use shared_structs::{blend_bilinear, equirect_bilinear, CpuImage};
+
+const WIDTH: u32 = 8;
+const HEIGHT: u32 = 4;
+
+// Every texel gets its own value, so blends of different texels can be told apart
+fn skybox_buffer() -> Vec<Vec4> {
+    (0..WIDTH * HEIGHT).map(|index| Vec4::new((index % WIDTH) as f32, (index / WIDTH) as f32, index as f32, 1.0)).collect()
+}
+
+fn lookup(skybox: &CpuImage, uv: Vec2) -> Vec4 {
+    let (texels, weights) = equirect_bilinear(uv, WIDTH, HEIGHT);
+    blend_bilinear(texels, weights, |texel| skybox.fetch(texel))
+}
+
+// The same lookup, written out texel by texel, as a reference
+fn reference_lookup(buffer: &[Vec4], uv: Vec2) -> Vec4 {
+    let texel = |x: i32, y: i32| {
+        let x = x.rem_euclid(WIDTH as i32) as usize;
+        let y = y.clamp(0, HEIGHT as i32 - 1) as usize;
+        buffer[y * WIDTH as usize + x]
+    };
+    let x = uv.x * WIDTH as f32 - 0.5;
+    let y = (uv.y * HEIGHT as f32 - 0.5).clamp(0.0, HEIGHT as f32 - 1.0);
+    let (x0, y0) = (x.floor() as i32, y.floor() as i32);
+    let (tx, ty) = (x - x.floor(), y - y.floor());
+    let top = texel(x0, y0) * (1.0 - tx) + texel(x0 + 1, y0) * tx;
+    let bottom = texel(x0, y0 + 1) * (1.0 - tx) + texel(x0 + 1, y0 + 1) * tx;
+    top * (1.0 - ty) + bottom * ty
+}
+
+#[test]
+fn lookup_matches_reference() {
+    let buffer = skybox_buffer();
+    let skybox = CpuImage::new(&buffer, WIDTH, HEIGHT);
+    for v in 0..=20 {
+        for u in 0..=40 {
+            let uv = Vec2::new(u as f32 / 40.0, v as f32 / 20.0);
+            let (lookup, reference) = (lookup(&skybox, uv), reference_lookup(&buffer, uv));
+            assert!(lookup.abs_diff_eq(reference, 1e-4), "{} at {}, expected {}", lookup, uv, reference);
+        }
+    }
+}
+
+// At u = 0, half way between the centers of the last and first columns, both count equally
+#[test]
+fn seam_blends_first_and_last_columns() {
+    let buffer = skybox_buffer();
+    let skybox = CpuImage::new(&buffer, WIDTH, HEIGHT);
+    for uv in [Vec2::new(0.0, 0.375), Vec2::new(1.0, 0.375)] {
+        let color = lookup(&skybox, uv);
+        assert!((color.x - (WIDTH - 1) as f32 * 0.5).abs() < 1e-4, "{} at {}", color, uv);
+        assert!((color.y - 1.0).abs() < 1e-4, "{} at {}", color, uv);
+    }
+}
+
+// Stepping across the seam changes the color as little as stepping anywhere else
+#[test]
+fn lookup_is_continuous_across_the_seam() {
+    let buffer = skybox_buffer();
+    let skybox = CpuImage::new(&buffer, WIDTH, HEIGHT);
+    let step = 1e-3;
+    let across = (lookup(&skybox, Vec2::new(step, 0.5)) - lookup(&skybox, Vec2::new(1.0 - step, 0.5))).abs().max_element();
+    let inside = (lookup(&skybox, Vec2::new(0.5 + step, 0.5)) - lookup(&skybox, Vec2::new(0.5 - step, 0.5))).abs().max_element();
+    assert!(across <= inside + 1e-3, "{} across the seam, {} elsewhere", across, inside);
+}
+
+// Past the centers of the top and bottom rows, the lookup stays on those rows rather than wrapping to the other pole
+#[test]
+fn poles_clamp_to_the_outer_rows() {
+    let buffer = skybox_buffer();
+    let skybox = CpuImage::new(&buffer, WIDTH, HEIGHT);
+    for u in 0..=16 {
+        let u = u as f32 / 16.0;
+        assert_eq!(lookup(&skybox, Vec2::new(u, 0.0)).y, 0.0);
+        assert_eq!(lookup(&skybox, Vec2::new(u, 1.0)).y, (HEIGHT - 1) as f32);
+    }
+    let (texels, _) = equirect_bilinear(Vec2::new(0.5, 1.0), WIDTH, HEIGHT);
+    assert_eq!((texels.z, texels.w), (HEIGHT - 1, HEIGHT - 1));
+    assert_eq!(skybox.fetch(UVec2::new(texels.x, texels.z)).y, (HEIGHT - 1) as f32);
+}
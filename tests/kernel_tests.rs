@@ -0,0 +1,74 @@
+use rustic::kernel::{EntryPoint, KERNEL, spirv_entry_point_names};
+
+#[test]
+fn entry_points_exist_in_kernel() {
+    let names = spirv_entry_point_names(KERNEL).expect("the kernel is a valid SPIR-V module");
+    for entry_point in EntryPoint::ALL {
+        assert!(names.iter().any(|name| name == entry_point.name()), "{} is missing, found {:?}", entry_point.name(), names);
+    }
+}
+
+#[test]
+fn invalid_spirv_is_rejected() {
+    assert!(spirv_entry_point_names(&[]).is_none());
+    assert!(spirv_entry_point_names(&[1, 2, 3]).is_none());
+    assert!(spirv_entry_point_names(&KERNEL[4..]).is_none());
+}
+
+#[test]
+fn kernel_is_valid() {
+    assert_eq!(rustic::kernel::validate_kernel(KERNEL), Ok(()));
+}
+
+#[test]
+fn missing_entry_points_are_listed() {
+    // Renaming an entry point in place keeps the module valid, but the host can no longer find it
+    let mut renamed = KERNEL.to_vec();
+    let name = EntryPoint::TraceCompact.name().as_bytes();
+    let position = renamed.windows(name.len() + 1).position(|window| window[..name.len()] == *name && window[name.len()] == 0).unwrap();
+    renamed[position] = b'X';
+
+    let error = rustic::kernel::validate_kernel(&renamed).unwrap_err();
+    assert!(error.contains("trace_kernel_compact"), "{}", error);
+    assert!(!error.contains("bindings"), "{}", error);
+}
+
+#[test]
+fn watcher_reloads_valid_kernels_only() {
+    let path = std::env::temp_dir().join("rustic_kernel_watcher_test.spv");
+    std::fs::write(&path, b"stale").unwrap();
+    let mut watcher = rustic::kernel::KernelWatcher::new(path.to_str().unwrap());
+    assert_eq!(watcher.poll(), None, "an unchanged kernel isn't reloaded");
+
+    std::fs::write(&path, KERNEL).unwrap();
+    assert_eq!(watcher.poll().as_deref(), Some(KERNEL));
+    assert_eq!(watcher.poll(), None, "a kernel is only reloaded once per change");
+
+    // A broken kernel is skipped, so the caller keeps the previous one
+    std::fs::write(&path, &KERNEL[..KERNEL.len() / 2]).unwrap();
+    assert_eq!(watcher.poll(), None);
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn kernels_are_loaded_from_disk_only_if_they_validate() {
+    use rustic::kernel::load_kernel;
+
+    let path = std::env::temp_dir().join("rustic_load_kernel_test.spv");
+    std::fs::write(&path, KERNEL).unwrap();
+    assert_eq!(load_kernel(path.to_str().unwrap()).as_deref(), Ok(KERNEL));
+
+    // A module without the entry points the host dispatches is rejected, naming what is missing
+    let mut renamed = KERNEL.to_vec();
+    let name = EntryPoint::Trace.name().as_bytes();
+    let position = renamed.windows(name.len() + 1).position(|window| window[..name.len()] == *name && window[name.len()] == 0).unwrap();
+    renamed[position] = b'X';
+    std::fs::write(&path, &renamed).unwrap();
+    let error = load_kernel(path.to_str().unwrap()).unwrap_err();
+    assert!(error.contains("trace_kernel"), "{}", error);
+
+    let _ = std::fs::remove_file(&path);
+    let error = load_kernel(path.to_str().unwrap()).unwrap_err();
+    assert!(error.contains("couldn't read"), "{}", error);
+}
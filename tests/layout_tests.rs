@@ -0,0 +1,160 @@
+use std::mem::size_of;
+
+use shared_structs::{ATrousConfig, BVHNode, CompactPrimitive, LightPickEntry, LightTreeNode, MaterialData, PerVertexData, TracingConfig};
+
+// Byte offset of a public field
+macro_rules! offset_of {
+    ($type:ty, $field:ident) => {{
+        let value = <$type as bytemuck::Zeroable>::zeroed();
+        std::ptr::addr_of!(value.$field) as usize - std::ptr::addr_of!(value) as usize
+    }};
+}
+
+// Byte offset of the 32 bit word changed by `set`, for private fields which are only reachable through setters
+fn offset_of_setter<T: bytemuck::Pod>(set: impl Fn(&mut T)) -> usize {
+    let mut value = T::zeroed();
+    set(&mut value);
+    let words: &[u32] = bytemuck::cast_slice(bytemuck::bytes_of(&value));
+    let changed = words.iter().enumerate().filter(|(_, word)| **word != 0).map(|(index, _)| index).collect::<Vec<_>>();
+    assert_eq!(changed.len(), 1, "setter changed {} words", changed.len());
+    changed[0] * 4
+}
+
+// These match the layouts documented in shared_structs, which kernels written against the buffers rely on
+#[test]
+fn shared_struct_sizes_match_documented_layouts() {
+    assert_eq!(size_of::<TracingConfig>(), 208);
+    assert_eq!(size_of::<ATrousConfig>(), 16);
+    assert_eq!(size_of::<MaterialData>(), 224);
+    assert_eq!(size_of::<PerVertexData>(), 80);
+    assert_eq!(size_of::<BVHNode>(), 32);
+    assert_eq!(size_of::<LightTreeNode>(), 64);
+    assert_eq!(size_of::<LightPickEntry>(), 28);
+    assert_eq!(size_of::<CompactPrimitive>(), 8);
+}
+
+// Arrays of structs containing vectors have a stride that is a multiple of 16 bytes in std430
+#[test]
+fn shared_struct_sizes_are_valid_array_strides() {
+    for size in [size_of::<MaterialData>(), size_of::<PerVertexData>(), size_of::<BVHNode>(), size_of::<LightTreeNode>()] {
+        assert_eq!(size % 16, 0);
+    }
+}
+
+#[test]
+fn atrous_config_offsets_match_std140() {
+    assert_eq!(offset_of!(ATrousConfig, width), 0);
+    assert_eq!(offset_of!(ATrousConfig, height), 4);
+    assert_eq!(offset_of!(ATrousConfig, step), 8);
+    assert_eq!(offset_of!(ATrousConfig, color_phi), 12);
+}
+
+#[test]
+fn tracing_config_offsets_match_std140() {
+    assert_eq!(offset_of!(TracingConfig, cam_position), 0);
+    assert_eq!(offset_of!(TracingConfig, cam_rotation), 16);
+    assert_eq!(offset_of!(TracingConfig, width), 32);
+    assert_eq!(offset_of!(TracingConfig, height), 36);
+    assert_eq!(offset_of!(TracingConfig, min_bounces), 40);
+    assert_eq!(offset_of!(TracingConfig, max_bounces), 44);
+    assert_eq!(offset_of!(TracingConfig, sun_direction), 48);
+    assert_eq!(offset_of!(TracingConfig, nee), 64);
+    assert_eq!(offset_of!(TracingConfig, has_skybox), 68);
+    assert_eq!(offset_of!(TracingConfig, specular_weight_clamp), 72);
+    assert_eq!(offset_of!(TracingConfig, background_color), 80);
+    assert_eq!(offset_of!(TracingConfig, ray_t_min), 96);
+    assert_eq!(offset_of!(TracingConfig, ray_t_max), 100);
+    assert_eq!(offset_of!(TracingConfig, light_sampling), 104);
+    assert_eq!(offset_of!(TracingConfig, transparent_background), 108);
+    assert_eq!(offset_of!(TracingConfig, aperture_radius), 112);
+    assert_eq!(offset_of!(TracingConfig, focus_distance), 116);
+    assert_eq!(offset_of!(TracingConfig, aperture_blades), 120);
+    assert_eq!(offset_of!(TracingConfig, aperture_rotation), 124);
+    assert_eq!(offset_of!(TracingConfig, working_space), 128);
+    assert_eq!(offset_of!(TracingConfig, skybox_rotation), 132);
+    assert_eq!(offset_of!(TracingConfig, fov), 136);
+    assert_eq!(offset_of!(TracingConfig, indirect_clamp), 140);
+    assert_eq!(offset_of!(TracingConfig, pixel_aspect), 144);
+    assert_eq!(offset_of!(TracingConfig, samples_per_dispatch), 148);
+    assert_eq!(offset_of!(TracingConfig, shadows), 152);
+    assert_eq!(offset_of!(TracingConfig, light_samples), 156);
+    assert_eq!(offset_of!(TracingConfig, ambient), 160);
+    assert_eq!(offset_of!(TracingConfig, russian_roulette), 176);
+    assert_eq!(offset_of!(TracingConfig, near_clip), 180);
+    assert_eq!(offset_of!(TracingConfig, skybox_width), 184);
+    assert_eq!(offset_of!(TracingConfig, skybox_height), 188);
+    assert_eq!(offset_of!(TracingConfig, mis_heuristic), 192);
+    assert_eq!(offset_of!(TracingConfig, ortho_height), 196);
+    assert_eq!(offset_of!(TracingConfig, clamp_mode), 200);
+}
+
+#[test]
+fn material_data_offsets_match_std430() {
+    assert_eq!(offset_of!(MaterialData, emissive), 0);
+    assert_eq!(offset_of!(MaterialData, albedo), 16);
+    assert_eq!(offset_of!(MaterialData, roughness), 32);
+    assert_eq!(offset_of!(MaterialData, metallic), 48);
+    assert_eq!(offset_of!(MaterialData, normals), 64);
+    assert_eq!(offset_of!(MaterialData, transmission), 80);
+    assert_eq!(offset_of!(MaterialData, occlusion), 96);
+    assert_eq!(offset_of_setter(|m: &mut MaterialData| m.set_has_albedo_texture(true)), 112);
+    assert_eq!(offset_of_setter(|m: &mut MaterialData| m.set_has_metallic_texture(true)), 116);
+    assert_eq!(offset_of_setter(|m: &mut MaterialData| m.set_has_roughness_texture(true)), 120);
+    assert_eq!(offset_of_setter(|m: &mut MaterialData| m.set_has_normal_texture(true)), 124);
+    assert_eq!(offset_of!(MaterialData, normal_scale), 128);
+    assert_eq!(offset_of_setter(|m: &mut MaterialData| m.set_flip_normal_green(true)), 132);
+    assert_eq!(offset_of_setter(|m: &mut MaterialData| m.set_has_occlusion_texture(true)), 136);
+    assert_eq!(offset_of_setter(|m: &mut MaterialData| m.set_shadow_catcher(true)), 140);
+    assert_eq!(offset_of!(MaterialData, max_bounces), 144);
+    assert_eq!(offset_of_setter(|m: &mut MaterialData| m.set_hidden_from_camera(true)), 148);
+    assert_eq!(offset_of_setter(|m: &mut MaterialData| m.set_has_emissive_texture(true)), 152);
+    assert_eq!(offset_of!(MaterialData, _padding), 156);
+    assert_eq!(offset_of!(MaterialData, specular), 160);
+    assert_eq!(offset_of!(MaterialData, specular_tint), 164);
+    assert_eq!(offset_of!(MaterialData, sheen), 168);
+    assert_eq!(offset_of!(MaterialData, sheen_tint), 172);
+    assert_eq!(offset_of!(MaterialData, clearcoat), 176);
+    assert_eq!(offset_of!(MaterialData, clearcoat_gloss), 180);
+    assert_eq!(offset_of!(MaterialData, subsurface), 184);
+    assert_eq!(offset_of!(MaterialData, anisotropy), 188);
+    assert_eq!(offset_of!(MaterialData, subsurface_radius), 192);
+    assert_eq!(offset_of!(MaterialData, emissive_texture), 208);
+}
+
+#[test]
+fn per_vertex_data_offsets_match_std430() {
+    assert_eq!(offset_of!(PerVertexData, vertex), 0);
+    assert_eq!(offset_of!(PerVertexData, radius), 12);
+    assert_eq!(offset_of!(PerVertexData, normal), 16);
+    assert_eq!(offset_of!(PerVertexData, tangent), 32);
+    assert_eq!(offset_of!(PerVertexData, uv0), 48);
+    assert_eq!(offset_of!(PerVertexData, uv1), 56);
+    assert_eq!(offset_of!(PerVertexData, color), 64);
+}
+
+#[test]
+fn bvh_node_offsets_match_std430() {
+    // The counts and indices are stored in the w components of the bounds
+    assert_eq!(offset_of_setter(|n: &mut BVHNode| n.set_aabb_min(&glam::Vec3::X)), 0);
+    assert_eq!(offset_of_setter(|n: &mut BVHNode| n.set_triangle_count(1)), 12);
+    assert_eq!(offset_of_setter(|n: &mut BVHNode| n.set_aabb_max(&glam::Vec3::X)), 16);
+    assert_eq!(offset_of_setter(|n: &mut BVHNode| n.set_left_node_index(1)), 28);
+}
+
+#[test]
+fn light_structs_offsets_match_std430() {
+    assert_eq!(offset_of!(LightTreeNode, aabb_min), 0);
+    assert_eq!(offset_of!(LightTreeNode, aabb_max), 16);
+    assert_eq!(offset_of!(LightTreeNode, cone_axis), 32);
+    assert_eq!(offset_of!(LightTreeNode, left_node_index), 48);
+    assert_eq!(offset_of!(LightTreeNode, leaf), 52);
+    assert_eq!(offset_of!(LightTreeNode, _padding), 56);
+
+    assert_eq!(offset_of!(LightPickEntry, triangle_index_a), 0);
+    assert_eq!(offset_of!(LightPickEntry, triangle_area_a), 4);
+    assert_eq!(offset_of!(LightPickEntry, triangle_pick_pdf_a), 8);
+    assert_eq!(offset_of!(LightPickEntry, triangle_index_b), 12);
+    assert_eq!(offset_of!(LightPickEntry, triangle_area_b), 16);
+    assert_eq!(offset_of!(LightPickEntry, triangle_pick_pdf_b), 20);
+    assert_eq!(offset_of!(LightPickEntry, ratio), 24);
+}
@@ -0,0 +1,87 @@
+use glam::{UVec4, Vec3, Vec4};
+use rustic::light_pick::{build_light_pick_table, compute_emissive_mask, pick_light};
+use shared_structs::{MaterialData, PerVertexData};
+
+fn vertex(x: f32, y: f32, z: f32) -> PerVertexData {
+    PerVertexData { vertex: Vec3::new(x, y, z), ..Default::default() }
+}
+
+fn emissive_material(strength: f32) -> MaterialData {
+    let mut material = MaterialData::default();
+    material.emissive = Vec4::new(1.0, 1.0, 1.0, strength);
+    material
+}
+
+#[test]
+fn light_pick_histogram_matches_power() {
+    // Unit-area quads side by side, with wildly different emissive strengths, plus a non-emissive one.
+    let strengths = [0.0, 1.0, 4.0, 5.0, 10.0, 100.0];
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    let mut materials = Vec::new();
+    for (i, strength) in strengths.iter().enumerate() {
+        let x = i as f32 * 2.0;
+        let base = vertices.len() as u32;
+        vertices.extend([
+            vertex(x, 0.0, 0.0),
+            vertex(x + 1.0, 0.0, 0.0),
+            vertex(x + 1.0, 1.0, 0.0),
+            vertex(x, 1.0, 0.0),
+        ]);
+        indices.push(UVec4::new(base, base + 1, base + 2, i as u32));
+        indices.push(UVec4::new(base, base + 2, base + 3, i as u32));
+        materials.push(emissive_material(*strength));
+    }
+
+    let mask = compute_emissive_mask(&indices, &materials);
+    let table = build_light_pick_table(&vertices, &indices, &mask, &materials);
+
+    let total_power: f32 = strengths.iter().sum();
+    let samples = 1_000_000;
+    let mut histogram = vec![0u32; indices.len()];
+    for _ in 0..samples {
+        histogram[pick_light(&table) as usize] += 1;
+    }
+
+    for (i, count) in histogram.iter().enumerate() {
+        let expected = strengths[indices[i].w as usize] / total_power / 2.0;
+        let observed = *count as f32 / samples as f32;
+        assert!((observed - expected).abs() < 0.005, "triangle {}: expected {}, observed {}", i, expected, observed);
+
+        // The pdf stored in the table must agree with the actual sampling distribution
+        for entry in table.iter() {
+            if entry.triangle_index_a == i as u32 {
+                assert!((entry.triangle_pick_pdf_a - expected).abs() < 1e-5);
+            }
+        }
+    }
+}
+
+#[test]
+fn light_pick_table_sentinel_without_lights() {
+    let vertices = vec![vertex(0.0, 0.0, 0.0), vertex(1.0, 0.0, 0.0), vertex(0.0, 1.0, 0.0)];
+    let indices = vec![UVec4::new(0, 1, 2, 0)];
+    let materials = vec![MaterialData::default()];
+    let mask = compute_emissive_mask(&indices, &materials);
+    let table = build_light_pick_table(&vertices, &indices, &mask, &materials);
+    assert_eq!(table.len(), 1);
+    assert!(table[0].is_sentinel());
+}
+
+#[test]
+fn lighting_warnings_follow_emitters_and_nee() {
+    use rustic::trace::lighting_warning;
+    use shared_structs::NextEventEstimation;
+
+    let cornell = rustic::asset::World::load(&[rustic::asset::SceneFile::new(rustic::scenes::cornell::SCENE_NAME)], Default::default()).unwrap();
+    assert!(cornell.has_emitters());
+    let mut unlit = rustic::scenes::SceneBuilder::default();
+    let material = unlit.push_material(rustic::scenes::diffuse(glam::Vec3::ONE));
+    unlit.push_sphere(glam::Vec3::ZERO, 1.0, material);
+    assert!(!unlit.build().has_emitters());
+
+    assert!(lighting_warning(true, NextEventEstimation::None).is_some());
+    assert!(lighting_warning(false, NextEventEstimation::MultipleImportanceSampling).is_some());
+    assert!(lighting_warning(true, NextEventEstimation::MultipleImportanceSampling).is_none());
+    assert!(lighting_warning(false, NextEventEstimation::None).is_none());
+}
@@ -0,0 +1,105 @@
+use glam::{UVec2, UVec4, Vec3, Vec4};
+use kernels::light_pick::pick_light_from_tree;
+use kernels::rng::RngState;
+use rustic::light_pick::{build_light_pick_table, compute_emissive_mask, pick_light};
+use rustic::light_tree::build_light_tree;
+use shared_structs::{MaterialData, PerVertexData};
+
+struct Scene {
+    vertices: Vec<PerVertexData>,
+    indices: Vec<UVec4>,
+    materials: Vec<MaterialData>,
+}
+
+// A ceiling covered in a 40x25 grid of small downwards facing lights
+fn many_lights_scene() -> Scene {
+    let mut light = MaterialData::default();
+    light.emissive = Vec4::ONE;
+    let mut scene = Scene {
+        vertices: Vec::new(),
+        indices: Vec::new(),
+        materials: vec![light],
+    };
+    for x in 0..40 {
+        for z in 0..25 {
+            let base = scene.vertices.len() as u32;
+            let corner = Vec3::new(x as f32, 2.0, z as f32);
+            scene.vertices.extend([corner, corner + Vec3::X * 0.1, corner + Vec3::Z * 0.1].map(|vertex| PerVertexData { vertex, ..Default::default() }));
+            scene.indices.push(UVec4::new(base, base + 1, base + 2, 0));
+        }
+    }
+    scene
+}
+
+// Unoccluded contribution of a light to a point on the floor, treating the light as a point at its centroid
+fn contribution(scene: &Scene, triangle_index: u32, point: Vec3, normal: Vec3) -> f32 {
+    let triangle = scene.indices[triangle_index as usize];
+    let a = scene.vertices[triangle.x as usize].vertex;
+    let b = scene.vertices[triangle.y as usize].vertex;
+    let c = scene.vertices[triangle.z as usize].vertex;
+    let to_light = (a + b + c) / 3.0 - point;
+    let direction = to_light.normalize();
+    let area = (b - a).cross(c - a).length() * 0.5;
+    3.0 * area * Vec3::Y.dot(direction).max(0.0) * normal.dot(direction).max(0.0) / to_light.length_squared()
+}
+
+fn mean_and_variance(estimates: &[f32]) -> (f32, f32) {
+    let mean = estimates.iter().sum::<f32>() / estimates.len() as f32;
+    let variance = estimates.iter().map(|e| (e - mean) * (e - mean)).sum::<f32>() / estimates.len() as f32;
+    (mean, variance)
+}
+
+#[test]
+fn light_tree_covers_all_lights() {
+    let scene = many_lights_scene();
+    let mask = compute_emissive_mask(&scene.indices, &scene.materials);
+    let tree = build_light_tree(&scene.vertices, &scene.indices, &mask, &scene.materials);
+
+    assert_eq!(tree.len(), scene.indices.len() * 2 - 1);
+    let mut leaves = tree.iter().filter(|node| node.is_leaf()).map(|node| node.triangle_index()).collect::<Vec<_>>();
+    leaves.sort();
+    assert_eq!(leaves, (0..scene.indices.len() as u32).collect::<Vec<_>>());
+
+    let total_power = tree.iter().filter(|node| node.is_leaf()).map(|node| node.power()).sum::<f32>();
+    assert!((tree[0].power() - total_power).abs() < 1e-4 * total_power);
+    assert!(tree[0].aabb_max.w < 1e-3); // All lights face the same way
+}
+
+#[test]
+fn light_tree_has_lower_variance_than_power_sampling() {
+    let scene = many_lights_scene();
+    let mask = compute_emissive_mask(&scene.indices, &scene.materials);
+    let table = build_light_pick_table(&scene.vertices, &scene.indices, &mask, &scene.materials);
+    let tree = build_light_tree(&scene.vertices, &scene.indices, &mask, &scene.materials);
+
+    // Shading point on the floor near a corner, where most lights contribute very little
+    let point = Vec3::new(2.0, 0.0, 2.0);
+    let normal = Vec3::Y;
+    let reference = (0..scene.indices.len() as u32).map(|i| contribution(&scene, i, point, normal)).sum::<f32>();
+
+    let mut power_pdfs = vec![0.0; scene.indices.len()];
+    for entry in table.iter() {
+        power_pdfs[entry.triangle_index_a as usize] = entry.triangle_pick_pdf_a;
+    }
+
+    let samples = 100_000;
+    let power_estimates = (0..samples)
+        .map(|_| {
+            let light = pick_light(&table);
+            contribution(&scene, light, point, normal) / power_pdfs[light as usize]
+        })
+        .collect::<Vec<_>>();
+    let tree_estimates = (0..samples)
+        .map(|i| {
+            // The traversal done in the kernel, with each sample at its own index of the low discrepancy sequence
+            let (light, _, pdf) = pick_light_from_tree(&tree, point, normal, &mut RngState::new(UVec2::new(i, 0)));
+            contribution(&scene, light, point, normal) / pdf
+        })
+        .collect::<Vec<_>>();
+
+    let (power_mean, power_variance) = mean_and_variance(&power_estimates);
+    let (tree_mean, tree_variance) = mean_and_variance(&tree_estimates);
+    assert!((power_mean - reference).abs() < reference * 0.05, "power: expected {}, got {}", reference, power_mean);
+    assert!((tree_mean - reference).abs() < reference * 0.02, "tree: expected {}, got {}", reference, tree_mean);
+    assert!(tree_variance * 5.0 < power_variance, "tree variance {}, power variance {}", tree_variance, power_variance);
+}
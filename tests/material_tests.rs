@@ -0,0 +1,124 @@
+use glam::{Vec3, Vec4};
+use shared_structs::MaterialData;
+
+#[test]
+fn valid_materials_are_unchanged() {
+    let mut material = MaterialData::default();
+    material.emissive = Vec4::new(17.0, 12.0, 4.0, 1.0);
+    material.albedo = Vec4::new(0.8, 0.5, 0.2, 1.0);
+    material.roughness = Vec4::splat(0.3);
+    material.metallic = Vec4::splat(1.0);
+    material.transmission = Vec4::splat(0.5);
+    assert!(!material.sanitize());
+    assert!(!MaterialData::default().sanitize());
+}
+
+#[test]
+fn albedo_is_clamped() {
+    let mut material = MaterialData::default();
+    material.albedo = Vec4::new(-0.5, 1.5, 0.5, 1.0);
+    assert!(material.sanitize());
+    assert_eq!(material.albedo, Vec4::new(0.0, 1.0, 0.5, 1.0));
+}
+
+#[test]
+fn roughness_and_metallic_are_clamped() {
+    let mut material = MaterialData::default();
+    material.roughness = Vec4::splat(2.0);
+    material.metallic = Vec4::splat(-1.0);
+    assert!(material.sanitize());
+    assert_eq!(material.roughness, Vec4::ONE);
+    assert_eq!(material.metallic, Vec4::ZERO);
+}
+
+#[test]
+fn transmission_is_clamped() {
+    let mut material = MaterialData::default();
+    material.transmission = Vec4::splat(1.5);
+    assert!(material.sanitize());
+    assert_eq!(material.transmission, Vec4::ONE);
+}
+
+#[test]
+fn non_finite_values_are_replaced() {
+    let mut material = MaterialData::default();
+    material.emissive = Vec4::new(f32::INFINITY, f32::NAN, -1.0, 1.0);
+    material.albedo = Vec4::new(f32::NAN, 0.5, 0.5, 1.0);
+    material.roughness = Vec4::splat(f32::NAN);
+    material.metallic = Vec4::splat(f32::NEG_INFINITY);
+    material.transmission = Vec4::splat(f32::NAN);
+    assert!(material.sanitize());
+    assert_eq!(material.emissive, Vec4::new(0.0, 0.0, 0.0, 1.0));
+    assert_eq!(material.albedo, Vec4::new(0.0, 0.5, 0.5, 1.0));
+    assert_eq!(material.roughness, Vec4::ONE);
+    assert_eq!(material.metallic, Vec4::ZERO);
+    assert_eq!(material.transmission, Vec4::ZERO);
+}
+
+#[test]
+fn textured_values_are_left_alone() {
+    // Textured values are atlas locations, which can be outside [0, 1]
+    let location = Vec4::new(0.25, 0.5, 2.0, 3.0);
+    let mut material = MaterialData::default();
+    material.albedo = location;
+    material.roughness = location;
+    material.metallic = location;
+    material.set_has_albedo_texture(true);
+    material.set_has_roughness_texture(true);
+    material.set_has_metallic_texture(true);
+    assert!(!material.sanitize());
+    assert_eq!(material.albedo, location);
+    assert_eq!(material.roughness, location);
+    assert_eq!(material.metallic, location);
+}
+
+#[test]
+fn normal_map_decoding() {
+    let texel = Vec3::new(0.75, 0.25, 1.0);
+    let mut material = MaterialData::default();
+    assert_eq!(material.decode_normal_map(texel), Vec3::new(0.5, -0.5, 1.0));
+
+    // DirectX style maps point green the other way
+    material.set_flip_normal_green(true);
+    assert_eq!(material.decode_normal_map(texel), Vec3::new(0.5, 0.5, 1.0));
+
+    // Strength only scales the tilt, 0 leaves the normal flat
+    material.normal_scale = 2.0;
+    assert_eq!(material.decode_normal_map(texel), Vec3::new(1.0, 1.0, 1.0));
+    material.normal_scale = 0.0;
+    assert_eq!(material.decode_normal_map(texel), Vec3::new(0.0, 0.0, 1.0));
+}
+
+#[test]
+fn normal_scale_is_non_negative() {
+    let mut material = MaterialData::default();
+    material.normal_scale = -1.0;
+    assert!(material.sanitize());
+    assert_eq!(material.normal_scale, 0.0);
+
+    material.normal_scale = f32::NAN;
+    assert!(material.sanitize());
+    assert_eq!(material.normal_scale, 1.0);
+}
+
+#[test]
+fn emissive_strength_scales_emission() {
+    let mut material = MaterialData::default();
+    material.emissive = Vec4::new(1.0, 0.5, 0.0, 8.0);
+    assert_eq!(material.emission(), Vec3::new(8.0, 4.0, 0.0));
+    assert_eq!(MaterialData::default().emission(), Vec3::ZERO);
+}
+
+#[test]
+fn principled_parameters_are_clamped() {
+    let mut material = MaterialData::default();
+    material.specular = 2.0;
+    material.sheen = -1.0;
+    material.clearcoat_gloss = f32::NAN;
+    material.subsurface = f32::INFINITY;
+    assert!(material.sanitize());
+    assert_eq!(material.specular, 1.0);
+    assert_eq!(material.sheen, 0.0);
+    assert_eq!(material.clearcoat_gloss, 1.0);
+    assert_eq!(material.subsurface, 0.0);
+}
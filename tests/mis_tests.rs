@@ -0,0 +1,78 @@
+use glam::{Vec3, Vec4, Vec4Swizzles};
+use rustic::asset::World;
+use shared_structs::{MisHeuristic, NextEventEstimation, TracingConfig};
+
+mod common;
+
+const HEURISTICS: [MisHeuristic; 4] = [MisHeuristic::Power, MisHeuristic::Balance, MisHeuristic::Cutoff, MisHeuristic::Maximum];
+
+// The light sample and the BSDF sample of the same light each get a weight, which need to add up to 1 for the sum of
+// both to be unbiased
+#[test]
+fn weights_of_both_strategies_sum_to_one() {
+    let pdfs = [1e-4, 0.01, 0.05, 0.099, 0.1, 0.5, 1.0, 2.0, 9.99, 10.0, 10.01, 100.0, 1e4];
+    for heuristic in HEURISTICS {
+        for &p1 in pdfs.iter() {
+            for &p2 in pdfs.iter() {
+                let (w1, w2) = (heuristic.weight(p1, p2), heuristic.weight(p2, p1));
+                assert!((0.0..=1.0).contains(&w1), "{:?} weighs {} against {} by {}", heuristic, p1, p2, w1);
+                assert!((w1 + w2 - 1.0).abs() < 1e-5, "{:?} weights of {} and {} sum to {}", heuristic, p1, p2, w1 + w2);
+            }
+        }
+    }
+}
+
+#[test]
+fn heuristic_weights_follow_their_formulas() {
+    assert!((MisHeuristic::Power.weight(1.0, 3.0) - 0.1).abs() < 1e-6);
+    assert!((MisHeuristic::Balance.weight(1.0, 3.0) - 0.25).abs() < 1e-6);
+    // Within the cutoff, like balance, past it, all or nothing
+    assert!((MisHeuristic::Cutoff.weight(1.0, 3.0) - 0.25).abs() < 1e-6);
+    assert_eq!(MisHeuristic::Cutoff.weight(1.0, 20.0), 0.0);
+    assert_eq!(MisHeuristic::Cutoff.weight(20.0, 1.0), 1.0);
+    assert_eq!(MisHeuristic::Maximum.weight(1.0, 3.0), 0.0);
+    assert_eq!(MisHeuristic::Maximum.weight(3.0, 1.0), 1.0);
+    assert_eq!(MisHeuristic::Maximum.weight(2.0, 2.0), 0.5);
+}
+
+#[test]
+fn heuristic_names_round_trip() {
+    assert_eq!(MisHeuristic::from_u32(TracingConfig::default().mis_heuristic), MisHeuristic::Power);
+    for (heuristic, name) in HEURISTICS.iter().zip(["power", "balance", "cutoff", "maximum"]) {
+        assert_eq!(MisHeuristic::from_name(name), Some(*heuristic));
+        assert_eq!(MisHeuristic::from_u32(heuristic.to_u32()), *heuristic);
+    }
+    assert_eq!(MisHeuristic::from_name("veach"), None);
+}
+
+// The floor lit by a small sphere, seen from above, where both strategies find the light
+fn lit_floor() -> World {
+    common::lit_floor(Vec3::new(0.5, 1.5, 0.0), 0.4)
+}
+
+fn average_radiance(world: &World, heuristic: MisHeuristic) -> Vec3 {
+    let config = TracingConfig {
+        width: 16,
+        height: 16,
+        cam_position: Vec4::new(0.0, 1.0, 0.0, 0.0),
+        cam_rotation: Vec4::new(std::f32::consts::FRAC_PI_2, 0.0, 0.0, 0.0),
+        nee: NextEventEstimation::MultipleImportanceSampling.to_u32(),
+        mis_heuristic: heuristic.to_u32(),
+        background_color: Vec4::new(0.0, 0.0, 0.0, 1.0),
+        ..Default::default()
+    };
+    let image = common::render_cpu(world, &config, 128);
+    image.iter().map(|pixel| pixel.xyz()).sum::<Vec3>() / image.len() as f32
+}
+
+// Every heuristic is unbiased, so they converge to the same image, only with different noise
+#[test]
+fn heuristics_agree_on_average_brightness() {
+    let world = lit_floor();
+    let reference = average_radiance(&world, MisHeuristic::Power);
+    assert!(reference.x > 0.0);
+    for heuristic in HEURISTICS {
+        let average = average_radiance(&world, heuristic);
+        assert!((average - reference).abs().max_element() < 0.05 * reference.max_element(), "{:?} averages {} but power {}", heuristic, average, reference);
+    }
+}
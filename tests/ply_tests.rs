@@ -0,0 +1,90 @@
+use glam::Vec3;
+use rustic::ply::{load_ply, parse_ply};
+
+// A unit cube with a quad per face, as binary little endian PLY with float positions and uchar colors
+fn binary_cube() -> Vec<u8> {
+    let mut bytes = b"ply\nformat binary_little_endian 1.0\ncomment a cube\n\
+element vertex 8\nproperty float x\nproperty float y\nproperty float z\n\
+property uchar red\nproperty uchar green\nproperty uchar blue\n\
+element face 6\nproperty list uchar int vertex_indices\nend_header\n".to_vec();
+    for i in 0..8 {
+        for axis in 0..3 {
+            let coordinate: f32 = if i & (1 << axis) == 0 { 0.0 } else { 1.0 };
+            bytes.extend(coordinate.to_le_bytes());
+        }
+        bytes.extend([255, 128, 0]);
+    }
+    let faces = [[0, 2, 3, 1], [4, 5, 7, 6], [0, 1, 5, 4], [2, 6, 7, 3], [0, 4, 6, 2], [1, 3, 7, 5]];
+    for face in faces {
+        bytes.push(4);
+        for index in face {
+            bytes.extend((index as i32).to_le_bytes());
+        }
+    }
+    bytes
+}
+
+#[test]
+fn binary_ply_is_triangulated() {
+    let mesh = parse_ply(&binary_cube()).unwrap();
+    assert_eq!(mesh.positions.len(), 8);
+    assert_eq!(mesh.triangles.len(), 12);
+
+    let min = mesh.positions.iter().fold(Vec3::splat(f32::INFINITY), |acc, v| acc.min(*v));
+    let max = mesh.positions.iter().fold(Vec3::splat(f32::NEG_INFINITY), |acc, v| acc.max(*v));
+    assert_eq!(min, Vec3::ZERO);
+    assert_eq!(max, Vec3::ONE);
+
+    let colors = mesh.colors.unwrap();
+    assert!(colors.iter().all(|color| color.abs_diff_eq(glam::Vec4::new(1.0, 128.0 / 255.0, 0.0, 1.0), 1e-6)));
+}
+
+#[test]
+fn missing_normals_are_computed() {
+    // The cube is wound so faces point outwards, so each corner's normal points away from the center
+    let mesh = parse_ply(&binary_cube()).unwrap();
+    for (position, normal) in mesh.positions.iter().zip(mesh.normals.iter()) {
+        let outwards = (*position - Vec3::splat(0.5)).normalize();
+        assert!(normal.dot(outwards) > 0.99, "normal {} at {} should point outwards", normal, position);
+    }
+}
+
+#[test]
+fn ascii_ply_keeps_its_normals() {
+    let ascii = "ply\nformat ascii 1.0\n\
+element vertex 4\nproperty float x\nproperty float y\nproperty float z\n\
+property float nx\nproperty float ny\nproperty float nz\n\
+element face 1\nproperty list uchar uint vertex_index\nend_header\n\
+0 0 0 0 0 2\n1 0 0 0 0 2\n1 1 0 0 0 2\n0 1 0 0 0 2\n\
+4 0 1 2 3\n";
+    let mesh = parse_ply(ascii.as_bytes()).unwrap();
+    assert_eq!(mesh.triangles.len(), 2);
+    assert!(mesh.colors.is_none());
+    assert!(mesh.normals.iter().all(|normal| *normal == Vec3::Z));
+}
+
+#[test]
+fn unsupported_ply_is_an_error() {
+    let big_endian = b"ply\nformat binary_big_endian 1.0\nelement vertex 0\nend_header\n";
+    assert!(parse_ply(big_endian).unwrap_err().contains("binary_big_endian"));
+
+    let truncated = &binary_cube()[..300];
+    assert!(parse_ply(truncated).is_err());
+
+    let out_of_range = "ply\nformat ascii 1.0\nelement vertex 1\nproperty float x\nproperty float y\nproperty float z\n\
+element face 1\nproperty list uchar int vertex_indices\nend_header\n0 0 0\n3 0 1 2\n";
+    assert!(parse_ply(out_of_range.as_bytes()).is_err());
+
+    assert!(parse_ply(b"solid not a ply").is_err());
+    assert!(load_ply("scenes/does_not_exist.ply").is_err());
+}
+
+#[test]
+fn ply_scenes_load_as_worlds() {
+    let path = std::env::temp_dir().join("rustic_ply_world_test.ply");
+    std::fs::write(&path, binary_cube()).unwrap();
+    let world = rustic::asset::World::from_path(path.to_str().unwrap()).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(world.index_buffer.len(), 12);
+    assert_eq!(world.material_data_buffer.len(), 1);
+}
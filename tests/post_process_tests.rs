@@ -0,0 +1,167 @@
+use rustic::post::*;
+
+const SIZE: usize = 64;
+
+fn pixel(image: &[f32], x: usize, y: usize) -> [f32; 3] {
+    let i = (y * SIZE + x) * 3;
+    [image[i], image[i + 1], image[i + 2]]
+}
+
+fn image_with_bright_pixel(background: f32, bright: f32) -> Vec<f32> {
+    let mut image = vec![background; SIZE * SIZE * 3];
+    let i = (SIZE / 2 * SIZE + SIZE / 2) * 3;
+    image[i..i + 3].copy_from_slice(&[bright; 3]);
+    image
+}
+
+#[test]
+fn bloom_creates_halo_around_bright_pixel() {
+    let mut image = image_with_bright_pixel(0.0, 1000.0);
+    bloom(SIZE, SIZE, &mut image, 1.0, 0.5);
+
+    // Neighbours light up, falling off with distance
+    let near = pixel(&image, SIZE / 2 + 2, SIZE / 2)[0];
+    let far = pixel(&image, SIZE / 2 + 12, SIZE / 2)[0];
+    assert!(near > 0.0);
+    assert!(far > 0.0);
+    assert!(near > far);
+
+    // Bloom is added on top, so the image only gets brighter
+    assert!(pixel(&image, SIZE / 2, SIZE / 2)[0] >= 1000.0);
+}
+
+#[test]
+fn bloom_ignores_pixels_below_threshold() {
+    let original = image_with_bright_pixel(0.25, 0.9);
+    let mut image = original.clone();
+    bloom(SIZE, SIZE, &mut image, 1.0, 1.0);
+    assert_eq!(image, original);
+}
+
+#[test]
+fn vignette_darkens_corners() {
+    let mut image = vec![1.0; SIZE * SIZE * 3];
+    vignette(SIZE, SIZE, &mut image, 0.5);
+
+    let center = pixel(&image, SIZE / 2, SIZE / 2)[0];
+    let edge = pixel(&image, 0, SIZE / 2)[0];
+    let corner = pixel(&image, 0, 0)[0];
+    assert!(center > 0.99);
+    assert!(edge < center);
+    assert!(corner < edge);
+    assert!((corner - 0.5).abs() < 0.05);
+}
+
+#[test]
+fn exposure_scales_by_powers_of_two() {
+    let mut image = vec![0.5, 1.0, 2.0];
+    apply_exposure(&mut image, 1.0);
+    assert_eq!(image, vec![1.0, 2.0, 4.0]);
+    apply_exposure(&mut image, -2.0);
+    assert_eq!(image, vec![0.25, 0.5, 1.0]);
+    apply_exposure(&mut image, 0.0);
+    assert_eq!(image, vec![0.25, 0.5, 1.0]);
+}
+
+#[test]
+fn lens_effects_disabled_by_default() {
+    let original = image_with_bright_pixel(0.5, 100.0);
+    let mut image = original.clone();
+    apply_post_processing(&PostProcessConfig::default(), SIZE, SIZE, &mut image);
+    assert_eq!(image, original);
+}
+
+#[test]
+fn chromatic_aberration_separates_channels() {
+    let mut image = image_with_bright_pixel(0.0, 1.0);
+    let offset = SIZE / 4;
+    let i = ((SIZE / 2) * SIZE + SIZE / 2 + offset) * 3;
+    image[i..i + 3].copy_from_slice(&[1.0; 3]);
+    chromatic_aberration(SIZE, SIZE, &mut image, 5.0);
+
+    // Green stays put, while red and blue are displaced off-center
+    let p = pixel(&image, SIZE / 2 + offset, SIZE / 2);
+    assert_eq!(p[1], 1.0);
+    assert!(p[0] < 1.0);
+    assert!(p[2] < 1.0);
+}
+
+#[test]
+fn resize_bilinear_upscales_preview() {
+    // 2x2 checker of distinct colors, upscaled to 4x4
+    let src = vec![0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 2.0, 2.0, 2.0, 3.0, 3.0, 3.0];
+    let mut dst = vec![0.0; 4 * 4 * 3];
+    resize_bilinear(&src, 2, 2, &mut dst, 4, 4);
+
+    // Corners keep the source values, and everything stays within the source range
+    assert_eq!(dst[0], 0.0);
+    assert_eq!(dst[(4 * 4 - 1) * 3], 3.0);
+    assert!(dst.iter().all(|&c| (0.0..=3.0).contains(&c)));
+    // Interpolated values in between
+    assert_eq!(dst[3], 0.25);
+}
+
+#[test]
+fn despeckle_removes_isolated_fireflies() {
+    // Noisy gradient background, with a firefly in the middle and one in a corner
+    let mut original = vec![0.0; SIZE * SIZE * 3];
+    for (i, c) in original.iter_mut().enumerate() {
+        *c = 0.2 + (i / 3 % SIZE) as f32 * 0.01 + (i * 7919 % 13) as f32 * 0.005;
+    }
+    let mut image = original.clone();
+    for (x, y) in [(SIZE / 2, SIZE / 2), (0, 0)] {
+        let i = (y * SIZE + x) * 3;
+        image[i..i + 3].copy_from_slice(&[500.0, 200.0, 100.0]);
+    }
+    despeckle(SIZE, SIZE, &mut image, DEFAULT_DESPECKLE_THRESHOLD);
+
+    // The fireflies take on a value from their neighbourhood, everything else is untouched
+    for (x, y) in [(SIZE / 2, SIZE / 2), (0, 0)] {
+        assert!(pixel(&image, x, y).iter().all(|&c| c < 1.0), "firefly at {}, {} survived", x, y);
+    }
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            if (x, y) != (SIZE / 2, SIZE / 2) && (x, y) != (0, 0) {
+                assert_eq!(pixel(&image, x, y), pixel(&original, x, y));
+            }
+        }
+    }
+}
+
+#[test]
+fn despeckle_keeps_edges() {
+    // Bright half next to a dark half, where every bright pixel has plenty of bright neighbours
+    let mut image = vec![0.01; SIZE * SIZE * 3];
+    for y in 0..SIZE {
+        for x in SIZE / 2..SIZE {
+            let i = (y * SIZE + x) * 3;
+            image[i..i + 3].copy_from_slice(&[10.0; 3]);
+        }
+    }
+    let original = image.clone();
+    despeckle(SIZE, SIZE, &mut image, DEFAULT_DESPECKLE_THRESHOLD);
+    assert_eq!(image, original);
+}
+
+#[test]
+fn white_balance_at_6500k_is_near_identity() {
+    let gain = white_balance_gain(6500.0, 0.0);
+    assert!(gain.iter().all(|c| (c - 1.0).abs() < 0.05), "{:?}", gain);
+}
+
+#[test]
+fn white_balance_corrects_warm_and_green_light() {
+    // Warm light is orange, so balancing for it turns the image blue, and the other way around for cool light
+    let warm = white_balance_gain(3200.0, 0.0);
+    assert!(warm[2] > warm[0], "{:?}", warm);
+    let cool = white_balance_gain(10000.0, 0.0);
+    assert!(cool[0] > cool[2], "{:?}", cool);
+    let magenta = white_balance_gain(6500.0, 1.0);
+    assert!(magenta[1] < magenta[0] && magenta[1] < magenta[2], "{:?}", magenta);
+
+    // White keeps its luminance
+    let mut image = vec![1.0; SIZE * SIZE * 3];
+    white_balance(&mut image, warm);
+    let luminance = pixel(&image, 0, 0)[0] * 0.2126 + pixel(&image, 0, 0)[1] * 0.7152 + pixel(&image, 0, 0)[2] * 0.0722;
+    assert!((luminance - 1.0).abs() < 1e-4);
+}
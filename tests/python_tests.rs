@@ -0,0 +1,21 @@
+#![cfg(feature = "pyo3")]
+
+use pyo3::Python;
+use rustic::python::framebuffer_to_ndarray;
+
+#[test]
+fn framebuffer_to_ndarray_is_rows_of_pixels() {
+    pyo3::prepare_freethreaded_python();
+    Python::with_gil(|py| {
+        // 2 wide and 3 tall, each value encoding its own row, column and channel
+        let (width, height) = (2, 3);
+        let framebuffer = (0..height * width * 3).map(|i| i as f32).collect::<Vec<_>>();
+        let array = framebuffer_to_ndarray(py, framebuffer, width, height).unwrap();
+        assert_eq!(array.shape(), [3, 2, 3]);
+        let array = array.readonly();
+        let array = array.as_array();
+        assert_eq!(array[[0, 0, 0]], 0.0);
+        assert_eq!(array[[0, 1, 2]], 5.0);
+        assert_eq!(array[[2, 1, 0]], 15.0);
+    });
+}
@@ -0,0 +1,17 @@
+use rustic::render::{combine_strata, t_quantile_95};
+
+#[test]
+fn strata_combine_into_mean_variance_and_confidence() {
+    let strata = vec![vec![1.0, 0.0, 2.0], vec![3.0, 0.0, 2.0], vec![5.0, 0.0, 2.0]];
+    let bootstrap = combine_strata(&strata);
+    assert_eq!(bootstrap.strata, 3);
+    assert_eq!(bootstrap.mean, vec![3.0, 0.0, 2.0]);
+    assert_eq!(bootstrap.variance, vec![4.0, 0.0, 0.0]);
+    assert!((bootstrap.confidence[0] - t_quantile_95(2) * (4.0f32 / 3.0).sqrt()).abs() < 1e-5);
+    assert_eq!(&bootstrap.confidence[1..], &[0.0, 0.0]);
+
+    // The quantile shrinks towards the normal one as the variance estimate gets better
+    assert!((t_quantile_95(1) - 12.706).abs() < 1e-3);
+    assert!((1..40).all(|degrees| t_quantile_95(degrees) > t_quantile_95(degrees + 1) || t_quantile_95(degrees + 1) == 1.96));
+    assert_eq!(t_quantile_95(1000), 1.96);
+}
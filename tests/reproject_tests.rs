@@ -0,0 +1,102 @@
+use glam::{Vec2, Vec4};
+use rustic::reproject::*;
+use rustic::trace::TracingConfig;
+
+const SIZE: u32 = 32;
+
+fn config(cam_position: Vec4, cam_rotation: Vec4) -> TracingConfig {
+    TracingConfig {
+        width: SIZE,
+        height: SIZE,
+        cam_position,
+        cam_rotation,
+        ..Default::default()
+    }
+}
+
+fn gradient_image() -> Vec<f32> {
+    (0..SIZE * SIZE).flat_map(|i| [i as f32; 3]).collect()
+}
+
+#[test]
+fn project_direction_inverts_primary_ray() {
+    let config = config(Vec4::ZERO, Vec4::new(0.3, -1.2, 0.0, 0.0));
+    for pixel in [Vec2::new(0.5, 0.5), Vec2::new(10.5, 20.5), Vec2::new(31.5, 3.5)] {
+        let direction = primary_ray_direction(&config, pixel);
+        let projected = project_direction(&config, direction).unwrap();
+        assert!((projected - pixel).length() < 1e-3);
+    }
+}
+
+#[test]
+fn pixel_aspect_squashes_horizontally() {
+    let square = config(Vec4::ZERO, Vec4::ZERO);
+    let anamorphic = TracingConfig { pixel_aspect: 2.0, ..square };
+    let diagonal = glam::Vec3::new(0.5, 0.5, 1.0);
+    let center = Vec2::splat(SIZE as f32 / 2.0);
+
+    // With square pixels a diagonal lands as far right as up, with wide pixels twice as far up
+    let offset = project_direction(&square, diagonal).unwrap() - center;
+    assert!((offset.x + offset.y).abs() < 1e-3, "{}", offset);
+    let offset = project_direction(&anamorphic, diagonal).unwrap() - center;
+    assert!((offset.x * 2.0 + offset.y).abs() < 1e-3, "{}", offset);
+
+    for pixel in [Vec2::new(0.5, 0.5), Vec2::new(10.5, 20.5), Vec2::new(31.5, 3.5)] {
+        let projected = project_direction(&anamorphic, primary_ray_direction(&anamorphic, pixel)).unwrap();
+        assert!((projected - pixel).length() < 1e-3);
+    }
+}
+
+#[test]
+fn reproject_static_camera_is_identity() {
+    let config = config(Vec4::new(0.0, 1.0, -5.0, 0.0), Vec4::new(0.1, 0.2, 0.0, 0.0));
+    let pixel_count = (SIZE * SIZE) as usize;
+    let color = gradient_image();
+    let depth = vec![3.0; pixel_count];
+    let normal = vec![0.0; pixel_count * 3];
+    let weight = vec![4.0; pixel_count];
+
+    let history = reproject(&config, &config, &color, &depth, &normal, &weight);
+    assert_eq!(history.color, color);
+    assert!(history.weight.iter().all(|&w| w == 4.0));
+    assert!(history.depth.iter().all(|&d| (d - 3.0).abs() < 1e-4));
+}
+
+#[test]
+fn reproject_rotation_shifts_sky() {
+    // Rotating by exactly the angle between two pixel centers on the horizon moves distant content by one pixel
+    let prev_config = config(Vec4::ZERO, Vec4::ZERO);
+    let pixel_count = (SIZE * SIZE) as usize;
+    let row = SIZE as f32 / 2.0 + 0.5;
+    let a = primary_ray_direction(&prev_config, Vec2::new(15.5, row));
+    let b = primary_ray_direction(&prev_config, Vec2::new(16.5, row));
+    let angle = a.angle_between(b);
+    let config = config(Vec4::ZERO, Vec4::new(0.0, angle, 0.0, 0.0));
+
+    let color = gradient_image();
+    let history = reproject(&prev_config, &config, &color, &vec![0.0; pixel_count], &vec![0.0; pixel_count * 3], &vec![1.0; pixel_count]);
+    let center = (SIZE / 2 * SIZE + SIZE / 2) as usize;
+    let moved_from = history.color[center * 3] as usize;
+    assert!(history.weight[center] > 0.0);
+    assert_eq!(moved_from / SIZE as usize, center / SIZE as usize);
+    assert_eq!((moved_from as isize - center as isize).abs(), 1);
+}
+
+#[test]
+fn blend_history_rejects_disocclusions() {
+    let config = config(Vec4::ZERO, Vec4::ZERO);
+    let pixel_count = (SIZE * SIZE) as usize;
+    let normal = vec![0.0, 1.0, 0.0].repeat(pixel_count);
+    let history = reproject(&config, &config, &vec![1.0; pixel_count * 3], &vec![2.0; pixel_count], &normal, &vec![3.0; pixel_count]);
+
+    // First half sees the same surface, second half sees something much closer
+    let mut depth = vec![2.0; pixel_count];
+    depth[pixel_count / 2..].fill(0.5);
+    let mut color = vec![0.0; pixel_count * 3];
+    let weights = blend_history(&history, 1.0, &mut color, &depth, &normal);
+
+    assert_eq!(color[0], 0.75);
+    assert_eq!(weights[0], 4.0);
+    assert_eq!(color[(pixel_count - 1) * 3], 0.0);
+    assert_eq!(weights[pixel_count - 1], 1.0);
+}
@@ -0,0 +1,326 @@
+use glam::{UVec4, Vec4};
+
+mod common;
+
+// Spread of a highlight along x and y, as the luminance weighted variance of the pixel positions
+fn highlight_spread(world: &rustic::asset::World, config: &shared_structs::TracingConfig, samples: u32) -> (f32, f32) {
+    use glam::Vec2;
+
+    let image = common::render_cpu(world, config, samples);
+    let weighted = image
+        .iter()
+        .enumerate()
+        .map(|(i, pixel)| (Vec2::new((i as u32 % config.width) as f32, (i as u32 / config.width) as f32), pixel.truncate().element_sum()))
+        .collect::<Vec<_>>();
+    let total = weighted.iter().map(|(_, weight)| weight).sum::<f32>();
+    let mean = weighted.iter().fold(Vec2::ZERO, |sum, (position, weight)| sum + *position * *weight) / total;
+    let variance = weighted.iter().fold(Vec2::ZERO, |sum, (position, weight)| sum + (*position - mean) * (*position - mean) * *weight) / total;
+    (variance.x, variance.y)
+}
+
+#[test]
+fn anisotropy_stretches_highlight_along_tangent() {
+    use glam::Vec3;
+    use rustic::scenes::SceneBuilder;
+
+    // A metal sphere lit by a small light just behind the camera, against a black background. Sphere tangents run
+    // around the up axis, so the highlight in the middle is stretched horizontally.
+    let build = |anisotropy: f32| {
+        let mut builder = SceneBuilder::default();
+        let mut metal = rustic::scenes::diffuse(Vec3::splat(0.9));
+        metal.metallic = Vec4::ONE;
+        metal.roughness = Vec4::splat(0.2);
+        metal.anisotropy = anisotropy;
+        let metal = builder.push_material(metal);
+        builder.push_sphere(Vec3::ZERO, 1.0, metal);
+        let mut light = rustic::scenes::diffuse(Vec3::ZERO);
+        light.emissive = Vec4::new(1.0, 1.0, 1.0, 50.0);
+        let light = builder.push_material(light);
+        builder.push_sphere(Vec3::new(0.0, 0.0, -3.6), 0.3, light);
+        builder.build()
+    };
+    let config = shared_structs::TracingConfig {
+        width: 48,
+        height: 48,
+        cam_position: Vec4::new(0.0, 0.0, -3.0, 0.0),
+        background_color: Vec4::new(0.0, 0.0, 0.0, 1.0),
+        ..Default::default()
+    };
+
+    let (iso_x, iso_y) = highlight_spread(&build(0.0), &config, 64);
+    let (aniso_x, aniso_y) = highlight_spread(&build(1.0), &config, 64);
+    assert!(iso_x / iso_y < 1.5, "isotropic highlights are round, spread {} by {}", iso_x, iso_y);
+    assert!(aniso_x / aniso_y > 2.0 * iso_x / iso_y, "anisotropic highlights are elongated, spread {} by {}", aniso_x, aniso_y);
+}
+
+#[test]
+fn subsurface_scattering_lets_light_through() {
+    use glam::Vec3;
+    use rustic::scenes::SceneBuilder;
+
+    // A wax sphere hiding a light directly behind it from the camera, against a black background. Without subsurface
+    // scattering, none of the light can reach the side facing the camera.
+    let build = |subsurface_radius: f32| {
+        let mut builder = SceneBuilder::default();
+        let mut wax = rustic::scenes::diffuse(Vec3::splat(0.9));
+        wax.subsurface = 1.0;
+        wax.subsurface_radius = Vec4::new(subsurface_radius, subsurface_radius, subsurface_radius, 0.0);
+        let wax = builder.push_material(wax);
+        builder.push_sphere(Vec3::ZERO, 1.0, wax);
+        let mut light = rustic::scenes::diffuse(Vec3::ZERO);
+        light.emissive = Vec4::new(1.0, 1.0, 1.0, 50.0);
+        let light = builder.push_material(light);
+        builder.push_sphere(Vec3::new(0.0, 0.0, 1.5), 0.3, light);
+        builder.build()
+    };
+    let config = shared_structs::TracingConfig {
+        width: 16,
+        height: 16,
+        cam_position: Vec4::new(0.0, 0.0, -3.0, 0.0),
+        background_color: Vec4::new(0.0, 0.0, 0.0, 1.0),
+        max_bounces: 8,
+        ..Default::default()
+    };
+    let total = |image: &[Vec4]| image.iter().map(|pixel| pixel.truncate().element_sum()).sum::<f32>();
+
+    let opaque = total(&common::render_cpu(&build(0.0), &config, 16));
+    let translucent = total(&common::render_cpu(&build(1.0), &config, 16));
+    assert_eq!(opaque, 0.0);
+    assert!(translucent.is_finite() && translucent > 0.0, "light should bleed through the sphere, got {}", translucent);
+}
+
+#[test]
+fn near_clip_cuts_away_geometry_close_to_the_camera() {
+    use glam::Vec3;
+    use rustic::scenes::SceneBuilder;
+
+    // A black wall half a unit in front of the camera, hiding a red light further away and a grey background
+    let mut builder = SceneBuilder::default();
+    let mut black = rustic::scenes::diffuse(Vec3::ZERO);
+    black.specular = 0.0;
+    let black = builder.push_material(black);
+    builder.push_quad([Vec3::new(-5.0, -5.0, -2.5), Vec3::new(5.0, -5.0, -2.5), Vec3::new(5.0, 5.0, -2.5), Vec3::new(-5.0, 5.0, -2.5)], -Vec3::Z, black);
+    let mut light = rustic::scenes::diffuse(Vec3::ZERO);
+    light.emissive = Vec4::new(1.0, 0.0, 0.0, 1.0);
+    let light = builder.push_material(light);
+    builder.push_sphere(Vec3::ZERO, 0.5, light);
+    let world = builder.build();
+    let mut config = shared_structs::TracingConfig {
+        width: 16,
+        height: 16,
+        cam_position: Vec4::new(0.0, 0.0, -3.0, 0.0),
+        background_color: Vec4::new(0.5, 0.5, 0.5, 1.0),
+        ..Default::default()
+    };
+    let (center, corner) = ((8 * 16 + 8) as usize, 0);
+
+    let unclipped = common::render_cpu(&world, &config, 4);
+    assert_eq!(unclipped[center].truncate(), Vec3::ZERO);
+    assert_eq!(unclipped[corner].truncate(), Vec3::ZERO);
+
+    // The wall is only half a unit away along the view direction, but further along rays towards the corners, which
+    // still have to be clipped
+    config.near_clip = 1.0;
+    let clipped = common::render_cpu(&world, &config, 4);
+    assert!(clipped[center].truncate().abs_diff_eq(Vec3::X, 1e-4), "the light should be seen, got {}", clipped[center]);
+    assert!(clipped[corner].truncate().abs_diff_eq(Vec3::splat(0.5), 1e-4), "the background should be seen, got {}", clipped[corner]);
+}
+
+// Counts the rays cast by every sample
+struct RayCounter(u64);
+
+impl kernels::stats::BounceStats for RayCounter {
+    fn record(&mut self, _bounce: u32, _throughput: glam::Vec3) {}
+    fn record_rays(&mut self, counts: kernels::stats::RayCounts) {
+        self.0 += counts.rays as u64;
+    }
+}
+
+#[test]
+fn albedo_russian_roulette_ends_paths_sooner_on_dark_surfaces() {
+    use glam::Vec3;
+    use rustic::scenes::SceneBuilder;
+    use shared_structs::RussianRoulette;
+
+    // A pit open to a white sky, with a bright floor, two bright walls and two dark ones, seen from above. Paths bounce
+    // around inside it for a while, off both kinds of material.
+    let mut builder = SceneBuilder::default();
+    let bright = builder.push_material(rustic::scenes::diffuse(Vec3::splat(0.9)));
+    let dark = builder.push_material(rustic::scenes::diffuse(Vec3::splat(0.1)));
+    let corner = |x: f32, y: f32, z: f32| Vec3::new(x, y, z);
+    builder.push_quad([corner(-1.0, 0.0, -1.0), corner(1.0, 0.0, -1.0), corner(1.0, 0.0, 1.0), corner(-1.0, 0.0, 1.0)], Vec3::Y, bright);
+    builder.push_quad([corner(-1.0, 0.0, -1.0), corner(-1.0, 0.0, 1.0), corner(-1.0, 1.0, 1.0), corner(-1.0, 1.0, -1.0)], Vec3::X, bright);
+    builder.push_quad([corner(1.0, 0.0, -1.0), corner(1.0, 0.0, 1.0), corner(1.0, 1.0, 1.0), corner(1.0, 1.0, -1.0)], -Vec3::X, dark);
+    builder.push_quad([corner(-1.0, 0.0, -1.0), corner(1.0, 0.0, -1.0), corner(1.0, 1.0, -1.0), corner(-1.0, 1.0, -1.0)], Vec3::Z, bright);
+    builder.push_quad([corner(-1.0, 0.0, 1.0), corner(1.0, 0.0, 1.0), corner(1.0, 1.0, 1.0), corner(-1.0, 1.0, 1.0)], -Vec3::Z, dark);
+    let world = builder.build();
+
+    let render = |russian_roulette: RussianRoulette| {
+        let config = shared_structs::TracingConfig {
+            width: 16,
+            height: 16,
+            cam_position: Vec4::new(0.0, 1.0, 0.0, 0.0),
+            cam_rotation: Vec4::new(std::f32::consts::FRAC_PI_2, 0.0, 0.0, 0.0),
+            background_color: Vec4::new(1.0, 1.0, 1.0, 1.0),
+            min_bounces: 0,
+            max_bounces: 8,
+            russian_roulette: russian_roulette.to_u32(),
+            ..Default::default()
+        };
+        let mut rays = RayCounter(0);
+        let image = common::render_cpu_with(&world, &world.index_buffer, &config, 256, &mut rays);
+        let total = image.iter().map(|pixel| pixel.truncate().element_sum()).sum::<f32>();
+        (total, rays.0)
+    };
+
+    // Both are unbiased, so they converge to the same image, but albedo gives up on paths sooner
+    let (throughput_total, throughput_rays) = render(RussianRoulette::Throughput);
+    let (albedo_total, albedo_rays) = render(RussianRoulette::Albedo);
+    assert!(throughput_total > 0.0 && albedo_total.is_finite());
+    assert!((albedo_total - throughput_total).abs() < 0.1 * throughput_total, "albedo gave {} but throughput {}", albedo_total, throughput_total);
+    assert!(albedo_rays < throughput_rays, "albedo cast {} rays but throughput {}", albedo_rays, throughput_rays);
+}
+
+#[test]
+fn lights_shine_through_blockers_without_shadows() {
+    use glam::Vec3;
+    use rustic::scenes::SceneBuilder;
+
+    // A wall facing the camera, lit by a light above the camera, with a blocker between the light and the wall that
+    // the camera can see past
+    let mut builder = SceneBuilder::default();
+    let grey = builder.push_material(rustic::scenes::diffuse(Vec3::splat(0.8)));
+    let wall = [Vec3::new(-3.0, -3.0, 1.0), Vec3::new(3.0, -3.0, 1.0), Vec3::new(3.0, 3.0, 1.0), Vec3::new(-3.0, 3.0, 1.0)];
+    builder.push_quad(wall, -Vec3::Z, grey);
+    let blocker = [Vec3::new(-2.0, 1.0, 0.5), Vec3::new(2.0, 1.0, 0.5), Vec3::new(2.0, 2.0, 0.5), Vec3::new(-2.0, 2.0, 0.5)];
+    builder.push_quad(blocker, -Vec3::Z, grey);
+    let mut light = rustic::scenes::diffuse(Vec3::ZERO);
+    light.emissive = Vec4::new(1.0, 1.0, 1.0, 50.0);
+    let light = builder.push_material(light);
+    builder.push_sphere(Vec3::new(0.0, 3.0, 0.0), 0.3, light);
+    let world = builder.build();
+
+    let mut config = shared_structs::TracingConfig {
+        width: 16,
+        height: 16,
+        cam_position: Vec4::new(0.0, 0.0, -3.0, 0.0),
+        background_color: Vec4::new(0.0, 0.0, 0.0, 1.0),
+        nee: shared_structs::NextEventEstimation::DirectLightSampling.to_u32(),
+        min_bounces: 0,
+        max_bounces: 1,
+        ..Default::default()
+    };
+    let center = |image: &[Vec4]| image[7 * 16 + 7] + image[7 * 16 + 8] + image[8 * 16 + 7] + image[8 * 16 + 8];
+
+    let shadowed = center(&common::render_cpu(&world, &config, 4));
+    config.shadows = 0;
+    let unshadowed = center(&common::render_cpu(&world, &config, 4));
+    assert_eq!(shadowed.truncate(), Vec3::ZERO);
+    assert!(unshadowed.x > 0.0, "the light should reach the wall through the blocker");
+}
+
+// Irradiance at `point` on a surface facing `normal`, from a polygon of unit radiance, by Lambert's formula
+fn polygon_irradiance(point: glam::Vec3, normal: glam::Vec3, polygon: &[glam::Vec3]) -> f32 {
+    let mut sum = 0.0;
+    for i in 0..polygon.len() {
+        let a = (polygon[i] - point).normalize();
+        let b = (polygon[(i + 1) % polygon.len()] - point).normalize();
+        sum += a.angle_between(b) * a.cross(b).normalize().dot(normal);
+    }
+    (sum * 0.5).abs()
+}
+
+#[test]
+fn triangle_light_matches_analytic_irradiance() {
+    use glam::{Vec2, Vec3};
+    use rustic::asset::World;
+
+    // A white Lambertian floor under a triangle light facing down. The light is smooth shaded with vertex normals
+    // tilted outwards, which must not change how much light it gives off.
+    let light = [Vec3::new(-1.0, 1.0, -1.0), Vec3::new(1.0, 1.0, -1.0), Vec3::new(0.0, 1.0, 1.5)];
+    let centroid = (light[0] + light[1] + light[2]) / 3.0;
+    let mut vertices = vec![
+        Vec3::new(-10.0, 0.0, -10.0),
+        Vec3::new(-10.0, 0.0, 10.0),
+        Vec3::new(10.0, 0.0, 10.0),
+        Vec3::new(10.0, 0.0, -10.0),
+    ];
+    let mut normals = vec![Vec4::new(0.0, 1.0, 0.0, 0.0); 4];
+    for corner in light {
+        vertices.push(corner);
+        normals.push((-Vec3::Y + (corner - centroid) * 0.5).normalize().extend(0.0));
+    }
+    let mut floor = rustic::scenes::diffuse(Vec3::ONE);
+    floor.specular = 0.0; // No Fresnel, so the floor reflects exactly albedo / pi
+    let mut emitter = rustic::scenes::diffuse(Vec3::ZERO);
+    emitter.emissive = Vec4::new(1.0, 1.0, 1.0, 5.0);
+    let world = World::from_geometry(
+        vertices,
+        vec![UVec4::new(0, 1, 2, 0), UVec4::new(0, 2, 3, 0), UVec4::new(4, 5, 6, 1)],
+        normals,
+        vec![Vec4::X; 7],
+        vec![Vec2::ZERO; 7],
+        Vec::new(),
+        Vec::new(),
+        Vec::new(),
+        vec![floor, emitter],
+        Vec::new(),
+    );
+
+    // Looking straight down from below the light, the center 2x2 pixels see the floor within 1/16 of the origin
+    let config = shared_structs::TracingConfig {
+        width: 16,
+        height: 16,
+        cam_position: Vec4::new(0.0, 0.5, 0.0, 0.0),
+        cam_rotation: Vec4::new(std::f32::consts::FRAC_PI_2, 0.0, 0.0, 0.0),
+        background_color: Vec4::new(0.0, 0.0, 0.0, 1.0),
+        nee: shared_structs::NextEventEstimation::DirectLightSampling.to_u32(),
+        min_bounces: 0,
+        max_bounces: 1,
+        ..Default::default()
+    };
+    let image = common::render_cpu(&world, &config, 256);
+    let rendered = (image[7 * 16 + 7] + image[7 * 16 + 8] + image[8 * 16 + 7] + image[8 * 16 + 8]).x / 4.0;
+
+    let mut irradiance = 0.0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let point = Vec3::new(x as f32 - 3.5, 0.0, y as f32 - 3.5) / 64.0;
+            irradiance += polygon_irradiance(point, Vec3::Y, &light) / 64.0;
+        }
+    }
+    let expected = 5.0 * irradiance / std::f32::consts::PI;
+    assert!((rendered - expected).abs() < expected * 0.03, "rendered {}, expected {}", rendered, expected);
+}
+
+#[test]
+fn orthographic_camera_keeps_the_size_of_things_with_distance() {
+    use glam::Vec3;
+    use rustic::scenes::SceneBuilder;
+
+    // A light to the right of the view direction, which an orthographic camera sees in the same place from any distance
+    let mut builder = SceneBuilder::default();
+    let mut light = rustic::scenes::diffuse(Vec3::ZERO);
+    light.emissive = Vec4::new(1.0, 1.0, 1.0, 1.0);
+    let light = builder.push_material(light);
+    builder.push_sphere(Vec3::new(1.0, 0.0, 0.0), 0.5, light);
+    let world = builder.build();
+
+    // 2 units on either side of the center across 16 pixels, so the light is centered on pixel 12
+    let lit = |config: &shared_structs::TracingConfig| {
+        let image = common::render_cpu(&world, config, 4);
+        (0..16).filter(|&x| image[8 * 16 + x].x > 0.5).collect::<Vec<_>>()
+    };
+    let mut config = shared_structs::TracingConfig {
+        width: 16,
+        height: 16,
+        cam_position: Vec4::new(0.0, 0.0, -5.0, 0.0),
+        background_color: Vec4::new(0.0, 0.0, 0.0, 1.0),
+        ortho_height: 2.0,
+        ..Default::default()
+    };
+    let near = lit(&config);
+    assert!(near.contains(&11) && near.contains(&12) && !near.contains(&8) && !near.contains(&14), "lit pixels {:?}", near);
+    config.cam_position.z = -500.0;
+    assert_eq!(lit(&config), near);
+}
@@ -0,0 +1,109 @@
+use glam::Vec3;
+use kernels::stats::{BounceStats, RayCounts, WorkgroupBounceStats, WORKGROUP_INVOCATIONS};
+use rustic::asset::SceneFile;
+use rustic::trace::{noise_estimate, ThroughputStats};
+use shared_structs::{NextEventEstimation, BOUNCE_STATS_LEN, RAY_STATS_ENTRY};
+
+mod common;
+
+// The invocations of a workgroup run one after another here, which the barriers between the phases allow
+#[test]
+fn workgroup_stats_are_added_up_once_per_workgroup() {
+    let mut stats = vec![0; BOUNCE_STATS_LEN];
+    stats[RAY_STATS_ENTRY] = u32::MAX; // Carries into the high word
+    let mut counters = [7; BOUNCE_STATS_LEN]; // Shared memory starts out undefined
+    for local_index in 0..WORKGROUP_INVOCATIONS - 1 {
+        WorkgroupBounceStats::new(&mut counters, &stats, local_index);
+    }
+    let mut workgroup_stats = WorkgroupBounceStats::new(&mut counters, &stats, WORKGROUP_INVOCATIONS - 1);
+    for _ in 0..3 {
+        workgroup_stats.record(0, Vec3::ONE);
+        workgroup_stats.record_rays(RayCounts { rays: 2, primitive_tests: 5 });
+    }
+    for local_index in 0..WORKGROUP_INVOCATIONS {
+        workgroup_stats.flush(&mut stats, local_index);
+    }
+
+    let mut gathered = ThroughputStats::default();
+    gathered.add_buffer(&stats);
+    assert_eq!(gathered.paths(0), 3);
+    assert_eq!(gathered.mean_throughput(0), 1.0);
+    assert_eq!(gathered.rays(), u32::MAX as u64 + 6);
+    assert_eq!(gathered.primitive_tests(), 15);
+}
+
+#[test]
+fn workgroup_stats_are_off_without_a_buffer() {
+    let mut stats = vec![0; 1];
+    let mut counters = [0; BOUNCE_STATS_LEN];
+    let mut workgroup_stats = WorkgroupBounceStats::new(&mut counters, &stats, 0);
+    workgroup_stats.record_rays(RayCounts { rays: 1, primitive_tests: 1 });
+    workgroup_stats.flush(&mut stats, 0);
+    assert_eq!(stats, [0]);
+}
+
+#[test]
+fn throughput_stats_count_ended_paths_as_lost() {
+    let mut stats = ThroughputStats::default();
+    stats.record(0, Vec3::ONE);
+    stats.record(0, Vec3::ONE);
+    stats.record(1, Vec3::new(0.25, 0.5, 0.75));
+    assert_eq!(stats.paths(1), 1);
+    assert_eq!(stats.mean_throughput(0), 1.0);
+    assert_eq!(stats.mean_throughput(1), 0.25);
+    assert_eq!(stats.mean_surviving_throughput(1), 0.5);
+
+    // Kernels split sums over 2 words, and merging adds them up
+    let mut buffer = vec![0; BOUNCE_STATS_LEN];
+    buffer[..3].copy_from_slice(&[u32::MAX, 1, 2]);
+    let mut gathered = ThroughputStats::default();
+    gathered.add_buffer(&buffer);
+    gathered.merge(&stats);
+    assert_eq!(gathered.paths(0), 4);
+    let sum = (u32::MAX as u64 + (1 << 32)) as f64 / shared_structs::BOUNCE_STATS_SCALE as f64 + 2.0;
+    assert!((gathered.mean_throughput(0) as f64 - sum / 4.0).abs() < 1e-3, "{}", gathered.mean_throughput(0));
+}
+
+#[test]
+fn ray_stats_count_every_ray_cast() {
+    // Kernels split the counts over 2 words each
+    let mut buffer = vec![0; BOUNCE_STATS_LEN];
+    buffer[RAY_STATS_ENTRY..].copy_from_slice(&[u32::MAX, 1, 7, 0]);
+    let mut gathered = ThroughputStats::default();
+    gathered.add_buffer(&buffer);
+    gathered.record_rays(RayCounts { rays: 1, primitive_tests: 3 });
+    assert_eq!(gathered.rays(), u32::MAX as u64 + (1 << 32) + 1);
+    assert_eq!(gathered.primitive_tests(), 10);
+
+    // Every path casts its camera ray, which hits a wall of the box, and at most a shadow ray per bounce on top
+    let world = rustic::asset::World::load(&[SceneFile::new(rustic::scenes::cornell::SCENE_NAME)], Default::default()).unwrap();
+    let config = shared_structs::TracingConfig {
+        width: 16,
+        height: 16,
+        cam_position: rustic::scenes::cornell::CAMERA_POSITION,
+        cam_rotation: rustic::scenes::cornell::CAMERA_ROTATION,
+        nee: NextEventEstimation::MultipleImportanceSampling.to_u32(),
+        ..Default::default()
+    };
+    let mut stats = ThroughputStats::default();
+    common::render_cpu_with(&world, &world.index_buffer, &config, 1, &mut stats);
+    let paths = stats.paths(0);
+    assert_eq!(paths, (config.width * config.height) as u64);
+    assert!(stats.rays() > paths, "{} rays for {} paths", stats.rays(), paths);
+    assert!(stats.rays() <= paths * 2 * config.max_bounces as u64);
+    assert!(stats.primitive_tests() >= paths);
+}
+
+#[test]
+fn welford_matches_two_pass_variance() {
+    let values = [0.5f32, 2.0, 0.0, 10.0, 3.25, 1.0];
+    let state = values.iter().fold(glam::Vec4::ZERO, |state, v| shared_structs::welford_update(state, glam::Vec3::splat(*v)));
+
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+    let variance = values.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / (values.len() - 1) as f32;
+    assert!((state.y - mean).abs() < 1e-5);
+    assert!((shared_structs::welford_variance_of_mean(state) - variance / values.len() as f32).abs() < 1e-5);
+
+    assert_eq!(noise_estimate(&[]), 0.0);
+    assert_eq!(noise_estimate(&[shared_structs::welford_update(glam::Vec4::ZERO, glam::Vec3::ONE)]), 0.0);
+}
@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use rustic::timer::GpuTimer;
+use rustic::trace::{StageTimings, TimingMethod};
+
+// A device with timestamp queries if the adapter has them, or without them if `timestamps` is false
+fn device(timestamps: bool) -> (wgpu::Device, wgpu::Queue) {
+    let instance = wgpu::Instance::new(wgpu::Backends::PRIMARY);
+    let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default())).unwrap();
+    let features = if timestamps { adapter.features() & wgpu::Features::TIMESTAMP_QUERY } else { wgpu::Features::empty() };
+    let descriptor = wgpu::DeviceDescriptor { label: None, features, limits: adapter.limits() };
+    pollster::block_on(adapter.request_device(&descriptor, None)).unwrap()
+}
+
+#[test]
+fn gpu_timer_needs_timestamp_queries() {
+    let (device, queue) = device(false);
+    assert!(GpuTimer::new(&device, &queue).is_none());
+}
+
+#[test]
+fn gpu_timer_times_submitted_work() {
+    let (device, queue) = device(true);
+    let Some(mut timer) = GpuTimer::new(&device, &queue) else {
+        return; // The adapter doesn't support them, which the test above covers
+    };
+    let size = 16 << 20;
+    let buffer = |usage| device.create_buffer(&wgpu::BufferDescriptor { label: None, size, usage, mapped_at_creation: false });
+    let (source, destination) = (buffer(wgpu::BufferUsages::COPY_SRC), buffer(wgpu::BufferUsages::COPY_DST));
+
+    // More copies than the timer holds at once, so it has to read some back in between
+    for _ in 0..100 {
+        timer.time(|| {
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+            encoder.copy_buffer_to_buffer(&source, 0, &destination, 0, size);
+            queue.submit(Some(encoder.finish()));
+        });
+    }
+    assert!(timer.elapsed() > Duration::ZERO);
+    assert_eq!(timer.elapsed(), Duration::ZERO, "starts over once read");
+}
+
+#[test]
+fn stage_timings_report_how_they_were_measured() {
+    let cpu = StageTimings::default().to_string();
+    assert!(cpu.contains("render") && cpu.ends_with("Timed with the CPU clock\n"), "{}", cpu);
+    let timestamps = StageTimings { render_method: TimingMethod::GpuTimestamps, ..Default::default() }.to_string();
+    assert!(timestamps.contains("timestamp queries") && !timestamps.contains("doesn't support"), "{}", timestamps);
+    let fallback = StageTimings { render_method: TimingMethod::CpuFallback, ..Default::default() }.to_string();
+    assert!(fallback.contains("doesn't support timestamp queries"), "{}", fallback);
+}
@@ -0,0 +1,42 @@
+use glam::Vec3;
+use rustic::app::Tonemapping;
+use rustic::tonemap::*;
+
+#[test]
+fn agx_keeps_neutral_colors_neutral() {
+    for value in [0.0, 0.01, 0.18, 1.0, 10.0, 1000.0] {
+        let mapped = agx(Vec3::splat(value));
+        assert!(mapped.max_element() - mapped.min_element() < 1e-3, "{value} mapped to {mapped}");
+    }
+}
+
+#[test]
+fn agx_is_monotonic_for_neutral_colors() {
+    let mut prev = 0.0;
+    for i in 0..100 {
+        let mapped = agx(Vec3::splat(i as f32 * 0.5)).x;
+        assert!(mapped >= prev);
+        prev = mapped;
+    }
+}
+
+#[test]
+fn agx_desaturates_bright_colors() {
+    // A dim primary stays saturated
+    let dim = agx(Vec3::new(1.0, 0.0, 0.0));
+    assert!(dim.x > 0.5 && dim.y < 0.1 && dim.z < 0.1);
+
+    // A very bright primary goes towards white instead of clipping to the primary
+    for bright in [Vec3::new(1000.0, 0.0, 0.0), Vec3::new(0.0, 1000.0, 0.0), Vec3::new(0.0, 0.0, 1000.0)] {
+        let mapped = agx(bright);
+        assert!(mapped.min_element() > 0.9, "{bright} mapped to {mapped}");
+    }
+}
+
+#[test]
+fn tonemapping_names() {
+    assert_eq!(Tonemapping::from_name("agx"), Some(Tonemapping::AgX));
+    assert_eq!(Tonemapping::from_name("aces"), Some(Tonemapping::ACESNarkowicz));
+    assert_eq!(Tonemapping::from_name("none"), Some(Tonemapping::None));
+    assert_eq!(Tonemapping::from_name("filmic"), None);
+}
@@ -0,0 +1,116 @@
+use glam::{Vec3, Vec4};
+use rustic::asset::SceneFile;
+use rustic::trace::{black_render_diagnostics, frame_rng_seeds, BounceController, SceneSummary, TracingConfig, TracingState};
+
+#[test]
+fn black_render_diagnostics_explain_black_images() {
+    let config = TracingConfig {
+        width: 40,
+        height: 30,
+        cam_position: Vec4::new(0.0, 0.0, -5.0, 0.0),
+        background_color: Vec4::new(0.0, 0.0, 0.0, 1.0),
+        ..Default::default()
+    };
+    let black = vec![0.0; 40 * 30 * 3];
+    let missed = vec![0.0; 40 * 30];
+    let unlit = SceneSummary { lights: 0, bounds: Some((Vec3::splat(-1.0), Vec3::splat(1.0))) };
+
+    let lines = black_render_diagnostics(&black, &missed, &config, &unlit).expect("the image is black");
+    assert!(lines.iter().any(|line| line.contains("no emissive geometry")), "{:?}", lines);
+    assert!(lines.iter().any(|line| line.contains("background is black")), "{:?}", lines);
+    assert!(lines.iter().any(|line| line.contains("No primary rays hit")), "{:?}", lines);
+    assert!(!lines.iter().any(|line| line.contains("behind the camera")), "{:?}", lines);
+
+    // Facing away from the scene
+    let turned = TracingConfig { cam_rotation: Vec4::new(0.0, std::f32::consts::PI, 0.0, 0.0), ..config };
+    let lines = black_render_diagnostics(&black, &missed, &turned, &unlit).unwrap();
+    assert!(lines.iter().any(|line| line.contains("behind the camera")), "{:?}", lines);
+
+    // A single lit pixel is enough for the image not to count as black
+    let mut lit = black.clone();
+    lit[(15 * 40 + 20) * 3 + 1] = 1.0;
+    assert!(black_render_diagnostics(&lit, &missed, &config, &unlit).is_none());
+
+    let cornell = rustic::asset::World::load(&[SceneFile::new(rustic::scenes::cornell::SCENE_NAME)], Default::default()).unwrap();
+    let summary = SceneSummary::of(&cornell);
+    assert!(summary.lights > 0 && summary.bounds.is_some());
+    let hit = vec![1.0; 40 * 30];
+    let lines = black_render_diagnostics(&black, &hit, &config, &summary).unwrap();
+    assert!(lines.iter().any(|line| line.contains(&format!("{} emissive primitives", summary.lights))), "{:?}", lines);
+    assert!(lines.iter().any(|line| line.contains("100.0% of pixels")), "{:?}", lines);
+}
+
+#[test]
+fn static_noise_repeats_across_frames() {
+    let seeds = (0..64).map(|i| glam::UVec2::new(0, i * 12345)).collect::<Vec<_>>();
+    assert_eq!(frame_rng_seeds(&seeds, 0, false), frame_rng_seeds(&seeds, 7, false));
+    assert_eq!(frame_rng_seeds(&seeds, 0, true), seeds);
+
+    let frame_a = frame_rng_seeds(&seeds, 1, true);
+    let frame_b = frame_rng_seeds(&seeds, 2, true);
+    assert!(frame_a.iter().zip(frame_b.iter()).all(|(a, b)| a != b));
+}
+
+#[test]
+fn paused_state_only_takes_requested_steps() {
+    let state = TracingState::new(4, 4);
+    assert_eq!(state.take_samples(32), 32);
+
+    state.paused.store(true, std::sync::atomic::Ordering::Relaxed);
+    assert_eq!(state.take_samples(32), 0);
+    state.step();
+    state.step();
+    state.step();
+    assert_eq!(state.take_samples(2), 2);
+    assert_eq!(state.take_samples(32), 1);
+    assert_eq!(state.take_samples(32), 0);
+}
+
+#[test]
+fn bounce_controller_follows_frame_time() {
+    let config = TracingConfig { max_bounces: 4, ..Default::default() };
+    let slow = std::time::Duration::from_millis(100);
+    let fast = std::time::Duration::from_millis(1);
+    let mut controller = BounceController::new();
+
+    // Without a target the configured bounces are used
+    assert!(!controller.update(slow, 0, config.max_bounces));
+    assert_eq!(controller.apply(config).max_bounces, 4);
+
+    // Slow frames at 30 FPS drop a bounce at a time, but always keep one
+    for _ in 0..10 {
+        controller.update(slow, 30, config.max_bounces);
+    }
+    assert_eq!(controller.apply(config).max_bounces, 1);
+
+    // Fast frames raise it back, no further than the configured bounces
+    for _ in 0..10 {
+        controller.update(fast, 30, config.max_bounces);
+    }
+    assert_eq!(controller.apply(config).max_bounces, 4);
+
+    // Turning the target off goes straight back to the configured bounces
+    for _ in 0..10 {
+        controller.update(slow, 30, config.max_bounces);
+    }
+    assert!(controller.update(slow, 0, config.max_bounces));
+    assert_eq!(controller.apply(config).max_bounces, 4);
+}
+
+#[test]
+fn priority_mask_follows_brush() {
+    let state = TracingState::new(8, 8);
+    assert_eq!(state.read_priority_mask(64), vec![1; 64]);
+
+    state.paint_priority(glam::Vec2::new(2.0, 2.0), 1.0, 4);
+    let mask = state.read_priority_mask(64);
+    let painted = (0..64).filter(|&i| mask[i] == 4).collect::<Vec<_>>();
+    assert_eq!(painted, [9, 10, 17, 18], "pixels whose centers are under the brush");
+    assert_eq!(state.read_priority_mask(16), vec![1; 16], "masks don't apply at other resolutions");
+
+    state.paint_priority(glam::Vec2::new(2.0, 2.0), 1.0, 1);
+    assert_eq!(state.read_priority_mask(64), vec![1; 64]);
+    state.paint_priority(glam::Vec2::new(2.0, 2.0), 1.0, 4);
+    state.clear_priority();
+    assert_eq!(state.read_priority_mask(64), vec![1; 64]);
+}
@@ -0,0 +1,45 @@
+use glam::Vec3;
+use rand::Rng;
+use rustic::trace::working_space_to_linear_srgb;
+use shared_structs::{WorkingSpace, ACESCG_TO_LINEAR_SRGB, LINEAR_SRGB_TO_ACESCG};
+
+#[test]
+fn acescg_round_trips() {
+    let identity = ACESCG_TO_LINEAR_SRGB * LINEAR_SRGB_TO_ACESCG;
+    assert!(identity.abs_diff_eq(glam::Mat3::IDENTITY, 1e-5));
+
+    let mut rng = rand::thread_rng();
+    for _ in 0..1000 {
+        let color = Vec3::new(rng.gen_range(0.0..10.0), rng.gen_range(0.0..10.0), rng.gen_range(0.0..10.0));
+        let round_trip = WorkingSpace::AcesCg.to_linear_srgb(WorkingSpace::AcesCg.from_linear_srgb(color));
+        assert!(round_trip.abs_diff_eq(color, 1e-4 * color.max_element().max(1.0)), "{} became {}", color, round_trip);
+    }
+}
+
+#[test]
+fn acescg_keeps_neutral_colors() {
+    for gray in [0.0, 0.18, 1.0, 15.0] {
+        let color = Vec3::splat(gray);
+        assert!(WorkingSpace::AcesCg.from_linear_srgb(color).abs_diff_eq(color, 1e-5 * gray.max(1.0)));
+    }
+
+    // The sRGB gamut is inside ACEScg, so sRGB primaries stay positive
+    for primary in [Vec3::X, Vec3::Y, Vec3::Z] {
+        assert!(WorkingSpace::AcesCg.from_linear_srgb(primary).min_element() > 0.0);
+    }
+}
+
+#[test]
+fn output_is_converted_back_to_linear_srgb() {
+    let colors = [Vec3::new(0.9, 0.2, 0.1), Vec3::new(0.0, 0.5, 1.0)];
+    let mut image = colors.iter().flat_map(|c| WorkingSpace::AcesCg.from_linear_srgb(*c).to_array()).collect::<Vec<_>>();
+    working_space_to_linear_srgb(WorkingSpace::AcesCg, &mut image);
+    for (pixel, color) in image.chunks(3).zip(colors) {
+        assert!(Vec3::from_slice(pixel).abs_diff_eq(color, 1e-5));
+    }
+
+    // Colors outside of sRGB are clipped rather than going negative
+    let mut image = vec![0.0, 1.0, 0.0];
+    working_space_to_linear_srgb(WorkingSpace::AcesCg, &mut image);
+    assert!(image.iter().all(|c| *c >= 0.0));
+}